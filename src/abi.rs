@@ -0,0 +1,96 @@
+//! How values are passed between functions, following the System V x86-64
+//! calling convention so that mochi functions and C functions can call each
+//! other directly.
+
+use crate::adt::AdtTable;
+use crate::mir::Type;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgClass {
+    /// Not passed at all, like `void` returns.
+    Ignore,
+    /// Passed in a single integer register.
+    Scalar,
+    /// A struct split into this many pointer-sized words, each passed in its
+    /// own integer register.
+    Words(usize),
+    /// A struct of this many words passed in memory. Arguments are copied onto
+    /// the stack by the caller. Return values are written to memory owned by
+    /// the caller through the hidden `sret` pointer passed as the first
+    /// argument, which the callee also returns.
+    Memory(usize),
+}
+
+/// Structs up to this many words are passed in registers.
+const MAX_REGISTER_WORDS: u32 = 2;
+
+/// The integer registers arguments are passed in: `rdi`, `rsi`, `rdx`, `rcx`,
+/// `r8` and `r9`.
+const ARG_REGISTERS: usize = 6;
+
+pub fn classify(adts: &AdtTable, ty: &Type, pointer_bytes: u32) -> ArgClass {
+    if let Type::Unit = ty {
+        return ArgClass::Ignore;
+    }
+    let name = match ty {
+        Type::Name(name) if adts.get_struct(*name).is_some() => *name,
+        _ => return ArgClass::Scalar,
+    };
+    let layout = adts.struct_layout(name, pointer_bytes);
+    let words = layout.size.div_ceil(pointer_bytes);
+    if words <= MAX_REGISTER_WORDS {
+        ArgClass::Words(words as usize)
+    } else {
+        ArgClass::Memory(words as usize)
+    }
+}
+
+/// Where the words of the arguments are passed, as the machine parameters
+/// of a signature after the `sret` pointer.
+///
+/// Arguments are passed in registers until they run out, except for
+/// `Memory` structs, which always go on the stack. A struct that doesn't fit
+/// in the registers that are left goes on the stack as a whole, and the
+/// arguments after it can still use them. Cranelift assigns registers to the
+/// first parameters and the stack to the rest, so the words passed in
+/// registers come first, then unused parameters filling the registers that
+/// are left, then the words passed on the stack.
+#[derive(Debug, PartialEq)]
+pub struct ArgLayout {
+    /// The parameter every word of the arguments is passed as, in order.
+    pub positions: Vec<usize>,
+    /// The number of parameters, including the unused ones.
+    pub params: usize,
+}
+
+pub fn arg_layout(args: &[ArgClass], sret: bool) -> ArgLayout {
+    let registers = if sret { ARG_REGISTERS - 1 } else { ARG_REGISTERS };
+    let mut free = registers;
+    let mut in_registers = Vec::new();
+    for &class in args {
+        let (words, fits) = match class {
+            ArgClass::Ignore => continue,
+            ArgClass::Scalar => (1, free >= 1),
+            ArgClass::Words(n) => (n, free >= n),
+            ArgClass::Memory(n) => (n, false),
+        };
+        if fits {
+            free -= words;
+        }
+        in_registers.extend((0..words).map(|_| fits));
+    }
+
+    let used = registers - free;
+    let on_stack = in_registers.len() - used;
+    let (mut register, mut stack) = (0, registers);
+    let positions = in_registers
+        .into_iter()
+        .map(|fits| {
+            let next = if fits { &mut register } else { &mut stack };
+            *next += 1;
+            *next - 1
+        })
+        .collect();
+    let params = if on_stack == 0 { used } else { registers + on_stack };
+    ArgLayout { positions, params }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use symbol::Symbol;
+
+use crate::ast::{Decl, Enum, Struct, Type};
+
+#[derive(Debug)]
+pub struct EnumInfo {
+    pub name: Symbol,
+    pub variants: Vec<Symbol>,
+}
+
+#[derive(Debug)]
+pub struct CtorInfo {
+    pub enum_name: Symbol,
+    pub tag: usize,
+    pub fields: Vec<Type>,
+}
+
+#[derive(Debug)]
+pub struct StructInfo {
+    pub name: Symbol,
+    pub fields: Vec<(Symbol, Type)>,
+}
+
+impl StructInfo {
+    pub fn get_field(&self, name: Symbol) -> Option<(usize, &Type)> {
+        self.fields
+            .iter()
+            .enumerate()
+            .find(|(_, (field, _))| *field == name)
+            .map(|(i, (_, ty))| (i, ty))
+    }
+}
+
+/// Size, alignment and field offsets of an aggregate, in bytes.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    pub size: u32,
+    pub align: u32,
+    pub offsets: Vec<u32>,
+}
+
+/// Every user-defined type in the program, indexed by type name and by
+/// constructor name.
+#[derive(Debug, Default)]
+pub struct AdtTable {
+    enums: HashMap<Symbol, EnumInfo>,
+    ctors: HashMap<Symbol, CtorInfo>,
+    structs: HashMap<Symbol, StructInfo>,
+}
+
+#[derive(Debug)]
+pub enum AdtError {
+    DuplicateType(Symbol),
+    DuplicateCtor(Symbol),
+    DuplicateField(Symbol, Symbol),
+    RecursiveStruct(Symbol),
+}
+
+impl fmt::Display for AdtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdtError::DuplicateType(name) => write!(f, "type `{}` is defined more than once", name),
+            AdtError::DuplicateCtor(name) => {
+                write!(f, "constructor `{}` is defined more than once", name)
+            }
+            AdtError::DuplicateField(name, field) => {
+                write!(f, "field `{}` of `{}` is defined more than once", field, name)
+            }
+            AdtError::RecursiveStruct(name) => write!(
+                f,
+                "struct `{}` contains itself and would have infinite size",
+                name
+            ),
+        }
+    }
+}
+
+impl AdtTable {
+    pub fn new() -> Self {
+        AdtTable::default()
+    }
+
+    pub fn from_decls<'a>(decls: impl IntoIterator<Item = &'a Decl>) -> Result<Self, AdtError> {
+        let mut adts = AdtTable::new();
+        for decl in decls {
+            match decl {
+                Decl::Enum(enum_) => adts.insert_enum(enum_)?,
+                Decl::Struct(struct_) => adts.insert_struct(struct_)?,
+                Decl::Class(_) | Decl::Extern(_, _, _) | Decl::Func(_) | Decl::Instance(_) | Decl::Test(_) | Decl::Use(_, _) => (),
+            }
+        }
+        Ok(adts)
+    }
+
+    pub fn insert_enum(&mut self, enum_: &Enum) -> Result<(), AdtError> {
+        if self.is_type(enum_.name) {
+            return Err(AdtError::DuplicateType(enum_.name));
+        }
+        for (tag, variant) in enum_.variants.iter().enumerate() {
+            if self.ctors.contains_key(&variant.name) {
+                return Err(AdtError::DuplicateCtor(variant.name));
+            }
+            let info = CtorInfo {
+                enum_name: enum_.name,
+                tag,
+                fields: variant.fields.clone(),
+            };
+            self.ctors.insert(variant.name, info);
+        }
+        let info = EnumInfo {
+            name: enum_.name,
+            variants: enum_.variants.iter().map(|variant| variant.name).collect(),
+        };
+        self.enums.insert(enum_.name, info);
+        Ok(())
+    }
+
+    pub fn insert_struct(&mut self, struct_: &Struct) -> Result<(), AdtError> {
+        if self.is_type(struct_.name) {
+            return Err(AdtError::DuplicateType(struct_.name));
+        }
+        for (i, (field, _)) in struct_.fields.iter().enumerate() {
+            if struct_.fields[..i].iter().any(|(other, _)| other == field) {
+                return Err(AdtError::DuplicateField(struct_.name, *field));
+            }
+        }
+        let info = StructInfo {
+            name: struct_.name,
+            fields: struct_.fields.clone(),
+        };
+        self.structs.insert(struct_.name, info);
+        Ok(())
+    }
+
+    /// Rejects structs that contain themselves by value.
+    pub fn check_structs(&self) -> Result<(), AdtError> {
+        fn visit(adts: &AdtTable, name: Symbol, path: &mut Vec<Symbol>) -> Result<(), AdtError> {
+            if path.contains(&name) {
+                return Err(AdtError::RecursiveStruct(path[0]));
+            }
+            path.push(name);
+            for (_, ty) in &adts.structs[&name].fields {
+                if let Type::Name(field) = ty {
+                    if adts.structs.contains_key(field) {
+                        visit(adts, *field, path)?;
+                    }
+                }
+            }
+            path.pop();
+            Ok(())
+        }
+        for name in self.structs.keys() {
+            visit(self, *name, &mut Vec::new())?;
+        }
+        Ok(())
+    }
+
+    /// Every field type of every constructor and struct. The order is the
+    /// same as `field_types_mut` as long as the table isn't modified.
+    pub fn field_types(&self) -> impl Iterator<Item = &Type> {
+        let ctors = self.ctors.values().flat_map(|ctor| ctor.fields.iter());
+        let structs = self.structs.values().flat_map(|info| info.fields.iter().map(|(_, ty)| ty));
+        ctors.chain(structs)
+    }
+
+    pub fn field_types_mut(&mut self) -> impl Iterator<Item = &mut Type> {
+        let ctors = self.ctors.values_mut().flat_map(|ctor| ctor.fields.iter_mut());
+        let structs = self
+            .structs
+            .values_mut()
+            .flat_map(|info| info.fields.iter_mut().map(|(_, ty)| ty));
+        ctors.chain(structs)
+    }
+
+    pub fn get_enum(&self, name: Symbol) -> Option<&EnumInfo> {
+        self.enums.get(&name)
+    }
+
+    pub fn get_ctor(&self, name: Symbol) -> Option<&CtorInfo> {
+        self.ctors.get(&name)
+    }
+
+    pub fn get_struct(&self, name: Symbol) -> Option<&StructInfo> {
+        self.structs.get(&name)
+    }
+
+    /// Every enum, sorted by name.
+    pub fn enums(&self) -> Vec<&EnumInfo> {
+        let mut enums: Vec<_> = self.enums.values().collect();
+        enums.sort_by_key(|info| info.name.as_str());
+        enums
+    }
+
+    /// Every struct, sorted by name.
+    pub fn structs(&self) -> Vec<&StructInfo> {
+        let mut structs: Vec<_> = self.structs.values().collect();
+        structs.sort_by_key(|info| info.name.as_str());
+        structs
+    }
+
+    /// Returns the struct the type refers to, if it is one.
+    pub fn as_struct(&self, ty: &Type) -> Option<&StructInfo> {
+        match ty {
+            Type::Name(name) => self.structs.get(name),
+            _ => None,
+        }
+    }
+
+    /// Finds the structs that have a field with the given name.
+    pub fn structs_with_field(&self, field: Symbol) -> Vec<&StructInfo> {
+        let mut found: Vec<_> = self
+            .structs
+            .values()
+            .filter(|info| info.get_field(field).is_some())
+            .collect();
+        found.sort_by_key(|info| info.name.as_str());
+        found
+    }
+
+    pub fn is_type(&self, name: Symbol) -> bool {
+        self.enums.contains_key(&name) || self.structs.contains_key(&name)
+    }
+
+    /// Size and alignment of a value of the given type stored in memory, for a
+    /// target with the given pointer width. Integers are pointer-sized and
+    /// enums are pointers to their heap-allocated payload.
+    pub fn size_align(&self, ty: &Type, pointer_bytes: u32) -> (u32, u32) {
+        match ty {
+            Type::Bool => (1, 1),
+            Type::Unit => (0, 1),
+            Type::Name(name) if self.structs.contains_key(name) => {
+                let layout = self.struct_layout(*name, pointer_bytes);
+                (layout.size, layout.align)
+            }
+            _ => (pointer_bytes, pointer_bytes),
+        }
+    }
+
+    /// Lays out fields in declaration order with C rules: each field is
+    /// aligned to its own alignment and the size is rounded up to the largest
+    /// one.
+    pub fn layout<'a>(&self, fields: impl IntoIterator<Item = &'a Type>, pointer_bytes: u32) -> Layout {
+        let mut size = 0;
+        let mut align = 1;
+        let mut offsets = Vec::new();
+        for ty in fields {
+            let (field_size, field_align) = self.size_align(ty, pointer_bytes);
+            size = round_up(size, field_align);
+            offsets.push(size);
+            size += field_size;
+            align = align.max(field_align);
+        }
+        Layout {
+            size: round_up(size, align),
+            align,
+            offsets,
+        }
+    }
+
+    pub fn struct_layout(&self, name: Symbol, pointer_bytes: u32) -> Layout {
+        let info = &self.structs[&name];
+        self.layout(info.fields.iter().map(|(_, ty)| ty), pointer_bytes)
+    }
+
+    /// The heap block of an enum value: the tag at offset 0, then the fields of
+    /// the variant.
+    pub fn ctor_layout(&self, name: Symbol, pointer_bytes: u32) -> Layout {
+        let info = &self.ctors[&name];
+        self.layout(Some(&Type::Int).into_iter().chain(&info.fields), pointer_bytes)
+    }
+}
+
+fn round_up(n: u32, align: u32) -> u32 {
+    n.div_ceil(align) * align
+}
@@ -1,3 +1,6 @@
+use std::fmt;
+use std::path::PathBuf;
+
 use symbol::Symbol;
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
@@ -7,6 +10,7 @@ pub enum Type {
     Var(Symbol),
     Unit,
     Int,
+    Bool,
 }
 
 impl Type {
@@ -15,10 +19,79 @@ impl Type {
     }
 }
 
-#[derive(Debug)]
-pub struct Path(Vec<Symbol>);
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Func(args, ret) => {
+                write!(f, "(")?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ") -> {}", ret)
+            }
+            Type::Name(name) => write!(f, "{}", name),
+            Type::Var(name) => write!(f, "'{}", name),
+            Type::Unit => write!(f, "()"),
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+        }
+    }
+}
 
-#[derive(Debug)]
+/// The bytes of a source file that something was written as.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Span {
+    /// The file, as the parser was given it.
+    pub file: Symbol,
+    pub lo: usize,
+    pub hi: usize,
+}
+
+/// A value, usually an error, with the part of the source it's about.
+#[derive(Clone, Debug)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(value: T, span: Span) -> Self {
+        Spanned { value, span }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Spanned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Path(pub Vec<Symbol>);
+
+impl Path {
+    /// Splits a name qualified by its module, like `a.b.name`.
+    pub fn from_qualified(name: Symbol) -> Path {
+        Path(name.as_str().split('.').map(Symbol::from).collect())
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, name) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{}", name)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum BinOp {
     LogicalOr,
     LogicalAnd,
@@ -40,58 +113,277 @@ pub enum BinOp {
     Mod,
 }
 
-#[derive(Debug)]
+impl BinOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOp::LogicalOr => "||",
+            BinOp::LogicalAnd => "&&",
+            BinOp::BitwiseOr => "|",
+            BinOp::BitwiseXor => "^",
+            BinOp::BitwiseAnd => "&",
+            BinOp::Equals => "==",
+            BinOp::NotEquals => "!=",
+            BinOp::LessThan => "<",
+            BinOp::LessThanEquals => "<=",
+            BinOp::GreaterThan => ">",
+            BinOp::GreaterThanEquals => ">=",
+            BinOp::LeftShift => "<<",
+            BinOp::RightShift => ">>",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum UnOp {
     LogicalNot,
     BitwiseNot,
 }
 
+/// The declarations of a module, and the names of the ones marked `pub`.
 #[derive(Debug)]
-pub struct Program(pub Vec<Decl>);
+pub struct Program(pub Vec<Decl>, pub Vec<Symbol>);
 
 #[derive(Debug)]
 pub enum Decl {
-    Extern(Symbol, Vec<Type>, Type),
+    Class(Class),
+    Enum(Enum),
+    /// A C function, with the names and types of its arguments.
+    Extern(Symbol, Vec<(Symbol, Type)>, Type),
     Func(Func),
+    Instance(Instance),
+    Struct(Struct),
+    Test(Test),
+    /// Imports an item from another module.
+    Use(Path, Span),
 }
 
 impl Decl {
-    pub fn get_signature(&self) -> Option<(Symbol, Type)> {
+    /// The name the declaration is known by in its module.
+    pub fn name(&self) -> Option<Symbol> {
         match self {
+            Decl::Class(class) => Some(class.name),
+            Decl::Enum(enum_) => Some(enum_.name),
+            Decl::Extern(name, _, _) => Some(*name),
+            Decl::Func(func) => Some(func.name),
+            Decl::Struct(struct_) => Some(struct_.name),
+            Decl::Instance(_) | Decl::Test(_) | Decl::Use(_, _) => None,
+        }
+    }
+
+    pub fn get_signatures(&self) -> Vec<(Symbol, Type)> {
+        match self {
+            Decl::Enum(enum_) => enum_
+                .variants
+                .iter()
+                .map(|variant| (variant.name, variant.get_type(enum_.name)))
+                .collect(),
+            Decl::Class(_) | Decl::Struct(_) | Decl::Test(_) | Decl::Use(_, _) => Vec::new(),
             Decl::Extern(name, args, returns) => {
-                let name = name.clone();
-                let ty = Type::Func(args.to_vec(), Box::new(returns.clone()));
-                Some((name, ty))
+                let name = *name;
+                let args = args.iter().map(|(_, ty)| ty.clone()).collect();
+                let ty = Type::Func(args, Box::new(returns.clone()));
+                vec![(name, ty)]
             }
             Decl::Func(func) => {
-                let name = func.name.clone();
+                let name = func.name;
                 let args = func.args.iter().map(|(_, ty)| ty.clone()).collect();
                 let ty = Type::Func(args, Box::new(func.returns.clone()));
-                Some((name, ty))
+                vec![(name, ty)]
             }
+            Decl::Instance(instance) => instance
+                .methods
+                .iter()
+                .map(|func| {
+                    let args = func.args.iter().map(|(_, ty)| ty.clone()).collect();
+                    let ty = Type::Func(args, Box::new(func.returns.clone()));
+                    (instance.method_symbol(func.name), ty)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A type class: a set of methods that types can implement with an instance.
+/// The methods are generic over `param`.
+#[derive(Debug)]
+pub struct Class {
+    pub name: Symbol,
+    pub param: Symbol,
+    /// The name, argument names and type of every method.
+    pub methods: Vec<(Symbol, Vec<Symbol>, Type)>,
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Symbol,
+    pub ty: Type,
+    pub methods: Vec<Func>,
+}
+
+impl Instance {
+    /// The symbol the method of this instance is compiled to.
+    pub fn method_symbol(&self, method: Symbol) -> Symbol {
+        mangle_method(self.class, &self.ty, method)
+    }
+}
+
+pub fn mangle_method(class: Symbol, ty: &Type, method: Symbol) -> Symbol {
+    Symbol::from(format!("{}${}${}", class, ty, method).as_str())
+}
+
+#[derive(Debug)]
+pub struct Enum {
+    pub name: Symbol,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug)]
+pub struct Variant {
+    pub name: Symbol,
+    pub fields: Vec<Type>,
+}
+
+impl Variant {
+    /// The type of the constructor when used as a value: a function from the
+    /// payload to the enum, or the enum itself for variants without a payload.
+    pub fn get_type(&self, enum_name: Symbol) -> Type {
+        let ty = Type::Name(enum_name);
+        if self.fields.is_empty() {
+            ty
+        } else {
+            Type::Func(self.fields.clone(), Box::new(ty))
         }
     }
 }
 
 #[derive(Debug)]
+pub struct Struct {
+    pub name: Symbol,
+    pub fields: Vec<(Symbol, Type)>,
+}
+
+#[derive(Clone, Debug)]
 pub struct Func {
     pub name: Symbol,
     pub args: Vec<(Symbol, Type)>,
     pub body: Vec<Stmt>,
     pub returns: Type,
+    pub inline: InlineHint,
+    /// Set with `@tailcall`: every call of the function to itself has to be
+    /// in tail position, so that it runs in constant stack space.
+    pub tailcall: bool,
+    /// The line declaring the function, up to its body.
+    pub span: Span,
 }
 
+impl Func {
+    pub fn apply(&mut self, attribute: Attribute) {
+        match attribute {
+            Attribute::Inline(hint) => self.inline = hint,
+            Attribute::TailCall => self.tailcall = true,
+        }
+    }
+}
+
+/// A `test "name":` block. Only `mochi test` compiles tests, and it runs each
+/// of them on its own.
 #[derive(Debug)]
-pub enum Stmt {
+pub struct Test {
+    pub name: String,
+    /// The file the test is written in, which is only known once modules are
+    /// linked.
+    pub file: PathBuf,
+    pub body: Vec<Stmt>,
+    pub span: Span,
+}
+
+/// An attribute put before a function, like `@inline`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Attribute {
+    Inline(InlineHint),
+    TailCall,
+}
+
+/// Whether calls to a function should be inlined, set with `@inline` and
+/// `@noinline`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InlineHint {
+    /// Up to the optimizer.
+    Auto,
+    Always,
+    Never,
+}
+
+/// A statement, with the line it starts on up to its body, if it has one.
+#[derive(Clone, Debug)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Stmt { kind, span }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum StmtKind {
     Expr(Expr),
+    Let(Symbol, Expr),
+    /// Assignment to a variable or to a field of a struct.
+    Assign(Expr, Expr),
     Return(Expr),
     If(Expr, Vec<Stmt>, Vec<Stmt>),
+    Match(Expr, Vec<Arm>),
+    /// `assert` or `assert_eq`, which can only be used in tests.
+    Assert(Assert),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+pub struct Assert {
+    /// The condition of an `assert`, or the two sides of an `assert_eq`.
+    pub args: Vec<Expr>,
+    /// The assertion as it's written, to say which one failed.
+    pub text: String,
+    pub line: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Arm {
+    pub pattern: Pattern,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Wildcard,
+    Int(i64),
+    /// Either a binding or a variant without a payload; which one is only
+    /// known once the enum declarations have been collected.
+    Ident(Symbol),
+    Ctor(Symbol, Vec<Pattern>),
+}
+
+#[derive(Clone, Debug)]
 pub enum Expr {
     Int(i64),
+    Ident(Symbol),
     Call(Box<Expr>, Vec<Expr>),
     BinOp(BinOp, Box<Expr>, Box<Expr>),
     UnOp(UnOp, Box<Expr>),
+    Struct(Symbol, Vec<(Symbol, Expr)>),
+    Field(Box<Expr>, Symbol),
+    /// A class method, with the type the class is used at and the type of the
+    /// method at that use. Replaced by the instance's function once types are
+    /// known.
+    Method(Symbol, Type, Type),
+    /// A top-level function, with the type it is used at. Replaced by the
+    /// specialization for that type once types are known.
+    Global(Symbol, Type),
 }
@@ -0,0 +1,541 @@
+//! Type classes. Instances are resolved statically: once types are inferred,
+//! every use of a method becomes a direct call to the function of the instance
+//! for the type the method is used at.
+//!
+//! The comparison operators are methods of the built-in classes `Eq` and
+//! `Ord`: `a == b` is `eq(a, b)` and `a < b` is `cmp(a, b) < 0`. The instances
+//! for `int`, and `Eq` for `bool`, are built in too, and compile to the
+//! operators themselves.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use symbol::Symbol;
+
+use crate::adt::AdtTable;
+use crate::ast::{mangle_method, BinOp, Decl, Expr, Func, InlineHint, Instance, Program, Span, Stmt, StmtKind, Type, UnOp};
+use crate::env::Environment;
+use crate::pattern::collect_bindings;
+use crate::typeck::{self, Substitution, TypeError};
+
+/// The built-in classes, with their methods.
+const EQ: &str = "Eq";
+const EQ_METHOD: &str = "eq";
+const ORD: &str = "Ord";
+const ORD_METHOD: &str = "cmp";
+
+#[derive(Debug)]
+pub struct ClassInfo {
+    pub name: Symbol,
+    pub param: Symbol,
+    /// Method signatures, where the class parameter is `Type::Var(param)`.
+    pub methods: Vec<(Symbol, Type)>,
+}
+
+impl ClassInfo {
+    pub fn get_method(&self, name: Symbol) -> Option<&Type> {
+        self.methods
+            .iter()
+            .find(|(method, _)| *method == name)
+            .map(|(_, ty)| ty)
+    }
+
+    /// The type of a method when the class is used at `ty`.
+    pub fn instantiate(&self, method: &Type, ty: &Type) -> Type {
+        let mut subst = Substitution::new();
+        subst.insert(self.param, ty.clone());
+        typeck::apply(&subst, method)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ClassTable {
+    classes: HashMap<Symbol, ClassInfo>,
+    /// The class each method belongs to.
+    methods: HashMap<Symbol, Symbol>,
+    instances: HashSet<(Symbol, Type)>,
+}
+
+#[derive(Debug)]
+pub enum ClassError {
+    DuplicateClass(Symbol),
+    DuplicateMethod(Symbol),
+    UnknownClass(Symbol),
+    DuplicateInstance(Symbol, Type),
+    MissingMethod(Symbol, Type, Symbol),
+    UnknownMethod(Symbol, Type, Symbol),
+    MethodSignature(Symbol, Type, Symbol, Type),
+    Type(TypeError),
+}
+
+impl fmt::Display for ClassError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClassError::DuplicateClass(name) => write!(f, "class `{}` is defined more than once", name),
+            ClassError::DuplicateMethod(name) => write!(
+                f,
+                "method `{}` is defined more than once or clashes with a function",
+                name
+            ),
+            ClassError::UnknownClass(name) => write!(f, "cannot find class `{}`", name),
+            ClassError::DuplicateInstance(class, ty) => {
+                write!(f, "`{}` has more than one instance of `{}`", ty, class)
+            }
+            ClassError::MissingMethod(class, ty, method) => write!(
+                f,
+                "instance `{}` for `{}` doesn't define method `{}`",
+                class, ty, method
+            ),
+            ClassError::UnknownMethod(class, ty, method) => write!(
+                f,
+                "instance `{}` for `{}` defines `{}`, which is not a method of the class",
+                class, ty, method
+            ),
+            ClassError::MethodSignature(class, ty, method, expected) => write!(
+                f,
+                "method `{}` of instance `{}` for `{}` should have type `{}`",
+                method, class, ty, expected
+            ),
+            ClassError::Type(err) => err.fmt(f),
+        }
+    }
+}
+
+impl From<TypeError> for ClassError {
+    fn from(err: TypeError) -> Self {
+        ClassError::Type(err)
+    }
+}
+
+impl ClassTable {
+    pub fn new() -> Self {
+        ClassTable::default()
+    }
+
+    /// The built-in classes, as if they were declared as
+    ///
+    /// ```text
+    /// class Eq a:
+    ///   fn eq(x: a, y: a) -> bool
+    ///
+    /// class Ord a:
+    ///   fn cmp(x: a, y: a) -> int
+    /// ```
+    ///
+    /// along with their instances for `int` and `Eq` for `bool`.
+    fn builtin() -> Self {
+        let mut classes = ClassTable::new();
+        let param = Symbol::from("a");
+        let arg = Type::Var(param);
+        for (class, method, returns) in [(EQ, EQ_METHOD, Type::Bool), (ORD, ORD_METHOD, Type::Int)] {
+            let (class, method) = (Symbol::from(class), Symbol::from(method));
+            let ty = Type::Func(vec![arg.clone(), arg.clone()], Box::new(returns));
+            classes.methods.insert(method, class);
+            classes.classes.insert(
+                class,
+                ClassInfo {
+                    name: class,
+                    param,
+                    methods: vec![(method, ty)],
+                },
+            );
+            classes.instances.insert((class, Type::Int));
+        }
+        classes.instances.insert((Symbol::from(EQ), Type::Bool));
+        classes
+    }
+
+    /// Collects the classes and instances of the program, after the built-in
+    /// ones. `type_env` must contain the builtin types.
+    pub fn from_decls(type_env: &Environment<Symbol, Type>, adts: &AdtTable, decls: &[Decl]) -> Result<Self, ClassError> {
+        let mut classes = ClassTable::builtin();
+        let functions: HashSet<_> = decls
+            .iter()
+            .filter(|decl| !matches!(decl, Decl::Instance(_)))
+            .flat_map(|decl| decl.get_signatures())
+            .map(|(name, _)| name)
+            .collect();
+
+        for decl in decls {
+            if let Decl::Class(class) = decl {
+                if classes.classes.contains_key(&class.name) {
+                    return Err(ClassError::DuplicateClass(class.name));
+                }
+                let mut methods = Vec::new();
+                for (name, _, ty) in &class.methods {
+                    if functions.contains(name) || classes.methods.contains_key(name) {
+                        return Err(ClassError::DuplicateMethod(*name));
+                    }
+                    let ty = typeck::resolve_type(type_env, adts, &param_to_var(ty, class.param))?;
+                    classes.methods.insert(*name, class.name);
+                    methods.push((*name, ty));
+                }
+                let info = ClassInfo {
+                    name: class.name,
+                    param: class.param,
+                    methods,
+                };
+                classes.classes.insert(class.name, info);
+            }
+        }
+
+        for decl in decls {
+            if let Decl::Instance(instance) = decl {
+                if !classes.classes.contains_key(&instance.class) {
+                    return Err(ClassError::UnknownClass(instance.class));
+                }
+                let ty = typeck::resolve_type(type_env, adts, &instance.ty)?;
+                if !classes.instances.insert((instance.class, ty.clone())) {
+                    return Err(ClassError::DuplicateInstance(instance.class, ty));
+                }
+            }
+        }
+        Ok(classes)
+    }
+
+    /// The class a method belongs to.
+    pub fn method_class(&self, method: Symbol) -> Option<&ClassInfo> {
+        self.methods.get(&method).and_then(|class| self.classes.get(class))
+    }
+
+    pub fn has_instance(&self, class: Symbol, ty: &Type) -> bool {
+        self.instances.contains(&(class, ty.clone()))
+    }
+
+    /// A use of `name` if it is a method, at a fresh type variable.
+    fn method(&self, name: Symbol) -> Option<Expr> {
+        let class = self.method_class(name)?;
+        let ty = Type::gen();
+        let method = class.instantiate(class.get_method(name).unwrap(), &ty);
+        Some(Expr::Method(name, ty, method))
+    }
+
+    /// Prepares the program for typechecking: fills in the signatures of
+    /// instance methods from their class and marks every use of a method,
+    /// comparisons included.
+    pub fn elaborate(&self, type_env: &Environment<Symbol, Type>, adts: &AdtTable, program: &mut Program) -> Result<(), ClassError> {
+        for decl in &mut program.0 {
+            match decl {
+                Decl::Func(func) => {
+                    let mut locals = func.args.iter().map(|(name, _)| *name).collect();
+                    self.mark_stmts(adts, &mut locals, &mut func.body);
+                }
+                Decl::Instance(instance) => {
+                    self.elaborate_instance(type_env, adts, instance)?;
+                    for func in &mut instance.methods {
+                        let mut locals = func.args.iter().map(|(name, _)| *name).collect();
+                        self.mark_stmts(adts, &mut locals, &mut func.body);
+                    }
+                }
+                Decl::Class(_) | Decl::Enum(_) | Decl::Extern(_, _, _) | Decl::Struct(_) | Decl::Test(_) | Decl::Use(_, _) => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn elaborate_instance(&self, type_env: &Environment<Symbol, Type>, adts: &AdtTable, instance: &mut Instance) -> Result<(), ClassError> {
+        let class = &self.classes[&instance.class];
+        let instance_ty = typeck::resolve_type(type_env, adts, &instance.ty)?;
+        instance.ty = instance_ty.clone();
+        for (method, _) in &class.methods {
+            if !instance.methods.iter().any(|func| func.name == *method) {
+                return Err(ClassError::MissingMethod(class.name, instance_ty, *method));
+            }
+        }
+
+        for func in &mut instance.methods {
+            let name = func.name;
+            let expected = match class.get_method(name) {
+                Some(ty) => class.instantiate(ty, &instance_ty),
+                None => return Err(ClassError::UnknownMethod(class.name, instance_ty.clone(), name)),
+            };
+            let mismatch = || ClassError::MethodSignature(class.name, instance_ty.clone(), name, expected.clone());
+            let (args, returns) = match &expected {
+                Type::Func(args, returns) => (args, returns),
+                _ => unreachable!("methods have function types"),
+            };
+            if args.len() != func.args.len() {
+                return Err(mismatch());
+            }
+
+            // omitted types are taken from the class, written ones have to agree
+            let written = func
+                .args
+                .iter_mut()
+                .map(|(_, ty)| ty)
+                .chain(Some(&mut func.returns))
+                .zip(args.iter().chain(Some(&**returns)));
+            for (ty, expected_ty) in written {
+                if let Type::Var(_) = ty {
+                    *ty = expected_ty.clone();
+                } else if typeck::resolve_type(type_env, adts, ty)? != *expected_ty {
+                    return Err(mismatch());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn mark_stmts(&self, adts: &AdtTable, locals: &mut Vec<Symbol>, stmts: &mut [Stmt]) {
+        let depth = locals.len();
+        for stmt in stmts {
+            match &mut stmt.kind {
+                StmtKind::Expr(expr) | StmtKind::Return(expr) => self.mark_expr(locals, expr),
+                StmtKind::Let(name, expr) => {
+                    self.mark_expr(locals, expr);
+                    locals.push(*name);
+                }
+                StmtKind::Assign(target, expr) => {
+                    self.mark_expr(locals, target);
+                    self.mark_expr(locals, expr);
+                }
+                StmtKind::If(cond, tbody, fbody) => {
+                    self.mark_expr(locals, cond);
+                    self.mark_stmts(adts, locals, tbody);
+                    self.mark_stmts(adts, locals, fbody);
+                }
+                StmtKind::Match(expr, arms) => {
+                    self.mark_expr(locals, expr);
+                    for arm in arms {
+                        let depth = locals.len();
+                        collect_bindings(adts, &arm.pattern, locals);
+                        self.mark_stmts(adts, locals, &mut arm.body);
+                        locals.truncate(depth);
+                    }
+                }
+                StmtKind::Assert(_) => unreachable!("assertions are taken out before typechecking"),
+            }
+        }
+        locals.truncate(depth);
+    }
+
+    fn mark_expr(&self, locals: &[Symbol], expr: &mut Expr) {
+        match expr {
+            Expr::Int(_) | Expr::Method(_, _, _) | Expr::Global(_, _) => (),
+            Expr::Ident(name) => {
+                if locals.contains(name) {
+                    return;
+                }
+                if let Some(method) = self.method(*name) {
+                    *expr = method;
+                }
+            }
+            Expr::Call(func, args) => {
+                self.mark_expr(locals, func);
+                for arg in args {
+                    self.mark_expr(locals, arg);
+                }
+            }
+            Expr::BinOp(op, left, right) => {
+                self.mark_expr(locals, left);
+                self.mark_expr(locals, right);
+                let op = *op;
+                let method = match op {
+                    BinOp::Equals | BinOp::NotEquals => EQ_METHOD,
+                    BinOp::LessThan | BinOp::LessThanEquals | BinOp::GreaterThan | BinOp::GreaterThanEquals => ORD_METHOD,
+                    _ => return,
+                };
+                let args = vec![take(left), take(right)];
+                let call = Expr::Call(Box::new(self.method(Symbol::from(method)).unwrap()), args);
+                *expr = match op {
+                    BinOp::Equals => call,
+                    BinOp::NotEquals => Expr::UnOp(UnOp::LogicalNot, Box::new(call)),
+                    op => Expr::BinOp(op, Box::new(call), Box::new(Expr::Int(0))),
+                };
+            }
+            Expr::UnOp(_, expr) | Expr::Field(expr, _) => self.mark_expr(locals, expr),
+            Expr::Struct(_, fields) => {
+                for (_, expr) in fields {
+                    self.mark_expr(locals, expr);
+                }
+            }
+        }
+    }
+
+    /// Replaces every method with the function of the instance it refers to,
+    /// now that the types are known. Typechecking has made sure that the
+    /// instances exist. The methods of the built-in instances are compiled
+    /// to the operators they stand for where they can be, and to functions
+    /// added to the program everywhere else.
+    pub fn resolve_methods(&self, subst: &Substitution, program: &mut Program) {
+        let mut builtins = Vec::new();
+        for decl in &mut program.0 {
+            match decl {
+                Decl::Func(func) => self.resolve_stmts(subst, &mut builtins, &mut func.body),
+                Decl::Instance(instance) => {
+                    for func in &mut instance.methods {
+                        self.resolve_stmts(subst, &mut builtins, &mut func.body);
+                    }
+                }
+                Decl::Class(_) | Decl::Enum(_) | Decl::Extern(_, _, _) | Decl::Struct(_) | Decl::Test(_) | Decl::Use(_, _) => (),
+            }
+        }
+        for (method, ty) in builtins {
+            program.0.push(Decl::Instance(builtin_instance(self.methods[&method], method, ty)));
+        }
+    }
+
+    fn resolve_stmts(&self, subst: &Substitution, builtins: &mut Vec<(Symbol, Type)>, stmts: &mut [Stmt]) {
+        for stmt in stmts {
+            match &mut stmt.kind {
+                StmtKind::Expr(expr) | StmtKind::Let(_, expr) | StmtKind::Return(expr) => self.resolve_expr(subst, builtins, expr),
+                StmtKind::Assign(target, expr) => {
+                    self.resolve_expr(subst, builtins, target);
+                    self.resolve_expr(subst, builtins, expr);
+                }
+                StmtKind::If(cond, tbody, fbody) => {
+                    self.resolve_expr(subst, builtins, cond);
+                    self.resolve_stmts(subst, builtins, tbody);
+                    self.resolve_stmts(subst, builtins, fbody);
+                }
+                StmtKind::Match(expr, arms) => {
+                    self.resolve_expr(subst, builtins, expr);
+                    for arm in arms {
+                        self.resolve_stmts(subst, builtins, &mut arm.body);
+                    }
+                }
+                StmtKind::Assert(_) => unreachable!("assertions are taken out before typechecking"),
+            }
+        }
+    }
+
+    fn resolve_expr(&self, subst: &Substitution, builtins: &mut Vec<(Symbol, Type)>, expr: &mut Expr) {
+        // the shapes comparisons are desugared to, which mean the same when
+        // they are written by hand
+        let operator = match expr {
+            Expr::Call(_, _) => self.builtin_call(subst, expr, EQ_METHOD).map(|(left, right)| (BinOp::Equals, left, right)),
+            Expr::UnOp(UnOp::LogicalNot, call) => self
+                .builtin_call(subst, call, EQ_METHOD)
+                .map(|(left, right)| (BinOp::NotEquals, left, right)),
+            Expr::BinOp(op @ (BinOp::LessThan | BinOp::LessThanEquals | BinOp::GreaterThan | BinOp::GreaterThanEquals), call, zero)
+                if matches!(**zero, Expr::Int(0)) =>
+            {
+                let op = *op;
+                self.builtin_call(subst, call, ORD_METHOD).map(|(left, right)| (op, left, right))
+            }
+            _ => None,
+        };
+        if let Some((op, left, right)) = operator {
+            *expr = Expr::BinOp(op, Box::new(left), Box::new(right));
+        }
+
+        match expr {
+            Expr::Int(_) | Expr::Ident(_) | Expr::Global(_, _) => (),
+            Expr::Method(name, ty, _) => {
+                let class = self.methods[name];
+                let ty = typeck::apply(subst, ty);
+                debug_assert!(self.has_instance(class, &ty), "instances are checked while typechecking");
+                if is_builtin(class, &ty) && !builtins.contains(&(*name, ty.clone())) {
+                    builtins.push((*name, ty.clone()));
+                }
+                *expr = Expr::Ident(mangle_method(class, &ty, *name));
+            }
+            Expr::Call(func, args) => {
+                self.resolve_expr(subst, builtins, func);
+                for arg in args {
+                    self.resolve_expr(subst, builtins, arg);
+                }
+            }
+            Expr::BinOp(_, left, right) => {
+                self.resolve_expr(subst, builtins, left);
+                self.resolve_expr(subst, builtins, right);
+            }
+            Expr::UnOp(_, expr) | Expr::Field(expr, _) => self.resolve_expr(subst, builtins, expr),
+            Expr::Struct(_, fields) => {
+                for (_, expr) in fields {
+                    self.resolve_expr(subst, builtins, expr);
+                }
+            }
+        }
+    }
+
+    /// The arguments of `expr` if it calls `method` of a built-in instance,
+    /// which are taken out of it.
+    fn builtin_call(&self, subst: &Substitution, expr: &mut Expr, method: &str) -> Option<(Expr, Expr)> {
+        let args = match expr {
+            Expr::Call(func, args) => match &**func {
+                Expr::Method(name, ty, _) if name.as_str() == method && is_builtin(self.methods[name], &typeck::apply(subst, ty)) => args,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let mut args = std::mem::take(args).into_iter();
+        Some((args.next()?, args.next()?))
+    }
+}
+
+/// The built-in instance for `ty` of the class of `method`, with only that
+/// method, for when it isn't called as an operator:
+///
+/// ```text
+/// instance Eq int:
+///   fn eq(x, y):
+///     return x == y
+///
+/// instance Ord int:
+///   fn cmp(x, y):
+///     if x < y:
+///       return -1
+///     if x > y:
+///       return 1
+///     return 0
+/// ```
+fn builtin_instance(class: Symbol, method: Symbol, ty: Type) -> Instance {
+    let span = Span {
+        file: Symbol::from("<builtin>"),
+        lo: 0,
+        hi: 0,
+    };
+    let (x, y) = (Symbol::from("x"), Symbol::from("y"));
+    let compare = |op| Expr::BinOp(op, Box::new(Expr::Ident(x)), Box::new(Expr::Ident(y)));
+    let ret = |expr| Stmt::new(StmtKind::Return(expr), span);
+    let (body, returns) = if method.as_str() == EQ_METHOD {
+        (vec![ret(compare(BinOp::Equals))], Type::Bool)
+    } else {
+        let body = vec![
+            Stmt::new(StmtKind::If(compare(BinOp::LessThan), vec![ret(Expr::Int(-1))], Vec::new()), span),
+            Stmt::new(StmtKind::If(compare(BinOp::GreaterThan), vec![ret(Expr::Int(1))], Vec::new()), span),
+            ret(Expr::Int(0)),
+        ];
+        (body, Type::Int)
+    };
+    let func = Func {
+        name: method,
+        args: vec![(x, ty.clone()), (y, ty.clone())],
+        body,
+        returns,
+        inline: InlineHint::Auto,
+        tailcall: false,
+        span,
+    };
+    Instance {
+        class,
+        ty,
+        methods: vec![func],
+    }
+}
+
+fn is_builtin(class: Symbol, ty: &Type) -> bool {
+    match ty {
+        Type::Int => class.as_str() == EQ || class.as_str() == ORD,
+        Type::Bool => class.as_str() == EQ,
+        _ => false,
+    }
+}
+
+/// Takes an operand out of its operator, which is about to be replaced.
+fn take(expr: &mut Expr) -> Expr {
+    std::mem::replace(expr, Expr::Int(0))
+}
+
+/// Turns the class parameter, written as a type name, into a type variable.
+fn param_to_var(ty: &Type, param: Symbol) -> Type {
+    match ty {
+        Type::Name(name) if *name == param => Type::Var(param),
+        Type::Func(args, ret) => {
+            let args = args.iter().map(|arg| param_to_var(arg, param)).collect();
+            Type::Func(args, Box::new(param_to_var(ret, param)))
+        }
+        Type::Name(_) | Type::Var(_) | Type::Unit | Type::Int | Type::Bool => ty.clone(),
+    }
+}
@@ -1,25 +1,107 @@
-use std::fs::File;
-use std::io::Write;
-use std::path::Path;
-use std::str::FromStr;
+use std::collections::HashMap;
+use std::fmt;
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
+use cranelift::codegen::binemit::{Addend, CodeOffset, NullTrapSink, Reloc, RelocSink};
+use cranelift::codegen::ir::{ExtFuncData, ExternalName, FuncRef, JumpTable, LibCall};
+use cranelift::codegen::isa::TargetIsa;
+use cranelift::frontend::Switch;
 use cranelift::prelude::{settings::Flags, *};
 use cranelift_faerie::{FaerieBackend, FaerieBuilder, FaerieTrapCollection};
 use cranelift_module::{Linkage, Module};
+use symbol::Symbol;
+use target_lexicon::{Architecture, BinaryFormat, Triple};
 
-use crate::ast::{Expr, Func, Stmt};
+use crate::abi::{arg_layout, classify, ArgClass, ArgLayout};
+use crate::adt::{AdtTable, Layout};
+use crate::ast::{self, BinOp, UnOp};
+use crate::disasm::{self, Symbols};
+use crate::lower::lower_type;
+use crate::mir::{BlockId, Constant, Decl, Func, Local, Operand, Place, Rvalue, Stmt, Terminator, Type};
+use crate::timing::{Phase, Timer, Timings};
+
+#[derive(Debug)]
+pub enum CodegenError {
+    /// Only x86-64 code can be generated, in ELF or Mach-O object files.
+    UnsupportedTarget(Triple),
+    /// A function needs a relocation that the object file can't have, with
+    /// what kind of relocation it is.
+    UnsupportedRelocation(ast::Path, String),
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodegenError::UnsupportedTarget(triple) => write!(
+                f,
+                "can't compile for `{}`, only for x86-64 with ELF or Mach-O object files",
+                triple
+            ),
+            CodegenError::UnsupportedRelocation(name, reloc) => {
+                write!(f, "can't compile `{}`: it needs {}, which aren't supported", name, reloc)
+            }
+        }
+    }
+}
+
+/// Enums are allocated on the heap with this function from libc.
+const MALLOC: &str = "malloc";
+
+/// The namespace that compiled functions call the C functions the compiler
+/// uses on its own in, by their index: only `MALLOC`. They are declared in the
+/// module once a function that calls them is put in the object file, while
+/// the functions of the program are in namespace 0, by their ids.
+const RUNTIME: u32 = 1;
+
+/// A readable form of the compiled functions.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Listing {
+    /// The Cranelift ir before and after Cranelift's own optimizations.
+    Clif,
+    /// The disassembled machine code.
+    Asm,
+}
+
+/// The machine code of a function, with the relocations the linker fills
+/// in. Functions are compiled to this and then put in the object file, so
+/// that a function compiled before can be put in as it is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompiledFunc {
+    pub code: Vec<u8>,
+    pub relocs: Vec<Relocation>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Relocation {
+    pub offset: u32,
+    /// The relocation type of the object file format.
+    pub kind: u32,
+    pub addend: i32,
+    pub symbol: String,
+}
 
 pub struct Codegen {
-    builder_ctx: FunctionBuilderContext,
-    ctx: codegen::Context,
     module: Module<FaerieBackend>,
+    functions: HashMap<ast::Path, Callee>,
+    /// The functions to put in the object file, in order.
+    defined: Vec<(String, CompiledFunc)>,
+    /// The listings being written, with what has been written so far.
+    listings: Vec<(Listing, String)>,
+    /// The time compiling every function took, by its name.
+    timings: Timings,
 }
 
 impl Codegen {
-    pub fn new() -> Self {
+    pub fn new(target: Triple) -> Result<Self, CodegenError> {
+        let format = matches!(target.binary_format, BinaryFormat::Elf | BinaryFormat::Macho);
+        if target.architecture != Architecture::X86_64 || !format {
+            return Err(CodegenError::UnsupportedTarget(target));
+        }
         let mut flag_builder = settings::builder();
         flag_builder.enable("is_pic").unwrap();
-        let isa_builder = isa::lookup(triple!("x86_64-unknown-unknown-elf")).unwrap();
+        let isa_builder = isa::lookup(target).unwrap();
         let isa = isa_builder.finish(Flags::new(flag_builder));
 
         let builder = FaerieBuilder::new(
@@ -30,85 +112,913 @@ impl Codegen {
         )
         .expect("failed");
         let module = Module::new(builder);
-        let builder_ctx = FunctionBuilderContext::new();
-        let ctx = module.make_context();
-        Self {
-            builder_ctx,
-            ctx,
+        Ok(Self {
             module,
+            functions: HashMap::new(),
+            defined: Vec::new(),
+            listings: Vec::new(),
+            timings: Timings::default(),
+        })
+    }
+
+    /// Makes `compile_funcs` add every function to a listing.
+    pub fn listing(mut self, listing: Listing) -> Self {
+        self.listings.push((listing, String::new()));
+        self
+    }
+
+    /// The listing of the functions compiled so far.
+    pub fn listing_output(&self, listing: Listing) -> &str {
+        self.listings
+            .iter()
+            .find(|(kind, _)| *kind == listing)
+            .map_or("", |(_, output)| output)
+    }
+
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    fn append(&mut self, listing: Listing, text: &str) {
+        if let Some((_, output)) = self.listings.iter_mut().find(|(kind, _)| *kind == listing) {
+            output.push_str(text);
+            output.push('\n');
+        }
+    }
+
+    /// Declares the symbols of a declaration so that functions can call each
+    /// other regardless of the order they are compiled in.
+    pub fn declare_decl(&mut self, adts: &AdtTable, decl: &Decl) {
+        match decl {
+            Decl::Extern(name, args, returns) => {
+                let ty = Type::Func(args.clone(), Box::new(returns.clone()));
+                self.declare_function(adts, name, Linkage::Import, &ty);
+            }
+            Decl::Func(func) => {
+                let linkage = if func.exported { Linkage::Export } else { Linkage::Local };
+                self.declare_function(adts, &func.name, linkage, &func.get_type());
+            }
         }
     }
 
-    pub fn compile_func(&mut self, func: &Func) {
-        let int = self.module.target_config().pointer_type();
-        self.ctx.func.signature.returns.push(AbiParam::new(int));
+    fn declare_function(&mut self, adts: &AdtTable, name: &ast::Path, linkage: Linkage, signature: &Type) {
+        let signature = make_signature(self.module.isa(), adts, signature);
+        let id = self
+            .module
+            .declare_function(&name.to_string(), linkage, &signature)
+            .map_err(|e| e.to_string())
+            .expect("failed");
+        let callee = Callee {
+            name: ExternalName::user(0, id.as_u32()),
+            signature,
+            colocated: linkage.is_final(),
+        };
+        self.functions.insert(name.clone(), callee);
+    }
+
+    /// Compiles functions, which `define_func` then puts in the object file,
+    /// on up to `jobs` threads. They are returned and added to the listings in
+    /// the order they are given in, however many threads compile them.
+    pub fn compile_funcs(&mut self, adts: &AdtTable, funcs: &[&Func], jobs: usize) -> Result<Vec<CompiledFunc>, CodegenError> {
+        let names = self.symbol_names();
+        let listings: Vec<_> = self.listings.iter().map(|(listing, _)| *listing).collect();
+        let isa = self.module.isa();
+        let functions = &self.functions;
+        let next = AtomicUsize::new(0);
+        // every thread takes the next function until there are none left
+        let work = || {
+            let mut worker = Worker::new(isa, adts, functions, &names, &listings);
+            let mut compiled = Vec::new();
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                match funcs.get(i) {
+                    Some(func) => compiled.push((i, worker.compile(func))),
+                    None => return compiled,
+                }
+            }
+        };
+        let mut compiled = match jobs.min(funcs.len()) {
+            0 | 1 => work(),
+            jobs => thread::scope(|scope| {
+                let threads: Vec<_> = (0..jobs).map(|_| scope.spawn(work)).collect();
+                threads
+                    .into_iter()
+                    .flat_map(|thread| thread.join().unwrap_or_else(|err| panic::resume_unwind(err)))
+                    .collect()
+            }),
+        };
+        compiled.sort_by_key(|(i, _)| *i);
+
+        let mut funcs = Vec::new();
+        for (_, result) in compiled {
+            let (func, texts, phase) = result?;
+            for (listing, text) in listings.iter().zip(texts) {
+                self.append(*listing, &text);
+            }
+            self.timings.add(phase);
+            funcs.push(func);
+        }
+        Ok(funcs)
+    }
+
+    /// Adds a compiled function to the object file. Functions are put in the
+    /// order they are added.
+    pub fn define_func(&mut self, name: &ast::Path, func: CompiledFunc) {
+        if func.relocs.iter().any(|reloc| reloc.symbol == MALLOC) && self.module.get_name(MALLOC).is_none() {
+            // declared once a function that is put in calls it, so that it
+            // is only in the object files that need it
+            let signature = malloc_signature(self.module.isa());
+            self.module
+                .declare_function(MALLOC, Linkage::Import, &signature)
+                .map_err(|e| e.to_string())
+                .expect("failed");
+        }
+        self.defined.push((name.to_string(), func));
+    }
+
+    /// The names of the functions of the program, by their ids.
+    fn symbol_names(&self) -> HashMap<u32, String> {
+        self.functions
+            .iter()
+            .map(|(path, callee)| match callee.name {
+                ExternalName::User { index, .. } => (index, path.to_string()),
+                _ => unreachable!("functions are named by their ids"),
+            })
+            .collect()
+    }
+
+    /// Returns the contents of the object file.
+    pub fn finish(self) -> Vec<u8> {
+        let mut artifact = self.module.finish().artifact;
+        for (name, func) in self.defined {
+            artifact.define(&name, func.code).expect("functions are declared");
+            for reloc in &func.relocs {
+                if !self.functions.keys().any(|path| path.to_string() == reloc.symbol) {
+                    // a C function or one of Cranelift's runtime functions
+                    artifact
+                        .declare(&reloc.symbol, faerie::Decl::function_import())
+                        .expect("failed");
+                }
+                let link = faerie::Link {
+                    from: &name,
+                    to: &reloc.symbol,
+                    at: u64::from(reloc.offset),
+                };
+                let kind = faerie::Reloc::Raw {
+                    reloc: reloc.kind,
+                    addend: reloc.addend,
+                };
+                artifact.link_with(link, kind).expect("failed");
+            }
+        }
+        artifact.emit().expect("failed")
+    }
+}
+
+/// A function of the module, as the functions that call it import it.
+struct Callee {
+    name: ExternalName,
+    signature: Signature,
+    colocated: bool,
+}
+
+/// Compiles functions one at a time with a Cranelift context of its own.
+/// Workers only read the module's functions, so that functions can be
+/// compiled on several threads at once.
+struct Worker<'a> {
+    isa: &'a dyn TargetIsa,
+    adts: &'a AdtTable,
+    functions: &'a HashMap<ast::Path, Callee>,
+    /// The names of the functions of the module by their ids.
+    names: &'a HashMap<u32, String>,
+    listings: &'a [Listing],
+    builder_ctx: FunctionBuilderContext,
+    ctx: codegen::Context,
+}
+
+impl<'a> Worker<'a> {
+    fn new(
+        isa: &'a dyn TargetIsa,
+        adts: &'a AdtTable,
+        functions: &'a HashMap<ast::Path, Callee>,
+        names: &'a HashMap<u32, String>,
+        listings: &'a [Listing],
+    ) -> Self {
+        Worker {
+            isa,
+            adts,
+            functions,
+            names,
+            listings,
+            builder_ctx: FunctionBuilderContext::new(),
+            ctx: codegen::Context::new(),
+        }
+    }
+
+    fn wants(&self, listing: Listing) -> bool {
+        self.listings.contains(&listing)
+    }
+
+    /// Compiles a function, along with its part of every listing and the
+    /// time it took.
+    fn compile(&mut self, func: &Func) -> Result<(CompiledFunc, Vec<String>, Phase), CodegenError> {
+        let timer = Timer::start();
+        let int = self.isa.pointer_type();
+        self.ctx.func.signature = make_signature(self.isa, self.adts, &func.get_type());
         let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_ctx);
         let entry_ebb = builder.create_ebb();
         builder.append_ebb_params_for_function_params(entry_ebb);
         builder.switch_to_block(entry_ebb);
-        builder.seal_block(entry_ebb);
+        let ebbs = func.blocks.iter().map(|_| builder.create_ebb()).collect();
+
         let mut trans = FunctionTranslator {
+            int,
+            pointer_bytes: int.bytes(),
             builder,
-            module: &mut self.module,
+            isa: self.isa,
+            adts: self.adts,
+            functions: self.functions,
+            func,
+            ebbs,
         };
-        for stmt in &func.body {
-            trans.translate_stmt(stmt);
+        let params = trans.builder.ebb_params(entry_ebb).to_vec();
+        trans.translate_entry(params);
+        for (i, block) in func.blocks.iter().enumerate() {
+            trans.builder.switch_to_block(trans.ebbs[i]);
+            for stmt in &block.stmts {
+                trans.translate_stmt(stmt);
+            }
+            trans.translate_terminator(&block.terminator);
         }
+        trans.builder.seal_all_blocks();
         trans.builder.finalize();
 
-        let id = self
-            .module
-            .declare_function(&func.name, Linkage::Export, &self.ctx.func.signature)
-            .map_err(|e| e.to_string())
-            .expect("failed");
-        self.module
-            .define_function(id, &mut self.ctx)
+        let mut clif = String::new();
+        if self.wants(Listing::Clif) {
+            clif = format!("; {}\n{}", func.name, self.ctx.func.display(self.isa));
+        }
+        let code_size = self
+            .ctx
+            .compile(self.isa)
             .map_err(|e| e.to_string())
             .expect("failed");
-        self.module.clear_context(&mut self.ctx);
-        self.module.finalize_definitions();
-        let code = self.module.get_finalized_function(id);
-        println!("code: {:?}", code);
+        let mut code = vec![0; code_size as usize];
+        let mut relocs = Relocations {
+            names: self.names,
+            format: self.isa.triple().binary_format,
+            relocs: Vec::new(),
+            unsupported: None,
+        };
+        // the code is only copied, so it doesn't matter where it's emitted
+        unsafe { self.ctx.emit_to_memory(self.isa, code.as_mut_ptr(), &mut relocs, &mut NullTrapSink {}) };
+        if let Some(reloc) = relocs.unsupported {
+            self.ctx.clear();
+            return Err(CodegenError::UnsupportedRelocation(func.name.clone(), reloc));
+        }
+        let phase = timer.stop(&func.name.to_string());
+        let mut listings = Vec::new();
+        for &listing in self.listings {
+            listings.push(match listing {
+                // the function has been legalized and its registers allocated
+                Listing::Clif => format!("{}\n; {}, compiled\n{}", clif, func.name, self.ctx.func.display(self.isa)),
+                Listing::Asm => self.disassemble(&func.name, code_size),
+            });
+        }
+        self.ctx.clear();
+        let compiled = CompiledFunc {
+            code,
+            relocs: relocs.relocs,
+        };
+        Ok((compiled, listings, phase))
+    }
+
+    /// Disassembles the function that was just compiled.
+    fn disassemble(&self, name: &ast::Path, code_size: CodeOffset) -> String {
+        let isa = self.isa;
+        let func = &self.ctx.func;
+        let mut code = vec![0; code_size as usize];
+        let mut relocs = RelocNames {
+            names: self.names,
+            symbols: Symbols::default(),
+        };
+        // the code isn't run, so it doesn't matter that it isn't relocated
+        unsafe { self.ctx.emit_to_memory(isa, code.as_mut_ptr(), &mut relocs, &mut NullTrapSink {}) };
+        let mut symbols = relocs.symbols;
+        for ebb in func.layout.ebbs() {
+            symbols.labels.insert(func.offsets[ebb] as usize, ebb.to_string());
+        }
+        for (jt, _) in func.jump_tables.iter() {
+            symbols.labels.insert(func.jt_offsets[jt] as usize, jt.to_string());
+        }
+
+        let mut listing = format!("{}:\n", name);
+        let encodings = isa.encoding_info();
+        for ebb in func.layout.ebbs() {
+            listing.push_str(&format!("{}:\n", ebb));
+            for (offset, _, size) in func.inst_offsets(ebb, &encodings) {
+                let (start, end) = (offset as usize, (offset + size) as usize);
+                listing.push_str(&disasm::instructions(&code, start, end, &symbols));
+            }
+        }
+        // jump tables follow the code, as offsets from their start
+        for (jt, _) in func.jump_tables.iter() {
+            listing.push_str(&format!("{}:\n", jt));
+            let start = func.jt_offsets[jt] as usize;
+            for (i, entry) in func.jump_tables[jt].iter().enumerate() {
+                let offset = start + 4 * i;
+                listing.push_str(&disasm::line(&code, offset, offset + 4, &format!(".long {}", entry)));
+            }
+        }
+        listing
+    }
+}
+
+/// Collects the relocations of a function as the object file writes them.
+struct Relocations<'a> {
+    /// The names of the functions of the module by their ids.
+    names: &'a HashMap<u32, String>,
+    format: BinaryFormat,
+    relocs: Vec<Relocation>,
+    /// The first relocation that the object file can't have, if any.
+    unsupported: Option<String>,
+}
+
+impl<'a> Relocations<'a> {
+    fn unsupported(&mut self, reloc: String) {
+        self.unsupported.get_or_insert(reloc);
+    }
+}
+
+impl<'a> RelocSink for Relocations<'a> {
+    fn reloc_ebb(&mut self, _: CodeOffset, reloc: Reloc, _: CodeOffset) {
+        // Faerie has no way to refer to a block either
+        self.unsupported(format!("`{}` relocations to blocks", reloc));
+    }
+
+    fn reloc_external(&mut self, offset: CodeOffset, reloc: Reloc, name: &ExternalName, addend: Addend) {
+        let symbol = match name {
+            ExternalName::LibCall(libcall) => libcall_name(*libcall),
+            _ => symbol_name(self.names, name).expect("only functions are declared"),
+        };
+        match raw_relocation(reloc, self.format) {
+            Some((kind, implied)) => self.relocs.push(Relocation {
+                offset,
+                kind,
+                addend: (addend + implied) as i32,
+                symbol,
+            }),
+            None => self.unsupported(format!("`{}` relocations in {} object files", reloc, self.format)),
+        }
+    }
+
+    fn reloc_jt(&mut self, _: CodeOffset, reloc: Reloc, _: JumpTable) {
+        self.unsupported(format!("`{}` relocations to jump tables", reloc));
+    }
+}
+
+/// The symbol of a function that compiled code calls, other than Cranelift's
+/// runtime functions.
+fn symbol_name(names: &HashMap<u32, String>, name: &ExternalName) -> Option<String> {
+    match name {
+        ExternalName::User { namespace: 0, index } => names.get(index).cloned(),
+        ExternalName::User { namespace: RUNTIME, index: 0 } => Some(MALLOC.to_string()),
+        _ => None,
+    }
+}
+
+fn libcall_name(libcall: LibCall) -> String {
+    (FaerieBuilder::default_libcall_names())(libcall)
+}
+
+// relocation types of the x86-64 ELF psABI
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_GOTPCREL: u32 = 9;
+const R_X86_64_32: u32 = 10;
+
+// relocation types of x86-64 Mach-O, from `<mach-o/x86_64/reloc.h>`
+const X86_64_RELOC_UNSIGNED: u32 = 0;
+const X86_64_RELOC_SIGNED: u32 = 1;
+const X86_64_RELOC_BRANCH: u32 = 2;
+const X86_64_RELOC_GOT_LOAD: u32 = 3;
+
+/// The relocation type of the object file format for a relocation, with the
+/// addend it implies, as Faerie's Cranelift backend picks them. `None` if
+/// the format has no such relocation.
+fn raw_relocation(reloc: Reloc, format: BinaryFormat) -> Option<(u32, i64)> {
+    Some(match (format, reloc) {
+        (BinaryFormat::Elf, Reloc::Abs4) => (R_X86_64_32, 0),
+        (BinaryFormat::Elf, Reloc::Abs8) => (R_X86_64_64, 0),
+        (BinaryFormat::Elf, Reloc::X86PCRel4) | (BinaryFormat::Elf, Reloc::X86CallPCRel4) => (R_X86_64_PC32, 0),
+        (BinaryFormat::Elf, Reloc::X86CallPLTRel4) => (R_X86_64_PLT32, 0),
+        (BinaryFormat::Elf, Reloc::X86GOTPCRel4) => (R_X86_64_GOTPCREL, 0),
+        (BinaryFormat::Macho, Reloc::Abs8) => (X86_64_RELOC_UNSIGNED, 0),
+        // Mach-O addends are from the end of the 4 bytes relocated
+        (BinaryFormat::Macho, Reloc::X86PCRel4) => (X86_64_RELOC_SIGNED, 4),
+        (BinaryFormat::Macho, Reloc::X86CallPCRel4) | (BinaryFormat::Macho, Reloc::X86CallPLTRel4) => {
+            (X86_64_RELOC_BRANCH, 4)
+        }
+        (BinaryFormat::Macho, Reloc::X86GOTPCRel4) => (X86_64_RELOC_GOT_LOAD, 4),
+        _ => return None,
+    })
+}
+
+/// Names the symbols that a function refers to, for its listing.
+struct RelocNames<'a> {
+    /// The names of the functions of the module by their ids.
+    names: &'a HashMap<u32, String>,
+    symbols: Symbols,
+}
+
+impl<'a> RelocSink for RelocNames<'a> {
+    fn reloc_ebb(&mut self, _: CodeOffset, _: Reloc, _: CodeOffset) {}
+
+    fn reloc_external(&mut self, offset: CodeOffset, _: Reloc, name: &ExternalName, _: Addend) {
+        let symbol = symbol_name(self.names, name).unwrap_or_else(|| name.to_string());
+        self.symbols.relocs.insert(offset as usize, symbol);
     }
 
-    pub fn finish(self, path: impl AsRef<Path>) {
-        let file = File::create(path).expect("failed");
-        let product = self.module.finish();
-        product.write(file);
+    fn reloc_jt(&mut self, offset: CodeOffset, _: Reloc, jt: JumpTable) {
+        self.symbols.relocs.insert(offset as usize, jt.to_string());
     }
 }
 
+/// Lowers a mochi function type to a machine signature, see `abi`.
+fn make_signature(isa: &dyn TargetIsa, adts: &AdtTable, ty: &Type) -> Signature {
+    let int = isa.pointer_type();
+    let pointer_bytes = int.bytes();
+    let mut signature = Signature::new(isa.default_call_conv());
+    let (args, returns) = match ty {
+        Type::Func(args, returns) => (args, returns),
+        _ => unreachable!("functions have function types"),
+    };
+
+    let (ret_class, layout) = layout_args(adts, args, returns, pointer_bytes);
+    if let ArgClass::Memory(_) = ret_class {
+        signature.params.push(AbiParam::new(int));
+    }
+    for _ in 0..layout.params {
+        signature.params.push(AbiParam::new(int));
+    }
+    match ret_class {
+        ArgClass::Ignore => (),
+        ArgClass::Scalar | ArgClass::Memory(_) => signature.returns.push(AbiParam::new(int)),
+        ArgClass::Words(n) => signature.returns.extend((0..n).map(|_| AbiParam::new(int))),
+    }
+    signature
+}
+
+/// Classifies the return value of a function and lays out its arguments.
+fn layout_args(adts: &AdtTable, args: &[Type], returns: &Type, pointer_bytes: u32) -> (ArgClass, ArgLayout) {
+    let ret_class = classify(adts, returns, pointer_bytes);
+    let classes: Vec<_> = args.iter().map(|arg| classify(adts, arg, pointer_bytes)).collect();
+    let layout = arg_layout(&classes, matches!(ret_class, ArgClass::Memory(_)));
+    (ret_class, layout)
+}
+
+/// `void *malloc(size_t)`.
+fn malloc_signature(isa: &dyn TargetIsa) -> Signature {
+    let int = isa.pointer_type();
+    let mut signature = Signature::new(isa.default_call_conv());
+    signature.params.push(AbiParam::new(int));
+    signature.returns.push(AbiParam::new(int));
+    signature
+}
+
+/// Every local is a variable held in a pointer-sized register. Integers,
+/// booleans and enums (pointers to the heap) are stored in it directly.
+/// Struct locals own a stack slot for their whole lifetime and the variable
+/// holds its address, so assigning to a struct copies into the slot.
 pub struct FunctionTranslator<'a> {
+    int: types::Type,
+    pointer_bytes: u32,
     builder: FunctionBuilder<'a>,
-    module: &'a mut Module<FaerieBackend>,
+    isa: &'a dyn TargetIsa,
+    adts: &'a AdtTable,
+    functions: &'a HashMap<ast::Path, Callee>,
+    func: &'a Func,
+    ebbs: Vec<Ebb>,
 }
 
 impl<'a> FunctionTranslator<'a> {
-    pub fn translate_stmt(&mut self, stmt: &Stmt) {
+    /// Declares the locals, binding the arguments to the incoming parameters,
+    /// and jumps to the first block.
+    fn translate_entry(&mut self, params: Vec<Value>) {
+        let func = self.func;
+        let args: Vec<_> = func.locals[1..=func.args].iter().map(|decl| decl.ty.clone()).collect();
+        let (ret_class, layout) = layout_args(self.adts, &args, func.returns(), self.pointer_bytes);
+        let sret = match ret_class {
+            ArgClass::Memory(_) => Some(params[0]),
+            _ => None,
+        };
+        let words = if sret.is_some() { &params[1..] } else { &params[..] };
+        let mut params = layout.positions.iter().map(|&position| words[position]);
+        for (i, decl) in func.locals.iter().enumerate() {
+            let var = Variable::new(i);
+            self.builder.declare_var(var, self.int);
+            let local = Local(i);
+            let is_arg = local.0 >= 1 && local.0 <= func.args;
+            let value = match self.classify(&decl.ty) {
+                ArgClass::Memory(_) if local == Local::RETURN => sret.unwrap(),
+                ArgClass::Scalar if is_arg => params.next().unwrap(),
+                // structs in memory are loaded from the stack like the ones in
+                // registers, into a slot the callee is free to modify
+                ArgClass::Words(n) | ArgClass::Memory(n) if is_arg => {
+                    let layout = self.struct_layout(&decl.ty);
+                    let slot = self.alloc_slot(&layout);
+                    for i in 0..n {
+                        let word = params.next().unwrap();
+                        let offset = (i as u32 * self.pointer_bytes) as i32;
+                        self.builder.ins().store(MemFlags::trusted(), word, slot, offset);
+                    }
+                    slot
+                }
+                _ if self.is_struct(&decl.ty) => {
+                    let layout = self.struct_layout(&decl.ty);
+                    self.alloc_slot(&layout)
+                }
+                // locals that are read before being assigned, like the return
+                // value when falling off the end of a function, are zero
+                _ => self.builder.ins().iconst(self.int, 0),
+            };
+            self.builder.def_var(var, value);
+        }
+        self.builder.ins().jump(self.ebbs[BlockId::ENTRY.0], &[]);
+    }
+
+    fn translate_stmt(&mut self, stmt: &Stmt) {
         match stmt {
-            Stmt::Expr(expr) => {
-                self.translate_expr(expr);
+            Stmt::Assign(place, rvalue) => {
+                let value = self.translate_rvalue(rvalue);
+                self.assign(place, value);
+            }
+        }
+    }
+
+    fn translate_terminator(&mut self, terminator: &Terminator) {
+        match terminator {
+            Terminator::Goto(target) => {
+                self.builder.ins().jump(self.ebbs[target.0], &[]);
+            }
+            Terminator::Branch(cond, then, else_) => {
+                let cond = self.translate_operand(cond);
+                self.builder.ins().brz(cond, self.ebbs[else_.0], &[]);
+                self.builder.ins().jump(self.ebbs[then.0], &[]);
+            }
+            Terminator::Switch(value, cases, otherwise) => {
+                let value = self.translate_operand(value);
+                let mut switch = Switch::new();
+                for (index, target) in cases {
+                    switch.set_entry(*index as u64, self.ebbs[target.0]);
+                }
+                switch.emit(&mut self.builder, value, self.ebbs[otherwise.0]);
+            }
+            Terminator::Call {
+                func,
+                args,
+                dest,
+                target,
+                unwind: _,
+            } => {
+                // nothing unwinds yet, so the unwind target is never taken
+                self.translate_call(func, args, *dest);
+                self.builder.ins().jump(self.ebbs[target.0], &[]);
+            }
+            Terminator::Return => self.translate_return(),
+            Terminator::Unreachable => {
+                self.builder.ins().trap(TrapCode::UnreachableCodeReached);
             }
-            Stmt::Return(expr) => {
-                let v = self.translate_expr(expr);
-                self.builder.ins().return_(&[v]);
+        }
+    }
+
+    /// Returns the return value according to the calling convention.
+    fn translate_return(&mut self) {
+        let returns = self.func.returns().clone();
+        let value = self.builder.use_var(Variable::new(Local::RETURN.0));
+        match self.classify(&returns) {
+            ArgClass::Ignore => {
+                self.builder.ins().return_(&[]);
+            }
+            ArgClass::Scalar | ArgClass::Memory(_) => {
+                self.builder.ins().return_(&[value]);
             }
-            Stmt::If(cond, tbody, fbody) => {
-                self.translate_expr(cond);
-                // TODO: finish
+            ArgClass::Words(n) => {
+                let words = self.load_words(&returns, value, n);
+                self.builder.ins().return_(&words);
+            }
+        }
+    }
+
+    fn translate_call(&mut self, name: &ast::Path, args: &[Operand], dest: Local) {
+        let returns = self.func.local_ty(dest).clone();
+        let ret_class = self.classify(&returns);
+        let dest_var = Variable::new(dest.0);
+
+        let mut words = Vec::new();
+        let mut tys = Vec::new();
+        for arg in args {
+            let ty = self.operand_ty(arg);
+            let value = self.translate_operand(arg);
+            match self.classify(&ty) {
+                ArgClass::Ignore => (),
+                ArgClass::Scalar => words.push(value),
+                // structs in memory are copied onto the stack word by word
+                ArgClass::Words(n) | ArgClass::Memory(n) => {
+                    let struct_words = self.load_words(&ty, value, n);
+                    words.extend(struct_words);
+                }
+            }
+            tys.push(ty);
+        }
+        let (_, layout) = layout_args(self.adts, &tys, &returns, self.pointer_bytes);
+        // registers left over before the arguments on the stack are unused
+        let unused = self.builder.ins().iconst(self.int, 0);
+        let mut call_args = vec![unused; layout.params];
+        for (word, &position) in words.into_iter().zip(&layout.positions) {
+            call_args[position] = word;
+        }
+        if let ArgClass::Memory(_) = ret_class {
+            // the callee writes the result straight into the slot of `dest`
+            let slot = self.builder.use_var(dest_var);
+            call_args.insert(0, slot);
+        }
+
+        let callee = &self.functions[name];
+        let func_ref = self.import(callee.name.clone(), &callee.signature, callee.colocated);
+        let call = self.builder.ins().call(func_ref, &call_args);
+        let results = self.builder.inst_results(call).to_vec();
+        match ret_class {
+            ArgClass::Ignore | ArgClass::Memory(_) => (),
+            ArgClass::Scalar => self.builder.def_var(dest_var, results[0]),
+            ArgClass::Words(_) => {
+                let slot = self.builder.use_var(dest_var);
+                for (i, word) in results.into_iter().enumerate() {
+                    let offset = (i as u32 * self.pointer_bytes) as i32;
+                    self.builder.ins().store(MemFlags::trusted(), word, slot, offset);
+                }
+            }
+        }
+    }
+
+    /// Makes a function callable from the one being built, like
+    /// `Module::declare_func_in_func`.
+    fn import(&mut self, name: ExternalName, signature: &Signature, colocated: bool) -> FuncRef {
+        let signature = self.builder.import_signature(signature.clone());
+        self.builder.import_function(ExtFuncData {
+            name,
+            signature,
+            colocated,
+        })
+    }
+
+    fn translate_operand(&mut self, operand: &Operand) -> Value {
+        match operand {
+            Operand::Copy(place) => {
+                let ty = self.place_ty(place);
+                match self.place_addr(place) {
+                    None => self.builder.use_var(Variable::new(place.local.0)),
+                    Some((addr, offset)) => self.load_value(&ty, addr, offset),
+                }
             }
+            Operand::Const(Constant::Int(n)) => self.builder.ins().iconst(self.int, *n),
+            Operand::Const(Constant::Bool(b)) => self.builder.ins().iconst(self.int, i64::from(*b)),
+            Operand::Const(Constant::Unit) => self.builder.ins().iconst(self.int, 0),
         }
     }
 
-    pub fn translate_expr(&mut self, expr: &Expr) -> Value {
-        match expr {
-            Expr::Int(n) => {
-                let int = self.module.target_config().pointer_type();
-                self.builder.ins().iconst(int, *n)
+    /// Evaluates an rvalue. Structs evaluate to the address of their storage.
+    fn translate_rvalue(&mut self, rvalue: &Rvalue) -> Value {
+        match rvalue {
+            Rvalue::Use(operand) => self.translate_operand(operand),
+            Rvalue::BinOp(op, left, right) => {
+                let left = self.translate_operand(left);
+                let right = self.translate_operand(right);
+                let ins = self.builder.ins();
+                let cmp = match op {
+                    BinOp::LogicalOr | BinOp::LogicalAnd => unreachable!("lowered to branches"),
+                    BinOp::BitwiseOr => return ins.bor(left, right),
+                    BinOp::BitwiseXor => return ins.bxor(left, right),
+                    BinOp::BitwiseAnd => return ins.band(left, right),
+                    BinOp::LeftShift => return ins.ishl(left, right),
+                    BinOp::RightShift => return ins.sshr(left, right),
+                    BinOp::Add => return ins.iadd(left, right),
+                    BinOp::Sub => return ins.isub(left, right),
+                    BinOp::Mul => return ins.imul(left, right),
+                    BinOp::Div => return ins.sdiv(left, right),
+                    BinOp::Mod => return ins.srem(left, right),
+                    BinOp::Equals => IntCC::Equal,
+                    BinOp::NotEquals => IntCC::NotEqual,
+                    BinOp::LessThan => IntCC::SignedLessThan,
+                    BinOp::LessThanEquals => IntCC::SignedLessThanOrEqual,
+                    BinOp::GreaterThan => IntCC::SignedGreaterThan,
+                    BinOp::GreaterThanEquals => IntCC::SignedGreaterThanOrEqual,
+                };
+                let result = self.builder.ins().icmp(cmp, left, right);
+                self.builder.ins().bint(self.int, result)
+            }
+            Rvalue::UnOp(op, operand) => {
+                let value = self.translate_operand(operand);
+                match op {
+                    UnOp::LogicalNot => {
+                        let result = self.builder.ins().icmp_imm(IntCC::Equal, value, 0);
+                        self.builder.ins().bint(self.int, result)
+                    }
+                    UnOp::BitwiseNot => self.builder.ins().bnot(value),
+                }
+            }
+            Rvalue::Struct(name, fields) => {
+                // built in a fresh slot, since the fields may be read from the
+                // place being assigned to
+                let ty = Type::Name(*name);
+                let layout = self.struct_layout(&ty);
+                let slot = self.alloc_slot(&layout);
+                for (field, operand) in fields {
+                    let offset = self.field_offset(&ty, *field);
+                    let field_ty = self.operand_ty(operand);
+                    let value = self.translate_operand(operand);
+                    self.store_value(&field_ty, value, slot, offset);
+                }
+                slot
+            }
+            Rvalue::Ctor(name, args) => self.translate_ctor(*name, args),
+            Rvalue::Discriminant(place) => {
+                let value = self.translate_operand(&Operand::Copy(place.clone()));
+                self.builder.ins().load(self.int, MemFlags::trusted(), value, 0)
+            }
+            Rvalue::Variant(place, ctor, i) => {
+                let value = self.translate_operand(&Operand::Copy(place.clone()));
+                let layout = self.adts.ctor_layout(*ctor, self.pointer_bytes);
+                let field = &self.adts.get_ctor(*ctor).expect("not a constructor").fields[*i];
+                let ty = lower_type(field).expect("fields are resolved");
+                self.load_value(&ty, value, layout.offsets[i + 1] as i32)
+            }
+        }
+    }
+
+    /// Enum values are heap-allocated: a tag word followed by the fields of the
+    /// variant, laid out like a struct.
+    fn translate_ctor(&mut self, name: Symbol, args: &[Operand]) -> Value {
+        let tag = self.adts.get_ctor(name).expect("not a constructor").tag;
+        let layout = self.adts.ctor_layout(name, self.pointer_bytes);
+        let args: Vec<_> = args
+            .iter()
+            .map(|arg| (self.translate_operand(arg), self.operand_ty(arg)))
+            .collect();
+
+        let size = self.builder.ins().iconst(self.int, i64::from(layout.size));
+        let signature = malloc_signature(self.isa);
+        let malloc = self.import(ExternalName::user(RUNTIME, 0), &signature, false);
+        let call = self.builder.ins().call(malloc, &[size]);
+        let ptr = self.builder.inst_results(call)[0];
+
+        let tag = self.builder.ins().iconst(self.int, tag as i64);
+        self.builder.ins().store(MemFlags::trusted(), tag, ptr, 0);
+        for ((arg, ty), offset) in args.into_iter().zip(&layout.offsets[1..]) {
+            self.store_value(&ty, arg, ptr, *offset as i32);
+        }
+        ptr
+    }
+
+    /// Stores a value to a place, copying structs into its storage.
+    fn assign(&mut self, place: &Place, value: Value) {
+        let ty = self.place_ty(place);
+        match self.place_addr(place) {
+            None if self.is_struct(&ty) => {
+                let dest = self.builder.use_var(Variable::new(place.local.0));
+                self.copy_into(&ty, dest, value);
+            }
+            None => self.builder.def_var(Variable::new(place.local.0), value),
+            Some((addr, offset)) => self.store_value(&ty, value, addr, offset),
+        }
+    }
+
+    /// The address of the struct a projected place is in and the offset of
+    /// the field in it. `None` if the place is a whole local.
+    fn place_addr(&mut self, place: &Place) -> Option<(Value, i32)> {
+        if place.fields.is_empty() {
+            return None;
+        }
+        let addr = self.builder.use_var(Variable::new(place.local.0));
+        let mut ty = self.func.local_ty(place.local).clone();
+        let mut offset = 0;
+        // nested structs are stored inline, so their offsets add up
+        for field in &place.fields {
+            offset += self.field_offset(&ty, *field);
+            ty = self.field_ty(&ty, *field);
+        }
+        Some((addr, offset))
+    }
+
+    fn place_ty(&self, place: &Place) -> Type {
+        self.func.place_ty(self.adts, place).expect("mir is validated")
+    }
+
+    fn operand_ty(&self, operand: &Operand) -> Type {
+        self.func.operand_ty(self.adts, operand).expect("mir is validated")
+    }
+
+    fn classify(&self, ty: &Type) -> ArgClass {
+        classify(self.adts, ty, self.pointer_bytes)
+    }
+
+    fn is_struct(&self, ty: &Type) -> bool {
+        match ty {
+            Type::Name(name) => self.adts.get_struct(*name).is_some(),
+            _ => false,
+        }
+    }
+
+    fn struct_layout(&self, ty: &Type) -> Layout {
+        match ty {
+            Type::Name(name) => self.adts.struct_layout(*name, self.pointer_bytes),
+            _ => unreachable!("not a struct"),
+        }
+    }
+
+    fn field_offset(&self, ty: &Type, field: Symbol) -> i32 {
+        let (i, _) = match ty {
+            Type::Name(name) => self.adts.get_struct(*name).expect("not a struct").get_field(field),
+            _ => unreachable!("not a struct"),
+        }
+        .expect("no such field");
+        self.struct_layout(ty).offsets[i] as i32
+    }
+
+    fn field_ty(&self, ty: &Type, field: Symbol) -> Type {
+        let (_, field_ty) = match ty {
+            Type::Name(name) => self.adts.get_struct(*name).expect("not a struct").get_field(field),
+            _ => unreachable!("not a struct"),
+        }
+        .expect("no such field");
+        lower_type(field_ty).expect("fields are resolved")
+    }
+
+    /// Allocates a stack slot for a struct. The slot is rounded up to whole
+    /// words so that it can be loaded word by word when passed in registers.
+    fn alloc_slot(&mut self, layout: &Layout) -> Value {
+        let words = layout.size.div_ceil(self.pointer_bytes);
+        let data = StackSlotData::new(StackSlotKind::ExplicitSlot, words * self.pointer_bytes);
+        let slot = self.builder.create_stack_slot(data);
+        self.builder.ins().stack_addr(self.int, slot, 0)
+    }
+
+    fn copy_into(&mut self, ty: &Type, dest: Value, src: Value) {
+        let layout = self.struct_layout(ty);
+        let config = self.isa.frontend_config();
+        let align = layout.align as u8;
+        self.builder
+            .emit_small_memcpy(config, dest, src, u64::from(layout.size), align, align);
+    }
+
+    fn copy_struct(&mut self, ty: &Type, value: Value) -> Value {
+        let layout = self.struct_layout(ty);
+        let slot = self.alloc_slot(&layout);
+        self.copy_into(ty, slot, value);
+        slot
+    }
+
+    /// Splits a struct into the words it is passed in.
+    fn load_words(&mut self, ty: &Type, value: Value, n: usize) -> Vec<Value> {
+        // a struct nested in another one isn't padded to whole words
+        let layout = self.struct_layout(ty);
+        let value = if !layout.size.is_multiple_of(self.pointer_bytes) {
+            self.copy_struct(ty, value)
+        } else {
+            value
+        };
+        (0..n)
+            .map(|i| {
+                let offset = (i as u32 * self.pointer_bytes) as i32;
+                self.builder.ins().load(self.int, MemFlags::trusted(), value, offset)
+            })
+            .collect()
+    }
+
+    /// Reads a value of type `ty` from memory. Structs aren't copied: the
+    /// result points into the enclosing value.
+    fn load_value(&mut self, ty: &Type, addr: Value, offset: i32) -> Value {
+        if self.is_struct(ty) {
+            return self.builder.ins().iadd_imm(addr, i64::from(offset));
+        }
+        match ty {
+            Type::Bool => self.builder.ins().uload8(self.int, MemFlags::trusted(), addr, offset),
+            _ => self.builder.ins().load(self.int, MemFlags::trusted(), addr, offset),
+        }
+    }
+
+    fn store_value(&mut self, ty: &Type, value: Value, addr: Value, offset: i32) {
+        if self.is_struct(ty) {
+            let dest = self.builder.ins().iadd_imm(addr, i64::from(offset));
+            self.copy_into(ty, dest, value);
+            return;
+        }
+        match ty {
+            Type::Bool => {
+                self.builder.ins().istore8(MemFlags::trusted(), value, addr, offset);
             }
-            Expr::Call(func, args) => {
-                let int = self.module.target_config().pointer_type();
-                self.builder.ins().iconst(int, 117)
+            _ => {
+                self.builder.ins().store(MemFlags::trusted(), value, addr, offset);
             }
         }
     }
@@ -0,0 +1,460 @@
+//! Prints x86-64 machine code in Intel syntax, for `--emit asm`. Only the
+//! instructions that Cranelift emits are known; anything else is printed as
+//! bytes. Cranelift says where every instruction starts and ends, so an
+//! instruction that isn't understood doesn't throw off the next one.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const REG64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+];
+const REG32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d",
+    "r15d",
+];
+const REG16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w",
+];
+const REG8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b",
+];
+/// The byte registers 4 to 7 without a rex prefix.
+const REG8_LEGACY: [&str; 4] = ["ah", "ch", "dh", "bh"];
+
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFT: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+const CONDITIONS: [&str; 16] = [
+    "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+
+/// What the bytes of a function refer to.
+#[derive(Default)]
+pub struct Symbols {
+    /// The names of the symbols whose addresses are patched in at these
+    /// offsets.
+    pub relocs: HashMap<usize, String>,
+    /// The names of the offsets that jumps can go to.
+    pub labels: HashMap<usize, String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Size {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl Size {
+    fn reg(self, reg: u8, rex: bool) -> &'static str {
+        let reg = reg as usize;
+        match self {
+            Size::Byte if !rex && (4..8).contains(&reg) => REG8_LEGACY[reg - 4],
+            Size::Byte => REG8[reg],
+            Size::Word => REG16[reg],
+            Size::Dword => REG32[reg],
+            Size::Qword => REG64[reg],
+        }
+    }
+
+    fn ptr(self) -> &'static str {
+        match self {
+            Size::Byte => "byte ptr ",
+            Size::Word => "word ptr ",
+            Size::Dword => "dword ptr ",
+            Size::Qword => "qword ptr ",
+        }
+    }
+}
+
+/// The r/m operand of an instruction.
+enum Operand {
+    Reg(u8),
+    /// A memory address, without its size.
+    Mem(String),
+}
+
+struct Decoder<'a> {
+    code: &'a [u8],
+    pos: usize,
+    end: usize,
+    symbols: &'a Symbols,
+    rex: Option<u8>,
+    operand_size_prefix: bool,
+}
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        if self.pos == self.end {
+            return None;
+        }
+        self.pos += 1;
+        Some(self.code[self.pos - 1])
+    }
+
+    fn i8(&mut self) -> Option<i64> {
+        self.byte().map(|b| i64::from(b as i8))
+    }
+
+    fn i32(&mut self) -> Option<i64> {
+        let mut bytes = [0; 4];
+        for byte in &mut bytes {
+            *byte = self.byte()?;
+        }
+        Some(i64::from(i32::from_le_bytes(bytes)))
+    }
+
+    fn i64(&mut self) -> Option<i64> {
+        let mut bytes = [0; 8];
+        for byte in &mut bytes {
+            *byte = self.byte()?;
+        }
+        Some(i64::from_le_bytes(bytes))
+    }
+
+    /// A 4-byte immediate, or the symbol whose address replaces it.
+    fn imm32(&mut self) -> Option<String> {
+        let start = self.pos;
+        let imm = self.i32()?;
+        Some(match self.symbols.relocs.get(&start) {
+            Some(name) => name.clone(),
+            None => hex(imm),
+        })
+    }
+
+    fn imm64(&mut self) -> Option<String> {
+        let start = self.pos;
+        let imm = self.i64()?;
+        Some(match self.symbols.relocs.get(&start) {
+            Some(name) => name.clone(),
+            None => hex(imm),
+        })
+    }
+
+    /// The target of a relative jump or call, whose displacement was just
+    /// read.
+    fn target(&self, disp_start: usize, disp: i64) -> String {
+        if let Some(name) = self.symbols.relocs.get(&disp_start) {
+            return name.clone();
+        }
+        let target = (self.pos as i64 + disp) as usize;
+        match self.symbols.labels.get(&target) {
+            Some(label) => label.clone(),
+            None => format!("{:#x}", target),
+        }
+    }
+
+    fn rex_bit(&self, bit: u8) -> u8 {
+        match self.rex {
+            Some(rex) if rex & bit != 0 => 8,
+            _ => 0,
+        }
+    }
+
+    /// The size of the operands of an instruction that isn't about bytes.
+    fn size(&self) -> Size {
+        if self.rex_bit(0x8) != 0 {
+            Size::Qword
+        } else if self.operand_size_prefix {
+            Size::Word
+        } else {
+            Size::Dword
+        }
+    }
+
+    fn reg(&self, size: Size, reg: u8) -> &'static str {
+        size.reg(reg, self.rex.is_some())
+    }
+
+    /// Reads a modrm byte and what follows it, and returns the reg field and
+    /// the r/m operand.
+    fn modrm(&mut self) -> Option<(u8, Operand)> {
+        let modrm = self.byte()?;
+        let mode = modrm >> 6;
+        let reg = (modrm >> 3 & 7) | self.rex_bit(0x4);
+        let rm = modrm & 7;
+        if mode == 3 {
+            return Some((reg, Operand::Reg(rm | self.rex_bit(0x1))));
+        }
+
+        let mut address = String::new();
+        if rm == 4 {
+            let sib = self.byte()?;
+            let scale = 1 << (sib >> 6);
+            let index = (sib >> 3 & 7) | self.rex_bit(0x2);
+            let base = sib & 7;
+            if base == 5 && mode == 0 {
+                let disp = self.i32()?;
+                address.push_str(&hex(disp));
+            } else {
+                address.push_str(REG64[(base | self.rex_bit(0x1)) as usize]);
+            }
+            if index != 4 {
+                write!(address, " + {}", REG64[index as usize]).unwrap();
+                if scale != 1 {
+                    write!(address, "*{}", scale).unwrap();
+                }
+            }
+        } else if rm == 5 && mode == 0 {
+            // Cranelift only addresses its jump tables like this, with lea,
+            // so the instruction ends with the displacement
+            let start = self.pos;
+            let disp = self.i32()?;
+            return Some((reg, Operand::Mem(format!("[rip + {}]", self.target(start, disp)))));
+        } else {
+            address.push_str(REG64[(rm | self.rex_bit(0x1)) as usize]);
+        }
+
+        let disp = match mode {
+            1 => self.i8()?,
+            2 => self.i32()?,
+            _ => 0,
+        };
+        if disp < 0 {
+            write!(address, " - {:#x}", -disp).unwrap();
+        } else if disp > 0 {
+            write!(address, " + {:#x}", disp).unwrap();
+        }
+        Some((reg, Operand::Mem(format!("[{}]", address))))
+    }
+
+    fn operand(&self, size: Size, operand: &Operand) -> String {
+        match operand {
+            Operand::Reg(reg) => self.reg(size, *reg).to_string(),
+            Operand::Mem(address) => format!("{}{}", size.ptr(), address),
+        }
+    }
+
+    /// `op r/m, r` or `op r, r/m`.
+    fn reg_rm(&mut self, op: &str, size: Size, reg_first: bool) -> Option<String> {
+        let (reg, rm) = self.modrm()?;
+        let reg = self.reg(size, reg);
+        let rm = self.operand(size, &rm);
+        Some(if reg_first {
+            format!("{} {}, {}", op, reg, rm)
+        } else {
+            format!("{} {}, {}", op, rm, reg)
+        })
+    }
+
+    fn instruction(&mut self) -> Option<String> {
+        let mut opcode = self.byte()?;
+        if opcode == 0x66 {
+            self.operand_size_prefix = true;
+            opcode = self.byte()?;
+        }
+        if opcode & 0xf0 == 0x40 {
+            self.rex = Some(opcode);
+            opcode = self.byte()?;
+        }
+        let size = self.size();
+
+        Some(match opcode {
+            0x00..=0x3f if opcode & 7 < 6 => {
+                let op = ALU[(opcode >> 3) as usize];
+                match opcode & 7 {
+                    0 => self.reg_rm(op, Size::Byte, false)?,
+                    1 => self.reg_rm(op, size, false)?,
+                    2 => self.reg_rm(op, Size::Byte, true)?,
+                    3 => self.reg_rm(op, size, true)?,
+                    4 => format!("{} al, {}", op, hex(self.i8()?)),
+                    _ => format!("{} {}, {}", op, self.reg(size, 0), self.imm32()?),
+                }
+            }
+            0x50..=0x57 => format!("push {}", REG64[((opcode & 7) | self.rex_bit(0x1)) as usize]),
+            0x58..=0x5f => format!("pop {}", REG64[((opcode & 7) | self.rex_bit(0x1)) as usize]),
+            0x63 => {
+                let (reg, rm) = self.modrm()?;
+                format!("movsxd {}, {}", self.reg(size, reg), self.operand(Size::Dword, &rm))
+            }
+            0x69 | 0x6b => {
+                let (reg, rm) = self.modrm()?;
+                let imm = if opcode == 0x69 { self.imm32()? } else { hex(self.i8()?) };
+                format!("imul {}, {}, {}", self.reg(size, reg), self.operand(size, &rm), imm)
+            }
+            0x70..=0x7f => {
+                let start = self.pos;
+                let disp = self.i8()?;
+                format!("j{} {}", CONDITIONS[(opcode & 0xf) as usize], self.target(start, disp))
+            }
+            0x80 | 0x81 | 0x83 => {
+                let size = if opcode == 0x80 { Size::Byte } else { size };
+                let (op, rm) = self.modrm()?;
+                let rm = self.operand(size, &rm);
+                let imm = if opcode == 0x81 { self.imm32()? } else { hex(self.i8()?) };
+                format!("{} {}, {}", ALU[(op & 7) as usize], rm, imm)
+            }
+            0x84 => self.reg_rm("test", Size::Byte, false)?,
+            0x85 => self.reg_rm("test", size, false)?,
+            0x88 => self.reg_rm("mov", Size::Byte, false)?,
+            0x89 => self.reg_rm("mov", size, false)?,
+            0x8a => self.reg_rm("mov", Size::Byte, true)?,
+            0x8b => self.reg_rm("mov", size, true)?,
+            0x8d => match self.modrm()? {
+                (reg, Operand::Mem(address)) => format!("lea {}, {}", self.reg(size, reg), address),
+                (_, Operand::Reg(_)) => return None,
+            },
+            0x90 => "nop".to_string(),
+            0x98 => match size {
+                Size::Qword => "cdqe",
+                Size::Dword => "cwde",
+                _ => "cbw",
+            }
+            .to_string(),
+            0x99 => match size {
+                Size::Qword => "cqo",
+                Size::Dword => "cdq",
+                _ => "cwd",
+            }
+            .to_string(),
+            0xb0..=0xb7 => {
+                let reg = self.reg(Size::Byte, (opcode & 7) | self.rex_bit(0x1));
+                format!("mov {}, {}", reg, hex(self.i8()?))
+            }
+            0xb8..=0xbf => {
+                let reg = self.reg(size, (opcode & 7) | self.rex_bit(0x1));
+                let imm = if size == Size::Qword { self.imm64()? } else { self.imm32()? };
+                format!("mov {}, {}", reg, imm)
+            }
+            0xc0 | 0xc1 | 0xd0 | 0xd1 | 0xd2 | 0xd3 => {
+                let size = if opcode & 1 == 0 { Size::Byte } else { size };
+                let (op, rm) = self.modrm()?;
+                let rm = self.operand(size, &rm);
+                let amount = match opcode {
+                    0xc0 | 0xc1 => hex(self.i8()?),
+                    0xd0 | 0xd1 => "1".to_string(),
+                    _ => "cl".to_string(),
+                };
+                format!("{} {}, {}", SHIFT[(op & 7) as usize], rm, amount)
+            }
+            0xc3 => "ret".to_string(),
+            0xc6 | 0xc7 => {
+                let size = if opcode == 0xc6 { Size::Byte } else { size };
+                let (_, rm) = self.modrm()?;
+                let rm = self.operand(size, &rm);
+                let imm = if size == Size::Byte { hex(self.i8()?) } else { self.imm32()? };
+                format!("mov {}, {}", rm, imm)
+            }
+            0xcc => "int3".to_string(),
+            0xe8 | 0xe9 => {
+                let start = self.pos;
+                let disp = self.i32()?;
+                let op = if opcode == 0xe8 { "call" } else { "jmp" };
+                format!("{} {}", op, self.target(start, disp))
+            }
+            0xeb => {
+                let start = self.pos;
+                let disp = self.i8()?;
+                format!("jmp {}", self.target(start, disp))
+            }
+            0xf6 | 0xf7 => {
+                let size = if opcode == 0xf6 { Size::Byte } else { size };
+                let (op, rm) = self.modrm()?;
+                let rm = self.operand(size, &rm);
+                match op & 7 {
+                    0 | 1 => {
+                        let imm = if size == Size::Byte { hex(self.i8()?) } else { self.imm32()? };
+                        format!("test {}, {}", rm, imm)
+                    }
+                    op => {
+                        let op = ["", "", "not", "neg", "mul", "imul", "div", "idiv"][op as usize];
+                        format!("{} {}", op, rm)
+                    }
+                }
+            }
+            0xfe | 0xff => {
+                let (op, rm) = self.modrm()?;
+                match (opcode, op & 7) {
+                    (0xfe, 0) => format!("inc {}", self.operand(Size::Byte, &rm)),
+                    (0xfe, 1) => format!("dec {}", self.operand(Size::Byte, &rm)),
+                    (0xff, 0) => format!("inc {}", self.operand(size, &rm)),
+                    (0xff, 1) => format!("dec {}", self.operand(size, &rm)),
+                    (0xff, 2) => format!("call {}", self.operand(Size::Qword, &rm)),
+                    (0xff, 4) => format!("jmp {}", self.operand(Size::Qword, &rm)),
+                    (0xff, 6) => format!("push {}", self.operand(Size::Qword, &rm)),
+                    _ => return None,
+                }
+            }
+            0x0f => self.two_byte()?,
+            _ => return None,
+        })
+    }
+
+    /// An instruction whose opcode starts with 0x0f.
+    fn two_byte(&mut self) -> Option<String> {
+        let opcode = self.byte()?;
+        let size = self.size();
+        Some(match opcode {
+            0x0b => "ud2".to_string(),
+            0x1f => {
+                let (_, rm) = self.modrm()?;
+                format!("nop {}", self.operand(size, &rm))
+            }
+            0x40..=0x4f => self.reg_rm(&format!("cmov{}", CONDITIONS[(opcode & 0xf) as usize]), size, true)?,
+            0x80..=0x8f => {
+                let start = self.pos;
+                let disp = self.i32()?;
+                format!("j{} {}", CONDITIONS[(opcode & 0xf) as usize], self.target(start, disp))
+            }
+            0x90..=0x9f => {
+                let (_, rm) = self.modrm()?;
+                format!("set{} {}", CONDITIONS[(opcode & 0xf) as usize], self.operand(Size::Byte, &rm))
+            }
+            0xaf => self.reg_rm("imul", size, true)?,
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                let op = if opcode < 0xb8 { "movzx" } else { "movsx" };
+                let from = if opcode & 1 == 0 { Size::Byte } else { Size::Word };
+                let (reg, rm) = self.modrm()?;
+                format!("{} {}, {}", op, self.reg(size, reg), self.operand(from, &rm))
+            }
+            _ => return None,
+        })
+    }
+}
+
+fn hex(n: i64) -> String {
+    if n < 0 {
+        format!("-{:#x}", -(n as i128))
+    } else {
+        format!("{:#x}", n)
+    }
+}
+
+/// Prints one line of a listing: the offset and bytes of `code[start..end]`,
+/// then what they mean.
+pub fn line(code: &[u8], start: usize, end: usize, text: &str) -> String {
+    let bytes: Vec<String> = code[start..end].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("  {:04x}:  {:<30} {}\n", start, bytes.join(" "), text)
+}
+
+/// Prints the machine instructions in `code[start..end]`, one per line with
+/// its offset and bytes. A single Cranelift instruction, like a branch on a
+/// comparison, can take several of them.
+pub fn instructions(code: &[u8], start: usize, end: usize, symbols: &Symbols) -> String {
+    let mut listing = String::new();
+    let mut pos = start;
+    while pos < end {
+        let mut decoder = Decoder {
+            code,
+            pos,
+            end,
+            symbols,
+            rex: None,
+            operand_size_prefix: false,
+        };
+        match decoder.instruction() {
+            Some(text) => {
+                listing.push_str(&line(code, pos, decoder.pos, &text));
+                pos = decoder.pos;
+            }
+            // whatever couldn't be decoded is printed as it is
+            None => {
+                let bytes: Vec<String> = code[pos..end].iter().map(|b| format!("{:#04x}", b)).collect();
+                listing.push_str(&line(code, pos, end, &format!(".byte {}", bytes.join(", "))));
+                break;
+            }
+        }
+    }
+    listing
+}
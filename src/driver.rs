@@ -0,0 +1,260 @@
+//! The stages of the front end, from the linked ast to mir. Errors are
+//! returned as messages instead of ending the process, so that the repl can
+//! carry on after one.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use symbol::Symbol;
+
+use crate::adt::AdtTable;
+use crate::ast::{Program, Span, Spanned, Type};
+use crate::class::ClassTable;
+use crate::env::Environment;
+use crate::incremental::Cache;
+use crate::timing::Timings;
+use crate::{harness, lower, mir, mono, pattern, prelude, scanner, typeck};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+/// An error or a warning about the program, with the part of the source it's
+/// about if it's known.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub span: Option<Span>,
+    /// The line and column the span starts at, once the source it's in is
+    /// known.
+    pub location: Option<(usize, usize)>,
+}
+
+impl Diagnostic {
+    pub fn error(err: impl ToString) -> Self {
+        Diagnostic {
+            level: Level::Error,
+            message: err.to_string(),
+            span: None,
+            location: None,
+        }
+    }
+
+    pub fn warning(warning: impl ToString) -> Self {
+        Diagnostic {
+            level: Level::Warning,
+            message: warning.to_string(),
+            span: None,
+            location: None,
+        }
+    }
+
+    pub fn at(mut self, span: Option<Span>) -> Self {
+        self.span = span;
+        self
+    }
+
+    /// Finds the line and column of the span in the source of its file. The
+    /// location names the file, so the message no longer starts with it.
+    pub fn locate(&mut self, source: &str) {
+        let span = match self.span {
+            Some(span) if span.lo <= source.len() && source.is_char_boundary(span.lo) => span,
+            _ => return,
+        };
+        self.location = Some((scanner::line(source, span.lo), scanner::column(source, span.lo)));
+        let prefix = format!("{}: ", span.file);
+        if let Some(message) = self.message.strip_prefix(prefix.as_str()) {
+            self.message = message.to_owned();
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Error => write!(f, "error"),
+            Level::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.span, self.location) {
+            (Some(span), Some((line, column))) => {
+                write!(f, "{}:{}:{}: {}: {}", span.file, line, column, self.level, self.message)
+            }
+            _ => write!(f, "{}: {}", self.level, self.message),
+        }
+    }
+}
+
+/// Wraps an error for `check`, which stops at the first stage that fails.
+fn fatal(err: impl ToString) -> Vec<Diagnostic> {
+    vec![Diagnostic::error(err)]
+}
+
+/// Like `fatal`, for an error about a part of the source.
+fn fatal_at<T: fmt::Display>(err: Spanned<T>) -> Vec<Diagnostic> {
+    vec![Diagnostic::error(err.value).at(Some(err.span))]
+}
+
+/// Adds an error to the warnings that were found before it.
+fn after(mut warnings: Vec<Diagnostic>, err: impl ToString) -> Vec<Diagnostic> {
+    warnings.push(Diagnostic::error(err));
+    warnings
+}
+
+/// A program that typechecks, with its generic functions specialized and its
+/// methods resolved.
+pub struct Checked {
+    pub adts: AdtTable,
+    /// The names declared by the program as it was written, before generic
+    /// functions were replaced by their specializations.
+    pub names: Vec<Symbol>,
+    /// The warnings found while checking.
+    pub warnings: Vec<Diagnostic>,
+    /// The types of the functions, which can still have type variables.
+    pub type_env: Environment<Symbol, Type>,
+    pub subst: typeck::Substitution,
+    pub locals: typeck::Locals,
+    pub references: typeck::References,
+    pub ast: Program,
+}
+
+impl Checked {
+    /// The inferred type of a function or constructor.
+    pub fn type_of(&self, name: Symbol) -> Option<Type> {
+        let ty = self.type_env.lookup(name)?;
+        Some(typeck::apply(&self.subst, ty))
+    }
+
+    /// The inferred types of the arguments and local variables of a function
+    /// or method, in the order they are bound.
+    pub fn locals_of(&self, func: Symbol) -> Vec<(Symbol, Type)> {
+        let locals = self.locals.get(&func).map_or(&[][..], Vec::as_slice);
+        locals
+            .iter()
+            .map(|(name, ty)| (*name, typeck::apply(&self.subst, ty)))
+            .collect()
+    }
+
+    /// The inferred type of every name the program declares, with readable
+    /// type variables.
+    pub fn dump_types(&self) -> String {
+        let mut output = String::new();
+        for &name in &self.names {
+            let ty = self.type_of(name).expect("declared names have types");
+            output.push_str(&format!("{}: {}\n", name, display_type(&ty)));
+        }
+        output
+    }
+
+    pub fn lower(&self) -> Result<mir::Program, String> {
+        let mut signatures = HashMap::new();
+        for decl in &self.ast.0 {
+            for (name, ty) in decl.get_signatures() {
+                let ty = typeck::resolve_type(&self.type_env, &self.adts, &ty).expect("resolved while checking");
+                signatures.insert(name, typeck::apply(&self.subst, &ty));
+            }
+        }
+        lower::lower_program(&self.adts, &signatures, &self.ast).map_err(|err| err.to_string())
+    }
+}
+
+/// Names the type variables of the type `'a`, `'b` and so on, in the order
+/// they appear in.
+pub fn display_type(ty: &Type) -> Type {
+    let names = typeck::free_vars(ty)
+        .into_iter()
+        .enumerate()
+        .map(|(i, var)| {
+            let name = match i {
+                0..=25 => ((b'a' + i as u8) as char).to_string(),
+                _ => format!("t{}", i),
+            };
+            (var, Type::Var(Symbol::from(name.as_str())))
+        })
+        .collect();
+    typeck::apply(&names, ty)
+}
+
+/// Typechecks a linked program and makes it monomorphic. If that fails, the
+/// errors are returned along with the warnings found before them.
+pub fn check(ast: Program) -> Result<Checked, Vec<Diagnostic>> {
+    check_with(ast, None, &mut Timings::default())
+}
+
+/// Like `check`, but reuses the types of the functions that are in the cache,
+/// if there is one, and adds those of the others to it. Generating and
+/// solving the constraints are timed.
+pub fn check_with(mut ast: Program, cache: Option<&mut Cache>, timings: &mut Timings) -> Result<Checked, Vec<Diagnostic>> {
+    // tests are only compiled by `mochi test`, which takes them out first
+    harness::strip(&mut ast).map_err(fatal)?;
+
+    // collect user-defined types
+    let mut adts = AdtTable::from_decls(&ast.0).map_err(fatal)?;
+
+    // create environment
+    let mut type_env = Environment::<Symbol, Type>::new();
+    prelude::load_prelude(&mut type_env);
+    typeck::resolve_adts(&type_env, &mut adts).map_err(fatal)?;
+    adts.check_structs().map_err(fatal)?;
+
+    // collect classes and mark the uses of their methods and of functions
+    let classes = ClassTable::from_decls(&type_env, &adts, &ast.0).map_err(fatal)?;
+    classes
+        .elaborate(&type_env, &adts, &mut ast)
+        .map_err(fatal)?;
+    mono::mark_globals(&adts, &mut ast);
+
+    type_env.push_scope();
+    let mut names = Vec::new();
+    for decl in &ast.0 {
+        for (name, ty) in decl.get_signatures() {
+            let ty = typeck::resolve_type(&type_env, &adts, &ty).map_err(fatal)?;
+            type_env.insert(name, ty);
+            names.push(name);
+        }
+    }
+
+    // typecheck the ast
+    let (subst, locals, references) = typeck::check_program(&mut type_env, &adts, &classes, &ast, cache, timings).map_err(fatal_at)?;
+
+    // check that matches are exhaustive
+    let warnings: Vec<Diagnostic> = pattern::check_program(&adts, &ast)
+        .into_iter()
+        .map(|Spanned { value: err, span }| {
+            let diagnostic = if err.is_error() {
+                Diagnostic::error(err)
+            } else {
+                Diagnostic::warning(err)
+            };
+            diagnostic.at(Some(span))
+        })
+        .collect();
+    if warnings.iter().any(|diagnostic| diagnostic.level == Level::Error) {
+        return Err(warnings);
+    }
+
+    // specialize generic functions and pick the instance of every method
+    let mut ast = match mono::monomorphize(&type_env, &subst, ast) {
+        Ok(ast) => ast,
+        Err(err) => return Err(after(warnings, err)),
+    };
+    classes.resolve_methods(&subst, &mut ast);
+
+    Ok(Checked {
+        adts,
+        names,
+        warnings,
+        type_env,
+        subst,
+        locals,
+        references,
+        ast,
+    })
+}
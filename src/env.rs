@@ -10,6 +10,12 @@ impl<K: Eq + Hash + Debug, V: Debug> Debug for Environment<K, V> {
     }
 }
 
+impl<K: Eq + Hash, V> Default for Environment<K, V> {
+    fn default() -> Self {
+        Environment::new()
+    }
+}
+
 impl<K: Eq + Hash, V> Environment<K, V> {
     pub fn new() -> Self {
         Environment(vec![HashMap::new()])
@@ -19,15 +25,18 @@ impl<K: Eq + Hash, V> Environment<K, V> {
         self.0.push(HashMap::new());
     }
 
-    pub fn pop_scope(&mut self) {
-        self.0.pop();
+    /// Leaves the innermost scope, and returns what was bound in it.
+    pub fn pop_scope(&mut self) -> HashMap<K, V> {
+        self.0.pop().expect("should not happen")
     }
 
-    pub fn insert(&mut self, key: K, value: V) {
+    /// Binds the key in the innermost scope, and returns what it was bound to
+    /// there before.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
         self.0
             .last_mut()
             .expect("should not happen")
-            .insert(key, value);
+            .insert(key, value)
     }
 
     pub fn lookup(&self, key: K) -> Option<&V> {
@@ -38,4 +47,13 @@ impl<K: Eq + Hash, V> Environment<K, V> {
         }
         None
     }
+
+    pub fn lookup_mut(&mut self, key: K) -> Option<&mut V> {
+        for scope in self.0.iter_mut().rev() {
+            if let Some(value) = scope.get_mut(&key) {
+                return Some(value);
+            }
+        }
+        None
+    }
 }
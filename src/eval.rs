@@ -0,0 +1,281 @@
+//! A tree-walking interpreter over the ast, for the repl. It runs the checked
+//! program once generic functions are specialized and methods are resolved,
+//! with the same values and operators as the mir interpreter, so the two
+//! agree with each other and with the compiled program.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use symbol::Symbol;
+
+use crate::adt::AdtTable;
+use crate::ast::{BinOp, Decl, Expr, Func, Path, Pattern, Program, Stmt, StmtKind, Type};
+use crate::env::Environment;
+use crate::lower::lower_type;
+use crate::mir::interp::{self, InterpError, Value};
+use crate::mir::Constant;
+
+/// Calls nested deeper than this are reported as a stack overflow. Every
+/// one of them takes several frames of the interpreter's own stack.
+pub const MAX_DEPTH: usize = 10_000;
+
+/// How a statement finished.
+enum Flow {
+    Next,
+    Return(Value),
+}
+
+pub struct Evaluator<'a> {
+    adts: &'a AdtTable,
+    funcs: HashMap<Symbol, &'a Func>,
+    externs: HashMap<Symbol, &'a Type>,
+    /// The functions being run, innermost last.
+    calls: Vec<Symbol>,
+    out: Box<dyn Write + 'a>,
+}
+
+impl<'a> Evaluator<'a> {
+    /// Creates an evaluator for the program that prints to stdout.
+    pub fn new(adts: &'a AdtTable, program: &'a Program) -> Self {
+        let mut funcs = HashMap::new();
+        let mut externs = HashMap::new();
+        for decl in &program.0 {
+            match decl {
+                Decl::Func(func) => {
+                    funcs.insert(func.name, func);
+                }
+                Decl::Instance(instance) => {
+                    for func in &instance.methods {
+                        funcs.insert(instance.method_symbol(func.name), func);
+                    }
+                }
+                Decl::Extern(name, _, returns) => {
+                    externs.insert(*name, returns);
+                }
+                Decl::Class(_) | Decl::Enum(_) | Decl::Struct(_) | Decl::Test(_) | Decl::Use(_, _) => (),
+            }
+        }
+        Evaluator {
+            adts,
+            funcs,
+            externs,
+            calls: Vec::new(),
+            out: Box::new(io::stdout()),
+        }
+    }
+
+    /// Sends what `print` writes here instead of to stdout.
+    pub fn output(mut self, out: impl Write + 'a) -> Self {
+        self.out = Box::new(out);
+        self
+    }
+
+    pub fn call(&mut self, name: Symbol, args: Vec<Value>) -> Result<Value, InterpError> {
+        let func = *self
+            .funcs
+            .get(&name)
+            .ok_or_else(|| InterpError::UnknownFunction(Path::from_qualified(name)))?;
+        if self.calls.len() == MAX_DEPTH {
+            return Err(InterpError::StackOverflow(MAX_DEPTH));
+        }
+        let mut env = Environment::new();
+        for ((arg, _), value) in func.args.iter().zip(args) {
+            env.insert(*arg, value);
+        }
+        self.calls.push(name);
+        let flow = self.body(&mut env, &func.body);
+        self.calls.pop();
+        match flow? {
+            Flow::Return(value) => Ok(value),
+            // falling off the end returns nothing
+            Flow::Next => Ok(self.zero(&func.returns)),
+        }
+    }
+
+    fn zero(&self, ty: &Type) -> Value {
+        lower_type(ty).map_or(Value::Unit, |ty| Value::zero(self.adts, &ty))
+    }
+
+    /// The function being run, for errors.
+    fn current(&self) -> Path {
+        Path::from_qualified(*self.calls.last().expect("called from a function"))
+    }
+
+    fn body(&mut self, env: &mut Environment<Symbol, Value>, stmts: &[Stmt]) -> Result<Flow, InterpError> {
+        env.push_scope();
+        let mut flow = Ok(Flow::Next);
+        for stmt in stmts {
+            flow = self.stmt(env, stmt);
+            if let Ok(Flow::Next) = flow {
+                continue;
+            }
+            break;
+        }
+        env.pop_scope();
+        flow
+    }
+
+    fn stmt(&mut self, env: &mut Environment<Symbol, Value>, stmt: &Stmt) -> Result<Flow, InterpError> {
+        match &stmt.kind {
+            StmtKind::Expr(expr) => {
+                self.expr(env, expr)?;
+            }
+            StmtKind::Let(name, expr) => {
+                let value = self.expr(env, expr)?;
+                env.insert(*name, value);
+            }
+            StmtKind::Assign(target, expr) => self.assign(env, target, expr)?,
+            StmtKind::Return(expr) => return Ok(Flow::Return(self.expr(env, expr)?)),
+            StmtKind::If(cond, tbody, fbody) => {
+                let body = match self.expr(env, cond)? {
+                    Value::Bool(true) => tbody,
+                    Value::Bool(false) => fbody,
+                    value => panic!("expected a bool, found {}", value),
+                };
+                return self.body(env, body);
+            }
+            StmtKind::Match(expr, arms) => {
+                let value = self.expr(env, expr)?;
+                for arm in arms {
+                    env.push_scope();
+                    let flow = if self.bind(env, &arm.pattern, &value) {
+                        Some(self.body(env, &arm.body))
+                    } else {
+                        None
+                    };
+                    env.pop_scope();
+                    if let Some(flow) = flow {
+                        return flow;
+                    }
+                }
+                return Err(InterpError::Unreachable(self.current()));
+            }
+            StmtKind::Assert(_) => unreachable!("assertions are taken out before typechecking"),
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Assigns to a variable or to a field nested in one. Fields of any other
+    /// value, like the result of a call, are assigned to a copy that is
+    /// thrown away, like in the compiled program.
+    fn assign(&mut self, env: &mut Environment<Symbol, Value>, target: &Expr, expr: &Expr) -> Result<(), InterpError> {
+        let mut fields = Vec::new();
+        let mut base = target;
+        while let Expr::Field(inner, field) = base {
+            fields.push(*field);
+            base = inner;
+        }
+        let name = match base {
+            Expr::Ident(name) if env.lookup(*name).is_some() => *name,
+            _ => {
+                self.expr(env, base)?;
+                self.expr(env, expr)?;
+                return Ok(());
+            }
+        };
+
+        let value = self.expr(env, expr)?;
+        let mut place = env.lookup_mut(name).unwrap();
+        for field in fields.into_iter().rev() {
+            place = place.field_mut(field);
+        }
+        *place = value;
+        Ok(())
+    }
+
+    /// Matches the value against the pattern, binding its variables in the
+    /// innermost scope.
+    fn bind(&self, env: &mut Environment<Symbol, Value>, pattern: &Pattern, value: &Value) -> bool {
+        match pattern {
+            Pattern::Wildcard => true,
+            Pattern::Int(n) => value.as_int() == *n,
+            Pattern::Ident(name) if self.adts.get_ctor(*name).is_some() => value.as_enum().0 == *name,
+            Pattern::Ident(name) => {
+                env.insert(*name, value.clone());
+                true
+            }
+            Pattern::Ctor(name, patterns) => {
+                let (ctor, fields) = value.as_enum();
+                ctor == *name
+                    && patterns
+                        .iter()
+                        .zip(fields)
+                        .all(|(pattern, field)| self.bind(env, pattern, field))
+            }
+        }
+    }
+
+    fn expr(&mut self, env: &mut Environment<Symbol, Value>, expr: &Expr) -> Result<Value, InterpError> {
+        Ok(match expr {
+            Expr::Int(n) => Value::Int(*n),
+            Expr::Ident(name) => match env.lookup(*name) {
+                Some(value) => value.clone(),
+                None => Value::Enum(Rc::new((*name, Vec::new()))),
+            },
+            Expr::Call(func, args) => {
+                let name = match func.as_ref() {
+                    Expr::Ident(name) => *name,
+                    _ => unreachable!("only names have function types"),
+                };
+                let args = args
+                    .iter()
+                    .map(|arg| self.expr(env, arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if self.adts.get_ctor(name).is_some() {
+                    return Ok(Value::Enum(Rc::new((name, args))));
+                }
+                if let Some(returns) = self.externs.get(&name) {
+                    interp::call_builtin(&Path::from_qualified(name), &args, &mut *self.out)?;
+                    return Ok(self.zero(returns));
+                }
+                self.call(name, args)?
+            }
+            Expr::BinOp(op @ BinOp::LogicalAnd, left, right) | Expr::BinOp(op @ BinOp::LogicalOr, left, right) => {
+                // the right operand is only evaluated if the left one doesn't
+                // decide the result
+                match (op, self.expr(env, left)?) {
+                    (BinOp::LogicalAnd, Value::Bool(false)) => Value::Bool(false),
+                    (BinOp::LogicalOr, Value::Bool(true)) => Value::Bool(true),
+                    _ => self.expr(env, right)?,
+                }
+            }
+            Expr::BinOp(op, left, right) => {
+                let left = self.expr(env, left)?.as_operand();
+                let right = self.expr(env, right)?.as_operand();
+                match interp::eval_binop(*op, left, right) {
+                    Some(constant) => Value::from(constant),
+                    None => return Err(InterpError::DivisionTrap(self.current())),
+                }
+            }
+            Expr::UnOp(op, expr) => {
+                let value = match self.expr(env, expr)? {
+                    Value::Int(n) => Constant::Int(n),
+                    Value::Bool(b) => Constant::Bool(b),
+                    value => panic!("expected an int or a bool, found {}", value),
+                };
+                Value::from(interp::eval_unop(*op, value).expect("typechecked"))
+            }
+            Expr::Struct(name, fields) => {
+                // fields are evaluated in the order they are written in
+                let mut values = Vec::new();
+                for (field, expr) in fields {
+                    values.push((*field, self.expr(env, expr)?));
+                }
+                let info = self.adts.get_struct(*name).expect("not a struct");
+                let values = info
+                    .fields
+                    .iter()
+                    .map(|(field, _)| {
+                        let i = values.iter().position(|(name, _)| name == field).expect("field is set");
+                        values.swap_remove(i)
+                    })
+                    .collect();
+                Value::Struct(*name, values)
+            }
+            Expr::Field(base, field) => self.expr(env, base)?.field(*field).clone(),
+            Expr::Method(_, _, _) => unreachable!("methods are resolved before evaluating"),
+            Expr::Global(_, _) => unreachable!("functions are specialized before evaluating"),
+        })
+    }
+}
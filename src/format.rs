@@ -0,0 +1,551 @@
+//! `mochi fmt`: prints a parsed module back as source in one canonical style.
+//! Blocks are indented by two spaces, binary operators have a space on either
+//! side, and expressions get only the parentheses the grammar needs.
+//!
+//! The ast doesn't keep comments or blank lines, so `format` puts them back
+//! afterwards. Every comment is tied to the token it comes before, or to the
+//! token it ends the line of, and the printed program has the same tokens as
+//! the source apart from separators and parentheses, so they can be matched
+//! up.
+
+use std::fmt::Write;
+
+use crate::ast::{BinOp, Class, Decl, Enum, Expr, Func, InlineHint, Instance, Pattern, Program, Stmt, StmtKind, Struct, Type, UnOp};
+use crate::scanner::{self, Scanner, Token};
+
+const INDENT: &str = "  ";
+
+/// How far ahead `align` looks for a token the printed program has in common
+/// with the source.
+const LOOKAHEAD: usize = 8;
+
+/// Binds tighter than every binary operator.
+const UNARY: u8 = 11;
+/// Literals, names, calls and fields, which never need parentheses.
+const FINAL: u8 = 12;
+
+/// Prints the program with the comments and blank lines of its source.
+pub fn format(program: &Program, source: &str) -> String {
+    restore_comments(source, &print(program))
+}
+
+/// Prints the program without comments.
+pub fn print(program: &Program) -> String {
+    let mut printer = Printer {
+        out: String::new(),
+        indent: 0,
+    };
+    printer.program(program);
+    printer.out
+}
+
+/// Prints a declaration on its own, without comments.
+pub fn print_decl(decl: &Decl) -> String {
+    let mut printer = Printer {
+        out: String::new(),
+        indent: 0,
+    };
+    printer.decl(decl, "");
+    printer.out
+}
+
+/// Prints a function or method on its own, without comments. The names
+/// typechecking puts in the ast are printed as they were written.
+pub fn print_func(func: &Func) -> String {
+    let mut printer = Printer {
+        out: String::new(),
+        indent: 0,
+    };
+    printer.func(func, "");
+    printer.out
+}
+
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::BinOp(op, _, _) => match op {
+            BinOp::LogicalOr => 1,
+            BinOp::LogicalAnd => 2,
+            BinOp::BitwiseOr => 3,
+            BinOp::BitwiseXor => 4,
+            BinOp::BitwiseAnd => 5,
+            BinOp::Equals | BinOp::NotEquals => 6,
+            BinOp::LessThan | BinOp::LessThanEquals | BinOp::GreaterThan | BinOp::GreaterThanEquals => 7,
+            BinOp::LeftShift | BinOp::RightShift => 8,
+            BinOp::Add | BinOp::Sub => 9,
+            BinOp::Mul | BinOp::Div | BinOp::Mod => 10,
+        },
+        Expr::UnOp(_, _) => UNARY,
+        _ => FINAL,
+    }
+}
+
+/// Whether the type was left out of the source, and inferred.
+fn is_inferred(ty: &Type) -> bool {
+    matches!(ty, Type::Var(_))
+}
+
+/// Joins the items with commas.
+fn list<T>(items: &[T], item: impl Fn(&T) -> String) -> String {
+    items.iter().map(item).collect::<Vec<_>>().join(", ")
+}
+
+fn ty(ty: &Type) -> String {
+    match ty {
+        Type::Func(args, returns) if args.len() == 1 => {
+            let arg = match &args[0] {
+                arg @ Type::Func(_, _) => format!("({})", self::ty(arg)),
+                arg => self::ty(arg),
+            };
+            format!("{} -> {}", arg, self::ty(returns))
+        }
+        ty => ty.to_string(),
+    }
+}
+
+/// An argument list, which is left out if it's empty.
+fn args(args: &[(impl ToString, Type)]) -> String {
+    if args.is_empty() {
+        return String::new();
+    }
+    let args = list(args, |(name, ty)| {
+        if is_inferred(ty) {
+            name.to_string()
+        } else {
+            format!("{}: {}", name.to_string(), self::ty(ty))
+        }
+    });
+    format!("({})", args)
+}
+
+fn returns(returns: &Type) -> String {
+    match returns {
+        Type::Var(_) | Type::Unit => String::new(),
+        returns => format!(" -> {}", ty(returns)),
+    }
+}
+
+fn expr(expr: &Expr, min: u8) -> String {
+    let printed = match expr {
+        Expr::Int(n) => n.to_string(),
+        Expr::Ident(name) => name.to_string(),
+        Expr::Call(func, args) => format!("{}({})", self::expr(func, FINAL), list(args, |arg| self::expr(arg, 0))),
+        Expr::BinOp(op, left, right) => {
+            // every operator is left associative
+            let prec = precedence(expr);
+            format!("{} {} {}", self::expr(left, prec), op.symbol(), self::expr(right, prec + 1))
+        }
+        Expr::UnOp(op, operand) => {
+            let op = match op {
+                UnOp::LogicalNot => "!",
+                UnOp::BitwiseNot => "~",
+            };
+            format!("{}{}", op, self::expr(operand, FINAL))
+        }
+        Expr::Struct(name, fields) if fields.is_empty() => format!("{} {{}}", name),
+        Expr::Struct(name, fields) => {
+            let fields = list(fields, |(field, value)| format!("{}: {}", field, self::expr(value, 0)));
+            format!("{} {{ {} }}", name, fields)
+        }
+        Expr::Field(base, field) => format!("{}.{}", self::expr(base, FINAL), field),
+        Expr::Method(name, _, _) | Expr::Global(name, _) => name.to_string(),
+    };
+    if precedence(expr) < min {
+        format!("({})", printed)
+    } else {
+        printed
+    }
+}
+
+fn pattern(pattern: &Pattern) -> String {
+    match pattern {
+        Pattern::Wildcard => "_".to_owned(),
+        Pattern::Int(n) => n.to_string(),
+        Pattern::Ident(name) => name.to_string(),
+        Pattern::Ctor(name, patterns) => format!("{}({})", name, list(patterns, self::pattern)),
+    }
+}
+
+struct Printer {
+    out: String,
+    indent: usize,
+}
+
+impl Printer {
+    fn line(&mut self, line: impl AsRef<str>) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+        self.out.push_str(line.as_ref());
+        self.out.push('\n');
+    }
+
+    fn block(&mut self, block: impl FnOnce(&mut Self)) {
+        self.indent += 1;
+        block(self);
+        self.indent -= 1;
+    }
+
+    fn program(&mut self, program: &Program) {
+        let mut last: Option<&Decl> = None;
+        for decl in &program.0 {
+            // imports are kept together
+            match (last, decl) {
+                (None, _) | (Some(Decl::Use(_, _)), Decl::Use(_, _)) => (),
+                _ => self.out.push('\n'),
+            }
+            let public = decl.name().is_some_and(|name| program.1.contains(&name));
+            self.decl(decl, if public { "pub " } else { "" });
+            last = Some(decl);
+        }
+    }
+
+    fn decl(&mut self, decl: &Decl, public: &str) {
+        match decl {
+            Decl::Class(class) => self.class(class, public),
+            Decl::Enum(enum_) => self.enum_(enum_, public),
+            Decl::Extern(name, args, returns) => {
+                self.line(format!("{}extern fn {}{}{}", public, name, self::args(args), self::returns(returns)))
+            }
+            Decl::Func(func) => self.func(func, public),
+            Decl::Instance(instance) => self.instance(instance),
+            Decl::Struct(struct_) => self.struct_(struct_, public),
+            Decl::Test(test) => {
+                self.line(format!("test \"{}\":", test.name));
+                self.body(&test.body);
+            }
+            Decl::Use(path, _) => self.line(format!("use {}", path)),
+        }
+    }
+
+    fn class(&mut self, class: &Class, public: &str) {
+        self.line(format!("{}class {} {}:", public, class.name, class.param));
+        self.block(|printer| {
+            for (name, names, ty) in &class.methods {
+                let (args, returns) = match ty {
+                    Type::Func(args, returns) => (args, returns),
+                    _ => unreachable!("methods have function types"),
+                };
+                let args: Vec<_> = names.iter().zip(args.iter().cloned()).collect();
+                printer.line(format!("fn {}{}{}", name, self::args(&args), self::returns(returns)));
+            }
+        });
+    }
+
+    fn enum_(&mut self, enum_: &Enum, public: &str) {
+        self.line(format!("{}enum {}:", public, enum_.name));
+        self.block(|printer| {
+            for variant in &enum_.variants {
+                if variant.fields.is_empty() {
+                    printer.line(variant.name.as_str());
+                } else {
+                    printer.line(format!("{}({})", variant.name, list(&variant.fields, ty)));
+                }
+            }
+        });
+    }
+
+    fn func(&mut self, func: &Func, public: &str) {
+        match func.inline {
+            InlineHint::Auto => (),
+            InlineHint::Always => self.line("@inline"),
+            InlineHint::Never => self.line("@noinline"),
+        }
+        if func.tailcall {
+            self.line("@tailcall");
+        }
+        self.line(format!(
+            "{}fn {}{}{}:",
+            public,
+            func.name,
+            args(&func.args),
+            returns(&func.returns)
+        ));
+        self.body(&func.body);
+    }
+
+    fn instance(&mut self, instance: &Instance) {
+        self.line(format!("instance {} {}:", instance.class, ty(&instance.ty)));
+        self.block(|printer| {
+            for func in &instance.methods {
+                printer.func(func, "");
+            }
+        });
+    }
+
+    fn struct_(&mut self, struct_: &Struct, public: &str) {
+        self.line(format!("{}struct {}:", public, struct_.name));
+        self.block(|printer| {
+            for (field, ty) in &struct_.fields {
+                printer.line(format!("{}: {}", field, self::ty(ty)));
+            }
+        });
+    }
+
+    fn body(&mut self, body: &[Stmt]) {
+        self.block(|printer| {
+            for stmt in body {
+                printer.stmt(stmt);
+            }
+        });
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::If(cond, tbody, fbody) => {
+                // there's no syntax for `else`
+                debug_assert!(fbody.is_empty(), "`if` with an `else` can't be printed");
+                self.line(format!("if {}:", expr(cond, 0)));
+                self.body(tbody);
+            }
+            StmtKind::Match(scrutinee, arms) => {
+                self.line(format!("match {}:", expr(scrutinee, 0)));
+                self.block(|printer| {
+                    for arm in &arms[..] {
+                        let head = pattern(&arm.pattern);
+                        match &arm.body[..] {
+                            [stmt] if simple(stmt).is_some() => {
+                                printer.line(format!("{} => {}", head, simple(stmt).unwrap()))
+                            }
+                            body => {
+                                printer.line(format!("{} =>", head));
+                                printer.body(body);
+                            }
+                        }
+                    }
+                });
+            }
+            _ => self.line(simple(stmt).expect("statement without a block")),
+        }
+    }
+}
+
+/// A statement that fits on one line, unlike `if` and `match`.
+fn simple(stmt: &Stmt) -> Option<String> {
+    Some(match &stmt.kind {
+        StmtKind::Expr(value) => expr(value, 0),
+        StmtKind::Let(name, value) => format!("let {} = {}", name, expr(value, 0)),
+        StmtKind::Assign(target, value) => format!("{} = {}", expr(target, FINAL), expr(value, 0)),
+        StmtKind::Return(value) => format!("return {}", expr(value, 0)),
+        StmtKind::Assert(assert) => {
+            let keyword = if assert.args.len() == 1 { "assert" } else { "assert_eq" };
+            format!("{}({})", keyword, list(&assert.args, |arg| expr(arg, 0)))
+        }
+        StmtKind::If(_, _, _) | StmtKind::Match(_, _) => return None,
+    })
+}
+
+/// Whether printing can change the token without changing the program: it
+/// separates lines or groups.
+fn is_layout(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Sep | Token::Indent | Token::Dedent | Token::SymSemicolon | Token::SymParenL | Token::SymParenR | Token::SymComma
+    )
+}
+
+/// The tokens of the source that printing keeps, with the byte they start at.
+fn tokens(source: &str) -> Vec<(usize, Token)> {
+    Scanner::new(source.as_bytes())
+        .filter_map(Result::ok)
+        .filter(|(_, token, _)| !is_layout(token))
+        .map(|(lo, token, _)| (lo, token))
+        .collect()
+}
+
+/// For every token of the source, the one it was printed as. Printing only
+/// drops tokens, like a repeated attribute, so the two are matched up in
+/// order, skipping over the tokens that differ.
+fn align(source: &[(usize, Token)], printed: &[(usize, Token)]) -> Vec<Option<usize>> {
+    let mut matched = vec![None; source.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < source.len() && j < printed.len() {
+        if source[i].1 == printed[j].1 {
+            matched[i] = Some(j);
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let skip = (1..=LOOKAHEAD).find_map(|distance| {
+            (0..=distance).find(|skipped| {
+                let (a, b) = (i + skipped, j + distance - skipped);
+                a < source.len() && b < printed.len() && source[a].1 == printed[b].1
+            })
+            .map(|skipped| (skipped, distance - skipped))
+        });
+        match skip {
+            Some((a, b)) => {
+                i += a;
+                j += b;
+            }
+            None => i += 1,
+        }
+    }
+    matched
+}
+
+/// What the source has between two tokens: comments on lines of their own,
+/// and blank lines, which are `None`.
+type Gap = Vec<Option<String>>;
+
+/// Puts the comments and blank lines of the source into the printed program.
+fn restore_comments(source: &str, printed: &str) -> String {
+    let comments = scanner::comments(source);
+    let source_tokens = tokens(source);
+    let printed_tokens = tokens(printed);
+    let matched = align(&source_tokens, &printed_tokens);
+
+    // what comes before every printed token, the comment at the end of its
+    // line, if any, and the comments that end the blocks it ends, with the
+    // indentation of each block
+    let mut gaps: Vec<Gap> = vec![Vec::new(); printed_tokens.len() + 1];
+    let mut trailing: Vec<Vec<String>> = vec![Vec::new(); printed_tokens.len() + 1];
+    let mut tails: Vec<Vec<(&str, Gap)>> = vec![Vec::new(); printed_tokens.len() + 1];
+    // the first printed token after each source token
+    let mut next = vec![printed_tokens.len(); source_tokens.len() + 1];
+    for i in (0..source_tokens.len()).rev() {
+        next[i] = matched[i].unwrap_or(next[i + 1]);
+    }
+
+    let lines: Vec<(usize, &str)> = source
+        .split_inclusive('\n')
+        .scan(0, |pos, line| {
+            let start = *pos;
+            *pos += line.len();
+            Some((start, line))
+        })
+        .collect();
+    // the lines of code so far, with their indentation and the last printed
+    // token on them
+    let mut code_lines: Vec<(usize, Option<usize>)> = Vec::new();
+    let mut token = 0;
+    let mut comment = 0;
+    // the comments on lines of their own since the last line of code, with
+    // their column, and the blank lines between them
+    let mut gap: Vec<(usize, Option<String>)> = Vec::new();
+    for &(start, line) in &lines {
+        let end = start + line.len();
+        let first = token;
+        while token < source_tokens.len() && source_tokens[token].0 < end {
+            token += 1;
+        }
+        let has_code = token > first;
+        if has_code {
+            let indent = line.len() - line.trim_start().len();
+            end_blocks(printed, &printed_tokens, &code_lines, indent, &mut gap, &mut tails);
+            gaps[next[first]].extend(gap.drain(..).map(|(_, line)| line));
+            let last = (first..token).rev().find_map(|i| matched[i]);
+            code_lines.push((indent, last));
+        }
+        while comment < comments.len() && comments[comment].pos < end {
+            let text = comments[comment].text.clone();
+            let column = comments[comment].pos - start;
+            comment += 1;
+            if has_code {
+                // the comment ends the line of the last token before it
+                let last = (first..token).rev().find_map(|i| matched[i]).unwrap_or(next[token]);
+                trailing[last].push(text);
+            } else {
+                gap.push((column, Some(text)));
+            }
+        }
+        if !has_code && line.trim().is_empty() && gap.last().map(|(_, line)| line) != Some(&None) {
+            gap.push((0, None));
+        }
+    }
+    end_blocks(printed, &printed_tokens, &code_lines, 0, &mut gap, &mut tails);
+    gaps[printed_tokens.len()].extend(gap.into_iter().map(|(_, line)| line));
+
+    let mut out = String::new();
+    let mut token = 0;
+    let mut pos = 0;
+    let mut block_start = true;
+    for line in printed.split_inclusive('\n') {
+        let end = pos + line.len();
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let mut comments = Vec::new();
+        let first = token;
+        while token < printed_tokens.len() && printed_tokens[token].0 < end {
+            write_gap(&mut out, indent, &gaps[token], &mut block_start);
+            comments.extend(trailing[token].iter().cloned());
+            token += 1;
+        }
+        if line.trim().is_empty() {
+            // blank lines between declarations make a gap's blank line
+            // redundant
+            if !out.ends_with("\n\n") {
+                out.push('\n');
+            }
+        } else if comments.is_empty() {
+            out.push_str(line);
+        } else {
+            writeln!(out, "{} //{}", line.trim_end(), comments.join(" //")).unwrap();
+        }
+        block_start = line.trim_end().ends_with(':') || line.trim_end().ends_with("=>");
+        for (indent, gap) in tails[first..token].iter().flatten() {
+            write_gap(&mut out, indent, gap, &mut block_start);
+        }
+        pos = end;
+    }
+    write_gap(&mut out, "", &gaps[printed_tokens.len()], &mut block_start);
+    while out.ends_with("\n\n") {
+        out.pop();
+    }
+    out
+}
+
+/// Moves the comments that end the blocks before a line of code, which are
+/// indented deeper than it, from the start of the gap to the last token of
+/// the line of code before them. Each is indented like the printed line it
+/// lines up with in the source.
+fn end_blocks<'a>(
+    printed: &'a str,
+    printed_tokens: &[(usize, Token)],
+    code_lines: &[(usize, Option<usize>)],
+    indent: usize,
+    gap: &mut Vec<(usize, Option<String>)>,
+    tails: &mut [Vec<(&'a str, Gap)>],
+) {
+    let last = match code_lines.last() {
+        Some(&(_, Some(last))) => last,
+        _ => return,
+    };
+    let mut end = gap
+        .iter()
+        .position(|(column, line)| line.is_some() && *column <= indent)
+        .unwrap_or(gap.len());
+    // blank lines after them stay before the line of code
+    while end > 0 && gap[end - 1].1.is_none() {
+        end -= 1;
+    }
+    for (column, line) in gap.drain(..end) {
+        let block = code_lines
+            .iter()
+            .rev()
+            .find(|(indent, token)| *indent <= column && token.is_some())
+            .and_then(|(_, token)| *token)
+            .unwrap_or(last);
+        let pos = printed_tokens[block].0;
+        let start = printed[..pos].rfind('\n').map_or(0, |i| i + 1);
+        let line_start = &printed[start..pos];
+        let block_indent = &line_start[..line_start.len() - line_start.trim_start().len()];
+        match tails[last].last_mut() {
+            Some((indent, lines)) if *indent == block_indent || line.is_none() => lines.push(line),
+            _ => tails[last].push((block_indent, vec![line])),
+        }
+    }
+}
+
+/// Writes the comments and blank lines of a gap, indented like the line after
+/// it. Blank lines don't start the file or a block, and don't repeat.
+fn write_gap(out: &mut String, indent: &str, gap: &[Option<String>], block_start: &mut bool) {
+    for line in gap {
+        match line {
+            Some(comment) => {
+                writeln!(out, "{}//{}", indent, comment).unwrap();
+                *block_start = false;
+            }
+            None if *block_start || out.is_empty() || out.ends_with("\n\n") => (),
+            None => out.push('\n'),
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! `test` blocks. A program is normally compiled without them, and `mochi
+//! test` turns each of them into a function of its own. The program is then
+//! compiled along with a runner written in C, and every test runs in a
+//! process of its own, so that one that traps doesn't stop the others; or
+//! each runs in an interpreter of its own. An assertion that fails calls
+//! `FAILED` with its number and the values it compared, which stops the
+//! test.
+
+use std::fmt::{self, Write as _};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path as FsPath, PathBuf};
+use std::process::Command;
+
+use symbol::Symbol;
+
+use crate::adt::AdtTable;
+use crate::ast::{Assert, BinOp, Decl, Expr, Func, InlineHint, Path, Program, Stmt, StmtKind, Type, UnOp};
+use crate::mir;
+use crate::mir::interp::{InterpError, Interpreter};
+
+/// The extern a failed assertion calls, which the interpreter and the runner
+/// provide.
+pub const FAILED: &str = "assert$failed";
+
+/// The status the runner exits with when an assertion fails, once it wrote
+/// the number of the assertion and the values it compared to stderr.
+const FAILED_STATUS: i32 = 101;
+
+#[derive(Debug)]
+pub enum HarnessError {
+    /// An assertion in a function, with the function and the line.
+    AssertOutsideTest(Symbol, usize),
+}
+
+impl fmt::Display for HarnessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HarnessError::AssertOutsideTest(func, line) => write!(
+                f,
+                "`{}` asserts on line {}, but assertions can only be used in tests",
+                func, line
+            ),
+        }
+    }
+}
+
+/// A test, compiled to a function without arguments.
+#[derive(Debug)]
+pub struct TestCase {
+    pub name: String,
+    pub func: Symbol,
+}
+
+/// An assertion, to report it when it fails.
+#[derive(Debug)]
+pub struct Assertion {
+    pub text: String,
+    pub file: PathBuf,
+    pub line: usize,
+    /// Whether it's an `assert_eq`, which reports the values it compared.
+    pub equal: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Harness {
+    pub tests: Vec<TestCase>,
+    /// Every assertion, by the number it fails with.
+    pub assertions: Vec<Assertion>,
+}
+
+/// The first assertion in the statements.
+fn find_assert(stmts: &[Stmt]) -> Option<&Assert> {
+    stmts.iter().find_map(|stmt| match &stmt.kind {
+        StmtKind::Assert(assert) => Some(assert),
+        StmtKind::If(_, tbody, fbody) => find_assert(tbody).or_else(|| find_assert(fbody)),
+        StmtKind::Match(_, arms) => arms.iter().find_map(|arm| find_assert(&arm.body)),
+        StmtKind::Expr(_) | StmtKind::Let(_, _) | StmtKind::Assign(_, _) | StmtKind::Return(_) => None,
+    })
+}
+
+/// Checks that functions don't assert, since only tests can.
+fn check_funcs(program: &Program) -> Result<(), HarnessError> {
+    for decl in &program.0 {
+        let funcs: Vec<&Func> = match decl {
+            Decl::Func(func) => vec![func],
+            Decl::Instance(instance) => instance.methods.iter().collect(),
+            _ => continue,
+        };
+        for func in funcs {
+            if let Some(assert) = find_assert(&func.body) {
+                return Err(HarnessError::AssertOutsideTest(func.name, assert.line));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Leaves the tests out of a linked program.
+pub fn strip(program: &mut Program) -> Result<(), HarnessError> {
+    check_funcs(program)?;
+    program.0.retain(|decl| !matches!(decl, Decl::Test(_)));
+    Ok(())
+}
+
+/// Turns the tests of a linked program into functions, and their assertions
+/// into calls to `FAILED` when they don't hold.
+pub fn build(program: &mut Program) -> Result<Harness, HarnessError> {
+    check_funcs(program)?;
+    let mut harness = Harness::default();
+    for decl in &mut program.0 {
+        if let Decl::Test(test) = decl {
+            let func = Symbol::from(format!("test${}", harness.tests.len()).as_str());
+            let body = std::mem::take(&mut test.body);
+            let body = harness.desugar(&test.file, body);
+            harness.tests.push(TestCase {
+                name: test.name.clone(),
+                func,
+            });
+            *decl = Decl::Func(Func {
+                name: func,
+                args: Vec::new(),
+                body,
+                returns: Type::Unit,
+                inline: InlineHint::Auto,
+                tailcall: false,
+                span: test.span,
+            });
+        }
+    }
+    let args = ["assertion", "left", "right"].iter().map(|name| (Symbol::from(*name), Type::Int));
+    program
+        .0
+        .push(Decl::Extern(Symbol::from(FAILED), args.collect(), Type::Unit));
+    Ok(harness)
+}
+
+impl Harness {
+    fn desugar(&mut self, file: &FsPath, stmts: Vec<Stmt>) -> Vec<Stmt> {
+        let mut output = Vec::new();
+        for Stmt { kind, span } in stmts {
+            let kind = match kind {
+                StmtKind::Assert(Assert { mut args, text, line }) => {
+                    let assertion = Expr::Int(self.assertions.len() as i64);
+                    self.assertions.push(Assertion {
+                        text,
+                        file: file.to_owned(),
+                        line,
+                        equal: args.len() == 2,
+                    });
+                    let fail = |left, right| {
+                        let func = Box::new(Expr::Ident(Symbol::from(FAILED)));
+                        vec![Stmt::new(StmtKind::Expr(Expr::Call(func, vec![assertion, left, right])), span)]
+                    };
+                    if args.len() == 1 {
+                        let cond = Expr::UnOp(UnOp::LogicalNot, Box::new(args.remove(0)));
+                        StmtKind::If(cond, fail(Expr::Int(0), Expr::Int(0)), Vec::new())
+                    } else {
+                        // both sides are only evaluated once
+                        let (left, right) = (Symbol::gensym(), Symbol::gensym());
+                        output.push(Stmt::new(StmtKind::Let(left, args.remove(0)), span));
+                        output.push(Stmt::new(StmtKind::Let(right, args.remove(0)), span));
+                        let (left, right) = (Expr::Ident(left), Expr::Ident(right));
+                        let cond = Expr::BinOp(BinOp::NotEquals, Box::new(left.clone()), Box::new(right.clone()));
+                        StmtKind::If(cond, fail(left, right), Vec::new())
+                    }
+                }
+                StmtKind::If(cond, tbody, fbody) => StmtKind::If(cond, self.desugar(file, tbody), self.desugar(file, fbody)),
+                StmtKind::Match(expr, mut arms) => {
+                    for arm in &mut arms {
+                        let body = std::mem::take(&mut arm.body);
+                        arm.body = self.desugar(file, body);
+                    }
+                    StmtKind::Match(expr, arms)
+                }
+                kind => kind,
+            };
+            output.push(Stmt::new(kind, span));
+        }
+        output
+    }
+
+    /// Runs a test in an interpreter of its own, and returns what it printed
+    /// along with why it failed, if it did.
+    pub fn run(&self, adts: &AdtTable, program: &mir::Program, test: &TestCase) -> (Result<(), String>, Vec<u8>) {
+        let mut output = Vec::new();
+        let result = Interpreter::new(adts, program)
+            .output(&mut output)
+            .call(&Path::from_qualified(test.func), Vec::new());
+        let result = match result {
+            Ok(_) => Ok(()),
+            Err(InterpError::AssertionFailed(assertion, left, right)) => Err(self.failure(assertion, left, right)),
+            // the function a test was turned into is only a name for it here
+            Err(err) => Err(err.to_string().replace(&format!("`{}`", test.func), "the test")),
+        };
+        (result, output)
+    }
+
+    /// Exports the functions of the tests and nothing else, since the runner
+    /// has its own `main`.
+    pub fn export(&self, program: &mut mir::Program) {
+        for decl in &mut program.0 {
+            if let mir::Decl::Func(func) = decl {
+                func.exported = self.tests.iter().any(|test| func.name == Path::from_qualified(test.func));
+            }
+        }
+    }
+
+    /// The C code of a runner for the tests once they're compiled: the
+    /// executable linked from both runs the test whose function it's given
+    /// as its argument. Like the interpreter, it provides `FAILED`, and
+    /// `print` if the program declares it.
+    pub fn runner(&self, program: &mir::Program) -> String {
+        let mut c = String::from("#include <stdio.h>\n#include <stdlib.h>\n#include <string.h>\n\n");
+        for (i, test) in self.tests.iter().enumerate() {
+            writeln!(c, "void test_{}(void) __asm__(\"{}\");", i, test.func).unwrap();
+        }
+        c.push_str("\nstatic const struct {\n    const char *name;\n    void (*run)(void);\n} tests[] = {\n");
+        for (i, test) in self.tests.iter().enumerate() {
+            writeln!(c, "    {{ \"{}\", test_{} }},", test.func, i).unwrap();
+        }
+        c.push_str("    { NULL, NULL },\n};\n\n");
+
+        writeln!(c, "void failed(long assertion, long left, long right) __asm__(\"{}\");", FAILED).unwrap();
+        c.push_str("void failed(long assertion, long left, long right) {\n");
+        c.push_str("    fprintf(stderr, \"%ld %ld %ld\\n\", assertion, left, right);\n");
+        writeln!(c, "    exit({});\n}}\n", FAILED_STATUS).unwrap();
+
+        let print = program.0.iter().find_map(|decl| match decl {
+            mir::Decl::Extern(name, args, _) if name.to_string() == "print" => Some(args),
+            _ => None,
+        });
+        if let Some(args) = print.filter(|args| args.iter().all(|arg| matches!(arg, mir::Type::Int | mir::Type::Bool))) {
+            let params: Vec<_> = (0..args.len()).map(|i| format!("long a{}", i)).collect();
+            writeln!(c, "void print({}) {{", if params.is_empty() { "void".to_owned() } else { params.join(", ") }).unwrap();
+            for (i, arg) in args.iter().enumerate() {
+                let sep = if i > 0 { " " } else { "" };
+                match arg {
+                    mir::Type::Bool => writeln!(c, "    printf(\"{}%s\", a{} ? \"true\" : \"false\");", sep, i),
+                    _ => writeln!(c, "    printf(\"{}%ld\", a{});", sep, i),
+                }
+                .unwrap();
+            }
+            c.push_str("    printf(\"\\n\");\n}\n\n");
+        }
+
+        c.push_str("int main(int argc, char **argv) {\n");
+        c.push_str("    for (int i = 0; argc > 1 && tests[i].name; i++) {\n");
+        c.push_str("        if (strcmp(tests[i].name, argv[1]) == 0) {\n");
+        c.push_str("            tests[i].run();\n            return 0;\n        }\n    }\n");
+        c.push_str("    fprintf(stderr, \"no such test\\n\");\n    return 2;\n}\n");
+        c
+    }
+
+    /// Runs a test in a process of its own, with the executable linked from
+    /// the compiled program and the runner, and returns what it printed
+    /// along with why it failed, if it did.
+    pub fn run_native(&self, executable: &FsPath, test: &TestCase) -> (Result<(), String>, Vec<u8>) {
+        let output = match Command::new(executable).arg(test.func.as_str()).output() {
+            Ok(output) => output,
+            Err(err) => return (Err(format!("couldn't run {}: {}", executable.display(), err)), Vec::new()),
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let result = match output.status.code() {
+            Some(0) => Ok(()),
+            Some(FAILED_STATUS) => {
+                let values: Vec<i64> = stderr
+                    .lines()
+                    .last()
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .filter_map(|value| value.parse().ok())
+                    .collect();
+                match values.as_slice() {
+                    [assertion, left, right] => Err(self.failure(*assertion, *left, *right)),
+                    _ => Err(format!("the test exited with {}", FAILED_STATUS)),
+                }
+            }
+            Some(code) => Err(format!("the test exited with {}", code)),
+            None => {
+                let signal = output.status.signal().expect("exited or killed");
+                Err(format!("the test was killed by {}", signal_name(signal)))
+            }
+        };
+        (result, output.stdout)
+    }
+
+    /// The message of an assertion that failed, with the values it compared.
+    fn failure(&self, assertion: i64, left: i64, right: i64) -> String {
+        let assertion = &self.assertions[assertion as usize];
+        let mut message = format!(
+            "assertion failed at {}:{}: {}",
+            assertion.file.display(),
+            assertion.line,
+            assertion.text
+        );
+        if assertion.equal {
+            message.push_str(&format!("\n  left: {}\n right: {}", left, right));
+        }
+        message
+    }
+}
+
+/// A signal a test can be killed by, with what usually causes it.
+fn signal_name(signal: i32) -> String {
+    match signal {
+        4 => "SIGILL, a trap".to_owned(),
+        8 => "SIGFPE, an arithmetic error".to_owned(),
+        11 => "SIGSEGV, a bad memory access".to_owned(),
+        signal => format!("signal {}", signal),
+    }
+}
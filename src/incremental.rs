@@ -0,0 +1,360 @@
+//! Incremental compilation. The results of typechecking and the machine code
+//! of functions are kept in a directory between compilations, each under a
+//! fingerprint of everything it was computed from, so that a compilation only
+//! redoes the work for what changed.
+//!
+//! A group of mutually recursive functions is typechecked from its source,
+//! the types of the functions it calls and the type declarations of the
+//! program, so its fingerprint hashes those. The machine code of a function
+//! is compiled from its optimized mir, the signatures of the functions it
+//! calls and the layouts of the types, which its fingerprint hashes instead.
+//! Entries are never removed: deleting the directory clears the cache.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path as FsPath, PathBuf};
+
+use symbol::Symbol;
+use target_lexicon::Triple;
+
+use crate::adt::AdtTable;
+use crate::ast::{Decl, Func, Path, Program, Type};
+use crate::codegen::{CompiledFunc, Relocation};
+use crate::format;
+use crate::mir::{self, Terminator};
+use crate::typeck;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Fingerprint(u128);
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+/// 128-bit FNV-1a, which unlike the hasher of the standard library gives the
+/// same hashes in every run of every build of the compiler.
+struct Hasher(u128);
+
+impl Hasher {
+    fn new() -> Self {
+        let mut hasher = Hasher(0x6c62_272e_07bb_0142_62b8_2175_6295_c58d);
+        // a new compiler can compile the same program differently
+        hasher.write(env!("CARGO_PKG_VERSION"));
+        hasher
+    }
+
+    fn write(&mut self, text: impl fmt::Display) {
+        // every piece ends with a byte that text doesn't have, so that moving
+        // text from one to the next changes the hash
+        for byte in text.to_string().bytes().chain(Some(0xff)) {
+            self.0 ^= u128::from(byte);
+            self.0 = self.0.wrapping_mul(0x0000_0000_0100_0000_0000_0000_0000_013b);
+        }
+    }
+
+    fn finish(&self) -> Fingerprint {
+        Fingerprint(self.0)
+    }
+}
+
+/// Renames the type variables of the types to `'t0`, `'t1` and so on, in
+/// the order they appear in, so that types inferred in different runs
+/// compare equal.
+fn normalize<'a>(types: impl IntoIterator<Item = &'a Type>) -> Vec<Type> {
+    let types: Vec<_> = types.into_iter().collect();
+    let mut names = typeck::Substitution::new();
+    for ty in &types {
+        for var in typeck::free_vars(ty) {
+            let name = Symbol::from(format!("t{}", names.len()).as_str());
+            names.entry(var).or_insert(Type::Var(name));
+        }
+    }
+    types.into_iter().map(|ty| typeck::apply(&names, ty)).collect()
+}
+
+/// Hashes the declarations of the program that functions are typechecked
+/// against: its types, classes and externs.
+pub fn declarations_fingerprint(program: &Program) -> Fingerprint {
+    let mut hasher = Hasher::new();
+    for decl in &program.0 {
+        match decl {
+            Decl::Class(_) | Decl::Enum(_) | Decl::Extern(_, _, _) | Decl::Struct(_) => {
+                hasher.write(format::print_decl(decl))
+            }
+            Decl::Func(_) | Decl::Instance(_) | Decl::Test(_) | Decl::Use(_, _) => (),
+        }
+    }
+    hasher.finish()
+}
+
+/// The fingerprint of a group of functions for typechecking, from the
+/// fingerprint of the declarations, the functions with the names they are
+/// checked under, and the types of the functions they call outside of the
+/// group.
+pub fn group_fingerprint(declarations: Fingerprint, funcs: &[(Symbol, &Func)], callees: &[(Symbol, Type)]) -> Fingerprint {
+    let mut hasher = Hasher::new();
+    hasher.write(declarations);
+    for (name, func) in funcs {
+        hasher.write(name);
+        hasher.write(format::print_func(func));
+    }
+    let types = normalize(callees.iter().map(|(_, ty)| ty));
+    for ((name, _), ty) in callees.iter().zip(types) {
+        hasher.write(name);
+        hasher.write(ty);
+    }
+    hasher.finish()
+}
+
+/// The fingerprint of the machine code of every function of the program.
+pub fn code_fingerprints(target: &Triple, adts: &AdtTable, program: &mir::Program) -> HashMap<Path, Fingerprint> {
+    let mut signatures = HashMap::new();
+    for decl in &program.0 {
+        // calls to C functions are compiled differently
+        let signature = match decl {
+            mir::Decl::Extern(name, args, returns) => (name, "extern", mir::Type::Func(args.clone(), Box::new(returns.clone()))),
+            mir::Decl::Func(func) => (&func.name, "fn", func.get_type()),
+        };
+        signatures.insert(signature.0, (signature.1, signature.2));
+    }
+    // the layouts of the types are all that a dump without functions has
+    let layouts = mir::dump(adts, &mir::Program(Vec::new()));
+
+    let mut fingerprints = HashMap::new();
+    for decl in &program.0 {
+        let func = match decl {
+            mir::Decl::Func(func) => func,
+            mir::Decl::Extern(_, _, _) => continue,
+        };
+        let mut hasher = Hasher::new();
+        hasher.write(target);
+        hasher.write(&layouts);
+        hasher.write(func);
+        for block in &func.blocks {
+            if let Terminator::Call { func: callee, .. } = &block.terminator {
+                let (kind, ty) = &signatures[callee];
+                hasher.write(callee);
+                hasher.write(kind);
+                hasher.write(ty);
+            }
+        }
+        fingerprints.insert(func.name.clone(), hasher.finish());
+    }
+    fingerprints
+}
+
+/// The solved types of a function: what the type variables of its signature
+/// and body were solved to, in the order they appear, and the types of its
+/// variables.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FuncTypes {
+    pub vars: Vec<Type>,
+    pub locals: Vec<(Symbol, Type)>,
+}
+
+/// What a compilation did with every function.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// The functions that were typechecked, and those whose types were in
+    /// the cache.
+    pub typechecked: Vec<Symbol>,
+    pub types_reused: Vec<Symbol>,
+    /// The functions that were compiled, and those whose machine code was in
+    /// the cache.
+    pub compiled: Vec<Path>,
+    pub code_reused: Vec<Path>,
+}
+
+pub struct Cache {
+    dir: PathBuf,
+    pub stats: Stats,
+    /// The first error writing to the directory since it was last taken.
+    error: Option<io::Error>,
+}
+
+impl Cache {
+    /// Keeps the cache in `dir`, which is created when the first entry is
+    /// written.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Cache {
+            dir: dir.into(),
+            stats: Stats::default(),
+            error: None,
+        }
+    }
+
+    pub fn dir(&self) -> &FsPath {
+        &self.dir
+    }
+
+    /// Takes the error that writing an entry ran into, if any. A cache that
+    /// can't be written to only makes compilations slower.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn path(&self, fingerprint: Fingerprint, kind: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", fingerprint, kind))
+    }
+
+    fn read(&self, fingerprint: Fingerprint, kind: &str) -> Option<String> {
+        fs::read_to_string(self.path(fingerprint, kind)).ok()
+    }
+
+    fn write(&mut self, fingerprint: Fingerprint, kind: &str, contents: String) {
+        let path = self.path(fingerprint, kind);
+        let written = fs::create_dir_all(&self.dir).and_then(|()| fs::write(path, contents));
+        if let Err(err) = written {
+            self.error.get_or_insert(err);
+        }
+    }
+
+    /// The types of a group of functions, with fresh type variables. An
+    /// entry that can't be read is left for the group to be checked again.
+    pub fn types(&self, fingerprint: Fingerprint) -> Option<Vec<FuncTypes>> {
+        let text = self.read(fingerprint, "types")?;
+        let mut vars = HashMap::new();
+        let mut fresh = |ty: Type| {
+            let names = typeck::free_vars(&ty)
+                .into_iter()
+                .map(|var| (var, vars.entry(var).or_insert_with(Type::gen).clone()))
+                .collect();
+            typeck::apply(&names, &ty)
+        };
+        let mut funcs = Vec::new();
+        for line in text.lines() {
+            let mut words = line.splitn(2, ' ');
+            match (words.next()?, words.next()) {
+                ("fn", None) => funcs.push(FuncTypes {
+                    vars: Vec::new(),
+                    locals: Vec::new(),
+                }),
+                ("var", Some(ty)) => funcs.last_mut()?.vars.push(fresh(parse_type(ty)?)),
+                ("local", Some(local)) => {
+                    let mut words = local.splitn(2, ' ');
+                    let name = Symbol::from(words.next()?);
+                    let ty = fresh(parse_type(words.next()?)?);
+                    funcs.last_mut()?.locals.push((name, ty));
+                }
+                _ => return None,
+            }
+        }
+        Some(funcs)
+    }
+
+    pub fn store_types(&mut self, fingerprint: Fingerprint, funcs: &[FuncTypes]) {
+        let all = funcs
+            .iter()
+            .flat_map(|func| func.vars.iter().chain(func.locals.iter().map(|(_, ty)| ty)));
+        let mut types = normalize(all).into_iter();
+        let mut text = String::new();
+        for func in funcs {
+            text.push_str("fn\n");
+            for _ in &func.vars {
+                text.push_str(&format!("var {}\n", types.next().unwrap()));
+            }
+            for (name, _) in &func.locals {
+                text.push_str(&format!("local {} {}\n", name, types.next().unwrap()));
+            }
+        }
+        self.write(fingerprint, "types", text);
+    }
+
+    /// The machine code of a function.
+    pub fn code(&self, fingerprint: Fingerprint) -> Option<CompiledFunc> {
+        let text = self.read(fingerprint, "code")?;
+        let mut lines = text.lines();
+        let code = lines.next()?.strip_prefix("code ")?;
+        let code = (0..code.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(code.get(i..i + 2)?, 16).ok())
+            .collect::<Option<_>>()?;
+        let mut relocs = Vec::new();
+        for line in lines {
+            let mut words = line.splitn(5, ' ');
+            if words.next()? != "reloc" {
+                return None;
+            }
+            relocs.push(Relocation {
+                offset: words.next()?.parse().ok()?,
+                kind: words.next()?.parse().ok()?,
+                addend: words.next()?.parse().ok()?,
+                symbol: words.next()?.to_owned(),
+            });
+        }
+        Some(CompiledFunc { code, relocs })
+    }
+
+    pub fn store_code(&mut self, fingerprint: Fingerprint, func: &CompiledFunc) {
+        let mut text = String::from("code ");
+        for byte in &func.code {
+            text.push_str(&format!("{:02x}", byte));
+        }
+        text.push('\n');
+        for reloc in &func.relocs {
+            text.push_str(&format!(
+                "reloc {} {} {} {}\n",
+                reloc.offset, reloc.kind, reloc.addend, reloc.symbol
+            ));
+        }
+        self.write(fingerprint, "code", text);
+    }
+}
+
+/// Reads a type as `Display` writes it.
+fn parse_type(text: &str) -> Option<Type> {
+    let (ty, rest) = parse_type_prefix(text)?;
+    if rest.is_empty() {
+        Some(ty)
+    } else {
+        None
+    }
+}
+
+fn parse_type_prefix(text: &str) -> Option<(Type, &str)> {
+    if let Some(mut rest) = text.strip_prefix('(') {
+        let mut args = Vec::new();
+        if let Some(after) = rest.strip_prefix(')') {
+            rest = after;
+        } else {
+            loop {
+                let (arg, after) = parse_type_prefix(rest)?;
+                args.push(arg);
+                if let Some(after) = after.strip_prefix(", ") {
+                    rest = after;
+                } else {
+                    rest = after.strip_prefix(')')?;
+                    break;
+                }
+            }
+        }
+        return match rest.strip_prefix(" -> ") {
+            Some(rest) => {
+                let (returns, rest) = parse_type_prefix(rest)?;
+                Some((Type::Func(args, Box::new(returns)), rest))
+            }
+            None if args.is_empty() => Some((Type::Unit, rest)),
+            None => None,
+        };
+    }
+    let (var, text) = match text.strip_prefix('\'') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let end = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || "_.$#".contains(c)))
+        .unwrap_or(text.len());
+    let (name, rest) = text.split_at(end);
+    let ty = match name {
+        "" => return None,
+        _ if var => Type::Var(Symbol::from(name)),
+        "int" => Type::Int,
+        "bool" => Type::Bool,
+        _ => Type::Name(Symbol::from(name)),
+    };
+    Some((ty, rest))
+}
@@ -0,0 +1,35 @@
+#[macro_use]
+extern crate lazy_static;
+
+lalrpop_mod!(#[allow(clippy::all, unused)] parser);
+
+mod abi;
+pub mod adt;
+pub mod ast;
+mod class;
+pub mod codegen;
+pub mod disasm;
+pub mod driver;
+pub mod env;
+pub mod eval;
+pub mod format;
+pub mod harness;
+pub mod incremental;
+pub mod lsp;
+mod lower;
+pub mod manifest;
+pub mod mir;
+pub mod module;
+mod mono;
+mod pattern;
+mod prelude;
+pub mod repl;
+pub mod scanner;
+pub mod session;
+pub mod timing;
+pub mod typeck;
+
+use lalrpop_util::lalrpop_mod;
+
+pub use crate::driver::{Diagnostic, Level};
+pub use crate::session::{Lowered, Session};
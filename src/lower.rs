@@ -0,0 +1,663 @@
+//! Lowers the typechecked, monomorphized ast to `mir`. Types are propagated
+//! from the function signatures, which are all concrete by now, so that the
+//! backend never has to infer anything. Matches are compiled to decision
+//! trees here, `&&` and `||` to branches, and functions returning a call to
+//! themselves to loops.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use symbol::Symbol;
+
+use crate::adt::AdtTable;
+use crate::ast::{self, BinOp, Decl, Path, Type as AstType};
+use crate::env::Environment;
+use crate::mir::{self, BlockId, Constant, Local, LocalDecl, Operand, Place, Rvalue, Terminator, Type};
+
+static WILDCARD: ast::Pattern = ast::Pattern::Wildcard;
+
+#[derive(Debug)]
+pub enum LowerError {
+    /// A type variable is left where the backend needs a concrete type.
+    Ambiguous(Symbol, AstType),
+    /// A function or constructor with fields is used without being called.
+    FunctionValue(Symbol),
+    /// A call through a local variable.
+    IndirectCall(Symbol),
+    /// A function marked `@tailcall` calls itself other than in tail
+    /// position.
+    NotTailCall(Symbol),
+}
+
+impl fmt::Display for LowerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LowerError::Ambiguous(func, ty) => write!(
+                f,
+                "type annotations needed: `{}` in `{}` can't be inferred",
+                ty, func
+            ),
+            LowerError::FunctionValue(name) => {
+                write!(f, "`{}` can only be called; functions aren't values yet", name)
+            }
+            LowerError::IndirectCall(name) => {
+                write!(f, "`{}` is a local variable; indirect calls aren't supported yet", name)
+            }
+            LowerError::NotTailCall(name) => write!(
+                f,
+                "`{}` is marked `@tailcall` but calls itself other than as `return {}(...)`",
+                name, name
+            ),
+        }
+    }
+}
+
+/// Converts a type that has to be concrete. `None` if it has type variables.
+pub fn lower_type(ty: &AstType) -> Option<Type> {
+    Some(match ty {
+        AstType::Func(args, ret) => {
+            let args = args.iter().map(lower_type).collect::<Option<_>>()?;
+            Type::Func(args, Box::new(lower_type(ret)?))
+        }
+        AstType::Name(name) => Type::Name(*name),
+        AstType::Var(_) => return None,
+        AstType::Unit => Type::Unit,
+        AstType::Int => Type::Int,
+        AstType::Bool => Type::Bool,
+    })
+}
+
+/// Lowers every function and extern of the program. `signatures` holds the
+/// type of every function, extern and constructor.
+pub fn lower_program(adts: &AdtTable, signatures: &HashMap<Symbol, AstType>, program: &ast::Program) -> Result<mir::Program, LowerError> {
+    let mut decls = Vec::new();
+    for decl in &program.0 {
+        match decl {
+            Decl::Extern(name, _, _) => {
+                let (args, returns) = signature(signatures, *name, *name)?;
+                decls.push(mir::Decl::Extern(Path::from_qualified(*name), args, returns));
+            }
+            Decl::Func(func) => {
+                let func = Builder::new(adts, signatures, func.name).func(func, true)?;
+                decls.push(mir::Decl::Func(func));
+            }
+            Decl::Instance(instance) => {
+                for func in &instance.methods {
+                    let name = instance.method_symbol(func.name);
+                    let func = Builder::new(adts, signatures, name).func(func, false)?;
+                    decls.push(mir::Decl::Func(func));
+                }
+            }
+            Decl::Class(_) | Decl::Enum(_) | Decl::Struct(_) | Decl::Test(_) | Decl::Use(_, _) => (),
+        }
+    }
+    Ok(mir::Program(decls))
+}
+
+/// The argument and return types of `name`, looked up while lowering `func`.
+fn signature(signatures: &HashMap<Symbol, AstType>, func: Symbol, name: Symbol) -> Result<(Vec<Type>, Type), LowerError> {
+    let ty = &signatures[&name];
+    match lower_type(ty) {
+        Some(Type::Func(args, returns)) => Ok((args, *returns)),
+        Some(_) => unreachable!("functions have function types"),
+        None => Err(LowerError::Ambiguous(func, ty.clone())),
+    }
+}
+
+/// A row of the pattern matrix being compiled into a decision tree.
+struct Row<'p> {
+    patterns: Vec<&'p ast::Pattern>,
+    bindings: Vec<(Symbol, Place)>,
+    arm: usize,
+}
+
+/// Where control goes once the patterns of an arm have matched.
+struct ArmTarget {
+    block: BlockId,
+    locals: HashMap<Symbol, Local>,
+}
+
+/// Builds the control flow graph of one function.
+struct Builder<'a> {
+    adts: &'a AdtTable,
+    signatures: &'a HashMap<Symbol, AstType>,
+    name: Symbol,
+    /// Whether calls to the function itself have to be tail calls.
+    tailcall: bool,
+    locals: Vec<LocalDecl>,
+    blocks: Vec<(Vec<mir::Stmt>, Option<Terminator>)>,
+    /// The block statements are added to, or `None` once it has been
+    /// terminated and the code that follows is unreachable.
+    block: Option<BlockId>,
+    scopes: Environment<Symbol, Local>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(adts: &'a AdtTable, signatures: &'a HashMap<Symbol, AstType>, name: Symbol) -> Self {
+        Builder {
+            adts,
+            signatures,
+            name,
+            tailcall: false,
+            locals: Vec::new(),
+            blocks: Vec::new(),
+            block: None,
+            scopes: Environment::new(),
+        }
+    }
+
+    fn func(mut self, func: &ast::Func, exported: bool) -> Result<mir::Func, LowerError> {
+        let (args, returns) = signature(self.signatures, self.name, self.name)?;
+        self.tailcall = func.tailcall;
+        self.new_local(None, returns.clone());
+        for ((name, _), ty) in func.args.iter().zip(&args) {
+            let local = self.new_local(Some(*name), ty.clone());
+            self.scopes.insert(*name, local);
+        }
+        let entry = self.new_block();
+        self.block = Some(entry);
+        self.body(&func.body)?;
+        if self.block.is_some() {
+            // typechecking only lets functions returning unit reach their end
+            assert_eq!(returns, Type::Unit, "`{}` can reach its end", self.name);
+            self.terminate(Terminator::Return);
+        }
+
+        let blocks = self
+            .blocks
+            .into_iter()
+            .map(|(stmts, terminator)| mir::Block {
+                stmts,
+                terminator: terminator.unwrap_or(Terminator::Unreachable),
+            })
+            .collect();
+        Ok(mir::Func {
+            name: Path::from_qualified(self.name),
+            exported,
+            inline: func.inline,
+            args: args.len(),
+            locals: self.locals,
+            blocks,
+        })
+    }
+
+    fn new_local(&mut self, name: Option<Symbol>, ty: Type) -> Local {
+        self.locals.push(LocalDecl { name, ty });
+        Local(self.locals.len() - 1)
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push((Vec::new(), None));
+        BlockId(self.blocks.len() - 1)
+    }
+
+    fn push(&mut self, place: Place, rvalue: Rvalue) {
+        let block = self.block.expect("no block to add statements to");
+        self.blocks[block.0].0.push(mir::Stmt::Assign(place, rvalue));
+    }
+
+    fn terminate(&mut self, terminator: Terminator) {
+        let block = self.block.take().expect("no block to terminate");
+        self.blocks[block.0].1 = Some(terminator);
+    }
+
+    /// Jumps to `merge` from the current block, if it can be reached,
+    /// creating the block on first use.
+    fn goto_merge(&mut self, merge: &mut Option<BlockId>) {
+        if self.block.is_some() {
+            let target = match *merge {
+                Some(target) => target,
+                None => {
+                    let target = self.new_block();
+                    *merge = Some(target);
+                    target
+                }
+            };
+            self.terminate(Terminator::Goto(target));
+        }
+    }
+
+    /// Assigns the rvalue to a new temporary and returns it.
+    fn temp(&mut self, rvalue: Rvalue) -> Place {
+        let ty = self.rvalue_ty(&rvalue);
+        let place = Place::local(self.new_local(None, ty));
+        self.push(place.clone(), rvalue);
+        place
+    }
+
+    fn operand_ty(&self, operand: &Operand) -> Type {
+        match operand {
+            Operand::Copy(place) => mir::place_ty(self.adts, &self.locals, place).expect("lowered from a valid place"),
+            Operand::Const(constant) => constant.ty(),
+        }
+    }
+
+    fn rvalue_ty(&self, rvalue: &Rvalue) -> Type {
+        match rvalue {
+            Rvalue::Use(operand) => self.operand_ty(operand),
+            Rvalue::BinOp(op, _, _) => mir::binop_result(*op),
+            Rvalue::UnOp(ast::UnOp::LogicalNot, _) => Type::Bool,
+            Rvalue::UnOp(ast::UnOp::BitwiseNot, _) => Type::Int,
+            Rvalue::Struct(name, _) => Type::Name(*name),
+            Rvalue::Ctor(name, _) => Type::Name(self.adts.get_ctor(*name).expect("not a constructor").enum_name),
+            Rvalue::Discriminant(_) => Type::Int,
+            Rvalue::Variant(_, ctor, i) => self.ctor_fields(*ctor).swap_remove(*i),
+        }
+    }
+
+    fn ctor_fields(&self, ctor: Symbol) -> Vec<Type> {
+        let fields = &self.adts.get_ctor(ctor).expect("not a constructor").fields;
+        fields
+            .iter()
+            .map(|field| lower_type(field).expect("fields are resolved"))
+            .collect()
+    }
+
+    /// A place holding the operand, copying constants to a temporary.
+    fn place(&mut self, operand: Operand) -> Place {
+        match operand {
+            Operand::Copy(place) => place,
+            operand => self.temp(Rvalue::Use(operand)),
+        }
+    }
+
+    fn body(&mut self, stmts: &[ast::Stmt]) -> Result<(), LowerError> {
+        self.scopes.push_scope();
+        for stmt in stmts {
+            // anything after a return is dead code
+            if self.block.is_none() {
+                break;
+            }
+            self.stmt(stmt)?;
+        }
+        self.scopes.pop_scope();
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &ast::Stmt) -> Result<(), LowerError> {
+        match &stmt.kind {
+            ast::StmtKind::Expr(expr) => {
+                self.operand(expr)?;
+            }
+            ast::StmtKind::Let(name, expr) => {
+                let operand = self.operand(expr)?;
+                let ty = self.operand_ty(&operand);
+                let local = self.new_local(Some(*name), ty);
+                self.push(Place::local(local), Rvalue::Use(operand));
+                self.scopes.insert(*name, local);
+            }
+            ast::StmtKind::Assign(ast::Expr::Ident(name), expr) => {
+                let local = *self.scopes.lookup(*name).expect("not a local variable");
+                let operand = self.operand(expr)?;
+                self.push(Place::local(local), Rvalue::Use(operand));
+            }
+            ast::StmtKind::Assign(ast::Expr::Field(base, field), expr) => {
+                let base = self.operand(base)?;
+                let base = self.place(base);
+                let operand = self.operand(expr)?;
+                self.push(base.field(*field), Rvalue::Use(operand));
+            }
+            ast::StmtKind::Assign(_, _) => unreachable!("rejected by the typechecker"),
+            ast::StmtKind::Return(ast::Expr::Call(func, args)) if self.is_self(func) => self.tail_call(args)?,
+            ast::StmtKind::Return(expr) => {
+                let operand = self.operand(expr)?;
+                self.push(Place::local(Local::RETURN), Rvalue::Use(operand));
+                self.terminate(Terminator::Return);
+            }
+            ast::StmtKind::If(cond, tbody, fbody) => {
+                let cond = self.operand(cond)?;
+                let then_block = self.new_block();
+                let else_block = self.new_block();
+                self.terminate(Terminator::Branch(cond, then_block, else_block));
+
+                let mut merge = None;
+                for (block, body) in &[(then_block, tbody), (else_block, fbody)] {
+                    self.block = Some(*block);
+                    self.body(body)?;
+                    self.goto_merge(&mut merge);
+                }
+                self.block = merge;
+            }
+            ast::StmtKind::Match(expr, arms) => self.match_(expr, arms)?,
+            ast::StmtKind::Assert(_) => unreachable!("assertions are taken out before typechecking"),
+        }
+        Ok(())
+    }
+
+    /// Whether the callee is the function being lowered.
+    fn is_self(&self, func: &ast::Expr) -> bool {
+        match func {
+            ast::Expr::Ident(name) => *name == self.name && self.scopes.lookup(*name).is_none(),
+            _ => false,
+        }
+    }
+
+    /// Lowers `return f(args)` in `f` itself by passing the arguments again
+    /// and jumping back to the start, so that the recursion doesn't use up
+    /// the stack.
+    fn tail_call(&mut self, args: &[ast::Expr]) -> Result<(), LowerError> {
+        let args: Vec<Operand> = args.iter().map(|arg| self.operand(arg)).collect::<Result<_, _>>()?;
+        // every argument is evaluated before the first one is overwritten,
+        // since they can read each other
+        let args: Vec<Operand> = args
+            .into_iter()
+            .map(|arg| match arg {
+                Operand::Copy(_) => Operand::Copy(self.temp(Rvalue::Use(arg))),
+                Operand::Const(_) => arg,
+            })
+            .collect();
+        for (i, arg) in args.into_iter().enumerate() {
+            self.push(Place::local(Local(i + 1)), Rvalue::Use(arg));
+        }
+        self.terminate(Terminator::Goto(BlockId::ENTRY));
+        Ok(())
+    }
+
+    fn operand(&mut self, expr: &ast::Expr) -> Result<Operand, LowerError> {
+        Ok(match expr {
+            ast::Expr::Int(n) => Operand::Const(Constant::Int(*n)),
+            ast::Expr::Ident(name) => {
+                if let Some(local) = self.scopes.lookup(*name) {
+                    return Ok(Operand::Copy(Place::local(*local)));
+                }
+                match self.adts.get_ctor(*name) {
+                    Some(ctor) if ctor.fields.is_empty() => Operand::Copy(self.temp(Rvalue::Ctor(*name, Vec::new()))),
+                    _ => return Err(LowerError::FunctionValue(*name)),
+                }
+            }
+            ast::Expr::Call(func, args) => {
+                let name = match func.as_ref() {
+                    ast::Expr::Ident(name) if self.scopes.lookup(*name).is_some() => {
+                        return Err(LowerError::IndirectCall(*name));
+                    }
+                    ast::Expr::Ident(name) => *name,
+                    _ => unreachable!("only names have function types"),
+                };
+                if self.tailcall && name == self.name {
+                    return Err(LowerError::NotTailCall(name));
+                }
+                let args = args.iter().map(|arg| self.operand(arg)).collect::<Result<_, _>>()?;
+                if self.adts.get_ctor(name).is_some() {
+                    return Ok(Operand::Copy(self.temp(Rvalue::Ctor(name, args))));
+                }
+                let (_, returns) = signature(self.signatures, self.name, name)?;
+                let dest = self.new_local(None, returns);
+                let target = self.new_block();
+                self.terminate(Terminator::Call {
+                    func: Path::from_qualified(name),
+                    args,
+                    dest,
+                    target,
+                    unwind: None,
+                });
+                self.block = Some(target);
+                Operand::Copy(Place::local(dest))
+            }
+            ast::Expr::BinOp(op @ BinOp::LogicalAnd, left, right) | ast::Expr::BinOp(op @ BinOp::LogicalOr, left, right) => {
+                // the right operand is only evaluated if the left one doesn't
+                // decide the result
+                let left = self.operand(left)?;
+                let result = self.temp(Rvalue::Use(left));
+                let right_block = self.new_block();
+                let merge = self.new_block();
+                let cond = Operand::Copy(result.clone());
+                self.terminate(match op {
+                    BinOp::LogicalAnd => Terminator::Branch(cond, right_block, merge),
+                    _ => Terminator::Branch(cond, merge, right_block),
+                });
+
+                self.block = Some(right_block);
+                let right = self.operand(right)?;
+                self.push(result.clone(), Rvalue::Use(right));
+                self.terminate(Terminator::Goto(merge));
+                self.block = Some(merge);
+                Operand::Copy(result)
+            }
+            ast::Expr::BinOp(op, left, right) => {
+                let left = self.operand(left)?;
+                let right = self.operand(right)?;
+                Operand::Copy(self.temp(Rvalue::BinOp(*op, left, right)))
+            }
+            ast::Expr::UnOp(op, expr) => {
+                let operand = self.operand(expr)?;
+                Operand::Copy(self.temp(Rvalue::UnOp(*op, operand)))
+            }
+            ast::Expr::Struct(name, fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(field, expr)| Ok((*field, self.operand(expr)?)))
+                    .collect::<Result<_, _>>()?;
+                Operand::Copy(self.temp(Rvalue::Struct(*name, fields)))
+            }
+            ast::Expr::Field(base, field) => {
+                let base = self.operand(base)?;
+                Operand::Copy(self.place(base).field(*field))
+            }
+            ast::Expr::Method(_, _, _) => unreachable!("methods are resolved before lowering"),
+            ast::Expr::Global(_, _) => unreachable!("functions are specialized before lowering"),
+        })
+    }
+
+    fn match_(&mut self, expr: &ast::Expr, arms: &[ast::Arm]) -> Result<(), LowerError> {
+        let value = self.operand(expr)?;
+        let ty = self.operand_ty(&value);
+        let value = self.place(value);
+
+        let mut targets = Vec::new();
+        for arm in arms {
+            let mut bindings = Vec::new();
+            self.binding_types(&arm.pattern, &ty, &mut bindings);
+            let locals = bindings
+                .into_iter()
+                .map(|(name, ty)| (name, self.new_local(Some(name), ty)))
+                .collect();
+            let block = self.new_block();
+            targets.push(ArmTarget { block, locals });
+        }
+
+        let rows = arms
+            .iter()
+            .enumerate()
+            .map(|(arm, ast::Arm { pattern, .. })| Row {
+                patterns: vec![pattern],
+                bindings: Vec::new(),
+                arm,
+            })
+            .collect();
+        self.decision(rows, &[(value, ty)], &targets);
+
+        let mut merge = None;
+        for (arm, target) in arms.iter().zip(targets) {
+            self.block = Some(target.block);
+            self.scopes.push_scope();
+            for (name, local) in target.locals {
+                self.scopes.insert(name, local);
+            }
+            self.body(&arm.body)?;
+            self.scopes.pop_scope();
+            self.goto_merge(&mut merge);
+        }
+        self.block = merge;
+        Ok(())
+    }
+
+    /// Collects the variables bound by a pattern matching a value of type `ty`.
+    fn binding_types(&self, pattern: &ast::Pattern, ty: &Type, bindings: &mut Vec<(Symbol, Type)>) {
+        match pattern {
+            ast::Pattern::Wildcard | ast::Pattern::Int(_) => (),
+            ast::Pattern::Ident(name) => {
+                if self.adts.get_ctor(*name).is_none() {
+                    bindings.push((*name, ty.clone()));
+                }
+            }
+            ast::Pattern::Ctor(name, args) => {
+                for (arg, ty) in args.iter().zip(self.ctor_fields(*name)) {
+                    self.binding_types(arg, &ty, bindings);
+                }
+            }
+        }
+    }
+
+    /// Whether the pattern matches anything, binding at most a variable.
+    fn is_irrefutable(&self, pattern: &ast::Pattern) -> bool {
+        match pattern {
+            ast::Pattern::Wildcard => true,
+            ast::Pattern::Ident(name) => self.adts.get_ctor(*name).is_none(),
+            ast::Pattern::Int(_) | ast::Pattern::Ctor(_, _) => false,
+        }
+    }
+
+    /// Moves the pattern at `column` out of the row, recording the binding if it
+    /// is a variable.
+    fn take_column<'p>(&self, row: &mut Row<'p>, column: usize, value: &Place) -> &'p ast::Pattern {
+        let pattern = row.patterns.remove(column);
+        if let ast::Pattern::Ident(name) = pattern {
+            if self.adts.get_ctor(*name).is_none() {
+                row.bindings.push((*name, value.clone()));
+            }
+        }
+        pattern
+    }
+
+    /// Compiles a pattern matrix into a decision tree that tests each value at
+    /// most once along any path, switching on enum tags and integer literals.
+    fn decision(&mut self, rows: Vec<Row>, values: &[(Place, Type)], targets: &[ArmTarget]) {
+        let first = match rows.first() {
+            Some(first) => first,
+            None => {
+                self.terminate(Terminator::Unreachable);
+                return;
+            }
+        };
+
+        let column = match first.patterns.iter().position(|pattern| !self.is_irrefutable(pattern)) {
+            Some(column) => column,
+            None => {
+                // the first row matches: bind its variables and run the arm
+                let mut row = rows.into_iter().next().unwrap();
+                for (i, (value, _)) in values.iter().enumerate().rev() {
+                    self.take_column(&mut row, i, value);
+                }
+                let target = &targets[row.arm];
+                for (name, value) in row.bindings {
+                    let local = target.locals[&name];
+                    self.push(Place::local(local), Rvalue::Use(Operand::Copy(value)));
+                }
+                self.terminate(Terminator::Goto(target.block));
+                return;
+            }
+        };
+
+        let (value, _) = values[column].clone();
+        let mut rest = values.to_vec();
+        rest.remove(column);
+
+        // the distinct constructors tested in this column, in order of appearance
+        let mut heads: Vec<&ast::Pattern> = Vec::new();
+        for row in &rows {
+            let pattern = row.patterns[column];
+            let is_new = !heads.iter().any(|head| same_head(head, pattern));
+            if !self.is_irrefutable(pattern) && is_new {
+                heads.push(pattern);
+            }
+        }
+
+        let is_enum = !matches!(heads[0], ast::Pattern::Int(_));
+        let discriminant = if is_enum {
+            self.temp(Rvalue::Discriminant(value.clone()))
+        } else {
+            value.clone()
+        };
+
+        let mut cases = Vec::new();
+        for head in &heads {
+            let block = self.new_block();
+            let index = match head {
+                ast::Pattern::Int(n) => *n,
+                _ => self.adts.get_ctor(head_name(head)).expect("not a constructor").tag as i64,
+            };
+            cases.push((index, block));
+        }
+        let otherwise = self.new_block();
+        self.terminate(Terminator::Switch(Operand::Copy(discriminant), cases.clone(), otherwise));
+
+        for (head, (_, block)) in heads.iter().zip(cases) {
+            self.block = Some(block);
+            let mut fields = Vec::new();
+            if let ast::Pattern::Ctor(name, _) = head {
+                for (i, ty) in self.ctor_fields(*name).into_iter().enumerate() {
+                    let field = self.temp(Rvalue::Variant(value.clone(), *name, i));
+                    fields.push((field, ty));
+                }
+            }
+            let arity = fields.len();
+            fields.extend_from_slice(&rest);
+
+            let mut specialized = Vec::new();
+            for row in &rows {
+                let mut row = Row {
+                    patterns: row.patterns.clone(),
+                    bindings: row.bindings.clone(),
+                    arm: row.arm,
+                };
+                let pattern = self.take_column(&mut row, column, &value);
+                let mut patterns = if self.is_irrefutable(pattern) {
+                    vec![&WILDCARD; arity]
+                } else if same_head(head, pattern) {
+                    match pattern {
+                        ast::Pattern::Ctor(_, args) => args.iter().collect(),
+                        _ => Vec::new(),
+                    }
+                } else {
+                    continue;
+                };
+                patterns.extend(row.patterns);
+                row.patterns = patterns;
+                specialized.push(row);
+            }
+            self.decision(specialized, &fields, targets);
+        }
+
+        self.block = Some(otherwise);
+        let num_variants = match heads[0] {
+            ast::Pattern::Int(_) => None,
+            head => self
+                .adts
+                .get_ctor(head_name(head))
+                .and_then(|ctor| self.adts.get_enum(ctor.enum_name))
+                .map(|info| info.variants.len()),
+        };
+        if num_variants == Some(heads.len()) {
+            // every variant has its own case, so the tag can't be anything else
+            self.terminate(Terminator::Unreachable);
+        } else {
+            let mut default = Vec::new();
+            for row in rows {
+                if self.is_irrefutable(row.patterns[column]) {
+                    let mut row = row;
+                    self.take_column(&mut row, column, &value);
+                    default.push(row);
+                }
+            }
+            self.decision(default, &rest, targets);
+        }
+    }
+}
+
+fn head_name(pattern: &ast::Pattern) -> Symbol {
+    match pattern {
+        ast::Pattern::Ident(name) | ast::Pattern::Ctor(name, _) => *name,
+        ast::Pattern::Wildcard | ast::Pattern::Int(_) => unreachable!("pattern has no constructor"),
+    }
+}
+
+fn same_head(a: &ast::Pattern, b: &ast::Pattern) -> bool {
+    match (a, b) {
+        (ast::Pattern::Int(x), ast::Pattern::Int(y)) => x == y,
+        (ast::Pattern::Ident(x), ast::Pattern::Ident(y))
+        | (ast::Pattern::Ident(x), ast::Pattern::Ctor(y, _))
+        | (ast::Pattern::Ctor(x, _), ast::Pattern::Ident(y))
+        | (ast::Pattern::Ctor(x, _), ast::Pattern::Ctor(y, _)) => x == y,
+        _ => false,
+    }
+}
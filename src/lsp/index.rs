@@ -0,0 +1,453 @@
+//! The names a module defines and uses, and where. The index is built from
+//! the tokens, so it's there while the module is being edited and doesn't
+//! parse. The lines are put back together into blocks, which are walked with
+//! an `Environment` of the definitions in scope, like typechecking does.
+
+use std::collections::HashMap;
+
+use symbol::Symbol;
+
+use crate::ast::{mangle_method, Type};
+use crate::env::Environment;
+use crate::scanner::Token;
+
+/// The bytes a token or a declaration spans.
+pub type Span = (usize, usize);
+
+type Spanned = (usize, Token, usize);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Function,
+    Extern,
+    Struct,
+    Enum,
+    Variant,
+    Class,
+    Method,
+    Import,
+    Argument,
+    Local,
+}
+
+#[derive(Debug)]
+pub struct Definition {
+    pub name: Symbol,
+    pub kind: Kind,
+    /// Where the name is defined.
+    pub span: Span,
+    /// The whole declaration.
+    pub extent: Span,
+    /// The name the item has once modules are linked, which is only different
+    /// for imports.
+    pub qualified: Symbol,
+    /// For arguments and local variables: the function they belong to, and
+    /// how many variables of the same name it binds before this one.
+    pub owner: Option<(Symbol, usize)>,
+    /// For arguments and local variables, where they go out of scope.
+    pub scope_end: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Index {
+    pub definitions: Vec<Definition>,
+    /// Every use of a name, with the definition it refers to.
+    pub references: Vec<(Span, usize)>,
+}
+
+impl Index {
+    pub fn new(tokens: &[Spanned]) -> Self {
+        let lines = lines(tokens);
+        let mut walker = Walker {
+            index: Index::default(),
+            env: Environment::new(),
+            owner: None,
+            bound: HashMap::new(),
+        };
+        // declarations can be used before they're declared
+        for line in &lines {
+            walker.declare(line);
+        }
+        for line in &lines {
+            walker.decl(line);
+        }
+        walker.index
+    }
+
+    /// The definition of the name at the byte, or of the name it refers to.
+    pub fn lookup(&self, pos: usize) -> Option<&Definition> {
+        let contains = |(lo, hi): Span| lo <= pos && pos <= hi;
+        match self.references.iter().find(|(span, _)| contains(*span)) {
+            Some((_, def)) => Some(&self.definitions[*def]),
+            None => self.definitions.iter().find(|def| contains(def.span)),
+        }
+    }
+
+    /// The definitions that are in scope at the byte.
+    pub fn visible(&self, pos: usize) -> Vec<&Definition> {
+        self.definitions
+            .iter()
+            .filter(|def| def.owner.is_none() || (def.span.1 <= pos && pos <= def.scope_end))
+            .collect()
+    }
+
+    /// The top-level declarations.
+    pub fn declarations(&self) -> impl Iterator<Item = &Definition> {
+        self.definitions.iter().filter(|def| def.owner.is_none())
+    }
+}
+
+/// A line with the lines indented under it.
+struct Line {
+    tokens: Vec<Spanned>,
+    children: Vec<Line>,
+}
+
+impl Line {
+    fn end(&self) -> usize {
+        match self.children.last() {
+            Some(child) => child.end(),
+            None => self.tokens.last().map_or(0, |(_, _, hi)| *hi),
+        }
+    }
+}
+
+/// Splits the tokens into lines, and nests the blocks.
+fn lines(tokens: &[Spanned]) -> Vec<Line> {
+    let mut blocks = vec![Vec::new()];
+    let mut current = Vec::new();
+    let finish = |blocks: &mut Vec<Vec<Line>>, current: &mut Vec<Spanned>| {
+        if !current.is_empty() {
+            blocks.last_mut().unwrap().push(Line {
+                tokens: std::mem::take(current),
+                children: Vec::new(),
+            });
+        }
+    };
+    for (lo, token, hi) in tokens {
+        match token {
+            Token::Sep | Token::SymSemicolon => finish(&mut blocks, &mut current),
+            Token::Indent => {
+                finish(&mut blocks, &mut current);
+                blocks.push(Vec::new());
+            }
+            Token::Dedent => {
+                finish(&mut blocks, &mut current);
+                if blocks.len() > 1 {
+                    let block = blocks.pop().unwrap();
+                    let parent = blocks.last_mut().unwrap();
+                    match parent.last_mut() {
+                        Some(line) => line.children.extend(block),
+                        None => parent.extend(block),
+                    }
+                }
+            }
+            token => current.push((*lo, token.clone(), *hi)),
+        }
+    }
+    finish(&mut blocks, &mut current);
+    while blocks.len() > 1 {
+        let block = blocks.pop().unwrap();
+        blocks.last_mut().unwrap().extend(block);
+    }
+    blocks.pop().unwrap()
+}
+
+/// The tokens of a declaration after its attributes and `pub`.
+fn skip_attributes(mut tokens: &[Spanned]) -> &[Spanned] {
+    loop {
+        match tokens {
+            [(_, Token::SymAt, _), (_, Token::Ident(_), _), rest @ ..] => tokens = rest,
+            [(_, Token::KwdPub, _), rest @ ..] => tokens = rest,
+            _ => return tokens,
+        }
+    }
+}
+
+fn ident(token: &Spanned) -> Option<(Symbol, Span)> {
+    match token {
+        (lo, Token::Ident(name), hi) => Some((*name, (*lo, *hi))),
+        _ => None,
+    }
+}
+
+struct Walker {
+    index: Index,
+    env: Environment<Symbol, usize>,
+    /// The function being walked.
+    owner: Option<Symbol>,
+    /// How many variables of every name the function has bound.
+    bound: HashMap<Symbol, usize>,
+}
+
+impl Walker {
+    fn define(&mut self, name: Symbol, kind: Kind, span: Span, extent: Span) {
+        self.index.definitions.push(Definition {
+            name,
+            kind,
+            span,
+            extent,
+            qualified: name,
+            owner: None,
+            scope_end: 0,
+        });
+        self.env.insert(name, self.index.definitions.len() - 1);
+    }
+
+    /// Binds an argument or a local variable of the function being walked.
+    fn bind(&mut self, name: Symbol, kind: Kind, span: Span) {
+        let owner = self.owner.expect("variables are bound in functions");
+        let count = self.bound.entry(name).or_insert(0);
+        self.index.definitions.push(Definition {
+            name,
+            kind,
+            span,
+            extent: span,
+            qualified: name,
+            owner: Some((owner, *count)),
+            scope_end: 0,
+        });
+        *count += 1;
+        let def = self.index.definitions.len() - 1;
+        if let Some(shadowed) = self.env.insert(name, def) {
+            self.index.definitions[shadowed].scope_end = span.0;
+        }
+    }
+
+    fn pop_scope(&mut self, end: usize) {
+        for (_, def) in self.env.pop_scope() {
+            self.index.definitions[def].scope_end = end;
+        }
+    }
+
+    /// The name an item used in the module has once modules are linked.
+    fn qualified(&self, name: Symbol) -> Symbol {
+        match self.env.lookup(name) {
+            Some(&def) => self.index.definitions[def].qualified,
+            None => name,
+        }
+    }
+
+    fn declare(&mut self, line: &Line) {
+        let extent = (line.tokens[0].0, line.end());
+        match skip_attributes(&line.tokens) {
+            [(_, Token::KwdFn, _), name, ..] => {
+                if let Some((name, span)) = ident(name) {
+                    self.define(name, Kind::Function, span, extent);
+                }
+            }
+            [(_, Token::KwdExtern, _), (_, Token::KwdFn, _), name, ..] => {
+                if let Some((name, span)) = ident(name) {
+                    self.define(name, Kind::Extern, span, extent);
+                }
+            }
+            [(_, Token::KwdStruct, _), name, ..] => {
+                if let Some((name, span)) = ident(name) {
+                    self.define(name, Kind::Struct, span, extent);
+                }
+            }
+            [(_, Token::KwdEnum, _), name, ..] => {
+                if let Some((name, span)) = ident(name) {
+                    self.define(name, Kind::Enum, span, extent);
+                }
+                for variant in &line.children {
+                    if let Some((name, span)) = ident(&variant.tokens[0]) {
+                        self.define(name, Kind::Variant, span, (span.0, variant.end()));
+                    }
+                }
+            }
+            [(_, Token::KwdClass, _), name, ..] => {
+                if let Some((name, span)) = ident(name) {
+                    self.define(name, Kind::Class, span, extent);
+                }
+                for method in &line.children {
+                    if let [(_, Token::KwdFn, _), name, ..] = &method.tokens[..] {
+                        if let Some((name, span)) = ident(name) {
+                            self.define(name, Kind::Method, span, (method.tokens[0].0, method.end()));
+                        }
+                    }
+                }
+            }
+            [(_, Token::KwdUse, _), path @ ..] => {
+                let names: Vec<_> = path.iter().filter_map(ident).collect();
+                if let Some(&(name, span)) = names.last() {
+                    self.define(name, Kind::Import, span, extent);
+                    let path: Vec<_> = names.iter().map(|(name, _)| name.as_str()).collect();
+                    self.index.definitions.last_mut().unwrap().qualified = Symbol::from(path.join(".").as_str());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn decl(&mut self, line: &Line) {
+        let tokens = skip_attributes(&line.tokens);
+        match tokens.first() {
+            Some((_, Token::KwdFn, _)) => {
+                if let Some((name, _)) = tokens.get(1).and_then(ident) {
+                    self.func(tokens, &line.children, name);
+                }
+            }
+            Some((_, Token::KwdTest, _)) => {
+                // tests aren't checked, and no function can be named `test`,
+                // so their variables have no types
+                self.func(tokens, &line.children, Symbol::from("test"));
+            }
+            Some((_, Token::KwdExtern, _)) => {
+                // the arguments are only named
+                let rest = self.args(&tokens[1..], false);
+                self.refs(rest);
+            }
+            Some((_, Token::KwdStruct, _)) | Some((_, Token::KwdEnum, _)) | Some((_, Token::KwdClass, _)) => {
+                for member in &line.children {
+                    // the name of a field, variant or method, and then types
+                    self.refs(&member.tokens[1..]);
+                }
+            }
+            Some((_, Token::KwdInstance, _)) => {
+                self.refs(&tokens[1..]);
+                let (class, ty) = match tokens {
+                    [_, class, ty, ..] => match (ident(class), ident(ty)) {
+                        (Some((class, _)), Some((ty, _))) => (self.qualified(class), Type::Name(self.qualified(ty))),
+                        _ => return,
+                    },
+                    _ => return,
+                };
+                for method in &line.children {
+                    let tokens = skip_attributes(&method.tokens);
+                    if let Some((name, _)) = tokens.get(1).and_then(ident) {
+                        self.func(tokens, &method.children, mangle_method(class, &ty, name));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Walks a function, which starts with `fn name`. `owner` is the name it
+    /// is checked under.
+    fn func(&mut self, tokens: &[Spanned], body: &[Line], owner: Symbol) {
+        self.owner = Some(owner);
+        self.bound.clear();
+        self.env.push_scope();
+        let rest = self.args(tokens, true);
+        self.refs(rest);
+        self.block(body);
+        let end = body.last().map_or(tokens[tokens.len() - 1].2, Line::end);
+        self.pop_scope(end);
+        self.owner = None;
+    }
+
+    /// Binds the arguments of a function or extern if `bind` is set, and
+    /// returns the tokens after them.
+    fn args<'a>(&mut self, tokens: &'a [Spanned], bind: bool) -> &'a [Spanned] {
+        let start = match tokens.iter().position(|(_, token, _)| *token == Token::SymParenL) {
+            // not in `-> (a -> b)` or in a body on the same line
+            Some(start) if !tokens[..start].iter().any(|(_, token, _)| matches!(token, Token::SymArrow | Token::SymColon)) => start,
+            _ => return &tokens[2.min(tokens.len())..],
+        };
+        let mut depth = 0;
+        for (i, token) in tokens.iter().enumerate().skip(start) {
+            match &token.1 {
+                Token::SymParenL => depth += 1,
+                Token::SymParenR => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return &tokens[i + 1..];
+                    }
+                }
+                Token::Ident(_) if depth == 1 => {
+                    let named = matches!(tokens[i - 1].1, Token::SymParenL | Token::SymComma);
+                    match ident(token) {
+                        Some((name, span)) if named && bind => self.bind(name, Kind::Argument, span),
+                        _ if named => (),
+                        _ => self.refs(std::slice::from_ref(token)),
+                    }
+                }
+                _ => self.refs(std::slice::from_ref(token)),
+            }
+        }
+        &[]
+    }
+
+    fn block(&mut self, lines: &[Line]) {
+        self.env.push_scope();
+        for line in lines {
+            self.stmt(&line.tokens, &line.children);
+        }
+        self.pop_scope(lines.last().map_or(0, Line::end));
+    }
+
+    fn stmt(&mut self, tokens: &[Spanned], children: &[Line]) {
+        match tokens {
+            [(_, Token::KwdLet, _), name, rest @ ..] => {
+                self.refs(rest);
+                if let Some((name, span)) = ident(name) {
+                    self.bind(name, Kind::Local, span);
+                }
+            }
+            [(_, Token::KwdMatch, _), rest @ ..] => {
+                self.refs(rest);
+                for arm in children {
+                    self.arm(arm);
+                }
+            }
+            tokens => {
+                self.refs(tokens);
+                if !children.is_empty() {
+                    self.block(children);
+                }
+            }
+        }
+    }
+
+    fn arm(&mut self, arm: &Line) {
+        let arrow = arm.tokens.iter().position(|(_, token, _)| *token == Token::SymDblArrow);
+        let (pattern, body) = match arrow {
+            Some(arrow) => (&arm.tokens[..arrow], &arm.tokens[arrow + 1..]),
+            None => (&arm.tokens[..], &[][..]),
+        };
+        self.env.push_scope();
+        for (i, token) in pattern.iter().enumerate() {
+            let (name, span) = match ident(token) {
+                Some(name) => name,
+                None => continue,
+            };
+            let is_ctor = pattern.get(i + 1).is_some_and(|(_, token, _)| *token == Token::SymParenL)
+                || self.env.lookup(name).is_some_and(|&def| self.index.definitions[def].kind == Kind::Variant);
+            if is_ctor {
+                self.refs(std::slice::from_ref(token));
+            } else {
+                self.bind(name, Kind::Local, span);
+            }
+        }
+        if body.is_empty() {
+            self.block(&arm.children);
+        } else {
+            self.stmt(body, &arm.children);
+        }
+        self.pop_scope(arm.end());
+    }
+
+    /// Records the names the tokens use.
+    fn refs(&mut self, tokens: &[Spanned]) {
+        let mut braces = 0;
+        for (i, token) in tokens.iter().enumerate() {
+            match &token.1 {
+                Token::SymBraceL => braces += 1,
+                Token::SymBraceR => braces -= 1,
+                Token::Ident(name) => {
+                    let field = i > 0 && tokens[i - 1].1 == Token::SymDot;
+                    let field_init = braces > 0 && tokens.get(i + 1).is_some_and(|(_, token, _)| *token == Token::SymColon);
+                    if field || field_init {
+                        continue;
+                    }
+                    if let Some(&def) = self.env.lookup(*name) {
+                        self.index.references.push(((token.0, token.2), def));
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,520 @@
+//! `mochi lsp`: a language server that editors talk to over stdin and
+//! stdout. Every open document is checked like `mochi check` whenever it
+//! changes, and its errors and warnings are published as diagnostics. Hover
+//! shows the inferred types of functions and variables once the document
+//! typechecks, and going to a definition follows the names as the typechecker
+//! resolved them, into the modules the document imports too. Otherwise they,
+//! completion and the outline work from its tokens, so they keep working while
+//! the document doesn't typecheck.
+
+mod index;
+mod rpc;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+use symbol::Symbol;
+
+use crate::driver::{display_type, Checked, Diagnostic, Level};
+use crate::scanner::{Scanner, Token};
+use crate::session::Session;
+use crate::typeck::Binding;
+
+use self::index::{Definition, Index, Kind, Span};
+
+const KEYWORDS: &[&str] = &[
+    "assert", "assert_eq", "class", "enum", "extern", "fn", "if", "instance", "let", "match", "pub", "return", "struct",
+    "test", "use",
+];
+
+// json-rpc error codes
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+type RequestError = (i64, String);
+
+/// Serves the client on stdin and stdout, and returns the exit status.
+pub fn run() -> i32 {
+    let stdin = io::stdin();
+    match serve(stdin.lock(), io::stdout()) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            1
+        }
+    }
+}
+
+/// Serves the messages read from `input` until the client says to exit. Like
+/// the protocol says, the exit status is 0 only if the client shut the server
+/// down first.
+pub fn serve(mut input: impl BufRead, mut output: impl Write) -> io::Result<i32> {
+    let mut server = Server {
+        documents: HashMap::new(),
+        shut_down: false,
+    };
+    while let Some(message) = rpc::read(&mut input)? {
+        // the server doesn't send requests, so it ignores responses
+        let method = match message["method"].as_str() {
+            Some(method) => method,
+            None => continue,
+        };
+        let params = &message["params"];
+        match message.get("id") {
+            Some(id) => {
+                let response = match server.request(method, params) {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, message)) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": code, "message": message },
+                    }),
+                };
+                rpc::write(&mut output, &response)?;
+            }
+            None if method == "exit" => return Ok(if server.shut_down { 0 } else { 1 }),
+            None => {
+                if let Some(uri) = server.notify(method, params) {
+                    rpc::write(&mut output, &server.diagnostics(&uri))?;
+                }
+            }
+        }
+    }
+    Ok(1)
+}
+
+/// The `file:` uri of a path.
+fn uri_of(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.display().to_string().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+/// The path of a `file:` uri.
+fn path_of(uri: &str) -> PathBuf {
+    let path = uri.trim_start_matches("file://");
+    let mut bytes = Vec::new();
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = match tail {
+            [high, low, ..] if byte == b'%' => std::str::from_utf8(&[*high, *low])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(escaped) => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// The byte every line of the text starts at.
+fn line_starts(text: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .collect()
+}
+
+/// The byte of the text at an lsp position, which counts utf-16 code units.
+fn offset(text: &str, lines: &[usize], position: &Value) -> Option<usize> {
+    let line = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+    let start = *lines.get(line)?;
+    let end = lines.get(line + 1).map_or(text.len(), |next| next - 1);
+    let mut units = 0;
+    for (i, c) in text[start..end].char_indices() {
+        if units >= character {
+            return Some(start + i);
+        }
+        units += c.len_utf16();
+    }
+    Some(end)
+}
+
+/// The lsp position of a byte of the text.
+fn position(text: &str, lines: &[usize], offset: usize) -> Value {
+    let offset = offset.min(text.len());
+    let line = match lines.binary_search(&offset) {
+        Ok(line) => line,
+        Err(next) => next - 1,
+    };
+    let start = lines[line];
+    let character = text.get(start..offset).map_or(0, |text| text.encode_utf16().count());
+    json!({ "line": line, "character": character })
+}
+
+/// The tokens of the text that scan, and the index built from them.
+fn index(text: &str) -> (Vec<(usize, Token, usize)>, Index) {
+    let tokens: Vec<_> = Scanner::new(text.as_bytes()).filter_map(Result::ok).collect();
+    let index = Index::new(&tokens);
+    (tokens, index)
+}
+
+struct Document {
+    path: PathBuf,
+    text: String,
+    /// The byte every line starts at.
+    lines: Vec<usize>,
+    tokens: Vec<(usize, Token, usize)>,
+    index: Index,
+    /// The errors and warnings, with where they are.
+    diagnostics: Vec<(Span, Diagnostic)>,
+    checked: Option<Checked>,
+}
+
+impl Document {
+    fn new(uri: &str, text: String) -> Self {
+        let path = path_of(uri);
+        let lines = line_starts(&text);
+        let (tokens, index) = index(&text);
+        let mut session = Session::new(&path, text.as_str());
+        let checked = session
+            .scan()
+            .and_then(|_| session.parse())
+            .and_then(|ast| session.typecheck(ast));
+        let diagnostics = session
+            .diagnostics()
+            .iter()
+            .map(|diagnostic| {
+                let span = session.locate(diagnostic).unwrap_or((0, 0));
+                (span, Diagnostic {
+                    level: diagnostic.level,
+                    message: session.message(diagnostic).to_owned(),
+                    span: diagnostic.span,
+                    location: diagnostic.location,
+                })
+            })
+            .collect();
+        Document {
+            path,
+            lines,
+            tokens,
+            index,
+            diagnostics,
+            checked,
+            text,
+        }
+    }
+
+    fn offset(&self, position: &Value) -> Option<usize> {
+        offset(&self.text, &self.lines, position)
+    }
+
+    fn range(&self, (lo, hi): Span) -> Value {
+        json!({ "start": position(&self.text, &self.lines, lo), "end": position(&self.text, &self.lines, hi) })
+    }
+
+    /// Where the name at the byte is defined, as an lsp location.
+    fn definition(&self, uri: &str, offset: usize) -> Option<Value> {
+        let here = |span| json!({ "uri": uri, "range": self.range(span) });
+        let (name, span) = self.tokens.iter().find_map(|token| match token {
+            (lo, Token::Ident(name), hi) if *lo <= offset && offset <= *hi => Some((*name, (*lo, *hi))),
+            _ => None,
+        })?;
+        if self.index.definitions.iter().any(|def| def.span == span) {
+            return Some(here(span));
+        }
+        let (func, binding) = match self.resolve(name, offset) {
+            Some(resolved) => resolved,
+            // not in a statement the typechecker got to
+            None => return self.index.lookup(offset).map(|def| here(def.span)),
+        };
+        match binding {
+            Binding::Local(local) => {
+                let locals = &self.checked.as_ref()?.locals[&func];
+                let n = locals[..local].iter().filter(|(other, _)| *other == name).count();
+                let def = self
+                    .index
+                    .definitions
+                    .iter()
+                    .find(|def| def.name == name && def.owner == Some((func, n)))?;
+                Some(here(def.span))
+            }
+            Binding::Global(qualified) => {
+                let declared = self
+                    .index
+                    .declarations()
+                    .find(|def| def.qualified == qualified && def.kind != Kind::Import);
+                if let Some(def) = declared {
+                    return Some(here(def.span));
+                }
+                // items of other modules are found in their files, and the
+                // import stands in for them if those don't scan
+                self.imported(qualified).or_else(|| {
+                    let import = self.index.declarations().find(|def| def.qualified == qualified)?;
+                    Some(here(import.span))
+                })
+            }
+        }
+    }
+
+    /// What the typechecker resolved the name used at the byte to, with the
+    /// function it's used in.
+    fn resolve(&self, name: Symbol, offset: usize) -> Option<(Symbol, Binding)> {
+        let checked = self.checked.as_ref()?;
+        let file = self.path.display().to_string();
+        let unqualified = |qualified: Symbol| qualified.as_str().rsplit('.').next() == Some(name.as_str());
+        checked.references.iter().find_map(|(&func, references)| {
+            references.iter().find_map(|&(span, binding)| {
+                let used = match binding {
+                    Binding::Local(local) => checked.locals[&func][local].0 == name,
+                    Binding::Global(qualified) => unqualified(qualified),
+                };
+                let contains = span.file.as_str() == file && span.lo <= offset && offset <= span.hi;
+                if used && contains {
+                    Some((func, binding))
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
+    /// The location of an item declared in a module the document imports.
+    /// Documents are checked on their own, so their modules are next to them.
+    fn imported(&self, qualified: Symbol) -> Option<Value> {
+        let path = qualified.as_str().split('.').collect::<Vec<_>>();
+        let (name, module) = path.split_last()?;
+        let mut file = self.path.parent()?.to_owned();
+        file.extend(module);
+        file.set_extension("mo");
+        let text = fs::read_to_string(&file).ok()?;
+        let (_, index) = index(&text);
+        let def = index.declarations().find(|def| def.name.as_str() == *name)?;
+        let lines = line_starts(&text);
+        Some(json!({
+            "uri": uri_of(&file),
+            "range": { "start": position(&text, &lines, def.span.0), "end": position(&text, &lines, def.span.1) },
+        }))
+    }
+
+    /// The inferred type of what the definition defines, if the document
+    /// typechecks.
+    fn type_of(&self, def: &Definition) -> Option<String> {
+        let checked = self.checked.as_ref()?;
+        let ty = match def.owner {
+            Some((func, n)) => checked
+                .locals_of(func)
+                .into_iter()
+                .filter(|(name, _)| *name == def.name)
+                .nth(n)
+                .map(|(_, ty)| ty)?,
+            None => checked.type_of(def.qualified)?,
+        };
+        Some(display_type(&ty).to_string())
+    }
+
+    /// The first line of the definition as it's written.
+    fn declaration(&self, def: &Definition) -> String {
+        let text = &self.text[def.extent.0..def.extent.1];
+        let line = text.lines().next().unwrap_or_default().trim();
+        line.trim_end_matches(':').to_owned()
+    }
+
+    fn hover(&self, def: &Definition) -> String {
+        match (def.kind, self.type_of(def)) {
+            (Kind::Struct, _) | (Kind::Enum, _) | (Kind::Class, _) | (Kind::Method, _) => self.declaration(def),
+            (_, Some(ty)) => format!("{}: {}", def.name, ty),
+            (Kind::Argument, None) | (Kind::Local, None) => def.name.to_string(),
+            (_, None) => self.declaration(def),
+        }
+    }
+}
+
+fn completion_kind(kind: Kind) -> u32 {
+    match kind {
+        Kind::Function | Kind::Extern => 3,
+        Kind::Struct => 22,
+        Kind::Enum => 13,
+        Kind::Variant => 20,
+        Kind::Class => 8,
+        Kind::Method => 2,
+        Kind::Import => 18,
+        Kind::Argument | Kind::Local => 6,
+    }
+}
+
+struct Server {
+    documents: HashMap<String, Document>,
+    shut_down: bool,
+}
+
+impl Server {
+    fn document(&self, params: &Value) -> Result<&Document, RequestError> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        self.documents
+            .get(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("`{}` isn't open", uri)))
+    }
+
+    /// The document and the byte the request is about.
+    fn position(&self, params: &Value) -> Result<(&Document, usize), RequestError> {
+        let document = self.document(params)?;
+        let offset = document
+            .offset(&params["position"])
+            .ok_or_else(|| (INVALID_PARAMS, "the position isn't in the document".to_owned()))?;
+        Ok((document, offset))
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, RequestError> {
+        if self.shut_down {
+            return Err((INVALID_REQUEST, "the server is shut down".to_owned()));
+        }
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // documents are sent whole on every change
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "completionProvider": {},
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "mochi" },
+            })),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Value::Null)
+            }
+            "textDocument/hover" => {
+                let (document, offset) = self.position(params)?;
+                Ok(match document.index.lookup(offset) {
+                    Some(def) => json!({
+                        "contents": {
+                            "kind": "markdown",
+                            "value": format!("```mochi\n{}\n```", document.hover(def)),
+                        },
+                    }),
+                    None => Value::Null,
+                })
+            }
+            "textDocument/definition" => {
+                let (document, offset) = self.position(params)?;
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                Ok(document.definition(uri, offset).unwrap_or(Value::Null))
+            }
+            "textDocument/completion" => {
+                let (document, offset) = self.position(params)?;
+                // an inner variable hides the outer ones of the same name
+                let mut names: HashMap<_, &Definition> = HashMap::new();
+                for def in document.index.visible(offset) {
+                    match names.get(&def.name) {
+                        Some(other) if other.span.0 > def.span.0 => (),
+                        _ => {
+                            names.insert(def.name, def);
+                        }
+                    }
+                }
+                let mut items: Vec<_> = names
+                    .values()
+                    .map(|def| {
+                        json!({
+                            "label": def.name.as_str(),
+                            "kind": completion_kind(def.kind),
+                            "detail": document.type_of(def),
+                        })
+                    })
+                    .collect();
+                items.sort_by(|a, b| a["label"].as_str().cmp(&b["label"].as_str()));
+                items.extend(KEYWORDS.iter().map(|keyword| json!({ "label": keyword, "kind": 14 })));
+                Ok(json!({ "isIncomplete": false, "items": items }))
+            }
+            "textDocument/documentSymbol" => {
+                let document = self.document(params)?;
+                let symbols: Vec<_> = document
+                    .index
+                    .declarations()
+                    .filter(|def| matches!(def.kind, Kind::Function | Kind::Extern))
+                    .map(|def| {
+                        json!({
+                            "name": def.name.as_str(),
+                            "detail": document.type_of(def),
+                            // function
+                            "kind": 12,
+                            "range": document.range(def.extent),
+                            "selectionRange": document.range(def.span),
+                        })
+                    })
+                    .collect();
+                Ok(json!(symbols))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("`{}` isn't supported", method))),
+        }
+    }
+
+    /// Handles a notification, and returns the uri of the document whose
+    /// diagnostics changed.
+    fn notify(&mut self, method: &str, params: &Value) -> Option<String> {
+        let uri = params["textDocument"]["uri"].as_str()?.to_owned();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str()?.to_owned();
+                self.documents.insert(uri.clone(), Document::new(&uri, text));
+            }
+            "textDocument/didChange" => {
+                let mut text = self.documents.get(&uri)?.text.clone();
+                for change in params["contentChanges"].as_array()? {
+                    let new = change["text"].as_str()?;
+                    // the client can send the part that changed instead of the
+                    // whole document, at positions in the text as the changes
+                    // before it left it
+                    let range = &change["range"];
+                    if range.is_null() {
+                        text = new.to_owned();
+                        continue;
+                    }
+                    let lines = line_starts(&text);
+                    let start = offset(&text, &lines, &range["start"])?;
+                    let end = offset(&text, &lines, &range["end"])?;
+                    text.replace_range(start..end.max(start), new);
+                }
+                self.documents.insert(uri.clone(), Document::new(&uri, text));
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+            }
+            _ => return None,
+        }
+        Some(uri)
+    }
+
+    fn diagnostics(&self, uri: &str) -> Value {
+        let diagnostics: Vec<_> = match self.documents.get(uri) {
+            Some(document) => document
+                .diagnostics
+                .iter()
+                .map(|(span, diagnostic)| {
+                    json!({
+                        "range": document.range(*span),
+                        "severity": if diagnostic.level == Level::Error { 1 } else { 2 },
+                        "source": "mochi",
+                        "message": diagnostic.message,
+                    })
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        })
+    }
+}
@@ -0,0 +1,46 @@
+//! The framing of json-rpc messages: every message is a json object after a
+//! `Content-Length` header and an empty line.
+
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Reads the next message, or `None` at the end of the input.
+pub fn read(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return match length {
+                None => Ok(None),
+                Some(_) => Err(invalid("the input ends in a header")),
+            };
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        // other headers, like `Content-Type`, don't change anything
+        let mut parts = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            if name.eq_ignore_ascii_case("content-length") {
+                let value = value.trim().parse().map_err(|_| invalid(format!("bad header `{}`", line)))?;
+                length = Some(value);
+            }
+        }
+    }
+    let length = length.ok_or_else(|| invalid("a message has no `Content-Length`"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|err| invalid(err.to_string()))
+}
+
+pub fn write(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
@@ -1,76 +1,656 @@
-#[macro_use]
-extern crate lazy_static;
-#[macro_use]
-extern crate target_lexicon;
-
-lalrpop_mod!(parser);
-
-mod ast;
-mod codegen;
-mod env;
-mod mir;
-mod scanner;
-mod prelude;
-mod typeck;
-
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
-
-use lalrpop_util::lalrpop_mod;
+use std::fmt::Display;
+use std::fs;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use mochi::codegen::Listing;
+use mochi::harness::Harness;
+use mochi::manifest::Packages;
+use mochi::mir::opt::OptLevel;
+use mochi::timing::CountingAlloc;
+use mochi::{mir, repl, scanner, Diagnostic, Lowered, Session};
+use serde_json::json;
+use structopt::clap::ErrorKind;
 use structopt::StructOpt;
-use symbol::Symbol;
+use target_lexicon::Triple;
+
+/// The exit status when the program has errors.
+const EXIT_ERRORS: i32 = 1;
+/// The exit status when the compiler can't do what it was asked to: the
+/// arguments are wrong, a file can't be read or written, or linking fails.
+const EXIT_FAILURE: i32 = 2;
+
+/// Whether diagnostics are printed as json, see `ErrorFormat`.
+static JSON_DIAGNOSTICS: AtomicBool = AtomicBool::new(false);
+
+/// How `-Z time-passes` prints the timings, if it was given.
+static TIME_PASSES: Mutex<Option<TimeFormat>> = Mutex::new(None);
+
+// counts what every phase allocates, for `-Z time-passes`
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+#[derive(StructOpt)]
+#[structopt(name = "mochi")]
+enum Opt {
+    /// Check a program for errors without generating code.
+    #[structopt(name = "check")]
+    Check(Input),
+    /// Compile a program, or the package in the current directory with the
+    /// packages it depends on.
+    #[structopt(name = "build")]
+    Build(Build),
+    /// Compile a program and run it, exiting with its exit status.
+    #[structopt(name = "run")]
+    Run(Run),
+    /// Format a source file in place.
+    #[structopt(name = "fmt")]
+    Fmt(Fmt),
+    /// Print the tokens of a source file.
+    #[structopt(name = "lex")]
+    Lex(Input),
+    /// Print the syntax tree of a source file, without the modules it uses.
+    #[structopt(name = "parse")]
+    Parse(Input),
+    /// Run the tests of a program and of the modules it uses.
+    #[structopt(name = "test")]
+    Test(Test),
+    /// Read definitions and expressions and evaluate them as they come.
+    #[structopt(name = "repl")]
+    Repl,
+    /// Run a language server over stdin and stdout.
+    #[structopt(name = "lsp")]
+    Lsp,
+}
+
+/// The options that every command reading a program takes.
+#[derive(StructOpt)]
+struct Common {
+    /// The target to compile for, `x86_64-unknown-unknown-elf` unless the
+    /// manifest says otherwise. Only x86-64 is supported.
+    #[structopt(long = "target")]
+    target: Option<Triple>,
+    /// The optimization level: 0, 1 or 2. It's 0 unless the manifest says
+    /// otherwise.
+    #[structopt(short = "O")]
+    opt_level: Option<OptLevel>,
+    /// Print how much each optimization pass changed.
+    #[structopt(long = "opt-stats")]
+    opt_stats: bool,
+    /// How errors and warnings are printed: `human`, or `json` for an object
+    /// with the `level` and the `message` of each on its own line, and the
+    /// `span` of source it's about, with its `file` and bytes `lo` to `hi`.
+    #[structopt(long = "error-format", default_value = "human")]
+    error_format: ErrorFormat,
+    /// A directory to keep the types and the machine code of functions in
+    /// between compilations, so that only the functions that changed are
+    /// typechecked and compiled again.
+    #[structopt(long = "incremental-dir", parse(from_os_str))]
+    incremental_dir: Option<PathBuf>,
+    /// The number of threads to compile functions on, one for each processor
+    /// by default. The output is the same for any number.
+    #[structopt(short = "j", long = "jobs")]
+    jobs: Option<usize>,
+    /// Options for working on the compiler: `time-passes` prints how long
+    /// every phase took and how much it allocated once the compiler is done,
+    /// as a table, or `time-passes=json` as json.
+    #[structopt(short = "Z", number_of_values = 1)]
+    unstable: Vec<Unstable>,
+}
+
+#[derive(StructOpt)]
+struct Input {
+    #[structopt(flatten)]
+    common: Common,
+    /// A source file, or a `.mir` file as written by `--emit mir`. Without
+    /// one, the entry of the package whose `mochi.toml` is in the current
+    /// directory or above it.
+    #[structopt(parse(from_os_str))]
+    file: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+struct Build {
+    #[structopt(flatten)]
+    input: Input,
+    /// What to write, separated by commas: `tokens`, `ast`, `types` for the
+    /// inferred type of every declaration, `mir`, `clif` for the Cranelift ir
+    /// of every function before and after Cranelift optimizes it, `asm` for
+    /// its machine code, `obj` and `exe`. `kind=path` writes one to a file;
+    /// otherwise objects and executables are named after the source file and
+    /// everything else is printed. Packages are built into an executable in
+    /// their `target` directory by default, files into an object.
+    #[structopt(long = "emit")]
+    emit: Option<Outputs>,
+    /// Where to write the output, if only one kind is emitted.
+    #[structopt(short = "o", parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+struct Run {
+    #[structopt(flatten)]
+    input: Input,
+    /// Run the program with the mir interpreter instead of compiling it.
+    #[structopt(long = "interpret")]
+    interpret: bool,
+}
 
-use crate::ast::Type as AstType;
-use crate::codegen::Codegen;
-use crate::env::Environment;
-use crate::parser::ProgramParser;
-use crate::scanner::Scanner;
+#[derive(StructOpt)]
+struct Fmt {
+    #[structopt(flatten)]
+    input: Input,
+    /// Leave the file as it is, and fail if it isn't formatted.
+    #[structopt(long = "check")]
+    check: bool,
+}
 
 #[derive(StructOpt)]
-struct Opt {
-    file: PathBuf,
+struct Test {
+    #[structopt(flatten)]
+    input: Input,
+    /// Only run the tests whose name contains this.
+    filter: Option<String>,
+    /// Run the tests with the mir interpreter instead of compiling them.
+    #[structopt(long = "interpret")]
+    interpret: bool,
+}
+
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(ErrorFormat::Human),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("unknown error format `{}`", s)),
+        }
+    }
+}
+
+enum Unstable {
+    TimePasses(TimeFormat),
+}
+
+#[derive(Clone, Copy)]
+enum TimeFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for Unstable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "time-passes" | "time-passes=table" => Ok(Unstable::TimePasses(TimeFormat::Table)),
+            "time-passes=json" => Ok(Unstable::TimePasses(TimeFormat::Json)),
+            _ => Err(format!("unknown option `-Z {}`", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Emit {
+    Tokens,
+    Ast,
+    Types,
+    Mir,
+    Clif,
+    Asm,
+    Obj,
+    Exe,
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tokens" => Ok(Emit::Tokens),
+            "ast" => Ok(Emit::Ast),
+            "types" => Ok(Emit::Types),
+            "mir" => Ok(Emit::Mir),
+            "clif" => Ok(Emit::Clif),
+            "asm" => Ok(Emit::Asm),
+            "obj" => Ok(Emit::Obj),
+            "exe" => Ok(Emit::Exe),
+            _ => Err(format!("unknown output kind `{}`", s)),
+        }
+    }
+}
+
+/// Something to emit, and the file to write it to.
+struct Output {
+    emit: Emit,
+    path: Option<PathBuf>,
+}
+
+struct Outputs(Vec<Output>);
+
+impl Outputs {
+    fn contains(&self, emit: Emit) -> bool {
+        self.0.iter().any(|output| output.emit == emit)
+    }
+
+    /// The files to write an object or an executable to.
+    fn paths(&self, emit: Emit, default: &Path) -> Vec<PathBuf> {
+        self.0
+            .iter()
+            .filter(|output| output.emit == emit)
+            .map(|output| output.path.clone().unwrap_or_else(|| default.to_owned()))
+            .collect()
+    }
+
+    /// Writes a textual output to its files, or to stdout for the ones without
+    /// a file. The text is only made if it was asked for.
+    fn write(&self, emit: Emit, text: impl FnOnce() -> String) {
+        if !self.contains(emit) {
+            return;
+        }
+        let text = text();
+        for output in self.0.iter().filter(|output| output.emit == emit) {
+            match &output.path {
+                Some(path) => fs::write(path, &text).unwrap_or_else(|err| fail_io(path, err)),
+                None => print!("{}", text),
+            }
+        }
+    }
+}
+
+impl FromStr for Outputs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut outputs = Vec::new();
+        for output in s.split(',') {
+            let mut parts = output.splitn(2, '=');
+            let emit = parts.next().unwrap().parse()?;
+            let path = parts.next().map(PathBuf::from);
+            outputs.push(Output { emit, path });
+        }
+        Ok(Outputs(outputs))
+    }
+}
+
+fn report(diagnostic: &Diagnostic) {
+    if JSON_DIAGNOSTICS.load(Ordering::Relaxed) {
+        let mut object = json!({ "level": diagnostic.level.to_string(), "message": diagnostic.message });
+        if let Some(span) = diagnostic.span {
+            object["span"] = json!({ "file": span.file.as_str(), "lo": span.lo, "hi": span.hi });
+        }
+        eprintln!("{}", object);
+    } else {
+        eprintln!("{}", diagnostic);
+    }
+}
+
+/// Prints the timings of the session if `-Z time-passes` asked for them.
+fn report_timings(session: &Session) {
+    let timings = session.timings();
+    match *TIME_PASSES.lock().unwrap() {
+        Some(TimeFormat::Table) => eprint!("{}", timings),
+        Some(TimeFormat::Json) => eprintln!("{}", json!({ "phases": timings.to_json() })),
+        None => (),
+    }
+}
+
+fn exit_with(code: i32, err: impl Display) -> ! {
+    report(&Diagnostic::error(err));
+    process::exit(code);
+}
+
+/// Reports a file that couldn't be read or written.
+fn fail_io(path: &Path, err: impl Display) -> ! {
+    exit_with(EXIT_FAILURE, format!("{}: {}", path.display(), err))
 }
 
 fn main() {
-    let opt = Opt::from_args();
+    let matches = Opt::clap().get_matches_safe().unwrap_or_else(|err| match err.kind {
+        ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => err.exit(),
+        _ => {
+            eprintln!("{}", err.message);
+            process::exit(EXIT_FAILURE);
+        }
+    });
+    let opt = Opt::from_clap(&matches);
+    match opt {
+        Opt::Check(input) => {
+            let mut session = start(&input);
+            front_end(&mut session, &Outputs(Vec::new()));
+            report_timings(&session);
+        }
+        Opt::Build(build) => {
+            let (mut session, packages) = open(&build.input);
+            let mut outputs = match (build.emit, &packages) {
+                (Some(outputs), _) => outputs,
+                (None, Some(packages)) => Outputs(vec![Output {
+                    emit: Emit::Exe,
+                    path: Some(package_executable(packages)),
+                }]),
+                (None, None) => Outputs(vec![Output {
+                    emit: Emit::Obj,
+                    path: None,
+                }]),
+            };
+            if let Some(path) = build.output {
+                match outputs.0.as_mut_slice() {
+                    [output] => output.path = Some(path),
+                    _ => exit_with(
+                        EXIT_FAILURE,
+                        "`-o` needs a single kind of output; name the files of several with `--emit kind=path`",
+                    ),
+                }
+            }
+            compile(&mut session, &build.input.common, &outputs);
+            report_timings(&session);
+        }
+        Opt::Run(run) => {
+            let mut session = start(&run.input);
+            let code = execute(&mut session, &run);
+            report_timings(&session);
+            process::exit(code);
+        }
+        Opt::Fmt(fmt) => format(&fmt),
+        Opt::Lex(input) => {
+            let mut session = start(&input);
+            let tokens = session.scan();
+            let tokens = checkpoint(&mut session, tokens);
+            print!("{}", scanner::dump(&tokens));
+        }
+        Opt::Parse(input) => {
+            let mut session = start(&input);
+            let module = session.parse_module();
+            let module = checkpoint(&mut session, module);
+            println!("{:#?}", module.program);
+        }
+        Opt::Test(test) => process::exit(run_tests(&test)),
+        Opt::Repl => repl::run(),
+        Opt::Lsp => process::exit(mochi::lsp::run()),
+    }
+}
+
+/// Reads the input file into a new session.
+fn start(input: &Input) -> Session {
+    open(input).0
+}
+
+/// Reads the input file into a new session, or the entry of the package in
+/// the current directory if there's no file, along with its packages.
+fn open(input: &Input) -> (Session, Option<Packages>) {
+    let common = &input.common;
+    if let ErrorFormat::Json = common.error_format {
+        JSON_DIAGNOSTICS.store(true, Ordering::Relaxed);
+    }
+    for option in &common.unstable {
+        match option {
+            Unstable::TimePasses(format) => *TIME_PASSES.lock().unwrap() = Some(*format),
+        }
+    }
+    let (session, packages) = match &input.file {
+        Some(file) => (Session::from_file(file).unwrap_or_else(|err| fail_io(file, err)), None),
+        None => {
+            let dir = std::env::current_dir().unwrap_or_else(|err| fail_io(Path::new("."), err));
+            let packages = Packages::discover(&dir).unwrap_or_else(|err| exit_with(EXIT_FAILURE, err));
+            let entry = packages.entry();
+            let session = Session::from_file(&entry).unwrap_or_else(|err| fail_io(&entry, err));
+            (session.package(&packages), Some(packages))
+        }
+    };
+    // the options override the manifest
+    let session = match &common.target {
+        Some(target) => session.target(target.clone()),
+        None => session,
+    };
+    let session = match common.opt_level {
+        Some(opt_level) => session.opt_level(opt_level),
+        None => session,
+    };
+    let session = match common.jobs {
+        Some(jobs) => session.jobs(jobs),
+        None => session,
+    };
+    let session = match &common.incremental_dir {
+        Some(dir) => session.incremental(dir),
+        None => session,
+    };
+    (session, packages)
+}
+
+/// Where a package is built to: `target/<name>` in its directory.
+fn package_executable(packages: &Packages) -> PathBuf {
+    let root = packages.root();
+    let dir = root.dir.join("target");
+    fs::create_dir_all(&dir).unwrap_or_else(|err| fail_io(&dir, err));
+    dir.join(&root.manifest.name)
+}
 
-    // read ast from file
-    let file = File::open(&opt.file).expect("failed");
-    let scanner = Scanner::new(file);
-    let parser = ProgramParser::new();
-    let ast = parser.parse(scanner).expect("failed");
-    println!("ast: {:?}", ast);
+/// Reports the diagnostics of the phases run so far, and exits if one of them
+/// failed.
+fn checkpoint<T>(session: &mut Session, result: Option<T>) -> T {
+    session.take_diagnostics().iter().for_each(report);
+    result.unwrap_or_else(|| {
+        report_timings(session);
+        process::exit(EXIT_ERRORS)
+    })
+}
 
-    // create environment
-    let mut type_env = Environment::<Symbol, AstType>::new();
-    prelude::load_prelude(&mut type_env);
-    type_env.push_scope();
-    for decl in &ast.0 {
-        if let Some((name, ty)) = decl.get_signature() {
-            type_env.insert(name, ty);
+/// Reads the program and lowers it to mir, writing the intermediate results
+/// that were asked for.
+fn front_end(session: &mut Session, outputs: &Outputs) -> Lowered {
+    if session.file().extension() == Some("mir".as_ref()) {
+        if outputs.contains(Emit::Tokens) || outputs.contains(Emit::Ast) || outputs.contains(Emit::Types) {
+            exit_with(EXIT_FAILURE, "`--emit tokens`, `ast` and `types` need a source file");
         }
+        let lowered = session.parse_mir();
+        return checkpoint(session, lowered);
     }
-    println!("type_env: {:?}", type_env);
 
-    // typecheck the ast
-    let mut constraints = HashSet::new();
-    for decl in &ast.0 {
-        typeck::get_constraints_decl(&mut type_env, &mut constraints, decl);
+    outputs.write(Emit::Tokens, || {
+        let tokens = session.scan();
+        scanner::dump(&checkpoint(session, tokens))
+    });
+    let ast = session.parse();
+    let ast = checkpoint(session, ast);
+    outputs.write(Emit::Ast, || format!("{:#?}\n", ast));
+    let checked = session.typecheck(ast);
+    let checked = checkpoint(session, checked);
+    outputs.write(Emit::Types, || checked.dump_types());
+    let lowered = session.lower(checked);
+    checkpoint(session, lowered)
+}
+
+fn optimize(session: &mut Session, common: &Common, lowered: &mut Lowered) {
+    let passes = session.optimize(lowered);
+    if common.opt_stats {
+        eprint!("{}", passes);
     }
-    println!("constraints: {:?}", constraints);
+}
+
+fn compile(session: &mut Session, common: &Common, outputs: &Outputs) {
+    let mut lowered = front_end(session, outputs);
+    optimize(session, common, &mut lowered);
+    outputs.write(Emit::Mir, || mir::dump(&lowered.adts, &lowered.program));
 
-    // generate ir from ast
-    let mut codegen = Codegen::new();
-    for decl in &ast.0 {
-        use crate::ast::Decl;
-        match decl {
-            Decl::Func(func) => codegen.compile_func(func),
-            _ => (),
+    let file = session.file().to_owned();
+    let objects = outputs.paths(Emit::Obj, &file.with_extension("o"));
+    let executables = outputs.paths(Emit::Exe, &file.with_extension(""));
+    let mut listings = Vec::new();
+    if outputs.contains(Emit::Clif) {
+        listings.push(Listing::Clif);
+    }
+    if outputs.contains(Emit::Asm) {
+        listings.push(Listing::Asm);
+    }
+    if listings.is_empty() && objects.is_empty() && executables.is_empty() {
+        return;
+    }
+
+    // generate ir from mir
+    let codegen = session.codegen(&lowered, &listings);
+    let codegen = checkpoint(session, codegen);
+    outputs.write(Emit::Clif, || codegen.listing_output(Listing::Clif).to_owned());
+    outputs.write(Emit::Asm, || codegen.listing_output(Listing::Asm).to_owned());
+    if objects.is_empty() && executables.is_empty() {
+        return;
+    }
+
+    // an executable is linked from an object file, which is only kept if it
+    // was asked for
+    let object = session.emit(codegen);
+    let object_paths = match objects.as_slice() {
+        [] => vec![executables[0].with_extension("o")],
+        objects => objects.to_vec(),
+    };
+    for path in &object_paths {
+        fs::write(path, &object).unwrap_or_else(|err| fail_io(path, err));
+    }
+    for executable in &executables {
+        link(&object_paths[..1], executable, session.libraries());
+    }
+    if objects.is_empty() {
+        fs::remove_file(&object_paths[0]).unwrap_or_else(|err| fail_io(&object_paths[0], err));
+    }
+}
+
+/// Links object files, or C files, with the C library and the libraries of
+/// its packages into an executable, using the system's C compiler.
+fn link(inputs: &[PathBuf], executable: &Path, libraries: &[String]) {
+    let status = process::Command::new("cc")
+        .args(inputs)
+        .arg("-o")
+        .arg(executable)
+        .args(libraries.iter().map(|library| format!("-l{}", library)))
+        .status()
+        .unwrap_or_else(|err| exit_with(EXIT_FAILURE, format!("couldn't run `cc`: {}", err)));
+    if !status.success() {
+        exit_with(EXIT_FAILURE, format!("linking {} failed", executable.display()));
+    }
+}
+
+fn format(fmt: &Fmt) {
+    let mut session = start(&fmt.input);
+    let file = session.file().to_owned();
+    if file.extension() == Some("mir".as_ref()) {
+        exit_with(EXIT_FAILURE, "`mochi fmt` needs a source file");
+    }
+    let formatted = session.format();
+    let formatted = checkpoint(&mut session, formatted);
+    if formatted == session.source() {
+        return;
+    }
+    if fmt.check {
+        exit_with(EXIT_ERRORS, format!("{} isn't formatted", file.display()));
+    }
+    fs::write(&file, formatted).unwrap_or_else(|err| fail_io(&file, err));
+}
+
+/// Compiles the tests along with their runner into an executable, in a
+/// directory of its own that the caller removes.
+fn build_tests(session: &mut Session, harness: &Harness, lowered: &Lowered) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mochi-test-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap_or_else(|err| fail_io(&dir, err));
+    let codegen = session.codegen(lowered, &[]);
+    let codegen = checkpoint(session, codegen);
+    let object = dir.join("tests.o");
+    fs::write(&object, session.emit(codegen)).unwrap_or_else(|err| fail_io(&object, err));
+    let runner = dir.join("runner.c");
+    fs::write(&runner, harness.runner(&lowered.program)).unwrap_or_else(|err| fail_io(&runner, err));
+    let executable = dir.join("tests");
+    link(&[object, runner], &executable, session.libraries());
+    executable
+}
+
+/// Runs the tests that match the filter, each in a process or an
+/// interpreter of its own, and returns the exit status: whether they all
+/// passed.
+fn run_tests(test: &Test) -> i32 {
+    let mut session = start(&test.input);
+    if session.file().extension() == Some("mir".as_ref()) {
+        exit_with(EXIT_FAILURE, "`mochi test` needs a source file");
+    }
+    let harness = session.harness();
+    let (harness, mut lowered) = checkpoint(&mut session, harness);
+    optimize(&mut session, &test.input.common, &mut lowered);
+
+    let filter = test.filter.as_deref().unwrap_or("");
+    let selected: Vec<_> = harness.tests.iter().filter(|case| case.name.contains(filter)).collect();
+    let executable = if test.interpret || selected.is_empty() {
+        None
+    } else {
+        Some(build_tests(&mut session, &harness, &lowered))
+    };
+    println!("running {} test{}", selected.len(), if selected.len() == 1 { "" } else { "s" });
+    let mut failures = Vec::new();
+    for case in &selected {
+        let (result, output) = match &executable {
+            Some(executable) => harness.run_native(executable, case),
+            None => harness.run(&lowered.adts, &lowered.program, case),
+        };
+        let status = if result.is_ok() { "ok" } else { "FAILED" };
+        println!("test {} ... {}", case.name, status);
+        if let Err(message) = result {
+            failures.push((case, message, output));
         }
     }
 
-    let output_file = opt.file.with_extension("o");
-    codegen.finish(&output_file);
+    if !failures.is_empty() {
+        println!("\nfailures:");
+        for (case, message, output) in &failures {
+            println!("\n---- {} ----", case.name);
+            print!("{}", String::from_utf8_lossy(output));
+            println!("{}", message);
+        }
+    }
+    println!(
+        "\ntest result: {}. {} passed; {} failed; {} filtered out",
+        if failures.is_empty() { "ok" } else { "FAILED" },
+        selected.len() - failures.len(),
+        failures.len(),
+        harness.tests.len() - selected.len()
+    );
+    if let Some(dir) = executable.as_ref().and_then(|executable| executable.parent()) {
+        fs::remove_dir_all(dir).unwrap_or_else(|err| fail_io(dir, err));
+    }
+    report_timings(&session);
+    if failures.is_empty() {
+        0
+    } else {
+        EXIT_ERRORS
+    }
+}
+
+/// Runs the program and returns its exit status.
+fn execute(session: &mut Session, run: &Run) -> i32 {
+    if run.interpret {
+        let mut lowered = front_end(session, &Outputs(Vec::new()));
+        optimize(session, &run.input.common, &mut lowered);
+        let code = session.interpret(&lowered);
+        // only the low byte makes it to the parent, like for a native program
+        return checkpoint(session, code) as i32;
+    }
+
+    let executable = std::env::temp_dir().join(format!("mochi-{}", process::id()));
+    let outputs = Outputs(vec![Output {
+        emit: Emit::Exe,
+        path: Some(executable.clone()),
+    }]);
+    compile(session, &run.input.common, &outputs);
+    let status = process::Command::new(&executable)
+        .status()
+        .unwrap_or_else(|err| fail_io(&executable, err));
+    fs::remove_file(&executable).unwrap_or_else(|err| fail_io(&executable, err));
+    // like a shell, a program killed by a signal exits with 128 plus its number
+    status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().expect("exited or killed"))
 }
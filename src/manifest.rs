@@ -0,0 +1,384 @@
+//! Packages. A directory with a `mochi.toml` manifest is a package:
+//!
+//! ```toml
+//! [package]
+//! name = "app"
+//! entry = "src/main.mo"
+//! source-dirs = ["src"]
+//! target = "x86_64-unknown-unknown-elf"
+//! opt-level = 2
+//! libraries = ["m"]
+//!
+//! [dependencies]
+//! geo = { path = "../geo" }
+//! ```
+//!
+//! Only `name` is needed. The entry is the root module of the program, and
+//! defaults to `src/main.mo`; modules are looked up in the source directories,
+//! which default to the directory of the entry. A package uses the modules of
+//! the packages it depends on by their names, `use geo.shapes.area` for
+//! `area` in the module `shapes` of `geo`, and is linked with the C libraries
+//! of all of them. Packages are compiled together with the program that uses
+//! them, since generic functions are only compiled for the types they are
+//! used at.
+//!
+//! Manifests are TOML, and keys other than these are rejected.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path as FsPath, PathBuf};
+
+use symbol::Symbol;
+use target_lexicon::Triple;
+use toml::value::{Table, Value};
+
+use crate::mir::opt::OptLevel;
+use crate::module::SourcePackage;
+
+/// The file name of manifests.
+pub const MANIFEST: &str = "mochi.toml";
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io(PathBuf, io::Error),
+    /// A manifest that isn't valid TOML.
+    Toml(PathBuf, toml::de::Error),
+    /// A key of a manifest that isn't valid, with what's wrong with it.
+    Invalid(PathBuf, String),
+    /// A key that every manifest needs.
+    Missing(PathBuf, &'static str),
+    /// No manifest in a directory or in any directory above it.
+    NotFound(PathBuf),
+    /// A dependency whose package has another name.
+    Renamed(String, PathBuf, String),
+    /// Two packages with the same name.
+    Conflict(String, PathBuf, PathBuf),
+    /// Packages that depend on each other, by their names.
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ManifestError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            ManifestError::Toml(path, err) => write!(f, "{}: {}", path.display(), err),
+            ManifestError::Invalid(path, message) => write!(f, "{}: {}", path.display(), message),
+            ManifestError::Missing(path, key) => write!(f, "{}: `{}` is missing", path.display(), key),
+            ManifestError::NotFound(dir) => write!(
+                f,
+                "no file given, and no `{}` in {} or above it",
+                MANIFEST,
+                dir.display()
+            ),
+            ManifestError::Renamed(dependency, dir, name) => write!(
+                f,
+                "the dependency `{}` in {} is a package named `{}`",
+                dependency,
+                dir.display(),
+                name
+            ),
+            ManifestError::Conflict(name, first, second) => write!(
+                f,
+                "both {} and {} are packages named `{}`",
+                first.display(),
+                second.display(),
+                name
+            ),
+            ManifestError::Cycle(names) => write!(f, "packages depend on each other: {}", names.join(" -> ")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub name: String,
+    /// The root module of the program, relative to the package like every
+    /// other path.
+    pub entry: PathBuf,
+    /// The directories modules are looked up in.
+    pub source_dirs: Vec<PathBuf>,
+    pub target: Option<Triple>,
+    pub opt_level: Option<OptLevel>,
+    /// The C libraries to link with, as `cc -l` takes them.
+    pub libraries: Vec<String>,
+    /// The packages it depends on, by their names, with their directories.
+    pub dependencies: Vec<(String, PathBuf)>,
+}
+
+/// What kind of value a TOML value is, for errors.
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "a string",
+        Value::Integer(_) => "an integer",
+        Value::Float(_) => "a float",
+        Value::Boolean(_) => "a boolean",
+        Value::Datetime(_) => "a date",
+        Value::Array(_) => "an array",
+        Value::Table(_) => "a table",
+    }
+}
+
+/// The message for a key whose value isn't of the kind it should be.
+fn expected(key: &str, what: &str, value: &Value) -> String {
+    format!("`{}` should be {}, not {}", key, what, kind(value))
+}
+
+fn string(key: &str, value: Value) -> Result<String, String> {
+    match value {
+        Value::String(string) => Ok(string),
+        value => Err(expected(key, "a string", &value)),
+    }
+}
+
+fn strings(key: &str, value: Value) -> Result<Vec<String>, String> {
+    let error = expected(key, "an array of strings", &value);
+    match value {
+        Value::Array(values) => values.into_iter().map(|value| string(key, value).map_err(|_| error.clone())).collect(),
+        _ => Err(error),
+    }
+}
+
+impl Manifest {
+    /// Reads the manifest in `file`, which only names it in errors.
+    pub fn parse(file: &FsPath, text: &str) -> Result<Self, ManifestError> {
+        let tables: Table = toml::from_str(text).map_err(|err| ManifestError::Toml(file.to_owned(), err))?;
+        Manifest::from_tables(tables).map_err(|message| match message {
+            Some(message) => ManifestError::Invalid(file.to_owned(), message),
+            None => ManifestError::Missing(file.to_owned(), "package.name"),
+        })
+    }
+
+    /// Reads the keys of a manifest, or says what's wrong with them: `None`
+    /// when there's no name.
+    fn from_tables(tables: Table) -> Result<Self, Option<String>> {
+        let mut manifest = Manifest {
+            name: String::new(),
+            entry: PathBuf::from("src/main.mo"),
+            source_dirs: Vec::new(),
+            target: None,
+            opt_level: None,
+            libraries: Vec::new(),
+            dependencies: Vec::new(),
+        };
+        let mut named = false;
+        for (table, value) in tables {
+            let entries = match value {
+                Value::Table(entries) => entries,
+                value if table == "package" || table == "dependencies" => {
+                    return Err(Some(expected(&table, "a table", &value)))
+                }
+                _ => return Err(Some(format!("unknown key `{}`", table))),
+            };
+            for (key, value) in entries {
+                let path = format!("{}.{}", table, key);
+                match (table.as_str(), key.as_str()) {
+                    ("package", "name") => {
+                        let name = string(&path, value)?;
+                        if !is_ident(&name) {
+                            return Err(Some(format!("`{}` isn't a valid package name", name)));
+                        }
+                        manifest.name = name;
+                        named = true;
+                    }
+                    ("package", "entry") => manifest.entry = PathBuf::from(string(&path, value)?),
+                    ("package", "source-dirs") => {
+                        let dirs = strings(&path, value)?;
+                        manifest.source_dirs = dirs.into_iter().map(PathBuf::from).collect();
+                    }
+                    ("package", "target") => {
+                        let target = string(&path, value)?;
+                        let triple = target.parse().map_err(|_| format!("unknown target `{}`", target))?;
+                        manifest.target = Some(triple);
+                    }
+                    ("package", "opt-level") => match value {
+                        Value::Integer(level) => manifest.opt_level = Some(level.to_string().parse()?),
+                        value => return Err(Some(expected(&path, "an integer", &value))),
+                    },
+                    ("package", "libraries") => manifest.libraries = strings(&path, value)?,
+                    ("dependencies", name) => {
+                        let dependency = match value {
+                            Value::Table(mut entries) if entries.len() == 1 => entries.remove("path"),
+                            Value::Table(_) => None,
+                            value => return Err(Some(expected(&path, "a table like `{ path = \"../geo\" }`", &value))),
+                        };
+                        match dependency {
+                            Some(dir) => {
+                                let dir = string(&format!("{}.path", path), dir)?;
+                                manifest.dependencies.push((name.to_owned(), PathBuf::from(dir)));
+                            }
+                            None => return Err(Some(format!("the dependency `{}` needs a `path` and nothing else", name))),
+                        }
+                    }
+                    _ => return Err(Some(format!("unknown key `{}`", path))),
+                }
+            }
+        }
+        if !named {
+            return Err(None);
+        }
+        if manifest.source_dirs.is_empty() {
+            let dir = manifest.entry.parent().unwrap_or_else(|| FsPath::new(""));
+            manifest.source_dirs.push(dir.to_owned());
+        }
+        Ok(manifest)
+    }
+}
+
+/// Whether a package name can start a module path.
+fn is_ident(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[derive(Debug)]
+pub struct Package {
+    pub dir: PathBuf,
+    pub manifest: Manifest,
+    /// The packages it depends on, by their index.
+    pub dependencies: Vec<usize>,
+}
+
+/// A package and every package it depends on, transitively.
+#[derive(Debug)]
+pub struct Packages {
+    /// Every package after the ones it depends on, so that the package that
+    /// was loaded comes last.
+    pub packages: Vec<Package>,
+}
+
+impl Packages {
+    /// Loads the package whose manifest is in `dir`, or in the closest
+    /// directory above it that has one.
+    pub fn discover(dir: &FsPath) -> Result<Self, ManifestError> {
+        let dir = fs::canonicalize(dir).map_err(|err| ManifestError::Io(dir.to_owned(), err))?;
+        match dir.ancestors().find(|dir| dir.join(MANIFEST).is_file()) {
+            Some(root) => Packages::load(root),
+            None => Err(ManifestError::NotFound(dir)),
+        }
+    }
+
+    /// Loads the package whose manifest is in `dir`, and its dependencies.
+    pub fn load(dir: &FsPath) -> Result<Self, ManifestError> {
+        let mut loader = PackageLoader {
+            packages: Vec::new(),
+            index: HashMap::new(),
+            names: HashMap::new(),
+            stack: Vec::new(),
+        };
+        loader.load(dir)?;
+        Ok(Packages {
+            packages: loader.packages,
+        })
+    }
+
+    pub fn root(&self) -> &Package {
+        self.packages.last().expect("there is a root package")
+    }
+
+    /// The root module of the root package.
+    pub fn entry(&self) -> PathBuf {
+        let root = self.root();
+        root.dir.join(&root.manifest.entry)
+    }
+
+    /// The C libraries of every package, in the order `cc` needs them: those
+    /// of a package before those of the packages it depends on.
+    pub fn libraries(&self) -> Vec<String> {
+        let mut libraries: Vec<String> = Vec::new();
+        for package in self.packages.iter().rev() {
+            for library in &package.manifest.libraries {
+                // a library that is needed later is only kept there
+                libraries.retain(|other| other != library);
+                libraries.push(library.clone());
+            }
+        }
+        libraries
+    }
+
+    /// Where the modules of every package are, in the same order.
+    pub fn source_packages(&self) -> Vec<SourcePackage> {
+        let root = self.packages.len() - 1;
+        self.packages
+            .iter()
+            .enumerate()
+            .map(|(i, package)| SourcePackage {
+                // the modules of the root package are used by their paths, as
+                // in a program without a manifest
+                name: if i == root {
+                    Vec::new()
+                } else {
+                    vec![Symbol::from(package.manifest.name.as_str())]
+                },
+                dirs: package
+                    .manifest
+                    .source_dirs
+                    .iter()
+                    .map(|dir| package.dir.join(dir))
+                    .collect(),
+                dependencies: package
+                    .dependencies
+                    .iter()
+                    .map(|&dependency| {
+                        let name = &self.packages[dependency].manifest.name;
+                        (Symbol::from(name.as_str()), dependency)
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+struct PackageLoader {
+    packages: Vec<Package>,
+    /// The packages loaded so far, by their directories.
+    index: HashMap<PathBuf, usize>,
+    /// The directories of the packages loaded so far, by their names.
+    names: HashMap<String, PathBuf>,
+    /// The packages being loaded, to detect cycles.
+    stack: Vec<(PathBuf, String)>,
+}
+
+impl PackageLoader {
+    fn load(&mut self, dir: &FsPath) -> Result<usize, ManifestError> {
+        let dir = fs::canonicalize(dir).map_err(|err| ManifestError::Io(dir.to_owned(), err))?;
+        if let Some(start) = self.stack.iter().position(|(other, _)| *other == dir) {
+            let mut cycle: Vec<_> = self.stack[start..].iter().map(|(_, name)| name.clone()).collect();
+            cycle.push(self.stack[start].1.clone());
+            return Err(ManifestError::Cycle(cycle));
+        }
+        if let Some(&i) = self.index.get(&dir) {
+            return Ok(i);
+        }
+
+        let file = dir.join(MANIFEST);
+        let text = fs::read_to_string(&file).map_err(|err| ManifestError::Io(file.clone(), err))?;
+        let manifest = Manifest::parse(&file, &text)?;
+        if let Some(other) = self.names.get(&manifest.name) {
+            return Err(ManifestError::Conflict(manifest.name, other.clone(), dir));
+        }
+        self.stack.push((dir.clone(), manifest.name.clone()));
+        let mut dependencies = Vec::new();
+        for (name, path) in &manifest.dependencies {
+            let dependency = self.load(&dir.join(path))?;
+            let package = &self.packages[dependency];
+            if package.manifest.name != *name {
+                return Err(ManifestError::Renamed(name.clone(), package.dir.clone(), package.manifest.name.clone()));
+            }
+            dependencies.push(dependency);
+        }
+        self.stack.pop();
+
+        let i = self.packages.len();
+        self.index.insert(dir.clone(), i);
+        self.names.insert(manifest.name.clone(), dir.clone());
+        self.packages.push(Package {
+            dir,
+            manifest,
+            dependencies,
+        });
+        Ok(i)
+    }
+}
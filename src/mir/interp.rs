@@ -0,0 +1,468 @@
+//! Runs mir directly, without generating any code. Values behave like they
+//! do in the compiled program: arithmetic wraps around, shifts only use the
+//! low bits of the amount, and dividing by zero traps. Calls push frames on a
+//! stack of their own rather than recursing, so deep recursion in the
+//! program doesn't overflow the stack of the interpreter.
+//!
+//! Besides `--interpret`, the optimizer folds constants with the operators
+//! here, and the output of compiled programs can be checked against it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use symbol::Symbol;
+
+use crate::adt::AdtTable;
+use crate::ast::{BinOp, Path, UnOp};
+use crate::harness;
+use crate::lower::lower_type;
+
+use super::{BlockId, Constant, Decl, Func, Local, Operand, Place, Program, Rvalue, Stmt, Terminator, Type};
+
+/// Calls nested deeper than this are taken to have overflowed the stack,
+/// which is roughly where the compiled program would run out of it.
+const MAX_DEPTH: usize = 100_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    Unit,
+    /// The fields of a struct in the order they are declared in.
+    Struct(Symbol, Vec<(Symbol, Value)>),
+    /// Enum values are never modified, so they are shared like the pointers
+    /// the compiled program passes around.
+    Enum(Rc<(Symbol, Vec<Value>)>),
+}
+
+impl Value {
+    /// The value a local of the type holds before it is assigned to.
+    pub fn zero(adts: &AdtTable, ty: &Type) -> Value {
+        match ty {
+            Type::Int => Value::Int(0),
+            Type::Bool => Value::Bool(false),
+            Type::Name(name) => match adts.get_struct(*name) {
+                Some(info) => Value::Struct(
+                    *name,
+                    info.fields
+                        .iter()
+                        .map(|(field, ty)| (*field, Value::zero(adts, &lower_type(ty).expect("fields are resolved"))))
+                        .collect(),
+                ),
+                // enums can't be read before they are assigned to
+                None => Value::Unit,
+            },
+            Type::Unit | Type::Func(_, _) => Value::Unit,
+        }
+    }
+
+    pub fn as_int(&self) -> i64 {
+        match self {
+            Value::Int(n) => *n,
+            _ => panic!("expected an int, found {}", self),
+        }
+    }
+
+    /// The value as an operand of `eval_binop`: bools are 0 and 1, as they
+    /// are in the generated code.
+    pub fn as_operand(&self) -> i64 {
+        match self {
+            Value::Bool(b) => *b as i64,
+            _ => self.as_int(),
+        }
+    }
+
+    pub fn as_enum(&self) -> (Symbol, &[Value]) {
+        match self {
+            Value::Enum(value) => (value.0, &value.1),
+            _ => panic!("expected an enum, found {}", self),
+        }
+    }
+
+    pub fn field(&self, field: Symbol) -> &Value {
+        match self {
+            Value::Struct(_, fields) => &fields.iter().find(|(name, _)| *name == field).expect("no such field").1,
+            _ => panic!("expected a struct, found {}", self),
+        }
+    }
+
+    pub fn field_mut(&mut self, field: Symbol) -> &mut Value {
+        match self {
+            Value::Struct(_, fields) => &mut fields.iter_mut().find(|(name, _)| *name == field).expect("no such field").1,
+            value => panic!("expected a struct, found {}", value),
+        }
+    }
+}
+
+impl From<Constant> for Value {
+    fn from(constant: Constant) -> Self {
+        match constant {
+            Constant::Int(n) => Value::Int(n),
+            Constant::Bool(b) => Value::Bool(b),
+            Constant::Unit => Value::Unit,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Unit => write!(f, "()"),
+            Value::Struct(name, fields) => {
+                write!(f, "{} {{", name)?;
+                for (i, (field, value)) in fields.iter().enumerate() {
+                    write!(f, "{}{}: {}", if i > 0 { ", " } else { " " }, field, value)?;
+                }
+                write!(f, " }}")
+            }
+            Value::Enum(value) => {
+                write!(f, "{}", value.0)?;
+                if !value.1.is_empty() {
+                    write!(f, "(")?;
+                    for (i, field) in value.1.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{}", field)?;
+                    }
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Evaluates a binary operator on integers the way the generated code does.
+/// `None` if the operation traps.
+pub fn eval_binop(op: BinOp, left: i64, right: i64) -> Option<Constant> {
+    // shifts only use the low bits of the amount, like x86
+    let shift = (right & 63) as u32;
+    Some(match op {
+        BinOp::LogicalOr | BinOp::LogicalAnd => unreachable!("lowered to branches"),
+        BinOp::BitwiseOr => Constant::Int(left | right),
+        BinOp::BitwiseXor => Constant::Int(left ^ right),
+        BinOp::BitwiseAnd => Constant::Int(left & right),
+        BinOp::Equals => Constant::Bool(left == right),
+        BinOp::NotEquals => Constant::Bool(left != right),
+        BinOp::LessThan => Constant::Bool(left < right),
+        BinOp::LessThanEquals => Constant::Bool(left <= right),
+        BinOp::GreaterThan => Constant::Bool(left > right),
+        BinOp::GreaterThanEquals => Constant::Bool(left >= right),
+        BinOp::LeftShift => Constant::Int(left.wrapping_shl(shift)),
+        BinOp::RightShift => Constant::Int(left.wrapping_shr(shift)),
+        BinOp::Add => Constant::Int(left.wrapping_add(right)),
+        BinOp::Sub => Constant::Int(left.wrapping_sub(right)),
+        BinOp::Mul => Constant::Int(left.wrapping_mul(right)),
+        // `checked_div` is `None` exactly when the division traps
+        BinOp::Div => Constant::Int(left.checked_div(right)?),
+        BinOp::Mod => Constant::Int(left.checked_rem(right)?),
+    })
+}
+
+pub fn eval_unop(op: UnOp, value: Constant) -> Option<Constant> {
+    match (op, value) {
+        (UnOp::LogicalNot, Constant::Bool(value)) => Some(Constant::Bool(!value)),
+        (UnOp::BitwiseNot, Constant::Int(value)) => Some(Constant::Int(!value)),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub enum InterpError {
+    /// The program called `exit`. Not a failure as such, but it stops the
+    /// program all the same.
+    Exit(i64),
+    /// A division by zero, or of the smallest integer by -1.
+    DivisionTrap(Path),
+    /// An `unreachable` terminator was reached in the function.
+    Unreachable(Path),
+    /// Calls were nested deeper than the limit.
+    StackOverflow(usize),
+    /// The step limit set with `Interpreter::fuel` was reached.
+    OutOfFuel,
+    UnknownFunction(Path),
+    /// An extern that isn't built into the interpreter.
+    UnsupportedExtern(Path),
+    /// An assertion of a test failed: its number, and the values an
+    /// `assert_eq` compared.
+    AssertionFailed(i64, i64, i64),
+    Io(io::Error),
+}
+
+impl fmt::Display for InterpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpError::Exit(code) => write!(f, "the program exited with {}", code),
+            InterpError::DivisionTrap(func) => write!(f, "division by zero or overflow in `{}`", func),
+            InterpError::Unreachable(func) => write!(f, "reached unreachable code in `{}`", func),
+            InterpError::StackOverflow(depth) => write!(f, "stack overflow: calls nested more than {} deep", depth),
+            InterpError::OutOfFuel => write!(f, "evaluation took too many steps"),
+            InterpError::UnknownFunction(func) => write!(f, "no function named `{}`", func),
+            InterpError::UnsupportedExtern(func) => {
+                write!(f, "extern `{}` isn't available in the interpreter, only `print` and `exit` are", func)
+            }
+            InterpError::AssertionFailed(assertion, _, _) => write!(f, "assertion #{} failed", assertion),
+            InterpError::Io(err) => write!(f, "couldn't write output: {}", err),
+        }
+    }
+}
+
+/// Runs one of the externs the interpreters provide themselves: `print`,
+/// which writes its arguments on a line, `exit`, and `harness::FAILED`, which
+/// a failed assertion in a test calls.
+pub fn call_builtin(name: &Path, args: &[Value], out: &mut dyn Write) -> Result<(), InterpError> {
+    match name.to_string().as_str() {
+        "print" => {
+            for (i, arg) in args.iter().enumerate() {
+                let sep = if i > 0 { " " } else { "" };
+                write!(out, "{}{}", sep, arg).map_err(InterpError::Io)?;
+            }
+            writeln!(out).map_err(InterpError::Io)
+        }
+        "exit" => Err(InterpError::Exit(args.first().map_or(0, Value::as_int))),
+        harness::FAILED => {
+            let arg = |i| args.get(i).map_or(0, Value::as_int);
+            Err(InterpError::AssertionFailed(arg(0), arg(1), arg(2)))
+        }
+        _ => Err(InterpError::UnsupportedExtern(name.clone())),
+    }
+}
+
+/// A call that hasn't returned yet.
+struct Frame<'a> {
+    func: &'a Func,
+    locals: Vec<Value>,
+    block: BlockId,
+    /// Where the caller wants the result.
+    dest: Local,
+}
+
+pub struct Interpreter<'a> {
+    adts: &'a AdtTable,
+    funcs: HashMap<&'a Path, &'a Func>,
+    externs: HashMap<&'a Path, &'a Type>,
+    /// The number of statements and terminators left to run, if limited.
+    fuel: Option<u64>,
+    out: Box<dyn Write + 'a>,
+}
+
+impl<'a> Interpreter<'a> {
+    /// Creates an interpreter for the program that prints to stdout.
+    pub fn new(adts: &'a AdtTable, program: &'a Program) -> Self {
+        let mut funcs = HashMap::new();
+        let mut externs = HashMap::new();
+        for decl in &program.0 {
+            match decl {
+                Decl::Func(func) => {
+                    funcs.insert(&func.name, func);
+                }
+                Decl::Extern(name, _, returns) => {
+                    externs.insert(name, returns);
+                }
+            }
+        }
+        Interpreter {
+            adts,
+            funcs,
+            externs,
+            fuel: None,
+            out: Box::new(io::stdout()),
+        }
+    }
+
+    /// Stops evaluation with `OutOfFuel` after this many steps, so that
+    /// evaluating at compile time can't loop forever.
+    pub fn fuel(mut self, steps: u64) -> Self {
+        self.fuel = Some(steps);
+        self
+    }
+
+    /// Sends what `print` writes here instead of to stdout.
+    pub fn output(mut self, out: impl Write + 'a) -> Self {
+        self.out = Box::new(out);
+        self
+    }
+
+    /// Calls the function and runs until it returns.
+    pub fn call(&mut self, name: &Path, args: Vec<Value>) -> Result<Value, InterpError> {
+        let func = *self.funcs.get(name).ok_or_else(|| InterpError::UnknownFunction(name.clone()))?;
+        let mut stack = vec![self.frame(func, args, Local::RETURN)];
+        loop {
+            let frame = stack.last_mut().unwrap();
+            let func = frame.func;
+            let block = func.block(frame.block);
+            for Stmt::Assign(place, rvalue) in &block.stmts {
+                self.step()?;
+                let value = self.rvalue(frame, rvalue)?;
+                *place_mut(&mut frame.locals, place) = value;
+            }
+
+            self.step()?;
+            match &block.terminator {
+                Terminator::Goto(target) => frame.block = *target,
+                Terminator::Branch(cond, then, else_) => {
+                    frame.block = match self.operand(frame, cond) {
+                        Value::Bool(true) => *then,
+                        Value::Bool(false) => *else_,
+                        value => panic!("expected a bool, found {}", value),
+                    };
+                }
+                Terminator::Switch(value, cases, otherwise) => {
+                    let value = self.operand(frame, value).as_int();
+                    frame.block = cases
+                        .iter()
+                        .find(|(case, _)| *case == value)
+                        .map_or(*otherwise, |(_, target)| *target);
+                }
+                Terminator::Call {
+                    func,
+                    args,
+                    dest,
+                    target,
+                    ..
+                } => {
+                    let args: Vec<Value> = args.iter().map(|arg| self.operand(frame, arg)).collect();
+                    frame.block = *target;
+                    match self.funcs.get(func) {
+                        Some(callee) => {
+                            if stack.len() == MAX_DEPTH {
+                                return Err(InterpError::StackOverflow(MAX_DEPTH));
+                            }
+                            let callee = self.frame(callee, args, *dest);
+                            stack.push(callee);
+                        }
+                        None => {
+                            let value = self.call_extern(func, args)?;
+                            frame.locals[dest.0] = value;
+                        }
+                    }
+                }
+                Terminator::Return => {
+                    let frame = stack.pop().unwrap();
+                    let value = frame.locals.into_iter().next().unwrap();
+                    match stack.last_mut() {
+                        Some(caller) => caller.locals[frame.dest.0] = value,
+                        None => return Ok(value),
+                    }
+                }
+                Terminator::Unreachable => return Err(InterpError::Unreachable(func.name.clone())),
+            }
+        }
+    }
+
+    /// Runs `main` and returns the exit status of the program, which is what
+    /// `main` returns, or 0 if it returns nothing.
+    pub fn run_main(&mut self) -> Result<i64, InterpError> {
+        match self.call(&Path::from_qualified(Symbol::from("main")), Vec::new()) {
+            Ok(Value::Int(code)) => Ok(code),
+            Ok(_) => Ok(0),
+            Err(InterpError::Exit(code)) => Ok(code),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn frame(&self, func: &'a Func, args: Vec<Value>, dest: Local) -> Frame<'a> {
+        let mut locals: Vec<Value> = func.locals.iter().map(|decl| Value::zero(self.adts, &decl.ty)).collect();
+        for (local, arg) in func.arg_locals().zip(args) {
+            locals[local.0] = arg;
+        }
+        Frame {
+            func,
+            locals,
+            block: BlockId::ENTRY,
+            dest,
+        }
+    }
+
+    fn step(&mut self) -> Result<(), InterpError> {
+        match &mut self.fuel {
+            Some(0) => Err(InterpError::OutOfFuel),
+            Some(fuel) => {
+                *fuel -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn call_extern(&mut self, name: &Path, args: Vec<Value>) -> Result<Value, InterpError> {
+        let returns = self.externs.get(name).ok_or_else(|| InterpError::UnknownFunction(name.clone()))?;
+        call_builtin(name, &args, &mut *self.out)?;
+        Ok(Value::zero(self.adts, returns))
+    }
+
+    fn operand(&self, frame: &Frame, operand: &Operand) -> Value {
+        match operand {
+            Operand::Copy(place) => read_place(&frame.locals, place).clone(),
+            Operand::Const(constant) => Value::from(*constant),
+        }
+    }
+
+    fn rvalue(&self, frame: &Frame, rvalue: &Rvalue) -> Result<Value, InterpError> {
+        Ok(match rvalue {
+            Rvalue::Use(operand) => self.operand(frame, operand),
+            Rvalue::BinOp(op, left, right) => {
+                let left = self.operand(frame, left).as_operand();
+                let right = self.operand(frame, right).as_operand();
+                match eval_binop(*op, left, right) {
+                    Some(constant) => Value::from(constant),
+                    None => return Err(InterpError::DivisionTrap(frame.func.name.clone())),
+                }
+            }
+            Rvalue::UnOp(op, operand) => {
+                let value = match self.operand(frame, operand) {
+                    Value::Int(n) => Constant::Int(n),
+                    Value::Bool(b) => Constant::Bool(b),
+                    value => panic!("expected an int or a bool, found {}", value),
+                };
+                Value::from(eval_unop(*op, value).expect("operand has the type of the operator"))
+            }
+            Rvalue::Struct(name, fields) => {
+                let info = self.adts.get_struct(*name).expect("not a struct");
+                let values = info
+                    .fields
+                    .iter()
+                    .map(|(field, _)| {
+                        let (_, operand) = fields.iter().find(|(name, _)| name == field).expect("field is set");
+                        (*field, self.operand(frame, operand))
+                    })
+                    .collect();
+                Value::Struct(*name, values)
+            }
+            Rvalue::Ctor(name, args) => {
+                let fields = args.iter().map(|arg| self.operand(frame, arg)).collect();
+                Value::Enum(Rc::new((*name, fields)))
+            }
+            Rvalue::Discriminant(place) => {
+                let (ctor, _) = read_place(&frame.locals, place).as_enum();
+                Value::Int(self.adts.get_ctor(ctor).expect("not a constructor").tag as i64)
+            }
+            Rvalue::Variant(place, ctor, i) => {
+                let (actual, fields) = read_place(&frame.locals, place).as_enum();
+                assert_eq!(actual, *ctor, "read a field of the wrong variant");
+                fields[*i].clone()
+            }
+        })
+    }
+}
+
+fn read_place<'v>(locals: &'v [Value], place: &Place) -> &'v Value {
+    let mut value = &locals[place.local.0];
+    for field in &place.fields {
+        value = value.field(*field);
+    }
+    value
+}
+
+fn place_mut<'v>(locals: &'v mut [Value], place: &Place) -> &'v mut Value {
+    let mut value = &mut locals[place.local.0];
+    for field in &place.fields {
+        value = value.field_mut(*field);
+    }
+    value
+}
@@ -0,0 +1,382 @@
+//! The intermediate representation the backend compiles: every function is a
+//! control flow graph of basic blocks over typed locals. Statements only
+//! assign an rvalue to a place, and all control flow, including calls, is in
+//! the terminators that end each block.
+
+pub mod interp;
+pub mod opt;
+pub mod parse;
+pub mod pretty;
+pub mod validate;
+
+pub use self::parse::parse;
+pub use self::pretty::dump;
+pub use self::validate::validate;
+
+use std::fmt;
+
+use symbol::Symbol;
+
+use crate::adt::AdtTable;
+use crate::ast::{BinOp, InlineHint, Path, UnOp};
+use crate::lower::lower_type;
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Type {
+    Func(Vec<Type>, Box<Type>),
+    Name(Symbol),
+    Unit,
+    Int,
+    Bool,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Func(args, ret) => {
+                write!(f, "(")?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ") -> {}", ret)
+            }
+            Type::Name(name) => write!(f, "{}", name),
+            Type::Unit => write!(f, "()"),
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Program(pub Vec<Decl>);
+
+#[derive(Debug)]
+pub enum Decl {
+    Extern(Path, Vec<Type>, Type),
+    Func(Func),
+}
+
+/// A local variable or temporary of a function.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Local(pub usize);
+
+impl Local {
+    /// Holds the value returned by the function.
+    pub const RETURN: Local = Local(0);
+}
+
+impl fmt::Display for Local {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "_{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct BlockId(pub usize);
+
+impl BlockId {
+    pub const ENTRY: BlockId = BlockId(0);
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LocalDecl {
+    /// The name of the variable in the source, if it isn't a temporary.
+    pub name: Option<Symbol>,
+    pub ty: Type,
+}
+
+#[derive(Clone, Debug)]
+pub struct Func {
+    pub name: Path,
+    /// Whether the symbol is visible outside of the object file.
+    pub exported: bool,
+    pub inline: InlineHint,
+    /// The number of arguments, which are the locals right after the return
+    /// value.
+    pub args: usize,
+    pub locals: Vec<LocalDecl>,
+    pub blocks: Vec<Block>,
+}
+
+impl Func {
+    pub fn returns(&self) -> &Type {
+        &self.locals[Local::RETURN.0].ty
+    }
+
+    pub fn arg_locals(&self) -> impl Iterator<Item = Local> {
+        (1..=self.args).map(Local)
+    }
+
+    pub fn get_type(&self) -> Type {
+        let args = self.arg_locals().map(|local| self.local_ty(local).clone()).collect();
+        Type::Func(args, Box::new(self.returns().clone()))
+    }
+
+    pub fn local_ty(&self, local: Local) -> &Type {
+        &self.locals[local.0].ty
+    }
+
+    pub fn block(&self, id: BlockId) -> &Block {
+        &self.blocks[id.0]
+    }
+
+    /// The type of the value stored at `place`. `None` if a field doesn't
+    /// exist.
+    pub fn place_ty(&self, adts: &AdtTable, place: &Place) -> Option<Type> {
+        place_ty(adts, &self.locals, place)
+    }
+
+    pub fn operand_ty(&self, adts: &AdtTable, operand: &Operand) -> Option<Type> {
+        match operand {
+            Operand::Copy(place) => self.place_ty(adts, place),
+            Operand::Const(constant) => Some(constant.ty()),
+        }
+    }
+
+    pub fn rvalue_ty(&self, adts: &AdtTable, rvalue: &Rvalue) -> Option<Type> {
+        Some(match rvalue {
+            Rvalue::Use(operand) => return self.operand_ty(adts, operand),
+            Rvalue::BinOp(op, _, _) => binop_result(*op),
+            Rvalue::UnOp(UnOp::LogicalNot, _) => Type::Bool,
+            Rvalue::UnOp(UnOp::BitwiseNot, _) => Type::Int,
+            Rvalue::Struct(name, _) => Type::Name(*name),
+            Rvalue::Ctor(name, _) => Type::Name(adts.get_ctor(*name)?.enum_name),
+            Rvalue::Discriminant(_) => Type::Int,
+            Rvalue::Variant(_, ctor, i) => {
+                let field = adts.get_ctor(*ctor)?.fields.get(*i)?;
+                lower_type(field).expect("fields are resolved")
+            }
+        })
+    }
+}
+
+/// The type of the value stored at `place`, given the locals of the function.
+pub fn place_ty(adts: &AdtTable, locals: &[LocalDecl], place: &Place) -> Option<Type> {
+    let mut ty = locals.get(place.local.0)?.ty.clone();
+    for field in &place.fields {
+        let info = match ty {
+            Type::Name(name) => adts.get_struct(name)?,
+            _ => return None,
+        };
+        let (_, field_ty) = info.get_field(*field)?;
+        ty = lower_type(field_ty).expect("fields are resolved");
+    }
+    Some(ty)
+}
+
+/// The type of the result of a binary operator.
+pub fn binop_result(op: BinOp) -> Type {
+    match op {
+        BinOp::LogicalOr
+        | BinOp::LogicalAnd
+        | BinOp::Equals
+        | BinOp::NotEquals
+        | BinOp::LessThan
+        | BinOp::LessThanEquals
+        | BinOp::GreaterThan
+        | BinOp::GreaterThanEquals => Type::Bool,
+        BinOp::BitwiseOr
+        | BinOp::BitwiseXor
+        | BinOp::BitwiseAnd
+        | BinOp::LeftShift
+        | BinOp::RightShift
+        | BinOp::Add
+        | BinOp::Sub
+        | BinOp::Mul
+        | BinOp::Div
+        | BinOp::Mod => Type::Int,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    pub terminator: Terminator,
+}
+
+#[derive(Clone, Debug)]
+pub enum Stmt {
+    Assign(Place, Rvalue),
+}
+
+/// A local, or a field nested in a struct held by a local.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Place {
+    pub local: Local,
+    pub fields: Vec<Symbol>,
+}
+
+impl Place {
+    pub fn local(local: Local) -> Place {
+        Place {
+            local,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn field(&self, field: Symbol) -> Place {
+        let mut fields = self.fields.clone();
+        fields.push(field);
+        Place {
+            local: self.local,
+            fields,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Constant {
+    Int(i64),
+    Bool(bool),
+    Unit,
+}
+
+impl Constant {
+    pub fn ty(&self) -> Type {
+        match self {
+            Constant::Int(_) => Type::Int,
+            Constant::Bool(_) => Type::Bool,
+            Constant::Unit => Type::Unit,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+    /// The value at a place. Structs are copied.
+    Copy(Place),
+    Const(Constant),
+}
+
+#[derive(Clone, Debug)]
+pub enum Rvalue {
+    Use(Operand),
+    /// Any operator but `&&` and `||`, which are lowered to branches. The
+    /// operands are ints, or bools for `==` and `!=`.
+    BinOp(BinOp, Operand, Operand),
+    UnOp(UnOp, Operand),
+    Struct(Symbol, Vec<(Symbol, Operand)>),
+    /// An enum value built from a constructor and its fields.
+    Ctor(Symbol, Vec<Operand>),
+    /// The tag of the enum value at the place.
+    Discriminant(Place),
+    /// A field of the enum value at the place, which has to have been built
+    /// with the given constructor.
+    Variant(Place, Symbol, usize),
+}
+
+impl Rvalue {
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Rvalue::Use(operand) | Rvalue::UnOp(_, operand) => vec![operand],
+            Rvalue::BinOp(_, left, right) => vec![left, right],
+            Rvalue::Struct(_, fields) => fields.iter().map(|(_, operand)| operand).collect(),
+            Rvalue::Ctor(_, args) => args.iter().collect(),
+            Rvalue::Discriminant(_) | Rvalue::Variant(_, _, _) => Vec::new(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Rvalue::Use(operand) | Rvalue::UnOp(_, operand) => vec![operand],
+            Rvalue::BinOp(_, left, right) => vec![left, right],
+            Rvalue::Struct(_, fields) => fields.iter_mut().map(|(_, operand)| operand).collect(),
+            Rvalue::Ctor(_, args) => args.iter_mut().collect(),
+            Rvalue::Discriminant(_) | Rvalue::Variant(_, _, _) => Vec::new(),
+        }
+    }
+
+    /// The enum read by the rvalue, which isn't an operand since it isn't
+    /// copied.
+    pub fn place(&self) -> Option<&Place> {
+        match self {
+            Rvalue::Discriminant(place) | Rvalue::Variant(place, _, _) => Some(place),
+            _ => None,
+        }
+    }
+
+    pub fn place_mut(&mut self) -> Option<&mut Place> {
+        match self {
+            Rvalue::Discriminant(place) | Rvalue::Variant(place, _, _) => Some(place),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Terminator {
+    Goto(BlockId),
+    /// Goes to the first block if the boolean is true, else to the second.
+    Branch(Operand, BlockId, BlockId),
+    /// Goes to the block of the case equal to the integer, else to the last
+    /// block.
+    Switch(Operand, Vec<(i64, BlockId)>, BlockId),
+    /// Stores the result of the call in `dest` and goes to `target`. `unwind`
+    /// is where control goes if the callee unwinds instead of returning.
+    Call {
+        func: Path,
+        args: Vec<Operand>,
+        dest: Local,
+        target: BlockId,
+        unwind: Option<BlockId>,
+    },
+    /// Returns the value of `Local::RETURN`.
+    Return,
+    Unreachable,
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Goto(target) => vec![*target],
+            Terminator::Branch(_, then, else_) => vec![*then, *else_],
+            Terminator::Switch(_, cases, otherwise) => {
+                let mut targets: Vec<_> = cases.iter().map(|(_, target)| *target).collect();
+                targets.push(*otherwise);
+                targets
+            }
+            Terminator::Call { target, unwind, .. } => Some(*target).into_iter().chain(*unwind).collect(),
+            Terminator::Return | Terminator::Unreachable => Vec::new(),
+        }
+    }
+
+    pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Goto(target) => vec![target],
+            Terminator::Branch(_, then, else_) => vec![then, else_],
+            Terminator::Switch(_, cases, otherwise) => {
+                let mut targets: Vec<_> = cases.iter_mut().map(|(_, target)| target).collect();
+                targets.push(otherwise);
+                targets
+            }
+            Terminator::Call { target, unwind, .. } => Some(target).into_iter().chain(unwind.as_mut()).collect(),
+            Terminator::Return | Terminator::Unreachable => Vec::new(),
+        }
+    }
+
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Terminator::Branch(operand, _, _) | Terminator::Switch(operand, _, _) => vec![operand],
+            Terminator::Call { args, .. } => args.iter().collect(),
+            Terminator::Goto(_) | Terminator::Return | Terminator::Unreachable => Vec::new(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Terminator::Branch(operand, _, _) | Terminator::Switch(operand, _, _) => vec![operand],
+            Terminator::Call { args, .. } => args.iter_mut().collect(),
+            Terminator::Goto(_) | Terminator::Return | Terminator::Unreachable => Vec::new(),
+        }
+    }
+}
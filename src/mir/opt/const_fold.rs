@@ -0,0 +1,64 @@
+//! Evaluates operators whose operands are constants, and branches and
+//! switches on constants. Operators are evaluated by the interpreter, so
+//! arithmetic wraps around like the generated code does, and divisions that
+//! would trap are left for the program to run.
+
+use crate::adt::AdtTable;
+use crate::ast::BinOp;
+use crate::mir::interp;
+use crate::mir::{Constant, Func, Operand, Rvalue, Stmt, Terminator};
+
+use super::Pass;
+
+pub struct ConstFold;
+
+impl Pass for ConstFold {
+    fn name(&self) -> &'static str {
+        "const-fold"
+    }
+
+    fn run(&self, _: &AdtTable, func: &mut Func) -> usize {
+        let mut changes = 0;
+        for block in &mut func.blocks {
+            for Stmt::Assign(_, rvalue) in &mut block.stmts {
+                let folded = match rvalue {
+                    Rvalue::BinOp(op, Operand::Const(left), Operand::Const(right)) => fold_binop(*op, *left, *right),
+                    Rvalue::UnOp(op, Operand::Const(value)) => interp::eval_unop(*op, *value),
+                    _ => None,
+                };
+                if let Some(constant) = folded {
+                    *rvalue = Rvalue::Use(Operand::Const(constant));
+                    changes += 1;
+                }
+            }
+
+            let target = match &block.terminator {
+                Terminator::Branch(Operand::Const(Constant::Bool(cond)), then, else_) => {
+                    Some(if *cond { *then } else { *else_ })
+                }
+                Terminator::Switch(Operand::Const(Constant::Int(value)), cases, otherwise) => Some(
+                    cases
+                        .iter()
+                        .find(|(index, _)| index == value)
+                        .map_or(*otherwise, |(_, target)| *target),
+                ),
+                _ => None,
+            };
+            if let Some(target) = target {
+                block.terminator = Terminator::Goto(target);
+                changes += 1;
+            }
+        }
+        changes
+    }
+}
+
+/// Folds an operator on constants with the semantics of the interpreter.
+/// `None` if it traps or the operands aren't constants it applies to.
+pub fn fold_binop(op: BinOp, left: Constant, right: Constant) -> Option<Constant> {
+    match (left, right) {
+        (Constant::Int(left), Constant::Int(right)) => interp::eval_binop(op, left, right),
+        (Constant::Bool(left), Constant::Bool(right)) => interp::eval_binop(op, left as i64, right as i64),
+        _ => None,
+    }
+}
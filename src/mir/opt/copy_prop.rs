@@ -0,0 +1,161 @@
+//! Replaces the uses of a local that is only ever assigned a constant or a
+//! copy of another place by that constant or place, which leaves the
+//! assignment dead.
+
+use crate::adt::AdtTable;
+use crate::mir::{BlockId, Func, Local, Operand, Place, Rvalue, Stmt, Terminator};
+
+use super::{count_predecessors, count_reads, count_writes, Cfg, Pass};
+
+pub struct CopyProp;
+
+impl Pass for CopyProp {
+    fn name(&self) -> &'static str {
+        "copy-prop"
+    }
+
+    fn run(&self, _: &AdtTable, func: &mut Func) -> usize {
+        let mut changes = 0;
+        // every replacement changes the uses of the others, so they are
+        // looked for again after each one
+        while let Some((local, value)) = find_copy(func) {
+            changes += replace_uses(func, local, &value);
+        }
+        changes
+    }
+}
+
+/// Where a local gets its value: a position as in `Cfg::available`, or
+/// `None` if it is never assigned and so keeps the value it starts with.
+fn def_position(func: &Func, preds: &[usize], local: Local) -> Option<Option<(BlockId, usize)>> {
+    let mut found = None;
+    for (i, block) in func.blocks.iter().enumerate() {
+        for (j, Stmt::Assign(place, _)) in block.stmts.iter().enumerate() {
+            if place.local == local {
+                found = Some((BlockId(i), j + 1));
+            }
+        }
+        if let Terminator::Call { dest, target, .. } = &block.terminator {
+            if *dest == local {
+                // only written on the way to the target
+                if preds[target.0] != 1 {
+                    return None;
+                }
+                found = Some((*target, 0));
+            }
+        }
+    }
+    Some(found)
+}
+
+/// Finds a local whose uses can all be replaced by the value it is assigned.
+fn find_copy(func: &Func) -> Option<(Local, Operand)> {
+    let reads = count_reads(func);
+    let writes = count_writes(func);
+    let preds = count_predecessors(func);
+    let cfg = Cfg::new(func);
+
+    for (i, block) in func.blocks.iter().enumerate() {
+        for (j, Stmt::Assign(place, rvalue)) in block.stmts.iter().enumerate() {
+            let local = place.local;
+            let is_arg = local.0 >= 1 && local.0 <= func.args;
+            if local == Local::RETURN || is_arg || !place.fields.is_empty() || writes[local.0] != 1 || reads[local.0] == 0 {
+                continue;
+            }
+            let value = match rvalue {
+                Rvalue::Use(value) => value,
+                _ => continue,
+            };
+            if let Operand::Copy(source) = value {
+                // in a loop, the source could be assigned again before the
+                // copy is used
+                if source.local == local || cfg.has_cycles || writes[source.local.0] > 1 {
+                    continue;
+                }
+                match def_position(func, &preds, source.local) {
+                    Some(None) => (),
+                    Some(Some(def)) if cfg.available(def, (BlockId(i), j)) => (),
+                    _ => continue,
+                }
+            }
+            let def = (BlockId(i), j + 1);
+            if uses_available(func, &cfg, local, def) {
+                return Some((local, value.clone()));
+            }
+        }
+    }
+    None
+}
+
+/// Whether every use of `local` comes after `def`.
+fn uses_available(func: &Func, cfg: &Cfg, local: Local, def: (BlockId, usize)) -> bool {
+    for (i, block) in func.blocks.iter().enumerate() {
+        let mut operands: Vec<(usize, &Operand)> = Vec::new();
+        let mut places: Vec<(usize, &Place)> = Vec::new();
+        for (j, Stmt::Assign(_, rvalue)) in block.stmts.iter().enumerate() {
+            operands.extend(rvalue.operands().into_iter().map(|operand| (j, operand)));
+            places.extend(rvalue.place().map(|place| (j, place)));
+        }
+        let end = block.stmts.len();
+        operands.extend(block.terminator.operands().into_iter().map(|operand| (end, operand)));
+        for (j, operand) in operands {
+            if let Operand::Copy(place) = operand {
+                places.push((j, place));
+            }
+        }
+        for (j, place) in places {
+            if place.local == local && !cfg.available(def, (BlockId(i), j)) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Replaces every use of `local` by `value` and returns how many there were.
+fn replace_uses(func: &mut Func, local: Local, value: &Operand) -> usize {
+    let mut changes = 0;
+    let replace_place = |place: &mut Place| {
+        if place.local != local {
+            return false;
+        }
+        if let Operand::Copy(source) = value {
+            let mut fields = source.fields.clone();
+            fields.append(&mut place.fields);
+            *place = Place {
+                local: source.local,
+                fields,
+            };
+        }
+        true
+    };
+    for block in &mut func.blocks {
+        let mut operands = Vec::new();
+        for Stmt::Assign(_, rvalue) in &mut block.stmts {
+            if let Some(place) = rvalue.place_mut() {
+                // only enums are read in place, and constants aren't enums
+                if replace_place(place) {
+                    changes += 1;
+                }
+            }
+            operands.extend(rvalue.operands_mut());
+        }
+        operands.extend(block.terminator.operands_mut());
+        for operand in operands {
+            let replaced = match operand {
+                Operand::Copy(place) if place.local == local => match value {
+                    Operand::Const(_) => true,
+                    Operand::Copy(_) => replace_place(place),
+                },
+                _ => false,
+            };
+            if replaced {
+                if let Operand::Const(_) = value {
+                    *operand = value.clone();
+                }
+                changes += 1;
+            }
+        }
+    }
+    changes
+}
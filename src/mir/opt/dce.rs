@@ -0,0 +1,96 @@
+//! Removes assignments to locals that are never read, like the temporaries of
+//! an expression statement without side effects, and then the locals that
+//! aren't used at all.
+
+use crate::adt::AdtTable;
+use crate::ast::BinOp;
+use crate::mir::{Constant, Func, Local, Operand, Place, Rvalue, Stmt, Terminator};
+
+use super::{count_reads, count_writes, Pass};
+
+pub struct DeadCode;
+
+impl Pass for DeadCode {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+
+    fn run(&self, _: &AdtTable, func: &mut Func) -> usize {
+        let mut changes = 0;
+        // removing an assignment can leave the ones it read from dead
+        loop {
+            let reads = count_reads(func);
+            let mut removed = 0;
+            for block in &mut func.blocks {
+                let before = block.stmts.len();
+                block
+                    .stmts
+                    .retain(|Stmt::Assign(place, rvalue)| reads[place.local.0] > 0 || !is_pure(rvalue));
+                removed += before - block.stmts.len();
+            }
+            if removed == 0 {
+                break;
+            }
+            changes += removed;
+        }
+        changes + remove_unused_locals(func)
+    }
+}
+
+/// Whether evaluating the rvalue can be skipped if its value isn't needed.
+fn is_pure(rvalue: &Rvalue) -> bool {
+    match rvalue {
+        // dividing by zero, or the smallest integer by -1, traps
+        Rvalue::BinOp(BinOp::Div, _, divisor) | Rvalue::BinOp(BinOp::Mod, _, divisor) => match divisor {
+            Operand::Const(Constant::Int(n)) => *n != 0 && *n != -1,
+            _ => false,
+        },
+        _ => true,
+    }
+}
+
+/// Removes the locals that are neither read nor written, other than the
+/// return value and the arguments, and numbers the rest again.
+fn remove_unused_locals(func: &mut Func) -> usize {
+    let reads = count_reads(func);
+    let writes = count_writes(func);
+    let mut renumbered = Vec::with_capacity(func.locals.len());
+    let mut locals = Vec::new();
+    for (i, decl) in func.locals.drain(..).enumerate() {
+        if i > func.args && reads[i] == 0 && writes[i] == 0 {
+            renumbered.push(None);
+        } else {
+            renumbered.push(Some(Local(locals.len())));
+            locals.push(decl);
+        }
+    }
+    let removed = renumbered.len() - locals.len();
+    func.locals = locals;
+    if removed == 0 {
+        return 0;
+    }
+
+    let renumber = |place: &mut Place| place.local = renumbered[place.local.0].expect("local is used");
+    for block in &mut func.blocks {
+        for Stmt::Assign(place, rvalue) in &mut block.stmts {
+            renumber(place);
+            if let Some(place) = rvalue.place_mut() {
+                renumber(place);
+            }
+            for operand in rvalue.operands_mut() {
+                if let Operand::Copy(place) = operand {
+                    renumber(place);
+                }
+            }
+        }
+        for operand in block.terminator.operands_mut() {
+            if let Operand::Copy(place) = operand {
+                renumber(place);
+            }
+        }
+        if let Terminator::Call { dest, .. } = &mut block.terminator {
+            *dest = renumbered[dest.0].expect("local is used");
+        }
+    }
+    removed
+}
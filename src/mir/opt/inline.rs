@@ -0,0 +1,179 @@
+//! Replaces calls to small functions by their bodies. Functions are inlined
+//! into their callers after they have been optimized themselves, so the
+//! callees of a function are handled before it and recursive functions are
+//! never inlined.
+
+use std::collections::HashMap;
+
+use crate::adt::AdtTable;
+use crate::ast::{InlineHint, Path};
+use crate::mir::{BlockId, Decl, Func, Local, Operand, Place, Program, Rvalue, Stmt, Terminator};
+use crate::typeck::components;
+
+use super::Pass;
+
+/// Functions up to this size are inlined without `@inline`.
+const THRESHOLD: usize = 16;
+
+/// Calls aren't inlined into a function that has grown to this size, unless
+/// the callee asks for it.
+const MAX_CALLER_SIZE: usize = 256;
+
+/// The number of statements and terminators of a function.
+pub fn size(func: &Func) -> usize {
+    func.blocks.iter().map(|block| block.stmts.len() + 1).sum()
+}
+
+/// The indices of the functions of the program in the order they should be
+/// optimized, callees first, and whether each of them is recursive.
+pub fn call_order(program: &Program) -> (Vec<usize>, Vec<bool>) {
+    let mut indices = HashMap::new();
+    for (i, decl) in program.0.iter().enumerate() {
+        if let Decl::Func(func) = decl {
+            indices.insert(&func.name, i);
+        }
+    }
+    let edges: Vec<Vec<usize>> = program
+        .0
+        .iter()
+        .map(|decl| match decl {
+            Decl::Func(func) => func
+                .blocks
+                .iter()
+                .filter_map(|block| match &block.terminator {
+                    Terminator::Call { func, .. } => indices.get(func).cloned(),
+                    _ => None,
+                })
+                .collect(),
+            Decl::Extern(_, _, _) => Vec::new(),
+        })
+        .collect();
+
+    let mut order = Vec::new();
+    let mut recursive = vec![false; program.0.len()];
+    for component in components(&edges) {
+        for &i in &component {
+            recursive[i] = component.len() > 1 || edges[i].contains(&i);
+            if let Decl::Func(_) = program.0[i] {
+                order.push(i);
+            }
+        }
+    }
+    (order, recursive)
+}
+
+#[derive(Default)]
+pub struct Inliner {
+    /// The optimized functions that calls can be replaced with.
+    callees: HashMap<Path, Func>,
+}
+
+impl Inliner {
+    pub fn new() -> Self {
+        Inliner::default()
+    }
+
+    /// Lets calls to the function be inlined from now on, unless it asks not
+    /// to be.
+    pub fn add(&mut self, func: Func) {
+        if func.inline != InlineHint::Never {
+            self.callees.insert(func.name.clone(), func);
+        }
+    }
+
+    /// The function a call should be replaced with, if any.
+    fn callee(&self, name: &Path, caller_size: usize) -> Option<&Func> {
+        let callee = self.callees.get(name)?;
+        match callee.inline {
+            InlineHint::Always => Some(callee),
+            InlineHint::Auto if size(callee) <= THRESHOLD && caller_size < MAX_CALLER_SIZE => Some(callee),
+            _ => None,
+        }
+    }
+}
+
+impl Pass for Inliner {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+
+    fn run(&self, _: &AdtTable, func: &mut Func) -> usize {
+        let mut changes = 0;
+        // the blocks that are added have been inlined into already
+        for i in 0..func.blocks.len() {
+            let callee = match &func.blocks[i].terminator {
+                Terminator::Call { func: name, .. } => match self.callee(name, size(func)) {
+                    Some(callee) => callee,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            inline_call(func, BlockId(i), callee);
+            changes += 1;
+        }
+        changes
+    }
+}
+
+/// Replaces the call that ends `block` by a copy of the callee's blocks.
+fn inline_call(func: &mut Func, block: BlockId, callee: &Func) {
+    let local_offset = func.locals.len();
+    let block_offset = func.blocks.len();
+    func.locals.extend(callee.locals.iter().cloned());
+    let rename = |local: Local| Local(local.0 + local_offset);
+    let rename_place = |place: &mut Place| place.local = rename(place.local);
+    let rename_operand = |operand: &mut Operand| {
+        if let Operand::Copy(place) = operand {
+            rename_place(place);
+        }
+    };
+
+    let block = &mut func.blocks[block.0];
+    let terminator = std::mem::replace(&mut block.terminator, Terminator::Goto(BlockId(block_offset)));
+    let (args, dest, target, unwind) = match terminator {
+        Terminator::Call {
+            args,
+            dest,
+            target,
+            unwind,
+            ..
+        } => (args, dest, target, unwind),
+        _ => unreachable!("only calls are inlined"),
+    };
+    for (arg, value) in callee.arg_locals().zip(args) {
+        block.stmts.push(Stmt::Assign(Place::local(rename(arg)), Rvalue::Use(value)));
+    }
+
+    for callee_block in &callee.blocks {
+        let mut block = callee_block.clone();
+        for Stmt::Assign(place, rvalue) in &mut block.stmts {
+            rename_place(place);
+            if let Some(place) = rvalue.place_mut() {
+                rename_place(place);
+            }
+            rvalue.operands_mut().into_iter().for_each(rename_operand);
+        }
+        block.terminator.operands_mut().into_iter().for_each(rename_operand);
+        for successor in block.terminator.successors_mut() {
+            successor.0 += block_offset;
+        }
+        match &mut block.terminator {
+            Terminator::Return => {
+                // every return point hands the value to the caller
+                let value = Operand::Copy(Place::local(rename(Local::RETURN)));
+                block.stmts.push(Stmt::Assign(Place::local(dest), Rvalue::Use(value)));
+                block.terminator = Terminator::Goto(target);
+            }
+            Terminator::Call {
+                dest, unwind: inner, ..
+            } => {
+                *dest = rename(*dest);
+                if inner.is_none() {
+                    *inner = unwind;
+                }
+            }
+            _ => (),
+        }
+        func.blocks.push(block);
+    }
+}
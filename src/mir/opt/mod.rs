@@ -0,0 +1,358 @@
+//! Optimizations of mir that need to know about the language, run before
+//! cranelift's own. Every pass rewrites one function at a time and keeps it
+//! valid.
+
+mod const_fold;
+mod copy_prop;
+mod dce;
+mod inline;
+mod simplify_cfg;
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::adt::AdtTable;
+use crate::timing::{Timer, Timings};
+
+use super::{BlockId, Func, Local, Operand, Place, Program, Stmt, Terminator};
+
+pub use self::const_fold::ConstFold;
+pub use self::copy_prop::CopyProp;
+pub use self::dce::DeadCode;
+pub use self::inline::Inliner;
+pub use self::simplify_cfg::SimplifyCfg;
+
+/// The passes `-O2` repeats until none of them changes anything, at most this
+/// many times.
+const MAX_ROUNDS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum OptLevel {
+    /// No optimizations.
+    O0,
+    /// Folds constants and removes dead code.
+    O1,
+    /// Also inlines small functions and propagates copies, repeating every
+    /// pass while they find something to do.
+    O2,
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            _ => Err(format!("unknown optimization level `{}`, expected 0, 1 or 2", s)),
+        }
+    }
+}
+
+pub trait Pass {
+    fn name(&self) -> &'static str;
+
+    /// Rewrites the function and returns how many changes were made.
+    fn run(&self, adts: &AdtTable, func: &mut Func) -> usize;
+}
+
+#[derive(Debug)]
+pub struct PassStats {
+    pub name: &'static str,
+    /// The number of functions the pass was run on, counting repeats.
+    pub runs: usize,
+    pub changes: usize,
+}
+
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    /// Runs once on every function, before the other passes.
+    inliner: Option<Inliner>,
+    repeat: bool,
+    /// The statistics of the inliner, if there is one, and then of the
+    /// other passes.
+    stats: Vec<PassStats>,
+    /// The time every pass took, by its name.
+    timings: Timings,
+}
+
+impl PassManager {
+    pub fn new(level: OptLevel) -> Self {
+        let passes: Vec<Box<dyn Pass>> = match level {
+            OptLevel::O0 => Vec::new(),
+            OptLevel::O1 => vec![Box::new(ConstFold), Box::new(SimplifyCfg), Box::new(DeadCode)],
+            OptLevel::O2 => vec![
+                Box::new(CopyProp),
+                Box::new(ConstFold),
+                Box::new(SimplifyCfg),
+                Box::new(DeadCode),
+            ],
+        };
+        let inliner = if level >= OptLevel::O2 { Some(Inliner::new()) } else { None };
+        PassManager::with(passes, inliner, level >= OptLevel::O2)
+    }
+
+    /// Runs only the pass with the given name, once, to test it on its own.
+    pub fn only(name: &str) -> Option<Self> {
+        let pass: Box<dyn Pass> = match name {
+            "inline" => return Some(PassManager::with(Vec::new(), Some(Inliner::new()), false)),
+            "const-fold" => Box::new(ConstFold),
+            "copy-prop" => Box::new(CopyProp),
+            "simplify-cfg" => Box::new(SimplifyCfg),
+            "dce" => Box::new(DeadCode),
+            _ => return None,
+        };
+        Some(PassManager::with(vec![pass], None, false))
+    }
+
+    fn with(passes: Vec<Box<dyn Pass>>, inliner: Option<Inliner>, repeat: bool) -> Self {
+        let stats = inliner
+            .iter()
+            .map(|inliner| inliner.name())
+            .chain(passes.iter().map(|pass| pass.name()))
+            .map(|name| PassStats {
+                name,
+                runs: 0,
+                changes: 0,
+            })
+            .collect();
+        PassManager {
+            passes,
+            inliner,
+            repeat,
+            stats,
+            timings: Timings::default(),
+        }
+    }
+
+    pub fn run(&mut self, adts: &AdtTable, program: &mut Program) {
+        if self.inliner.is_none() {
+            for decl in &mut program.0 {
+                if let super::Decl::Func(func) = decl {
+                    self.run_func(adts, func);
+                }
+            }
+            return;
+        }
+
+        // callees are optimized first so that what gets inlined is small
+        let (order, recursive) = inline::call_order(program);
+        for i in order {
+            if let super::Decl::Func(func) = &mut program.0[i] {
+                self.run_func(adts, func);
+                if !recursive[i] {
+                    self.inliner.as_mut().unwrap().add(func.clone());
+                }
+            }
+        }
+    }
+
+    pub fn run_func(&mut self, adts: &AdtTable, func: &mut Func) {
+        let mut stats = self.stats.iter_mut();
+        if let Some(inliner) = &self.inliner {
+            let stats = stats.next().unwrap();
+            stats.runs += 1;
+            let timer = Timer::start();
+            stats.changes += inliner.run(adts, func);
+            self.timings.record(inliner.name(), timer);
+        }
+        let mut stats: Vec<&mut PassStats> = stats.collect();
+
+        let rounds = if self.repeat { MAX_ROUNDS } else { 1 };
+        for _ in 0..rounds {
+            let mut changes = 0;
+            for (pass, stats) in self.passes.iter().zip(&mut stats) {
+                let timer = Timer::start();
+                let changed = pass.run(adts, func);
+                self.timings.record(pass.name(), timer);
+                stats.runs += 1;
+                stats.changes += changed;
+                changes += changed;
+            }
+            if changes == 0 {
+                break;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> &[PassStats] {
+        &self.stats
+    }
+
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+}
+
+impl fmt::Display for PassManager {
+    /// Formats the statistics as a table.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<16} {:>8} {:>8}", "pass", "runs", "changes")?;
+        for stats in &self.stats {
+            writeln!(f, "{:<16} {:>8} {:>8}", stats.name, stats.runs, stats.changes)?;
+        }
+        Ok(())
+    }
+}
+
+/// How many times each local is read.
+fn count_reads(func: &Func) -> Vec<usize> {
+    let mut reads = vec![0; func.locals.len()];
+    let mut read = |place: &Place| reads[place.local.0] += 1;
+    for block in &func.blocks {
+        for Stmt::Assign(_, rvalue) in &block.stmts {
+            for operand in rvalue.operands() {
+                if let Operand::Copy(place) = operand {
+                    read(place);
+                }
+            }
+            if let Some(place) = rvalue.place() {
+                read(place);
+            }
+        }
+        for operand in block.terminator.operands() {
+            if let Operand::Copy(place) = operand {
+                read(place);
+            }
+        }
+    }
+    // the return value is read by returning
+    reads[Local::RETURN.0] += 1;
+    reads
+}
+
+/// How many times each local is assigned to, in part or as a whole.
+fn count_writes(func: &Func) -> Vec<usize> {
+    let mut writes = vec![0; func.locals.len()];
+    for block in &func.blocks {
+        for Stmt::Assign(place, _) in &block.stmts {
+            writes[place.local.0] += 1;
+        }
+        if let Terminator::Call { dest, .. } = &block.terminator {
+            writes[dest.0] += 1;
+        }
+    }
+    writes
+}
+
+/// The number of edges into each block.
+fn count_predecessors(func: &Func) -> Vec<usize> {
+    let mut preds = vec![0; func.blocks.len()];
+    for block in &func.blocks {
+        for target in block.terminator.successors() {
+            preds[target.0] += 1;
+        }
+    }
+    preds
+}
+
+/// The blocks reachable from the entry, in reverse postorder.
+fn reverse_postorder(func: &Func) -> Vec<BlockId> {
+    let mut visited = vec![false; func.blocks.len()];
+    let mut order = Vec::new();
+    // blocks with the index of the next successor to visit
+    let mut stack = vec![(BlockId::ENTRY, 0)];
+    visited[BlockId::ENTRY.0] = true;
+    while let Some((block, next)) = stack.pop() {
+        let successors = func.block(block).terminator.successors();
+        match successors.get(next) {
+            Some(&succ) => {
+                stack.push((block, next + 1));
+                if !visited[succ.0] {
+                    visited[succ.0] = true;
+                    stack.push((succ, 0));
+                }
+            }
+            None => order.push(block),
+        }
+    }
+    order.reverse();
+    order
+}
+
+/// The control flow graph of a function and its dominator tree.
+struct Cfg {
+    /// The immediate dominator of every reachable block. The entry is its
+    /// own.
+    idom: Vec<Option<BlockId>>,
+    has_cycles: bool,
+}
+
+impl Cfg {
+    /// Computes the dominators with the iterative algorithm of Cooper, Harvey
+    /// and Kennedy.
+    fn new(func: &Func) -> Self {
+        let order = reverse_postorder(func);
+        let mut index = vec![usize::MAX; func.blocks.len()];
+        for (i, block) in order.iter().enumerate() {
+            index[block.0] = i;
+        }
+        let mut preds = vec![Vec::new(); func.blocks.len()];
+        let mut has_cycles = false;
+        for &block in &order {
+            for succ in func.block(block).terminator.successors() {
+                preds[succ.0].push(block);
+                has_cycles |= index[succ.0] <= index[block.0];
+            }
+        }
+
+        let mut idom = vec![None; func.blocks.len()];
+        idom[BlockId::ENTRY.0] = Some(BlockId::ENTRY);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &order[1..] {
+                let mut new_idom: Option<BlockId> = None;
+                for &pred in &preds[block.0] {
+                    if idom[pred.0].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => pred,
+                        Some(mut a) => {
+                            let mut b = pred;
+                            while a != b {
+                                while index[a.0] > index[b.0] {
+                                    a = idom[a.0].unwrap();
+                                }
+                                while index[b.0] > index[a.0] {
+                                    b = idom[b.0].unwrap();
+                                }
+                            }
+                            a
+                        }
+                    });
+                }
+                if idom[block.0] != new_idom {
+                    idom[block.0] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        Cfg { idom, has_cycles }
+    }
+
+    fn dominates(&self, a: BlockId, mut b: BlockId) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom[b.0] {
+                Some(parent) if parent != b => b = parent,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Whether a value written at `def` has been written whenever `at` is
+    /// reached. Positions are a block and the index of a statement in it, the
+    /// terminator coming after the last one.
+    fn available(&self, def: (BlockId, usize), at: (BlockId, usize)) -> bool {
+        if def.0 == at.0 {
+            def.1 <= at.1
+        } else {
+            self.dominates(def.0, at.0)
+        }
+    }
+}
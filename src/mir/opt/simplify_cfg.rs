@@ -0,0 +1,111 @@
+//! Cleans up the control flow graph: jumps to blocks that only jump on are
+//! redirected, blocks are merged into their only predecessor, and blocks
+//! that can't be reached, like the code after a folded branch, are removed.
+
+use crate::adt::AdtTable;
+use crate::mir::{Block, BlockId, Func, Terminator};
+
+use super::{count_predecessors, reverse_postorder, Pass};
+
+pub struct SimplifyCfg;
+
+impl Pass for SimplifyCfg {
+    fn name(&self) -> &'static str {
+        "simplify-cfg"
+    }
+
+    fn run(&self, _: &AdtTable, func: &mut Func) -> usize {
+        skip_forwarders(func) + merge_blocks(func) + remove_unreachable(func)
+    }
+}
+
+/// Redirects jumps to empty blocks that only jump to another one.
+fn skip_forwarders(func: &mut Func) -> usize {
+    let forward: Vec<Option<BlockId>> = func
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| match block.terminator {
+            Terminator::Goto(target) if block.stmts.is_empty() && target.0 != i => Some(target),
+            _ => None,
+        })
+        .collect();
+    let resolve = |mut block: BlockId| {
+        // a cycle of forwarders is an infinite loop, which is kept as it is
+        let mut steps = 0;
+        while let Some(target) = forward[block.0] {
+            if steps == forward.len() {
+                break;
+            }
+            block = target;
+            steps += 1;
+        }
+        block
+    };
+
+    let mut changes = 0;
+    for block in &mut func.blocks {
+        for target in block.terminator.successors_mut() {
+            let resolved = resolve(*target);
+            if resolved != *target {
+                *target = resolved;
+                changes += 1;
+            }
+        }
+    }
+    changes
+}
+
+/// Appends blocks to the block that jumps to them if it is their only
+/// predecessor.
+fn merge_blocks(func: &mut Func) -> usize {
+    let preds = count_predecessors(func);
+    let mut changes = 0;
+    for i in 0..func.blocks.len() {
+        while let Terminator::Goto(target) = func.blocks[i].terminator {
+            if target.0 == i || target == BlockId::ENTRY || preds[target.0] != 1 {
+                break;
+            }
+            // the merged block is left unreachable
+            let merged = std::mem::replace(
+                &mut func.blocks[target.0],
+                Block {
+                    stmts: Vec::new(),
+                    terminator: Terminator::Unreachable,
+                },
+            );
+            let block = &mut func.blocks[i];
+            block.stmts.extend(merged.stmts);
+            block.terminator = merged.terminator;
+            changes += 1;
+        }
+    }
+    changes
+}
+
+/// Removes the blocks that can't be reached from the entry, keeping the rest
+/// in order.
+fn remove_unreachable(func: &mut Func) -> usize {
+    let mut reachable = vec![false; func.blocks.len()];
+    for block in reverse_postorder(func) {
+        reachable[block.0] = true;
+    }
+    let mut renumbered = Vec::with_capacity(func.blocks.len());
+    let mut blocks = Vec::new();
+    for (block, reachable) in func.blocks.drain(..).zip(&reachable) {
+        if *reachable {
+            renumbered.push(Some(BlockId(blocks.len())));
+            blocks.push(block);
+        } else {
+            renumbered.push(None);
+        }
+    }
+    let removed = renumbered.len() - blocks.len();
+    func.blocks = blocks;
+    for block in &mut func.blocks {
+        for target in block.terminator.successors_mut() {
+            *target = renumbered[target.0].expect("successors are reachable");
+        }
+    }
+    removed
+}
@@ -0,0 +1,644 @@
+//! Reads the textual form of mir written by `pretty::dump`, so that passes
+//! can be run on hand-written functions.
+
+use std::fmt;
+
+use symbol::Symbol;
+
+use crate::adt::AdtTable;
+use crate::ast::{self, BinOp, InlineHint, Path, UnOp};
+
+use super::pretty::KEYWORDS;
+use super::{Block, BlockId, Constant, Decl, Func, Local, LocalDecl, Operand, Place, Program, Rvalue, Stmt, Terminator, Type};
+
+/// Operators, longest first so that the lexer can take the first match.
+const PUNCTUATION: &[&str] = &[
+    "->", "=>", "==", "!=", "<=", ">=", "<<", ">>", "&&", "||", "(", ")", "{", "}", "[", "]", ",", ":", ";", ".", "=", "!",
+    "~", "|", "^", "&", "<", ">", "+", "-", "*", "/", "%", "@",
+];
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.message)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    /// A name in double quotes.
+    Quoted(String),
+    Int(i64),
+    Punct(&'static str),
+    Eof,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "`{}`", name),
+            Token::Quoted(name) => write!(f, "`\"{}\"`", name),
+            Token::Int(n) => write!(f, "`{}`", n),
+            Token::Punct(punct) => write!(f, "`{}`", punct),
+            Token::Eof => write!(f, "end of file"),
+        }
+    }
+}
+
+/// Parses a program and the types it uses.
+pub fn parse(src: &str) -> Result<(AdtTable, Program), ParseError> {
+    let tokens = lex(src)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        adts: AdtTable::new(),
+        type_names: Vec::new(),
+    };
+    let program = parser.program()?;
+    for (name, line, col) in &parser.type_names {
+        if !parser.adts.is_type(*name) {
+            let message = format!("type `{}` isn't declared", name);
+            return Err(ParseError { line: *line, col: *col, message });
+        }
+    }
+    parser.adts.check_structs().map_err(|err| ParseError {
+        line: 1,
+        col: 1,
+        message: err.to_string(),
+    })?;
+    Ok((parser.adts, program))
+}
+
+fn lex(src: &str) -> Result<Vec<(Token, usize, usize)>, ParseError> {
+    let mut tokens = Vec::new();
+    for (i, line) in src.lines().enumerate() {
+        let line = match line.find("//") {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut rest = line;
+        while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+            rest = &rest[start..];
+            let col = line.len() - rest.len() + 1;
+            let error = |message: String| ParseError { line: i + 1, col, message };
+            let first = rest.chars().next().unwrap();
+            let (token, len) = if first.is_ascii_alphabetic() || first == '_' {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '#'))
+                    .unwrap_or(rest.len());
+                (Token::Ident(rest[..len].to_string()), len)
+            } else if first.is_ascii_digit() {
+                let len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                let n = rest[..len]
+                    .parse()
+                    .map_err(|_| error(format!("integer `{}` is too large", &rest[..len])))?;
+                (Token::Int(n), len)
+            } else if first == '"' {
+                let end = rest[1..]
+                    .find('"')
+                    .ok_or_else(|| error("unterminated quoted name".to_string()))?;
+                (Token::Quoted(rest[1..=end].to_string()), end + 2)
+            } else {
+                let punct = PUNCTUATION
+                    .iter()
+                    .find(|punct| rest.starts_with(*punct))
+                    .ok_or_else(|| error(format!("unexpected character `{}`", first)))?;
+                (Token::Punct(punct), punct.len())
+            };
+            tokens.push((token, i + 1, col));
+            rest = &rest[len..];
+        }
+    }
+    let line = src.lines().count().max(1);
+    tokens.push((Token::Eof, line, 1));
+    Ok(tokens)
+}
+
+/// A local or block written as a prefix followed by its index, like `_1` or
+/// `bb1`.
+fn numbered(name: &str, prefix: &str) -> Option<usize> {
+    if name.len() > prefix.len() && name.starts_with(prefix) && name[prefix.len()..].bytes().all(|b| b.is_ascii_digit()) {
+        name[prefix.len()..].parse().ok()
+    } else {
+        None
+    }
+}
+
+fn ast_type(ty: &Type) -> ast::Type {
+    match ty {
+        Type::Func(args, returns) => ast::Type::Func(args.iter().map(ast_type).collect(), Box::new(ast_type(returns))),
+        Type::Name(name) => ast::Type::Name(*name),
+        Type::Unit => ast::Type::Unit,
+        Type::Int => ast::Type::Int,
+        Type::Bool => ast::Type::Bool,
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize, usize)>,
+    pos: usize,
+    adts: AdtTable,
+    /// Every type name used, with where, to check once all types are known.
+    type_names: Vec<(Symbol, usize, usize)>,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        if self.pos + 1 < self.tokens.len() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn error_at(&self, pos: usize, message: String) -> ParseError {
+        let (_, line, col) = self.tokens[pos];
+        ParseError { line, col, message }
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        self.error_at(self.pos, message)
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> Result<T, ParseError> {
+        Err(self.error(format!("expected {}, found {}", expected, self.peek())))
+    }
+
+    fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Token::Punct(p) if *p == punct)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Ident(name) if name == keyword)
+    }
+
+    fn eat_punct(&mut self, punct: &str) -> bool {
+        let found = self.is_punct(punct);
+        if found {
+            self.next();
+        }
+        found
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.next();
+        }
+        found
+    }
+
+    fn expect_punct(&mut self, punct: &str) -> Result<(), ParseError> {
+        if self.eat_punct(punct) {
+            Ok(())
+        } else {
+            self.unexpected(&format!("`{}`", punct))
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            self.unexpected(&format!("`{}`", keyword))
+        }
+    }
+
+    /// Parses items separated by commas up to the closing delimiter, which is
+    /// consumed.
+    fn list<T>(&mut self, close: &str, mut item: impl FnMut(&mut Self) -> Result<T, ParseError>) -> Result<Vec<T>, ParseError> {
+        let mut items = Vec::new();
+        while !self.eat_punct(close) {
+            if !items.is_empty() {
+                self.expect_punct(",")?;
+            }
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn int(&mut self) -> Result<i64, ParseError> {
+        let negative = self.eat_punct("-");
+        match self.peek() {
+            Token::Int(n) => {
+                let n = *n;
+                self.next();
+                Ok(if negative { -n } else { n })
+            }
+            _ => self.unexpected("an integer"),
+        }
+    }
+
+    fn name_part(&mut self) -> Result<Symbol, ParseError> {
+        match self.peek().clone() {
+            Token::Ident(name) if !KEYWORDS.contains(&name.as_str()) => {
+                self.next();
+                Ok(Symbol::from(name.as_str()))
+            }
+            Token::Quoted(name) => {
+                self.next();
+                Ok(Symbol::from(name.as_str()))
+            }
+            _ => self.unexpected("a name"),
+        }
+    }
+
+    fn path(&mut self) -> Result<Path, ParseError> {
+        let mut parts = vec![self.name_part()?];
+        while self.eat_punct(".") {
+            parts.push(self.name_part()?);
+        }
+        Ok(Path(parts))
+    }
+
+    /// A possibly qualified name, like the name of a type.
+    fn name(&mut self) -> Result<Symbol, ParseError> {
+        Ok(Symbol::from(self.path()?.to_string().as_str()))
+    }
+
+    fn local(&mut self) -> Result<Local, ParseError> {
+        if let Token::Ident(name) = self.peek() {
+            if let Some(index) = numbered(name, "_") {
+                self.next();
+                return Ok(Local(index));
+            }
+        }
+        self.unexpected("a local")
+    }
+
+    fn block_id(&mut self) -> Result<BlockId, ParseError> {
+        if let Token::Ident(name) = self.peek() {
+            if let Some(index) = numbered(name, "bb") {
+                self.next();
+                return Ok(BlockId(index));
+            }
+        }
+        self.unexpected("a block")
+    }
+
+    fn is_local(&self) -> bool {
+        matches!(self.peek(), Token::Ident(name) if numbered(name, "_").is_some())
+    }
+
+    fn ty(&mut self) -> Result<Type, ParseError> {
+        if self.eat_keyword("int") {
+            return Ok(Type::Int);
+        }
+        if self.eat_keyword("bool") {
+            return Ok(Type::Bool);
+        }
+        if self.eat_punct("(") {
+            let start = self.pos;
+            let args = self.list(")", Self::ty)?;
+            if self.eat_punct("->") {
+                let returns = self.ty()?;
+                return Ok(Type::Func(args, Box::new(returns)));
+            }
+            if !args.is_empty() {
+                return Err(self.error_at(start, "expected `()` or a function type".to_string()));
+            }
+            return Ok(Type::Unit);
+        }
+        let (_, line, col) = self.tokens[self.pos];
+        let name = self.name()?;
+        self.type_names.push((name, line, col));
+        Ok(Type::Name(name))
+    }
+
+    fn program(&mut self) -> Result<Program, ParseError> {
+        let mut decls = Vec::new();
+        loop {
+            let start = self.pos;
+            if self.eat_keyword("struct") {
+                let name = self.name()?;
+                self.expect_punct("{")?;
+                let fields = self.list("}", |p| {
+                    let field = p.name_part()?;
+                    p.expect_punct(":")?;
+                    Ok((field, ast_type(&p.ty()?)))
+                })?;
+                let struct_ = ast::Struct { name, fields };
+                self.adts
+                    .insert_struct(&struct_)
+                    .map_err(|err| self.error_at(start, err.to_string()))?;
+            } else if self.eat_keyword("enum") {
+                let name = self.name()?;
+                self.expect_punct("{")?;
+                let variants = self.list("}", |p| {
+                    let name = p.name()?;
+                    let fields = if p.eat_punct("(") {
+                        p.list(")", |p| Ok(ast_type(&p.ty()?)))?
+                    } else {
+                        Vec::new()
+                    };
+                    Ok(ast::Variant { name, fields })
+                })?;
+                let enum_ = ast::Enum { name, variants };
+                self.adts
+                    .insert_enum(&enum_)
+                    .map_err(|err| self.error_at(start, err.to_string()))?;
+            } else if self.eat_keyword("extern") {
+                self.expect_keyword("fn")?;
+                let name = self.path()?;
+                self.expect_punct("(")?;
+                let args = self.list(")", Self::ty)?;
+                self.expect_punct("->")?;
+                let returns = self.ty()?;
+                self.expect_punct(";")?;
+                decls.push(Decl::Extern(name, args, returns));
+            } else if self.is_keyword("pub") || self.is_keyword("fn") || self.is_punct("@") {
+                decls.push(Decl::Func(self.func()?));
+            } else if *self.peek() == Token::Eof {
+                return Ok(Program(decls));
+            } else {
+                return self.unexpected("a declaration");
+            }
+        }
+    }
+
+    fn func(&mut self) -> Result<Func, ParseError> {
+        let inline = if self.eat_punct("@") {
+            if self.eat_keyword("inline") {
+                InlineHint::Always
+            } else if self.eat_keyword("noinline") {
+                InlineHint::Never
+            } else {
+                return self.unexpected("`inline` or `noinline`");
+            }
+        } else {
+            InlineHint::Auto
+        };
+        let exported = self.eat_keyword("pub");
+        self.expect_keyword("fn")?;
+        let name = self.path()?;
+
+        self.expect_punct("(")?;
+        let mut next_local = 1;
+        let args = self.list(")", |p| {
+            let start = p.pos;
+            let local = p.local()?;
+            if local.0 != next_local {
+                return Err(p.error_at(start, format!("expected argument _{}", next_local)));
+            }
+            next_local += 1;
+            p.expect_punct(":")?;
+            p.ty()
+        })?;
+        self.expect_punct("->")?;
+        let returns = self.ty()?;
+        self.expect_punct("{")?;
+
+        let mut locals = vec![LocalDecl { name: None, ty: returns }];
+        locals.extend(args.into_iter().map(|ty| LocalDecl { name: None, ty }));
+        let num_args = locals.len() - 1;
+        loop {
+            let start = self.pos;
+            if self.eat_keyword("let") {
+                let local = self.local()?;
+                if local.0 != locals.len() {
+                    return Err(self.error_at(start + 1, format!("expected local _{}", locals.len())));
+                }
+                self.expect_punct(":")?;
+                let ty = self.ty()?;
+                self.expect_punct(";")?;
+                locals.push(LocalDecl { name: None, ty });
+            } else if self.eat_keyword("debug") {
+                let name = self.name_part()?;
+                self.expect_punct("=>")?;
+                let local_pos = self.pos;
+                let local = self.local()?;
+                self.expect_punct(";")?;
+                match locals.get_mut(local.0) {
+                    Some(decl) => decl.name = Some(name),
+                    None => return Err(self.error_at(local_pos, format!("{} isn't declared", local))),
+                }
+            } else {
+                break;
+            }
+        }
+
+        let mut blocks = Vec::new();
+        while !self.eat_punct("}") {
+            let start = self.pos;
+            let id = self.block_id()?;
+            if id.0 != blocks.len() {
+                return Err(self.error_at(start, format!("expected block bb{}", blocks.len())));
+            }
+            self.expect_punct(":")?;
+            self.expect_punct("{")?;
+            blocks.push(self.block()?);
+        }
+
+        Ok(Func {
+            name,
+            exported,
+            inline,
+            args: num_args,
+            locals,
+            blocks,
+        })
+    }
+
+    fn block(&mut self) -> Result<Block, ParseError> {
+        let mut stmts = Vec::new();
+        loop {
+            let terminator = if self.eat_keyword("goto") {
+                self.expect_punct("->")?;
+                Terminator::Goto(self.block_id()?)
+            } else if self.eat_keyword("branch") {
+                self.expect_punct("(")?;
+                let cond = self.operand()?;
+                self.expect_punct(")")?;
+                self.expect_punct("->")?;
+                self.expect_punct("[")?;
+                self.expect_keyword("true")?;
+                self.expect_punct(":")?;
+                let then = self.block_id()?;
+                self.expect_punct(",")?;
+                self.expect_keyword("false")?;
+                self.expect_punct(":")?;
+                let else_ = self.block_id()?;
+                self.expect_punct("]")?;
+                Terminator::Branch(cond, then, else_)
+            } else if self.eat_keyword("switch") {
+                self.expect_punct("(")?;
+                let value = self.operand()?;
+                self.expect_punct(")")?;
+                self.expect_punct("->")?;
+                self.expect_punct("[")?;
+                let mut cases = Vec::new();
+                while !self.eat_keyword("otherwise") {
+                    let index = self.int()?;
+                    self.expect_punct(":")?;
+                    cases.push((index, self.block_id()?));
+                    self.expect_punct(",")?;
+                }
+                self.expect_punct(":")?;
+                let otherwise = self.block_id()?;
+                self.expect_punct("]")?;
+                Terminator::Switch(value, cases, otherwise)
+            } else if self.eat_keyword("return") {
+                Terminator::Return
+            } else if self.eat_keyword("unreachable") {
+                Terminator::Unreachable
+            } else {
+                let place = self.place()?;
+                self.expect_punct("=")?;
+                if !self.eat_keyword("call") {
+                    let rvalue = self.rvalue()?;
+                    self.expect_punct(";")?;
+                    stmts.push(Stmt::Assign(place, rvalue));
+                    continue;
+                }
+                if !place.fields.is_empty() {
+                    return Err(self.error("the result of a call has to be stored in a local".to_string()));
+                }
+                let func = self.path()?;
+                self.expect_punct("(")?;
+                let args = self.list(")", Self::operand)?;
+                self.expect_punct("->")?;
+                let (target, unwind) = if self.eat_punct("[") {
+                    self.expect_keyword("return")?;
+                    self.expect_punct(":")?;
+                    let target = self.block_id()?;
+                    self.expect_punct(",")?;
+                    self.expect_keyword("unwind")?;
+                    self.expect_punct(":")?;
+                    let unwind = self.block_id()?;
+                    self.expect_punct("]")?;
+                    (target, Some(unwind))
+                } else {
+                    (self.block_id()?, None)
+                };
+                Terminator::Call {
+                    func,
+                    args,
+                    dest: place.local,
+                    target,
+                    unwind,
+                }
+            };
+            self.expect_punct(";")?;
+            self.expect_punct("}")?;
+            return Ok(Block { stmts, terminator });
+        }
+    }
+
+    fn place(&mut self) -> Result<Place, ParseError> {
+        let mut place = Place::local(self.local()?);
+        while self.eat_punct(".") {
+            place = place.field(self.name_part()?);
+        }
+        Ok(place)
+    }
+
+    fn operand(&mut self) -> Result<Operand, ParseError> {
+        if self.is_local() {
+            return Ok(Operand::Copy(self.place()?));
+        }
+        if !self.eat_keyword("const") {
+            return self.unexpected("a local or a constant");
+        }
+        let constant = if self.eat_keyword("true") {
+            Constant::Bool(true)
+        } else if self.eat_keyword("false") {
+            Constant::Bool(false)
+        } else if self.eat_punct("(") {
+            self.expect_punct(")")?;
+            Constant::Unit
+        } else {
+            Constant::Int(self.int()?)
+        };
+        Ok(Operand::Const(constant))
+    }
+
+    fn binop(&mut self) -> Option<BinOp> {
+        let punct = match self.peek() {
+            Token::Punct(punct) => *punct,
+            _ => return None,
+        };
+        let op = [
+            BinOp::LogicalOr,
+            BinOp::LogicalAnd,
+            BinOp::BitwiseOr,
+            BinOp::BitwiseXor,
+            BinOp::BitwiseAnd,
+            BinOp::Equals,
+            BinOp::NotEquals,
+            BinOp::LessThan,
+            BinOp::LessThanEquals,
+            BinOp::GreaterThan,
+            BinOp::GreaterThanEquals,
+            BinOp::LeftShift,
+            BinOp::RightShift,
+            BinOp::Add,
+            BinOp::Sub,
+            BinOp::Mul,
+            BinOp::Div,
+            BinOp::Mod,
+        ]
+        .iter()
+        .copied()
+        .find(|op| op.symbol() == punct)?;
+        self.next();
+        Some(op)
+    }
+
+    fn rvalue(&mut self) -> Result<Rvalue, ParseError> {
+        if self.eat_punct("!") {
+            return Ok(Rvalue::UnOp(UnOp::LogicalNot, self.operand()?));
+        }
+        if self.eat_punct("~") {
+            return Ok(Rvalue::UnOp(UnOp::BitwiseNot, self.operand()?));
+        }
+        if self.eat_keyword("discriminant") {
+            self.expect_punct("(")?;
+            let place = self.place()?;
+            self.expect_punct(")")?;
+            return Ok(Rvalue::Discriminant(place));
+        }
+        if self.eat_punct("(") {
+            let place = self.place()?;
+            self.expect_keyword("as")?;
+            let ctor = self.name()?;
+            self.expect_punct(")")?;
+            self.expect_punct(".")?;
+            let index = self.int()?;
+            return Ok(Rvalue::Variant(place, ctor, index as usize));
+        }
+        if self.is_local() || self.is_keyword("const") {
+            let left = self.operand()?;
+            return Ok(match self.binop() {
+                Some(op) => Rvalue::BinOp(op, left, self.operand()?),
+                None => Rvalue::Use(left),
+            });
+        }
+
+        let name = self.name()?;
+        if self.eat_punct("{") {
+            let fields = self.list("}", |p| {
+                let field = p.name_part()?;
+                p.expect_punct(":")?;
+                Ok((field, p.operand()?))
+            })?;
+            return Ok(Rvalue::Struct(name, fields));
+        }
+        let args = if self.eat_punct("(") {
+            self.list(")", Self::operand)?
+        } else {
+            Vec::new()
+        };
+        Ok(Rvalue::Ctor(name, args))
+    }
+}
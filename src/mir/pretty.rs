@@ -0,0 +1,303 @@
+//! The textual form of mir, which `parse` reads back. A dump starts with the
+//! user-defined types so that it can be compiled on its own:
+//!
+//! ```text
+//! struct Point { x: int, y: int }
+//! enum List { Nil, Cons(int, List) }
+//!
+//! extern fn print(int) -> ();
+//!
+//! pub fn sum(_1: List) -> int {
+//!     debug l => _1;
+//!     let _2: int;
+//!     let _3: int;
+//!     debug x => _3;
+//!
+//!     bb0: {
+//!         _2 = discriminant(_1);
+//!         switch(_2) -> [1: bb1, otherwise: bb2];
+//!     }
+//!     ...
+//! }
+//! ```
+//!
+//! Functions without `pub` are local to the object file, and `@inline` and
+//! `@noinline` come before `pub` like in the source. Names that aren't
+//! plain identifiers, like those of specialized functions, are quoted.
+
+use std::fmt::{self, Write};
+
+use crate::adt::AdtTable;
+use crate::ast::{InlineHint, UnOp};
+use crate::lower::lower_type;
+
+use super::{Block, Constant, Decl, Func, Operand, Place, Program, Rvalue, Stmt, Terminator};
+
+/// Words of the format that can't be used as unquoted names.
+pub(super) const KEYWORDS: &[&str] = &[
+    "as",
+    "bool",
+    "branch",
+    "call",
+    "const",
+    "debug",
+    "discriminant",
+    "enum",
+    "extern",
+    "false",
+    "fn",
+    "goto",
+    "int",
+    "let",
+    "otherwise",
+    "pub",
+    "return",
+    "struct",
+    "switch",
+    "true",
+    "unreachable",
+    "unwind",
+];
+
+/// Writes the types of `adts` and the program, in the format `parse` reads.
+pub fn dump(adts: &AdtTable, program: &Program) -> String {
+    let mut out = String::new();
+    for info in adts.structs() {
+        write!(out, "struct ").unwrap();
+        write_name(&mut out, info.name.as_str()).unwrap();
+        write!(out, " {{").unwrap();
+        for (i, (field, ty)) in info.fields.iter().enumerate() {
+            write!(out, "{}", if i == 0 { " " } else { ", " }).unwrap();
+            write_name(&mut out, field.as_str()).unwrap();
+            write!(out, ": {}", lower_type(ty).expect("fields are resolved")).unwrap();
+        }
+        writeln!(out, " }}").unwrap();
+    }
+    for info in adts.enums() {
+        write!(out, "enum ").unwrap();
+        write_name(&mut out, info.name.as_str()).unwrap();
+        write!(out, " {{").unwrap();
+        for (i, variant) in info.variants.iter().enumerate() {
+            write!(out, "{}", if i == 0 { " " } else { ", " }).unwrap();
+            write_name(&mut out, variant.as_str()).unwrap();
+            let fields = &adts.get_ctor(*variant).expect("not a constructor").fields;
+            if !fields.is_empty() {
+                let fields = fields.iter().map(|ty| lower_type(ty).expect("fields are resolved"));
+                write!(out, "(").unwrap();
+                write_list(&mut out, fields).unwrap();
+                write!(out, ")").unwrap();
+            }
+        }
+        writeln!(out, " }}").unwrap();
+    }
+    for decl in &program.0 {
+        if !out.is_empty() {
+            writeln!(out).unwrap();
+        }
+        write!(out, "{}", decl).unwrap();
+    }
+    out
+}
+
+/// Writes a possibly qualified name, quoting the parts that need it.
+fn write_name(f: &mut impl Write, name: &str) -> fmt::Result {
+    for (i, part) in name.split('.').enumerate() {
+        if i > 0 {
+            write!(f, ".")?;
+        }
+        if is_plain(part) {
+            write!(f, "{}", part)?;
+        } else {
+            write!(f, "\"{}\"", part)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a name can be written without quotes: an identifier that can't be
+/// mistaken for a keyword, a local or a block.
+fn is_plain(name: &str) -> bool {
+    let mut chars = name.chars();
+    let starts_ident = match chars.next() {
+        Some(c) => c.is_ascii_alphabetic() || c == '_',
+        None => false,
+    };
+    let numbered = |prefix: &str| {
+        name.starts_with(prefix) && name.len() > prefix.len() && name[prefix.len()..].bytes().all(|b| b.is_ascii_digit())
+    };
+    starts_ident
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '#')
+        && !KEYWORDS.contains(&name)
+        && !numbered("_")
+        && !numbered("bb")
+}
+
+fn write_list<T: fmt::Display>(f: &mut impl Write, items: impl IntoIterator<Item = T>) -> fmt::Result {
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    Ok(())
+}
+
+/// A path or name, quoted where needed.
+struct Name<'a>(&'a str);
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_name(f, self.0)
+    }
+}
+
+impl fmt::Display for Decl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Decl::Extern(name, args, returns) => {
+                write!(f, "extern fn {}(", Name(&name.to_string()))?;
+                write_list(f, args)?;
+                writeln!(f, ") -> {};", returns)
+            }
+            Decl::Func(func) => write!(f, "{}", func),
+        }
+    }
+}
+
+impl fmt::Display for Func {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.inline {
+            InlineHint::Auto => (),
+            InlineHint::Always => write!(f, "@inline ")?,
+            InlineHint::Never => write!(f, "@noinline ")?,
+        }
+        if self.exported {
+            write!(f, "pub ")?;
+        }
+        write!(f, "fn {}(", Name(&self.name.to_string()))?;
+        let args = self
+            .arg_locals()
+            .map(|local| format!("{}: {}", local, self.local_ty(local)));
+        write_list(f, args)?;
+        writeln!(f, ") -> {} {{", self.returns())?;
+        for (i, decl) in self.locals.iter().enumerate() {
+            if i > self.args {
+                writeln!(f, "    let _{}: {};", i, decl.ty)?;
+            }
+            if let Some(name) = decl.name {
+                writeln!(f, "    debug {} => _{};", Name(name.as_str()), i)?;
+            }
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            writeln!(f)?;
+            writeln!(f, "    bb{}: {{", i)?;
+            write!(f, "{}", block)?;
+            writeln!(f, "    }}")?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for stmt in &self.stmts {
+            match stmt {
+                Stmt::Assign(place, rvalue) => writeln!(f, "        {} = {};", place, rvalue)?,
+            }
+        }
+        writeln!(f, "        {};", self.terminator)
+    }
+}
+
+impl fmt::Display for Place {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.local)?;
+        for field in &self.fields {
+            write!(f, ".{}", Name(field.as_str()))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Constant::Int(n) => write!(f, "{}", n),
+            Constant::Bool(b) => write!(f, "{}", b),
+            Constant::Unit => write!(f, "()"),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Copy(place) => write!(f, "{}", place),
+            Operand::Const(constant) => write!(f, "const {}", constant),
+        }
+    }
+}
+
+impl fmt::Display for Rvalue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rvalue::Use(operand) => write!(f, "{}", operand),
+            Rvalue::BinOp(op, left, right) => write!(f, "{} {} {}", left, op.symbol(), right),
+            Rvalue::UnOp(UnOp::LogicalNot, operand) => write!(f, "!{}", operand),
+            Rvalue::UnOp(UnOp::BitwiseNot, operand) => write!(f, "~{}", operand),
+            Rvalue::Struct(name, fields) => {
+                write!(f, "{} {{", Name(name.as_str()))?;
+                for (i, (field, operand)) in fields.iter().enumerate() {
+                    write!(f, "{}", if i == 0 { " " } else { ", " })?;
+                    write!(f, "{}: {}", Name(field.as_str()), operand)?;
+                }
+                write!(f, " }}")
+            }
+            Rvalue::Ctor(name, args) => {
+                write!(f, "{}", Name(name.as_str()))?;
+                if !args.is_empty() {
+                    write!(f, "(")?;
+                    write_list(f, args)?;
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+            Rvalue::Discriminant(place) => write!(f, "discriminant({})", place),
+            Rvalue::Variant(place, ctor, i) => write!(f, "({} as {}).{}", place, Name(ctor.as_str()), i),
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Terminator::Goto(target) => write!(f, "goto -> {}", target),
+            Terminator::Branch(cond, then, else_) => {
+                write!(f, "branch({}) -> [true: {}, false: {}]", cond, then, else_)
+            }
+            Terminator::Switch(value, cases, otherwise) => {
+                write!(f, "switch({}) -> [", value)?;
+                for (index, target) in cases {
+                    write!(f, "{}: {}, ", index, target)?;
+                }
+                write!(f, "otherwise: {}]", otherwise)
+            }
+            Terminator::Call {
+                func,
+                args,
+                dest,
+                target,
+                unwind,
+            } => {
+                write!(f, "{} = call {}(", dest, Name(&func.to_string()))?;
+                write_list(f, args)?;
+                match unwind {
+                    Some(unwind) => write!(f, ") -> [return: {}, unwind: {}]", target, unwind),
+                    None => write!(f, ") -> {}", target),
+                }
+            }
+            Terminator::Return => write!(f, "return"),
+            Terminator::Unreachable => write!(f, "unreachable"),
+        }
+    }
+}
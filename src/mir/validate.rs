@@ -0,0 +1,279 @@
+//! Checks that mir is well-formed: every block, local, field and function that
+//! is referred to exists, and values are only used at their own type. Passes
+//! that transform mir are expected to keep it valid.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use symbol::Symbol;
+
+use crate::adt::AdtTable;
+use crate::ast::{BinOp, Path, UnOp};
+use crate::lower::lower_type;
+
+use super::{BlockId, Decl, Func, Local, Operand, Place, Program, Rvalue, Stmt, Terminator, Type};
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    NoBlocks,
+    MissingArgs(usize),
+    UnknownBlock(BlockId),
+    UnknownLocal(Local),
+    InvalidPlace(Place),
+    UnknownFunction(Path),
+    UnknownStruct(Type),
+    UnknownField(Type, Symbol),
+    DuplicateField(Symbol),
+    UnknownCtor(String),
+    NotAnEnum(Type),
+    Arity(String, usize, usize),
+    LogicalOp(BinOp),
+    Mismatch(Type, Type),
+}
+
+/// An error and where it is: the function, and the block unless the error is
+/// in the declarations of the function.
+#[derive(Debug)]
+pub struct ValidateError {
+    pub func: Path,
+    pub block: Option<BlockId>,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for ValidateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid mir in `{}`", self.func)?;
+        if let Some(block) = self.block {
+            write!(f, " at {}", block)?;
+        }
+        write!(f, ": ")?;
+        match &self.kind {
+            ErrorKind::NoBlocks => write!(f, "the function has no blocks"),
+            ErrorKind::MissingArgs(args) => write!(f, "there are fewer locals than the {} argument(s)", args),
+            ErrorKind::UnknownBlock(block) => write!(f, "{} doesn't exist", block),
+            ErrorKind::UnknownLocal(local) => write!(f, "{} doesn't exist", local),
+            ErrorKind::InvalidPlace(place) => {
+                write!(f, "{}", place.local)?;
+                for field in &place.fields {
+                    write!(f, ".{}", field)?;
+                }
+                write!(f, " isn't a field of a struct")
+            }
+            ErrorKind::UnknownFunction(func) => write!(f, "function `{}` doesn't exist", func),
+            ErrorKind::UnknownStruct(ty) => write!(f, "`{}` isn't a struct", ty),
+            ErrorKind::UnknownField(ty, field) => write!(f, "`{}` has no field `{}`", ty, field),
+            ErrorKind::DuplicateField(field) => write!(f, "field `{}` is given more than once", field),
+            ErrorKind::UnknownCtor(ctor) => write!(f, "`{}` isn't a constructor", ctor),
+            ErrorKind::NotAnEnum(ty) => write!(f, "`{}` isn't an enum", ty),
+            ErrorKind::Arity(what, expected, got) => {
+                write!(f, "`{}` takes {} value(s) but is given {}", what, expected, got)
+            }
+            ErrorKind::LogicalOp(op) => write!(f, "`{:?}` has to be lowered to branches", op),
+            ErrorKind::Mismatch(expected, got) => write!(f, "expected `{}`, found `{}`", expected, got),
+        }
+    }
+}
+
+pub fn validate(adts: &AdtTable, program: &Program) -> Result<(), ValidateError> {
+    let mut signatures = HashMap::new();
+    for decl in &program.0 {
+        match decl {
+            Decl::Extern(name, args, returns) => {
+                signatures.insert(name.clone(), (args.clone(), returns.clone()));
+            }
+            Decl::Func(func) => {
+                let args = func
+                    .arg_locals()
+                    .filter_map(|local| func.locals.get(local.0))
+                    .map(|decl| decl.ty.clone())
+                    .collect();
+                signatures.insert(func.name.clone(), (args, func.returns().clone()));
+            }
+        }
+    }
+    for decl in &program.0 {
+        if let Decl::Func(func) = decl {
+            let validator = Validator {
+                adts,
+                signatures: &signatures,
+                func,
+                block: None,
+            };
+            validator.func()?;
+        }
+    }
+    Ok(())
+}
+
+struct Validator<'a> {
+    adts: &'a AdtTable,
+    signatures: &'a HashMap<Path, (Vec<Type>, Type)>,
+    func: &'a Func,
+    block: Option<BlockId>,
+}
+
+impl<'a> Validator<'a> {
+    fn error(&self, kind: ErrorKind) -> ValidateError {
+        ValidateError {
+            func: self.func.name.clone(),
+            block: self.block,
+            kind,
+        }
+    }
+
+    fn func(mut self) -> Result<(), ValidateError> {
+        if self.func.blocks.is_empty() {
+            return Err(self.error(ErrorKind::NoBlocks));
+        }
+        if self.func.locals.len() <= self.func.args {
+            return Err(self.error(ErrorKind::MissingArgs(self.func.args)));
+        }
+        for (i, block) in self.func.blocks.iter().enumerate() {
+            self.block = Some(BlockId(i));
+            for stmt in &block.stmts {
+                match stmt {
+                    Stmt::Assign(place, rvalue) => {
+                        let expected = self.place(place)?;
+                        let got = self.rvalue(rvalue)?;
+                        self.expect(&expected, &got)?;
+                    }
+                }
+            }
+            self.terminator(&block.terminator)?;
+        }
+        Ok(())
+    }
+
+    fn expect(&self, expected: &Type, got: &Type) -> Result<(), ValidateError> {
+        if expected != got {
+            return Err(self.error(ErrorKind::Mismatch(expected.clone(), got.clone())));
+        }
+        Ok(())
+    }
+
+    fn target(&self, block: BlockId) -> Result<(), ValidateError> {
+        if block.0 >= self.func.blocks.len() {
+            return Err(self.error(ErrorKind::UnknownBlock(block)));
+        }
+        Ok(())
+    }
+
+    fn place(&self, place: &Place) -> Result<Type, ValidateError> {
+        if place.local.0 >= self.func.locals.len() {
+            return Err(self.error(ErrorKind::UnknownLocal(place.local)));
+        }
+        self.func
+            .place_ty(self.adts, place)
+            .ok_or_else(|| self.error(ErrorKind::InvalidPlace(place.clone())))
+    }
+
+    fn operand(&self, operand: &Operand) -> Result<Type, ValidateError> {
+        match operand {
+            Operand::Copy(place) => self.place(place),
+            Operand::Const(constant) => Ok(constant.ty()),
+        }
+    }
+
+    fn rvalue(&self, rvalue: &Rvalue) -> Result<Type, ValidateError> {
+        match rvalue {
+            Rvalue::Use(operand) => self.operand(operand),
+            Rvalue::BinOp(op, left, right) => {
+                if let BinOp::LogicalAnd | BinOp::LogicalOr = op {
+                    return Err(self.error(ErrorKind::LogicalOp(*op)));
+                }
+                let left = self.operand(left)?;
+                let ty = match op {
+                    BinOp::Equals | BinOp::NotEquals if left == Type::Bool => Type::Bool,
+                    _ => Type::Int,
+                };
+                self.expect(&ty, &left)?;
+                self.expect(&ty, &self.operand(right)?)?;
+                Ok(super::binop_result(*op))
+            }
+            Rvalue::UnOp(op, operand) => {
+                let ty = match op {
+                    UnOp::LogicalNot => Type::Bool,
+                    UnOp::BitwiseNot => Type::Int,
+                };
+                self.expect(&ty, &self.operand(operand)?)?;
+                Ok(ty)
+            }
+            Rvalue::Struct(name, fields) => {
+                let ty = Type::Name(*name);
+                let info = match self.adts.get_struct(*name) {
+                    Some(info) => info,
+                    None => return Err(self.error(ErrorKind::UnknownStruct(ty))),
+                };
+                if fields.len() != info.fields.len() {
+                    return Err(self.error(ErrorKind::Arity(name.to_string(), info.fields.len(), fields.len())));
+                }
+                for (i, (field, operand)) in fields.iter().enumerate() {
+                    if fields[..i].iter().any(|(other, _)| other == field) {
+                        return Err(self.error(ErrorKind::DuplicateField(*field)));
+                    }
+                    let expected = match info.get_field(*field) {
+                        Some((_, expected)) => lower_type(expected).expect("fields are resolved"),
+                        None => return Err(self.error(ErrorKind::UnknownField(ty, *field))),
+                    };
+                    self.expect(&expected, &self.operand(operand)?)?;
+                }
+                Ok(ty)
+            }
+            Rvalue::Ctor(name, args) => {
+                let ctor = match self.adts.get_ctor(*name) {
+                    Some(ctor) => ctor,
+                    None => return Err(self.error(ErrorKind::UnknownCtor(name.to_string()))),
+                };
+                if args.len() != ctor.fields.len() {
+                    return Err(self.error(ErrorKind::Arity(name.to_string(), ctor.fields.len(), args.len())));
+                }
+                for (field, arg) in ctor.fields.iter().zip(args) {
+                    let field = lower_type(field).expect("fields are resolved");
+                    self.expect(&field, &self.operand(arg)?)?;
+                }
+                Ok(Type::Name(ctor.enum_name))
+            }
+            Rvalue::Discriminant(place) => {
+                let ty = self.place(place)?;
+                match ty {
+                    Type::Name(name) if self.adts.get_enum(name).is_some() => Ok(Type::Int),
+                    ty => Err(self.error(ErrorKind::NotAnEnum(ty))),
+                }
+            }
+            Rvalue::Variant(place, name, i) => {
+                let ty = self.place(place)?;
+                let ctor = match self.adts.get_ctor(*name) {
+                    Some(ctor) if *i < ctor.fields.len() => ctor,
+                    _ => return Err(self.error(ErrorKind::UnknownCtor(format!("{}.{}", name, i)))),
+                };
+                self.expect(&Type::Name(ctor.enum_name), &ty)?;
+                Ok(lower_type(&ctor.fields[*i]).expect("fields are resolved"))
+            }
+        }
+    }
+
+    fn terminator(&self, terminator: &Terminator) -> Result<(), ValidateError> {
+        for target in terminator.successors() {
+            self.target(target)?;
+        }
+        match terminator {
+            Terminator::Goto(_) | Terminator::Return | Terminator::Unreachable => Ok(()),
+            Terminator::Branch(cond, _, _) => self.expect(&Type::Bool, &self.operand(cond)?),
+            Terminator::Switch(value, _, _) => self.expect(&Type::Int, &self.operand(value)?),
+            Terminator::Call { func, args, dest, .. } => {
+                let (arg_types, returns) = match self.signatures.get(func) {
+                    Some(signature) => signature,
+                    None => return Err(self.error(ErrorKind::UnknownFunction(func.clone()))),
+                };
+                if args.len() != arg_types.len() {
+                    return Err(self.error(ErrorKind::Arity(func.to_string(), arg_types.len(), args.len())));
+                }
+                for (ty, arg) in arg_types.iter().zip(args) {
+                    self.expect(ty, &self.operand(arg)?)?;
+                }
+                let dest = self.place(&Place::local(*dest))?;
+                self.expect(&dest, returns)
+            }
+        }
+    }
+}
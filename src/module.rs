@@ -0,0 +1,573 @@
+//! Programs made of several files. `use geo.shapes.area` imports `area` from
+//! the module `geo.shapes`, which lives in `geo/shapes.mo` next to the root
+//! file. Every module is loaded, its names are qualified with the module path,
+//! and all of them are merged into a single program.
+//!
+//! Programs with a manifest look modules up in the source directories of their
+//! package instead, and `use geo.shapes.area` imports from the module `shapes`
+//! of the package `geo` when the package depends on one by that name.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path as FsPath, PathBuf};
+
+use symbol::Symbol;
+
+use crate::ast::{Decl, Expr, Func, Path, Pattern, Program, Span, Stmt, StmtKind, Type};
+use crate::env::Environment;
+use crate::parser::ProgramParser;
+use crate::scanner::{ScanError, Scanner};
+use crate::timing::{Timer, Timings};
+
+#[derive(Debug)]
+pub struct Module {
+    /// The path other modules import this one by; empty for the root module.
+    pub path: Vec<Symbol>,
+    pub file: PathBuf,
+    pub program: Program,
+}
+
+impl Module {
+    fn name(&self) -> String {
+        if self.path.is_empty() {
+            self.file.display().to_string()
+        } else {
+            Path(self.path.clone()).to_string()
+        }
+    }
+
+    /// The name an item declared in this module is known by in the merged
+    /// program. Items of the root module keep their names.
+    fn qualify(&self, name: Symbol) -> Symbol {
+        if self.path.is_empty() {
+            name
+        } else {
+            Symbol::from(format!("{}.{}", Path(self.path.clone()), name).as_str())
+        }
+    }
+}
+
+/// Where the modules of a package are.
+#[derive(Clone, Debug)]
+pub struct SourcePackage {
+    /// What the paths of its modules start with; empty for the package of the
+    /// root module.
+    pub name: Vec<Symbol>,
+    /// The directories its modules are looked up in, in order.
+    pub dirs: Vec<PathBuf>,
+    /// The packages it depends on, by their names, with their index.
+    pub dependencies: Vec<(Symbol, usize)>,
+}
+
+impl SourcePackage {
+    /// The package of a program without a manifest, whose modules are next to
+    /// its root file.
+    pub fn next_to(root_file: &FsPath) -> Vec<SourcePackage> {
+        let root_dir = root_file.parent().unwrap_or_else(|| FsPath::new(""));
+        vec![SourcePackage {
+            name: Vec::new(),
+            dirs: vec![root_dir.to_owned()],
+            dependencies: Vec::new(),
+        }]
+    }
+}
+
+/// Every module of a program, ordered so that a module comes after the modules
+/// it imports. The root module is last.
+#[derive(Debug)]
+pub struct ModuleTree {
+    pub modules: Vec<Module>,
+}
+
+#[derive(Debug)]
+pub enum ModuleError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, ScanError),
+    NotFound(Path, PathBuf, Span),
+    Cycle(Vec<String>),
+    InvalidUse(Path, Span),
+    UnknownItem(Path, Span),
+    PrivateItem(Path, Span),
+    Conflict(String, Symbol),
+    /// Two imports of a module bringing in different items by the same name,
+    /// with the items and the second import.
+    ImportConflict(String, Symbol, Symbol, Symbol, Span),
+}
+
+impl ModuleError {
+    /// The part of a module the error is about, if it's about one.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            ModuleError::Parse(file, err) => {
+                let file = Symbol::from(file.display().to_string().as_str());
+                err.span().map(|(lo, hi)| Span { file, lo, hi })
+            }
+            ModuleError::NotFound(_, _, span)
+            | ModuleError::InvalidUse(_, span)
+            | ModuleError::UnknownItem(_, span)
+            | ModuleError::PrivateItem(_, span)
+            | ModuleError::ImportConflict(_, _, _, _, span) => Some(*span),
+            ModuleError::Io(_, _) | ModuleError::Cycle(_) | ModuleError::Conflict(_, _) => None,
+        }
+    }
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModuleError::Io(file, err) => write!(f, "{}: {}", file.display(), err),
+            ModuleError::Parse(file, err) => write!(f, "{}: {}", file.display(), err),
+            ModuleError::NotFound(path, file, _) => write!(
+                f,
+                "cannot find module `{}` (looked for {})",
+                path,
+                file.display()
+            ),
+            ModuleError::Cycle(modules) => {
+                write!(f, "modules import each other: {}", modules.join(" -> "))
+            }
+            ModuleError::InvalidUse(path, _) => write!(
+                f,
+                "`use {}` should name a module and an item in it",
+                path
+            ),
+            ModuleError::UnknownItem(path, _) => write!(f, "cannot find `{}`", path),
+            ModuleError::PrivateItem(path, _) => write!(f, "`{}` is private", path),
+            ModuleError::Conflict(module, name) => {
+                write!(f, "`{}` is defined more than once in {}", name, module)
+            }
+            ModuleError::ImportConflict(module, name, first, second, _) => write!(
+                f,
+                "`{}` is imported from both `{}` and `{}` in {}",
+                name, first, second, module
+            ),
+        }
+    }
+}
+
+pub fn parse_file(file: &FsPath, timings: &mut Timings) -> Result<Program, ModuleError> {
+    let source = fs::read_to_string(file).map_err(|err| ModuleError::Io(file.to_owned(), err))?;
+    parse_source(file, &source, timings)
+}
+
+/// Parses the source of a module, with `file` naming it in errors. It's
+/// scanned first, so that scanning and parsing are timed apart.
+pub fn parse_source(file: &FsPath, source: &str, timings: &mut Timings) -> Result<Program, ModuleError> {
+    let timer = Timer::start();
+    let tokens: Vec<_> = Scanner::new(source.as_bytes()).collect();
+    timings.record("scan", timer);
+    let timer = Timer::start();
+    let program = ProgramParser::new()
+        .parse(source, Symbol::from(file.display().to_string().as_str()), tokens.iter().cloned())
+        .map_err(|err| ModuleError::Parse(file.to_owned(), ScanError::from(err).at_line_end(&tokens)));
+    timings.record("parse", timer);
+    program
+}
+
+/// Loads the module in `root_file` and everything it imports, transitively.
+pub fn load(root_file: &FsPath, timings: &mut Timings) -> Result<ModuleTree, ModuleError> {
+    let program = parse_file(root_file, timings)?;
+    load_imports(root_file, program, timings)
+}
+
+/// Loads everything the root module imports, transitively. The modules are
+/// looked up next to `root_file`, which doesn't need to exist.
+pub fn load_imports(root_file: &FsPath, program: Program, timings: &mut Timings) -> Result<ModuleTree, ModuleError> {
+    load_package_imports(root_file, program, &SourcePackage::next_to(root_file), timings)
+}
+
+/// Loads everything the root module imports, transitively, looking modules up
+/// in `packages`. The root module belongs to the last package.
+pub fn load_package_imports(
+    root_file: &FsPath,
+    program: Program,
+    packages: &[SourcePackage],
+    timings: &mut Timings,
+) -> Result<ModuleTree, ModuleError> {
+    let mut loader = Loader {
+        packages,
+        modules: Vec::new(),
+        loaded: HashSet::new(),
+        stack: Vec::new(),
+        timings,
+    };
+    loader.load(Vec::new(), packages.len() - 1, root_file.to_owned(), program)?;
+    Ok(ModuleTree {
+        modules: loader.modules,
+    })
+}
+
+struct Loader<'a> {
+    packages: &'a [SourcePackage],
+    modules: Vec<Module>,
+    loaded: HashSet<Vec<Symbol>>,
+    /// The modules being loaded, to detect cycles.
+    stack: Vec<Vec<Symbol>>,
+    timings: &'a mut Timings,
+}
+
+impl<'a> Loader<'a> {
+    /// Loads the module at `path`, which is in the package `package`, and the
+    /// modules it imports.
+    fn load(&mut self, path: Vec<Symbol>, package: usize, file: PathBuf, mut program: Program) -> Result<(), ModuleError> {
+        self.stack.push(path.clone());
+        for decl in &mut program.0 {
+            if let Decl::Use(use_path, span) = decl {
+                let span = *span;
+                let (item, module) = match use_path.0.split_last() {
+                    Some((item, module)) if !module.is_empty() => (*item, module.to_vec()),
+                    _ => return Err(ModuleError::InvalidUse(Path(use_path.0.clone()), span)),
+                };
+                // the first name is a package only if a module in it follows
+                let dependency = self.packages[package]
+                    .dependencies
+                    .iter()
+                    .find(|(name, _)| module.len() > 1 && *name == module[0]);
+                let (module_package, in_package) = match dependency {
+                    Some(&(_, dependency)) => (dependency, &module[1..]),
+                    None => (package, &module[..]),
+                };
+                let mut module: Vec<_> = self.packages[module_package].name.clone();
+                module.extend_from_slice(in_package);
+                use_path.0 = module.iter().cloned().chain(Some(item)).collect();
+
+                if let Some(start) = self.stack.iter().position(|other| *other == module) {
+                    let mut cycle: Vec<_> = self.stack[start..]
+                        .iter()
+                        .map(|path| Path(path.clone()).to_string())
+                        .collect();
+                    cycle.push(Path(module).to_string());
+                    return Err(ModuleError::Cycle(cycle));
+                }
+                if self.loaded.contains(&module) {
+                    continue;
+                }
+
+                let candidates: Vec<_> = self.packages[module_package]
+                    .dirs
+                    .iter()
+                    .map(|dir| {
+                        let mut module_file = dir.clone();
+                        for name in in_package {
+                            module_file.push(name.as_str());
+                        }
+                        module_file.set_extension("mo");
+                        module_file
+                    })
+                    .collect();
+                let module_file = match candidates.iter().find(|file| file.is_file()) {
+                    Some(module_file) => module_file.clone(),
+                    None => return Err(ModuleError::NotFound(Path(module), candidates[0].clone(), span)),
+                };
+                let program = parse_file(&module_file, self.timings)?;
+                self.load(module, module_package, module_file, program)?;
+            }
+        }
+        self.stack.pop();
+        self.loaded.insert(path.clone());
+        self.modules.push(Module { path, file, program });
+        Ok(())
+    }
+}
+
+impl ModuleTree {
+    /// Resolves the names of every module and merges them into one program.
+    pub fn link(self) -> Result<Program, ModuleError> {
+        let index: HashMap<_, _> = self
+            .modules
+            .iter()
+            .enumerate()
+            .map(|(i, module)| (module.path.clone(), i))
+            .collect();
+
+        // the items each module declares, along with the names that come with
+        // them: the constructors of an enum and the methods of a class
+        let mut declared = Vec::new();
+        let mut ctors = HashSet::new();
+        for module in &self.modules {
+            let mut items: HashMap<Symbol, Vec<(Symbol, Symbol)>> = HashMap::new();
+            for decl in &module.program.0 {
+                let name = match decl.name() {
+                    Some(name) => name,
+                    None => continue,
+                };
+                if items.contains_key(&name) {
+                    return Err(ModuleError::Conflict(module.name(), name));
+                }
+                let item = |name| {
+                    let qualified = match decl {
+                        // C symbols aren't namespaced
+                        Decl::Extern(_, _, _) => name,
+                        _ => module.qualify(name),
+                    };
+                    (name, qualified)
+                };
+                let mut names = vec![item(name)];
+                match decl {
+                    Decl::Enum(enum_) => {
+                        for variant in &enum_.variants {
+                            let ctor = item(variant.name);
+                            ctors.insert(ctor.1);
+                            names.push(ctor);
+                        }
+                    }
+                    Decl::Class(class) => names.extend(class.methods.iter().map(|(method, _, _)| item(*method))),
+                    _ => (),
+                }
+                items.insert(name, names);
+            }
+            declared.push(items);
+        }
+
+        let exported: Vec<HashSet<_>> = self
+            .modules
+            .iter()
+            .map(|module| module.program.1.iter().cloned().collect())
+            .collect();
+
+        let mut decls = Vec::new();
+        let mut exports = Vec::new();
+        for (i, module) in self.modules.into_iter().enumerate() {
+            // imports go in an outer scope, the module's own items shadow them
+            let mut names = Environment::new();
+            for decl in &module.program.0 {
+                if let Decl::Use(path, span) = decl {
+                    let (item, target) = path.0.split_last().expect("checked while loading");
+                    let target = index[target];
+                    let imported = declared[target]
+                        .get(item)
+                        .ok_or_else(|| ModuleError::UnknownItem(Path(path.0.clone()), *span))?;
+                    if !exported[target].contains(item) {
+                        return Err(ModuleError::PrivateItem(Path(path.0.clone()), *span));
+                    }
+                    for (name, qualified) in imported {
+                        match names.lookup(*name) {
+                            // the same item may be imported twice
+                            Some(other) if other != qualified => {
+                                return Err(ModuleError::ImportConflict(module.name(), *name, *other, *qualified, *span));
+                            }
+                            _ => {
+                                names.insert(*name, *qualified);
+                            }
+                        }
+                    }
+                }
+            }
+            names.push_scope();
+            for items in declared[i].values() {
+                for (name, qualified) in items {
+                    names.insert(*name, *qualified);
+                }
+            }
+
+            let mut resolver = Resolver {
+                names,
+                ctors: &ctors,
+                locals: Vec::new(),
+            };
+            let Program(module_decls, module_exports) = module.program;
+            for mut decl in module_decls {
+                match &mut decl {
+                    Decl::Use(_, _) => continue,
+                    Decl::Test(test) => {
+                        test.file = module.file.clone();
+                        if !module.path.is_empty() {
+                            test.name = format!("{}.{}", Path(module.path.clone()), test.name);
+                        }
+                    }
+                    _ => (),
+                }
+                resolver.decl(&mut decl);
+                decls.push(decl);
+            }
+            if i == index[&Vec::new()] {
+                exports.extend(module_exports);
+            }
+        }
+        Ok(Program(decls, exports))
+    }
+}
+
+/// Rewrites the names a module refers to into their qualified names.
+struct Resolver<'a> {
+    names: Environment<Symbol, Symbol>,
+    ctors: &'a HashSet<Symbol>,
+    locals: Vec<Symbol>,
+}
+
+impl<'a> Resolver<'a> {
+    fn name(&self, name: Symbol) -> Symbol {
+        if self.locals.contains(&name) {
+            return name;
+        }
+        self.names.lookup(name).cloned().unwrap_or(name)
+    }
+
+    fn ty(&self, ty: &mut Type) {
+        match ty {
+            Type::Name(name) => *name = self.name(*name),
+            Type::Func(args, ret) => {
+                for arg in args {
+                    self.ty(arg);
+                }
+                self.ty(ret);
+            }
+            Type::Var(_) | Type::Unit | Type::Int | Type::Bool => (),
+        }
+    }
+
+    fn decl(&mut self, decl: &mut Decl) {
+        match decl {
+            Decl::Class(class) => {
+                class.name = self.name(class.name);
+                for (name, _, ty) in &mut class.methods {
+                    *name = self.name(*name);
+                    // the class parameter is a type name too
+                    self.locals.push(class.param);
+                    self.ty(ty);
+                    self.locals.pop();
+                }
+            }
+            Decl::Enum(enum_) => {
+                enum_.name = self.name(enum_.name);
+                for variant in &mut enum_.variants {
+                    variant.name = self.name(variant.name);
+                    for ty in &mut variant.fields {
+                        self.ty(ty);
+                    }
+                }
+            }
+            Decl::Extern(_, args, returns) => {
+                for (_, ty) in args {
+                    self.ty(ty);
+                }
+                self.ty(returns);
+            }
+            Decl::Func(func) => {
+                func.name = self.name(func.name);
+                self.func(func);
+            }
+            Decl::Instance(instance) => {
+                instance.class = self.name(instance.class);
+                self.ty(&mut instance.ty);
+                for func in &mut instance.methods {
+                    // methods are looked up by their name in the class
+                    func.name = self.name(func.name);
+                    self.func(func);
+                }
+            }
+            Decl::Struct(struct_) => {
+                struct_.name = self.name(struct_.name);
+                for (_, ty) in &mut struct_.fields {
+                    self.ty(ty);
+                }
+            }
+            Decl::Test(test) => {
+                self.locals.clear();
+                self.stmts(&mut test.body);
+            }
+            Decl::Use(_, _) => (),
+        }
+    }
+
+    fn func(&mut self, func: &mut Func) {
+        for (_, ty) in &mut func.args {
+            self.ty(ty);
+        }
+        self.ty(&mut func.returns);
+        self.locals = func.args.iter().map(|(name, _)| *name).collect();
+        self.stmts(&mut func.body);
+        self.locals.clear();
+    }
+
+    fn stmts(&mut self, stmts: &mut [Stmt]) {
+        let depth = self.locals.len();
+        for stmt in stmts {
+            match &mut stmt.kind {
+                StmtKind::Expr(expr) | StmtKind::Return(expr) => self.expr(expr),
+                StmtKind::Let(name, expr) => {
+                    self.expr(expr);
+                    self.locals.push(*name);
+                }
+                StmtKind::Assign(target, expr) => {
+                    self.expr(target);
+                    self.expr(expr);
+                }
+                StmtKind::If(cond, tbody, fbody) => {
+                    self.expr(cond);
+                    self.stmts(tbody);
+                    self.stmts(fbody);
+                }
+                StmtKind::Match(expr, arms) => {
+                    self.expr(expr);
+                    for arm in arms {
+                        let depth = self.locals.len();
+                        self.pattern(&mut arm.pattern);
+                        self.stmts(&mut arm.body);
+                        self.locals.truncate(depth);
+                    }
+                }
+                StmtKind::Assert(assert) => {
+                    for arg in &mut assert.args {
+                        self.expr(arg);
+                    }
+                }
+            }
+        }
+        self.locals.truncate(depth);
+    }
+
+    fn pattern(&mut self, pattern: &mut Pattern) {
+        match pattern {
+            Pattern::Wildcard | Pattern::Int(_) => (),
+            Pattern::Ident(name) => {
+                let resolved = self.name(*name);
+                if self.ctors.contains(&resolved) {
+                    *name = resolved;
+                } else {
+                    self.locals.push(*name);
+                }
+            }
+            Pattern::Ctor(name, args) => {
+                *name = self.name(*name);
+                for arg in args {
+                    self.pattern(arg);
+                }
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Int(_) => (),
+            Expr::Ident(name) => *name = self.name(*name),
+            Expr::Call(func, args) => {
+                self.expr(func);
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            Expr::BinOp(_, left, right) => {
+                self.expr(left);
+                self.expr(right);
+            }
+            Expr::UnOp(_, expr) | Expr::Field(expr, _) => self.expr(expr),
+            Expr::Struct(name, fields) => {
+                *name = self.name(*name);
+                for (_, expr) in fields {
+                    self.expr(expr);
+                }
+            }
+            Expr::Method(_, ty, method) => {
+                self.ty(ty);
+                self.ty(method);
+            }
+            Expr::Global(name, ty) => {
+                *name = self.name(*name);
+                self.ty(ty);
+            }
+        }
+    }
+}
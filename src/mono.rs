@@ -0,0 +1,314 @@
+//! Generic functions are compiled once for every type they are used at.
+//!
+//! Before typechecking, references to top-level functions are marked so that
+//! the type of each use is recorded in the ast. Once types are known, the
+//! functions reachable from the monomorphic ones are copied for every
+//! instantiation, and the references are pointed at those copies.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use symbol::Symbol;
+
+use crate::adt::AdtTable;
+use crate::ast::{Decl, Expr, Func, Program, Stmt, StmtKind, Type};
+use crate::env::Environment;
+use crate::pattern::collect_bindings;
+use crate::typeck::{self, Substitution};
+
+#[derive(Debug)]
+pub enum MonoError {
+    /// A generic function is used at a type that isn't fully known.
+    Ambiguous(Symbol, Type),
+    /// The entry point has type variables left in its signature.
+    GenericMain(Type),
+}
+
+impl fmt::Display for MonoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MonoError::Ambiguous(name, ty) => write!(
+                f,
+                "type annotations needed: `{}` is used at `{}`, which can't be inferred",
+                name, ty
+            ),
+            MonoError::GenericMain(ty) => write!(
+                f,
+                "type annotations needed: `main` can't be generic, but its type is `{}`",
+                ty
+            ),
+        }
+    }
+}
+
+/// Replaces every reference to a top-level function with `Expr::Global`.
+pub fn mark_globals(adts: &AdtTable, program: &mut Program) {
+    let funcs = program
+        .0
+        .iter()
+        .filter_map(|decl| match decl {
+            Decl::Func(func) => Some(func.name),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    for decl in &mut program.0 {
+        match decl {
+            Decl::Func(func) => mark_func(adts, &funcs, func),
+            Decl::Instance(instance) => {
+                for func in &mut instance.methods {
+                    mark_func(adts, &funcs, func);
+                }
+            }
+            Decl::Class(_) | Decl::Enum(_) | Decl::Extern(_, _, _) | Decl::Struct(_) | Decl::Test(_) | Decl::Use(_, _) => (),
+        }
+    }
+}
+
+fn mark_func(adts: &AdtTable, funcs: &HashSet<Symbol>, func: &mut Func) {
+    let mut locals = func.args.iter().map(|(name, _)| *name).collect();
+    mark_stmts(adts, funcs, &mut locals, &mut func.body);
+}
+
+fn mark_stmts(adts: &AdtTable, funcs: &HashSet<Symbol>, locals: &mut Vec<Symbol>, stmts: &mut [Stmt]) {
+    let depth = locals.len();
+    for stmt in stmts {
+        match &mut stmt.kind {
+            StmtKind::Expr(expr) | StmtKind::Return(expr) => mark_expr(funcs, locals, expr),
+            StmtKind::Let(name, expr) => {
+                mark_expr(funcs, locals, expr);
+                locals.push(*name);
+            }
+            StmtKind::Assign(target, expr) => {
+                mark_expr(funcs, locals, target);
+                mark_expr(funcs, locals, expr);
+            }
+            StmtKind::If(cond, tbody, fbody) => {
+                mark_expr(funcs, locals, cond);
+                mark_stmts(adts, funcs, locals, tbody);
+                mark_stmts(adts, funcs, locals, fbody);
+            }
+            StmtKind::Match(expr, arms) => {
+                mark_expr(funcs, locals, expr);
+                for arm in arms {
+                    let depth = locals.len();
+                    collect_bindings(adts, &arm.pattern, locals);
+                    mark_stmts(adts, funcs, locals, &mut arm.body);
+                    locals.truncate(depth);
+                }
+            }
+            StmtKind::Assert(_) => unreachable!("assertions are taken out before typechecking"),
+        }
+    }
+    locals.truncate(depth);
+}
+
+fn mark_expr(funcs: &HashSet<Symbol>, locals: &[Symbol], expr: &mut Expr) {
+    match expr {
+        Expr::Int(_) | Expr::Method(_, _, _) | Expr::Global(_, _) => (),
+        Expr::Ident(name) => {
+            if funcs.contains(name) && !locals.contains(name) {
+                *expr = Expr::Global(*name, Type::gen());
+            }
+        }
+        Expr::Call(func, args) => {
+            mark_expr(funcs, locals, func);
+            for arg in args {
+                mark_expr(funcs, locals, arg);
+            }
+        }
+        Expr::BinOp(_, left, right) => {
+            mark_expr(funcs, locals, left);
+            mark_expr(funcs, locals, right);
+        }
+        Expr::UnOp(_, expr) | Expr::Field(expr, _) => mark_expr(funcs, locals, expr),
+        Expr::Struct(_, fields) => {
+            for (_, expr) in fields {
+                mark_expr(funcs, locals, expr);
+            }
+        }
+    }
+}
+
+/// The symbol the instantiation of a generic function is compiled to.
+pub fn mangle_instance(name: Symbol, args: &[Type]) -> Symbol {
+    let mut mangled = name.to_string();
+    for arg in args {
+        mangled.push_str(&format!("${}", arg));
+    }
+    Symbol::from(mangled.as_str())
+}
+
+/// Rewrites the program so that it only has monomorphic functions: generic
+/// ones are replaced by a copy for every instantiation reachable from the
+/// rest of the program, and the ones that are never used are dropped. Every
+/// function signature and every type recorded in the bodies is made concrete.
+pub fn monomorphize(type_env: &Environment<Symbol, Type>, subst: &Substitution, program: Program) -> Result<Program, MonoError> {
+    let Program(decls, exports) = program;
+
+    let mut generics = HashMap::new();
+    let mut output = Vec::new();
+    for decl in decls {
+        match decl {
+            Decl::Func(func) => {
+                let ty = signature(type_env, subst, func.name);
+                if typeck::free_vars(&ty).is_empty() {
+                    output.push(Decl::Func(func));
+                } else if func.name.as_str() == "main" {
+                    return Err(MonoError::GenericMain(ty));
+                } else {
+                    generics.insert(func.name, func);
+                }
+            }
+            decl => output.push(decl),
+        }
+    }
+
+    let mut monomorphize = Monomorphize {
+        type_env,
+        subst,
+        generics,
+        seen: HashSet::new(),
+        queue: VecDeque::new(),
+    };
+    for decl in &mut output {
+        match decl {
+            Decl::Func(func) => {
+                let name = func.name;
+                monomorphize.specialize(func, name, &Substitution::new())?;
+            }
+            Decl::Instance(instance) => {
+                for i in 0..instance.methods.len() {
+                    let name = instance.method_symbol(instance.methods[i].name);
+                    monomorphize.specialize(&mut instance.methods[i], name, &Substitution::new())?;
+                }
+            }
+            Decl::Class(_) | Decl::Enum(_) | Decl::Extern(_, _, _) | Decl::Struct(_) | Decl::Test(_) | Decl::Use(_, _) => (),
+        }
+    }
+    while let Some((name, mangled, instance)) = monomorphize.queue.pop_front() {
+        let mut func = monomorphize.generics[&name].clone();
+        func.name = mangled;
+        monomorphize.specialize(&mut func, name, &instance)?;
+        output.push(Decl::Func(func));
+    }
+    Ok(Program(output, exports))
+}
+
+struct Monomorphize<'a> {
+    type_env: &'a Environment<Symbol, Type>,
+    subst: &'a Substitution,
+    /// The generic functions, by name.
+    generics: HashMap<Symbol, Func>,
+    /// The instantiations that have been queued so far, by mangled name.
+    seen: HashSet<Symbol>,
+    /// Instantiations to compile: the function, its mangled name and the types
+    /// its type variables are instantiated with.
+    queue: VecDeque<(Symbol, Symbol, Substitution)>,
+}
+
+impl<'a> Monomorphize<'a> {
+    fn signature(&self, name: Symbol) -> Type {
+        signature(self.type_env, self.subst, name)
+    }
+
+    /// The type of `ty` in the instantiation.
+    fn concrete(&self, instance: &Substitution, ty: &Type) -> Type {
+        typeck::apply(instance, &typeck::apply(self.subst, ty))
+    }
+
+    /// Makes the signature and the body of `func` concrete. `name` is the one
+    /// the function was typechecked under.
+    fn specialize(&mut self, func: &mut Func, name: Symbol, instance: &Substitution) -> Result<(), MonoError> {
+        let (args, returns) = match self.concrete(instance, &self.signature(name)) {
+            Type::Func(args, returns) => (args, *returns),
+            _ => unreachable!("functions have function types"),
+        };
+        for ((_, ty), arg) in func.args.iter_mut().zip(args) {
+            *ty = arg;
+        }
+        func.returns = returns;
+        self.specialize_stmts(instance, &mut func.body)
+    }
+
+    fn specialize_stmts(&mut self, instance: &Substitution, stmts: &mut [Stmt]) -> Result<(), MonoError> {
+        for stmt in stmts {
+            match &mut stmt.kind {
+                StmtKind::Expr(expr) | StmtKind::Let(_, expr) | StmtKind::Return(expr) => self.specialize_expr(instance, expr)?,
+                StmtKind::Assign(target, expr) => {
+                    self.specialize_expr(instance, target)?;
+                    self.specialize_expr(instance, expr)?;
+                }
+                StmtKind::If(cond, tbody, fbody) => {
+                    self.specialize_expr(instance, cond)?;
+                    self.specialize_stmts(instance, tbody)?;
+                    self.specialize_stmts(instance, fbody)?;
+                }
+                StmtKind::Match(expr, arms) => {
+                    self.specialize_expr(instance, expr)?;
+                    for arm in arms {
+                        self.specialize_stmts(instance, &mut arm.body)?;
+                    }
+                }
+                StmtKind::Assert(_) => unreachable!("assertions are taken out before typechecking"),
+            }
+        }
+        Ok(())
+    }
+
+    fn specialize_expr(&mut self, instance: &Substitution, expr: &mut Expr) -> Result<(), MonoError> {
+        match expr {
+            Expr::Int(_) | Expr::Ident(_) => (),
+            Expr::Method(_, ty, method) => {
+                *ty = self.concrete(instance, ty);
+                *method = self.concrete(instance, method);
+            }
+            Expr::Global(name, ty) => {
+                let name = *name;
+                let ty = self.concrete(instance, ty);
+                if !self.generics.contains_key(&name) {
+                    *expr = Expr::Ident(name);
+                    return Ok(());
+                }
+                if !typeck::free_vars(&ty).is_empty() {
+                    return Err(MonoError::Ambiguous(name, ty));
+                }
+                // the types the callee's variables are instantiated with
+                let generic = self.signature(name);
+                let mut callee = Substitution::new();
+                typeck::bind_vars(&generic, &ty, &mut callee);
+                let args = typeck::free_vars(&generic)
+                    .iter()
+                    .map(|var| callee[var].clone())
+                    .collect::<Vec<_>>();
+                let mangled = mangle_instance(name, &args);
+                if self.seen.insert(mangled) {
+                    self.queue.push_back((name, mangled, callee));
+                }
+                *expr = Expr::Ident(mangled);
+            }
+            Expr::Call(func, args) => {
+                self.specialize_expr(instance, func)?;
+                for arg in args {
+                    self.specialize_expr(instance, arg)?;
+                }
+            }
+            Expr::BinOp(_, left, right) => {
+                self.specialize_expr(instance, left)?;
+                self.specialize_expr(instance, right)?;
+            }
+            Expr::UnOp(_, expr) | Expr::Field(expr, _) => self.specialize_expr(instance, expr)?,
+            Expr::Struct(_, fields) => {
+                for (_, expr) in fields {
+                    self.specialize_expr(instance, expr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The inferred type of a function, generic over the type variables left.
+fn signature(type_env: &Environment<Symbol, Type>, subst: &Substitution, name: Symbol) -> Type {
+    let ty = type_env.lookup(name).expect("functions are declared");
+    typeck::apply(subst, ty)
+}
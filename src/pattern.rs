@@ -0,0 +1,295 @@
+//! Exhaustiveness and reachability checking for `match` statements, based on
+//! the usefulness algorithm from Maranget's "Warnings for pattern matching".
+
+use std::fmt;
+
+use symbol::Symbol;
+
+use crate::adt::AdtTable;
+use crate::ast::{Decl, Pattern, Program, Spanned, Stmt, StmtKind};
+
+#[derive(Debug)]
+pub enum MatchError {
+    /// A match in the given function doesn't cover the witness pattern.
+    NonExhaustive(Symbol, String),
+    /// The arm with the given index can never be reached.
+    UnreachableArm(Symbol, usize),
+    /// A pattern binds the same name more than once.
+    DuplicateBinding(Symbol, Symbol),
+}
+
+impl MatchError {
+    pub fn is_error(&self) -> bool {
+        match self {
+            MatchError::NonExhaustive(_, _) | MatchError::DuplicateBinding(_, _) => true,
+            MatchError::UnreachableArm(_, _) => false,
+        }
+    }
+}
+
+impl fmt::Display for MatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatchError::NonExhaustive(func, witness) => write!(
+                f,
+                "non-exhaustive match in `{}`: pattern `{}` not covered",
+                func, witness
+            ),
+            MatchError::UnreachableArm(func, arm) => {
+                write!(f, "unreachable match arm #{} in `{}`", arm + 1, func)
+            }
+            MatchError::DuplicateBinding(func, name) => write!(
+                f,
+                "`{}` is bound more than once in the same pattern in `{}`",
+                name, func
+            ),
+        }
+    }
+}
+
+/// A pattern with bindings erased and nullary constructors resolved.
+#[derive(Clone, Debug)]
+enum Pat {
+    Wild,
+    Int(i64),
+    Ctor(Symbol, Vec<Pat>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Head {
+    Int(i64),
+    Ctor(Symbol),
+}
+
+impl fmt::Display for Pat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pat::Wild => write!(f, "_"),
+            Pat::Int(n) => write!(f, "{}", n),
+            Pat::Ctor(name, args) if args.is_empty() => write!(f, "{}", name),
+            Pat::Ctor(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Checks every match in the program, and returns the errors and warnings
+/// found with the match they're about.
+pub fn check_program(adts: &AdtTable, program: &Program) -> Vec<Spanned<MatchError>> {
+    let mut errors = Vec::new();
+    for decl in &program.0 {
+        match decl {
+            Decl::Func(func) => check_stmts(adts, func.name, &func.body, &mut errors),
+            Decl::Instance(instance) => {
+                for func in &instance.methods {
+                    check_stmts(adts, instance.method_symbol(func.name), &func.body, &mut errors);
+                }
+            }
+            Decl::Class(_) | Decl::Enum(_) | Decl::Extern(_, _, _) | Decl::Struct(_) | Decl::Test(_) | Decl::Use(_, _) => (),
+        }
+    }
+    errors
+}
+
+fn check_stmts(adts: &AdtTable, func: Symbol, stmts: &[Stmt], errors: &mut Vec<Spanned<MatchError>>) {
+    for stmt in stmts {
+        match &stmt.kind {
+            StmtKind::Expr(_) | StmtKind::Let(_, _) | StmtKind::Assign(_, _) | StmtKind::Return(_) | StmtKind::Assert(_) => (),
+            StmtKind::If(_, tbody, fbody) => {
+                check_stmts(adts, func, tbody, errors);
+                check_stmts(adts, func, fbody, errors);
+            }
+            StmtKind::Match(_, arms) => {
+                let mut rows = Vec::new();
+                for (i, arm) in arms.iter().enumerate() {
+                    let mut bindings = Vec::new();
+                    collect_bindings(adts, &arm.pattern, &mut bindings);
+                    for (j, name) in bindings.iter().enumerate() {
+                        if bindings[..j].contains(name) {
+                            errors.push(Spanned::new(MatchError::DuplicateBinding(func, *name), stmt.span));
+                        }
+                    }
+
+                    let pat = lower(adts, &arm.pattern);
+                    if useful(adts, &rows, std::slice::from_ref(&pat)).is_none() {
+                        errors.push(Spanned::new(MatchError::UnreachableArm(func, i), stmt.span));
+                    }
+                    rows.push(vec![pat]);
+                    check_stmts(adts, func, &arm.body, errors);
+                }
+                if let Some(witness) = useful(adts, &rows, &[Pat::Wild]) {
+                    errors.push(Spanned::new(MatchError::NonExhaustive(func, witness[0].to_string()), stmt.span));
+                }
+            }
+        }
+    }
+}
+
+/// Collects the names bound by a pattern, in order of appearance.
+pub fn collect_bindings(adts: &AdtTable, pattern: &Pattern, bindings: &mut Vec<Symbol>) {
+    match pattern {
+        Pattern::Wildcard | Pattern::Int(_) => (),
+        Pattern::Ident(name) => {
+            if adts.get_ctor(*name).is_none() {
+                bindings.push(*name);
+            }
+        }
+        Pattern::Ctor(_, args) => {
+            for arg in args {
+                collect_bindings(adts, arg, bindings);
+            }
+        }
+    }
+}
+
+fn lower(adts: &AdtTable, pattern: &Pattern) -> Pat {
+    match pattern {
+        Pattern::Wildcard => Pat::Wild,
+        Pattern::Int(n) => Pat::Int(*n),
+        Pattern::Ident(name) => match adts.get_ctor(*name) {
+            Some(_) => Pat::Ctor(*name, Vec::new()),
+            None => Pat::Wild,
+        },
+        Pattern::Ctor(name, args) => {
+            Pat::Ctor(*name, args.iter().map(|arg| lower(adts, arg)).collect())
+        }
+    }
+}
+
+fn head(pat: &Pat) -> Option<Head> {
+    match pat {
+        Pat::Wild => None,
+        Pat::Int(n) => Some(Head::Int(*n)),
+        Pat::Ctor(name, _) => Some(Head::Ctor(*name)),
+    }
+}
+
+fn arity(adts: &AdtTable, head: &Head) -> usize {
+    match head {
+        Head::Int(_) => 0,
+        Head::Ctor(name) => adts.get_ctor(*name).map(|ctor| ctor.fields.len()).unwrap_or(0),
+    }
+}
+
+/// Keeps the rows whose first column matches `head`, replacing that column with
+/// the sub-patterns of the constructor.
+fn specialize(rows: &[Vec<Pat>], head: &Head, arity: usize) -> Vec<Vec<Pat>> {
+    rows.iter()
+        .filter_map(|row| {
+            let mut new_row = match &row[0] {
+                Pat::Wild => vec![Pat::Wild; arity],
+                Pat::Int(n) if *head == Head::Int(*n) => Vec::new(),
+                Pat::Ctor(name, args) if *head == Head::Ctor(*name) => args.clone(),
+                _ => return None,
+            };
+            new_row.extend_from_slice(&row[1..]);
+            Some(new_row)
+        })
+        .collect()
+}
+
+/// Returns a witness: a vector of values matched by `v` but by none of `rows`.
+fn useful(adts: &AdtTable, rows: &[Vec<Pat>], v: &[Pat]) -> Option<Vec<Pat>> {
+    let (first, rest) = match v.split_first() {
+        Some(split) => split,
+        None => return if rows.is_empty() { Some(Vec::new()) } else { None },
+    };
+
+    if let Some(head) = head(first) {
+        let arity = arity(adts, &head);
+        let mut new_v = match first {
+            Pat::Ctor(_, args) => args.clone(),
+            _ => Vec::new(),
+        };
+        new_v.extend_from_slice(rest);
+        let witness = useful(adts, &specialize(rows, &head, arity), &new_v)?;
+        return Some(rebuild(&head, arity, witness));
+    }
+
+    let mut heads = Vec::new();
+    for row in rows {
+        if let Some(head) = head(&row[0]) {
+            if !heads.contains(&head) {
+                heads.push(head);
+            }
+        }
+    }
+
+    match complete_signature(adts, &heads) {
+        Some(all) => {
+            for head in all {
+                let arity = arity(adts, &head);
+                let mut new_v = vec![Pat::Wild; arity];
+                new_v.extend_from_slice(rest);
+                if let Some(witness) = useful(adts, &specialize(rows, &head, arity), &new_v) {
+                    return Some(rebuild(&head, arity, witness));
+                }
+            }
+            None
+        }
+        None => {
+            let default: Vec<_> = rows
+                .iter()
+                .filter(|row| head(&row[0]).is_none())
+                .map(|row| row[1..].to_vec())
+                .collect();
+            let mut witness = useful(adts, &default, rest)?;
+            witness.insert(0, missing(adts, &heads));
+            Some(witness)
+        }
+    }
+}
+
+/// Folds the first `arity` patterns of the witness back under `head`.
+fn rebuild(head: &Head, arity: usize, mut witness: Vec<Pat>) -> Vec<Pat> {
+    let rest = witness.split_off(arity);
+    let pat = match head {
+        Head::Int(n) => Pat::Int(*n),
+        Head::Ctor(name) => Pat::Ctor(*name, witness),
+    };
+    let mut result = vec![pat];
+    result.extend(rest);
+    result
+}
+
+/// If `heads` mention every variant of an enum, returns all of its variants.
+fn complete_signature(adts: &AdtTable, heads: &[Head]) -> Option<Vec<Head>> {
+    let enum_name = match heads.first()? {
+        Head::Ctor(name) => adts.get_ctor(*name)?.enum_name,
+        Head::Int(_) => return None,
+    };
+    let info = adts.get_enum(enum_name)?;
+    let all: Vec<_> = info.variants.iter().map(|name| Head::Ctor(*name)).collect();
+    if all.iter().all(|head| heads.contains(head)) {
+        Some(all)
+    } else {
+        None
+    }
+}
+
+/// A pattern matching some value not covered by `heads`.
+fn missing(adts: &AdtTable, heads: &[Head]) -> Pat {
+    let enum_name = match heads.first() {
+        Some(Head::Ctor(name)) => adts.get_ctor(*name).map(|ctor| ctor.enum_name),
+        _ => None,
+    };
+    let info = match enum_name.and_then(|name| adts.get_enum(name)) {
+        Some(info) => info,
+        None => return Pat::Wild,
+    };
+    for name in &info.variants {
+        let head = Head::Ctor(*name);
+        if !heads.contains(&head) {
+            return Pat::Ctor(*name, vec![Pat::Wild; arity(adts, &head)]);
+        }
+    }
+    Pat::Wild
+}
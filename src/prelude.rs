@@ -5,4 +5,5 @@ use crate::env::Environment;
 
 pub fn load_prelude(env: &mut Environment<Symbol, Type>) {
     env.insert(Symbol::from("int"), Type::Int);
+    env.insert(Symbol::from("bool"), Type::Bool);
 }
@@ -0,0 +1,210 @@
+//! `mochi repl`: reads definitions and expressions a line at a time.
+//! Definitions are kept and checked together with everything defined so
+//! far, and defining a name again replaces the old definition. Expressions
+//! are checked as the body of a function of their own, run with the ast
+//! interpreter, and printed with their type.
+//!
+//! A line ending in `:` starts a block; like in a file, the block is
+//! indented, and it ends with an empty line.
+
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+
+use symbol::Symbol;
+
+use crate::ast::{Decl, Program, Type};
+use crate::driver::{self, display_type, Checked, Level};
+use crate::eval::Evaluator;
+use crate::mir::interp::InterpError;
+use crate::module::{Module, ModuleTree};
+use crate::parser::ProgramParser;
+use crate::scanner::{ScanError, Scanner, Token};
+use crate::typeck;
+
+const PROMPT: &str = ">>> ";
+const CONTINUATION: &str = "... ";
+
+/// The function an expression is evaluated as the body of.
+const EXPR_FUNC: &str = "_it";
+
+/// The interpreter recurses for every call of the program, so it runs on a
+/// stack that is large enough for `eval::MAX_DEPTH` of them.
+const STACK_SIZE: usize = 512 << 20;
+
+pub fn run() {
+    let repl = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(|| Repl { defs: Vec::new() }.run())
+        .expect("couldn't start the repl");
+    if let Err(err) = repl.join().expect("the repl panicked") {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+fn parse(source: &str) -> Result<Program, String> {
+    ProgramParser::new()
+        .parse(source, Symbol::from("<repl>"), Scanner::new(source.as_bytes()))
+        .map_err(|err| ScanError::from(err).to_string())
+}
+
+/// The tokens of the input, without the line separators.
+fn tokens(input: &str) -> Vec<Token> {
+    Scanner::new(input.as_bytes())
+        .filter_map(Result::ok)
+        .map(|(_, token, _)| token)
+        .filter(|token| !matches!(token, Token::Sep))
+        .collect()
+}
+
+/// Whether the input continues on the next line: it is in an indented block,
+/// or ends where a block or a function after an attribute has to follow.
+fn needs_more(input: &str) -> bool {
+    let tokens = tokens(input);
+    if tokens.iter().any(|token| matches!(token, Token::Indent)) {
+        return true;
+    }
+    let mut rest = tokens.iter().rev().skip_while(|token| matches!(token, Token::Dedent));
+    matches!(
+        (rest.next(), rest.next()),
+        (Some(Token::SymColon), _) | (Some(Token::Ident(_)), Some(Token::SymAt))
+    )
+}
+
+/// Whether the input declares something rather than being an expression.
+fn is_definition(input: &str) -> bool {
+    matches!(
+        tokens(input).first(),
+        Some(Token::KwdClass)
+            | Some(Token::KwdEnum)
+            | Some(Token::KwdExtern)
+            | Some(Token::KwdFn)
+            | Some(Token::KwdInstance)
+            | Some(Token::KwdPub)
+            | Some(Token::KwdStruct)
+            | Some(Token::KwdUse)
+            | Some(Token::SymAt)
+    )
+}
+
+struct Repl {
+    /// The definitions entered so far, with the names they declare.
+    defs: Vec<(Vec<Symbol>, String)>,
+}
+
+impl Repl {
+    fn run(mut self) -> io::Result<()> {
+        let stdin = io::stdin();
+        let mut input = String::new();
+        loop {
+            print!("{}", if input.is_empty() { PROMPT } else { CONTINUATION });
+            io::stdout().flush()?;
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                println!();
+                return Ok(());
+            }
+            let blank = line.trim().is_empty();
+            if blank && input.is_empty() {
+                continue;
+            }
+            input.push_str(&line);
+            if !blank && needs_more(&input) {
+                continue;
+            }
+
+            let input = mem::take(&mut input);
+            let result = if is_definition(&input) {
+                self.define(&input)
+            } else {
+                self.evaluate(&input)
+            };
+            match result {
+                Ok(output) => output.iter().for_each(|line| println!("{}", line)),
+                Err(err) => eprintln!("error: {}", err),
+            }
+        }
+    }
+
+    /// Checks the definitions along with the source that follows them.
+    fn check(&self, extra: &str) -> Result<Checked, String> {
+        let mut source = String::new();
+        for (_, def) in &self.defs {
+            source.push_str(def);
+            source.push('\n');
+        }
+        source.push_str(extra);
+        let module = Module {
+            path: Vec::new(),
+            file: PathBuf::from("<repl>"),
+            program: parse(&source)?,
+        };
+        let ast = ModuleTree { modules: vec![module] }
+            .link()
+            .map_err(|err| err.to_string())?;
+        let checked = driver::check(ast).map_err(|diagnostics| {
+            let (errors, warnings): (Vec<_>, Vec<_>) = diagnostics
+                .into_iter()
+                .partition(|diagnostic| diagnostic.level == Level::Error);
+            warnings.iter().for_each(|warning| eprintln!("{}", warning));
+            let errors: Vec<_> = errors.into_iter().map(|error| error.message).collect();
+            errors.join("\nerror: ")
+        })?;
+        checked.warnings.iter().for_each(|warning| eprintln!("{}", warning));
+        // lowering finds the errors the interpreter would run into
+        checked.lower()?;
+        Ok(checked)
+    }
+
+    /// Adds the definitions and returns the types of the functions.
+    fn define(&mut self, input: &str) -> Result<Vec<String>, String> {
+        let program = parse(input)?;
+        let names: Vec<Symbol> = program.0.iter().filter_map(Decl::name).collect();
+        let old = self.defs.clone();
+        self.defs.retain(|(defined, _)| !defined.iter().any(|name| names.contains(name)));
+        self.defs.push((names.clone(), input.to_owned()));
+        let checked = match self.check("") {
+            Ok(checked) => checked,
+            Err(err) => {
+                self.defs = old;
+                return Err(err);
+            }
+        };
+
+        let mut output = Vec::new();
+        for decl in &program.0 {
+            if let Decl::Func(func) = decl {
+                let ty = checked.type_of(func.name).expect("functions are declared");
+                output.push(format!("{}: {}", func.name, display_type(&ty)));
+            }
+        }
+        Ok(output)
+    }
+
+    /// Runs the expression and returns its value and type, unless it has
+    /// none.
+    fn evaluate(&self, input: &str) -> Result<Vec<String>, String> {
+        let name = Symbol::from(EXPR_FUNC);
+        let checked = self.check(&format!("fn {}:\n  return {}\n", name, input.trim()))?;
+        let ty = match checked.type_of(name) {
+            Some(Type::Func(_, returns)) => *returns,
+            _ => unreachable!("functions have function types"),
+        };
+        if !typeck::free_vars(&ty).is_empty() {
+            return Err(format!("type annotations needed: the expression has type `{}`", ty));
+        }
+
+        let value = match Evaluator::new(&checked.adts, &checked.ast).call(name, Vec::new()) {
+            Ok(value) => value,
+            Err(InterpError::Exit(code)) => process::exit(code as i32),
+            Err(err) => return Err(err.to_string()),
+        };
+        Ok(match ty {
+            Type::Unit => Vec::new(),
+            ty => vec![format!("{}: {}", value, ty)],
+        })
+    }
+}
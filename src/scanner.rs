@@ -7,20 +7,26 @@ use lalrpop_util::ParseError as LalrpopError;
 use regex::Regex;
 use symbol::Symbol;
 
-type Position = (usize, usize);
 type Spanned<Location, Token, Error> = Result<(Location, Token, Location), Error>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
+    KwdAssert,
+    KwdAssertEq,
     KwdClass,
+    KwdEnum,
     KwdExtern,
     KwdFor,
     KwdFn,
     KwdIf,
     KwdIn,
+    KwdInstance,
     KwdLet,
     KwdMatch,
+    KwdPub,
     KwdReturn,
+    KwdStruct,
+    KwdTest,
     KwdUse,
 
     SymArrow,
@@ -36,7 +42,10 @@ pub enum Token {
     SymRightShift,
 
     SymAmpersand,
+    SymAt,
     SymBang,
+    SymBraceL,
+    SymBraceR,
     SymCaret,
     SymColon,
     SymComma,
@@ -85,28 +94,92 @@ lazy_static! {
         Regex::new(r#"^("[^"]*")"#).unwrap(),
 
         // 2-char symbols
-        Regex::new(r"^((\.\.)|(==)|(!=)|(->)|(=>))").unwrap(),
+        Regex::new(r"^((\.\.)|(==)|(!=)|(->)|(=>)|(>=)|(<=)|(<<)|(>>)|(\|\|)|(&&))").unwrap(),
 
         // 1-char symbols
-        Regex::new(r#"^[=<>\(\)\[\]:\.,_%"'\*+-;]"#).unwrap(),
+        Regex::new(r#"^[=<>\(\)\[\]\{\}:\.,_%"'\*+\-;&!\^|/~@]"#).unwrap(),
 
         // whitespace
         Regex::new(r"^([ \t\n]+)").unwrap(),
     ];
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum ScanError {
     BadSymbol(usize),
     InvalidToken(usize),
     UnrecognizedToken(Option<(usize, Token, usize)>, Vec<String>),
     ExtraToken(usize, Token, usize),
+    UnknownAttribute(usize, Symbol),
+    MisplacedAttribute(usize),
+    /// An integer literal that doesn't fit in 64 bits.
+    IntTooLarge(usize, usize),
 }
 
 impl fmt::Display for ScanError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            _ => write!(f, "unimplemented"),
+            ScanError::BadSymbol(pos) => write!(f, "unrecognized character at byte {}", pos),
+            ScanError::InvalidToken(pos) => write!(f, "invalid token at byte {}", pos),
+            ScanError::UnrecognizedToken(Some((pos, token, _)), expected) => write!(
+                f,
+                "unexpected {:?} at byte {}, expected one of {}",
+                token,
+                pos,
+                expected.join(", ")
+            ),
+            ScanError::UnrecognizedToken(None, expected) => write!(
+                f,
+                "unexpected end of file, expected one of {}",
+                expected.join(", ")
+            ),
+            ScanError::ExtraToken(pos, token, _) => {
+                write!(f, "unexpected {:?} at byte {}", token, pos)
+            }
+            ScanError::UnknownAttribute(pos, name) => write!(f, "unknown attribute `@{}` at byte {}", name, pos),
+            ScanError::MisplacedAttribute(pos) => write!(f, "attributes at byte {} can only be put on functions", pos),
+            ScanError::IntTooLarge(pos, _) => write!(f, "integer literal at byte {} is too large for `int`", pos),
+        }
+    }
+}
+
+impl ScanError {
+    /// Where in the source the error is, unless it's at the end.
+    pub fn span(&self) -> Option<(usize, usize)> {
+        match self {
+            ScanError::BadSymbol(pos)
+            | ScanError::InvalidToken(pos)
+            | ScanError::UnknownAttribute(pos, _)
+            | ScanError::MisplacedAttribute(pos) => Some((*pos, *pos + 1)),
+            ScanError::UnrecognizedToken(Some((lo, _, hi)), _) | ScanError::ExtraToken(lo, _, hi) | ScanError::IntTooLarge(lo, hi) => {
+                Some((*lo, *hi))
+            }
+            ScanError::UnrecognizedToken(None, _) => None,
+        }
+    }
+
+    /// Puts a line break that isn't expected where its line ends, after the
+    /// last of the `tokens` on it, rather than where the next line starts.
+    pub fn at_line_end(self, tokens: &[ScanOutput]) -> Self {
+        let layout = |token: &Token| matches!(token, Token::Sep | Token::Indent | Token::Dedent);
+        let line_end = |pos: usize| {
+            tokens
+                .iter()
+                .rev()
+                .filter_map(|token| token.as_ref().ok())
+                .find(|(_, token, end)| *end <= pos && !layout(token))
+                .map(|(_, _, end)| *end)
+        };
+        match self {
+            ScanError::UnrecognizedToken(Some((lo, token, hi)), expected) if layout(&token) => {
+                let (lo, hi) = line_end(lo).map_or((lo, hi), |end| (end, end));
+                ScanError::UnrecognizedToken(Some((lo, token, hi)), expected)
+            }
+            ScanError::ExtraToken(lo, token, hi) if layout(&token) => {
+                let (lo, hi) = line_end(lo).map_or((lo, hi), |end| (end, end));
+                ScanError::ExtraToken(lo, token, hi)
+            }
+            err => err,
         }
     }
 }
@@ -150,9 +223,8 @@ impl<I: Read> Iterator for Scanner<I> {
     type Item = ScanOutput;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.queue.pop_front() {
-            Some(token) => return Some(token),
-            None => (),
+        if let Some(token) = self.queue.pop_front() {
+            return Some(token);
         }
 
         let mut line = String::new();
@@ -162,6 +234,7 @@ impl<I: Read> Iterator for Scanner<I> {
             match self.input.read_line(&mut line) {
                 Ok(n) => {
                     off += n;
+                    strip_comment(&mut line);
                     if n == 0 {
                         while self.indents.len() > 1 {
                             self.indents.pop();
@@ -169,7 +242,7 @@ impl<I: Read> Iterator for Scanner<I> {
                                 .push_back(Ok((self.pos, Token::Dedent, self.pos)));
                         }
                         return self.queue.pop_front();
-                    } else if line.trim().len() == 0 {
+                    } else if line.trim().is_empty() {
                         break;
                     } else if line.ends_with("\\\r\n") || line.ends_with("\\\n") {
                         line = line.trim_end().trim_end_matches('\\').to_owned();
@@ -183,7 +256,7 @@ impl<I: Read> Iterator for Scanner<I> {
             }
         }
 
-        if line.trim().len() > 0 {
+        if !line.trim().is_empty() {
             let whitespace = match WHITESPACE.find(&line) {
                 Some(mat) => mat.end() - mat.start(),
                 None => 0,
@@ -196,7 +269,7 @@ impl<I: Read> Iterator for Scanner<I> {
                             .indents
                             .binary_search(&whitespace)
                             .expect("inconsistent indentation");
-                        for i in ind..self.indents.len() - 1 {
+                        for _ in ind..self.indents.len() - 1 {
                             self.indents.pop();
                             self.queue
                                 .push_back(Ok((self.pos, Token::Dedent, self.pos)));
@@ -216,8 +289,9 @@ impl<I: Read> Iterator for Scanner<I> {
             self.queue.push_back(Ok((self.pos, Token::Sep, self.pos)));
         }
 
-        line = line.trim().to_owned();
-        let mut end = 0;
+        // positions count from the start of the line, before the indentation
+        let mut end = line.len() - line.trim_start().len();
+        line = line.trim_end().to_owned();
         'outer: while end < line.len() {
             'inner: for (i, regex) in TABLE.iter().enumerate() {
                 match regex.find(&line[end..]) {
@@ -226,15 +300,22 @@ impl<I: Read> Iterator for Scanner<I> {
                         let hi = mat.end();
                         let tok = match i {
                             0 => match mat.as_str() {
+                                "assert" => Token::KwdAssert,
+                                "assert_eq" => Token::KwdAssertEq,
                                 "class" => Token::KwdClass,
+                                "enum" => Token::KwdEnum,
                                 "extern" => Token::KwdExtern,
                                 "for" => Token::KwdFor,
                                 "fn" => Token::KwdFn,
                                 "if" => Token::KwdIf,
                                 "in" => Token::KwdIn,
+                                "instance" => Token::KwdInstance,
                                 "let" => Token::KwdLet,
                                 "match" => Token::KwdMatch,
+                                "pub" => Token::KwdPub,
                                 "return" => Token::KwdReturn,
+                                "struct" => Token::KwdStruct,
+                                "test" => Token::KwdTest,
                                 "use" => Token::KwdUse,
                                 name => Token::Ident(name.into()),
                             },
@@ -261,7 +342,10 @@ impl<I: Read> Iterator for Scanner<I> {
                             },
                             4 => match mat.as_str() {
                                 "&" => Token::SymAmpersand,
+                                "@" => Token::SymAt,
                                 "!" => Token::SymBang,
+                                "{" => Token::SymBraceL,
+                                "}" => Token::SymBraceR,
                                 "^" => Token::SymCaret,
                                 ":" => Token::SymColon,
                                 "," => Token::SymComma,
@@ -303,14 +387,83 @@ impl<I: Read> Iterator for Scanner<I> {
             break;
         }
         self.pos += off;
-        if self.indents.len() > 1 {
-            self.queue.push_back(Ok((self.pos, Token::Sep, self.pos)));
-        }
+        self.queue.push_back(Ok((self.pos, Token::Sep, self.pos)));
 
-        if line.trim().len() == 0 {
+        if line.trim().is_empty() {
             self.next()
         } else {
             self.queue.pop_front()
         }
     }
 }
+
+/// Where the comment on the line starts, if it has one. A `//` in a string
+/// literal doesn't start a comment.
+fn comment_start(line: &str) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let mut in_string = false;
+    for (i, byte) in bytes.iter().enumerate() {
+        match byte {
+            b'"' => in_string = !in_string,
+            b'/' if !in_string && bytes.get(i + 1) == Some(&b'/') => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Removes the comment from the end of the line, keeping the line break.
+fn strip_comment(line: &mut String) {
+    if let Some(start) = comment_start(line) {
+        let newline = line.ends_with('\n');
+        line.truncate(start);
+        if newline {
+            line.push('\n');
+        }
+    }
+}
+
+/// A `//` comment, which the scanner skips.
+#[derive(Clone, Debug)]
+pub struct Comment {
+    /// The byte the `//` starts at.
+    pub pos: usize,
+    /// The text after the `//`.
+    pub text: String,
+}
+
+/// Finds the comments in the source, in order.
+pub fn comments(source: &str) -> Vec<Comment> {
+    let mut comments = Vec::new();
+    let mut pos = 0;
+    for line in source.split_inclusive('\n') {
+        if let Some(start) = comment_start(line) {
+            comments.push(Comment {
+                pos: pos + start,
+                text: line[start + 2..].trim_end().to_owned(),
+            });
+        }
+        pos += line.len();
+    }
+    comments
+}
+
+/// The line the byte is on, counting from 1.
+pub fn line(source: &str, pos: usize) -> usize {
+    source[..pos].matches('\n').count() + 1
+}
+
+/// The column the byte is at, counting characters from 1.
+pub fn column(source: &str, pos: usize) -> usize {
+    let start = source[..pos].rfind('\n').map_or(0, |i| i + 1);
+    source[start..pos].chars().count() + 1
+}
+
+/// Lists tokens with the bytes they span, for `--emit tokens`.
+pub fn dump(tokens: &[(usize, Token, usize)]) -> String {
+    let mut output = String::new();
+    for (lo, token, hi) in tokens {
+        output.push_str(&format!("{}..{} {:?}\n", lo, hi, token));
+    }
+    output
+}
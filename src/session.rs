@@ -0,0 +1,435 @@
+//! The compiler as a library. A `Session` compiles one program, given as
+//! source text or read from a file, and runs its phases one at a time so that
+//! tools can look at the results in between. Errors are collected as
+//! diagnostics instead of being printed, and a phase that fails returns
+//! `None`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use target_lexicon::Triple;
+
+use crate::adt::AdtTable;
+use crate::ast::Program;
+use crate::codegen::{Codegen, Listing};
+use crate::driver::{self, Checked, Diagnostic, Level};
+use crate::format;
+use crate::harness::{self, Harness};
+use crate::incremental::{self, Cache, Stats};
+use crate::manifest::Packages;
+use crate::mir;
+use crate::mir::interp::Interpreter;
+use crate::mir::opt::{OptLevel, PassManager};
+use crate::module::{self, Module, ModuleError, ModuleTree, SourcePackage};
+use crate::scanner::{Scanner, Token};
+use crate::timing::{Timer, Timings};
+
+/// A program lowered to mir, with the types it uses.
+pub struct Lowered {
+    pub adts: AdtTable,
+    pub program: mir::Program,
+}
+
+pub struct Session {
+    file: PathBuf,
+    source: String,
+    target: Triple,
+    opt_level: OptLevel,
+    jobs: usize,
+    cache: Option<Cache>,
+    /// Where modules are looked up; next to the file when empty.
+    packages: Vec<SourcePackage>,
+    libraries: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+    /// Started when the session was created, to time all of it.
+    created: Timer,
+    timings: Timings,
+}
+
+impl Session {
+    /// Creates a session for the source of a root module. The modules it uses
+    /// are looked up next to `file`, which doesn't need to exist.
+    pub fn new(file: impl Into<PathBuf>, source: impl Into<String>) -> Self {
+        Session {
+            file: file.into(),
+            source: source.into(),
+            target: "x86_64-unknown-unknown-elf".parse().unwrap(),
+            opt_level: OptLevel::O0,
+            jobs: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            cache: None,
+            packages: Vec::new(),
+            libraries: Vec::new(),
+            diagnostics: Vec::new(),
+            created: Timer::start(),
+            timings: Timings::default(),
+        }
+    }
+
+    /// Creates a session for a source file, or a `.mir` file for `parse_mir`.
+    pub fn from_file(file: impl Into<PathBuf>) -> io::Result<Self> {
+        let file = file.into();
+        let source = fs::read_to_string(&file)?;
+        Ok(Session::new(file, source))
+    }
+
+    pub fn target(mut self, target: Triple) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    /// Looks modules up in the packages instead of next to the file, and takes
+    /// the target and the optimization level from the manifest of the root
+    /// package when it has them.
+    pub fn package(mut self, packages: &Packages) -> Self {
+        let manifest = &packages.root().manifest;
+        if let Some(target) = &manifest.target {
+            self.target = target.clone();
+        }
+        if let Some(opt_level) = manifest.opt_level {
+            self.opt_level = opt_level;
+        }
+        self.packages = packages.source_packages();
+        self.libraries = packages.libraries();
+        self
+    }
+
+    /// The number of threads that functions are compiled on, one for each
+    /// processor by default.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Keeps the types and the machine code of functions in `dir`, and reuses
+    /// those of the functions that didn't change since they were put there.
+    pub fn incremental(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache = Some(Cache::new(dir));
+        self
+    }
+
+    /// What the incremental cache was used for, if there is one.
+    pub fn cache_stats(&self) -> Option<&Stats> {
+        self.cache.as_ref().map(|cache| &cache.stats)
+    }
+
+    /// How long every phase run so far took and what it allocated, and then
+    /// the `total` since the session was created.
+    pub fn timings(&self) -> Timings {
+        let mut timings = self.timings.clone();
+        timings.record("total", self.created);
+        timings
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The C libraries the program is linked with, from the manifests of its
+    /// packages.
+    pub fn libraries(&self) -> &[String] {
+        &self.libraries
+    }
+
+    /// The errors and warnings found so far.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Removes the diagnostics found so far, to report them as they come.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.level == Level::Error)
+    }
+
+    /// The bytes of the root module a diagnostic is about, if it's about a
+    /// part of it.
+    pub fn locate(&self, diagnostic: &Diagnostic) -> Option<(usize, usize)> {
+        let span = diagnostic.span?;
+        if span.file.as_str() != self.file.display().to_string() {
+            return None;
+        }
+        let len = self.source.len();
+        Some((span.lo.min(len), span.hi.min(len)))
+    }
+
+    /// The message of a diagnostic without the file name that the ones about
+    /// the root module start with.
+    pub fn message<'a>(&self, diagnostic: &'a Diagnostic) -> &'a str {
+        let file = self.file.display().to_string();
+        diagnostic
+            .message
+            .strip_prefix(file.as_str())
+            .and_then(|message| message.strip_prefix(": "))
+            .unwrap_or(&diagnostic.message)
+    }
+
+    fn error<T>(&mut self, err: impl ToString) -> Option<T> {
+        self.diagnostics.push(Diagnostic::error(err));
+        None
+    }
+
+    /// Adds diagnostics, finding where their spans are in the files they're
+    /// about.
+    fn add(&mut self, diagnostics: impl IntoIterator<Item = Diagnostic>) {
+        let file = self.file.display().to_string();
+        for mut diagnostic in diagnostics {
+            match diagnostic.span {
+                Some(span) if span.file.as_str() == file => diagnostic.locate(&self.source),
+                // the modules the root module imports are read again
+                Some(span) => {
+                    if let Ok(source) = fs::read_to_string(span.file.as_str()) {
+                        diagnostic.locate(&source);
+                    }
+                }
+                None => (),
+            }
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    /// The tokens of the root module with the bytes they span.
+    pub fn scan(&mut self) -> Option<Vec<(usize, Token, usize)>> {
+        let tokens: Result<Vec<_>, _> = Scanner::new(self.source.as_bytes()).collect();
+        match tokens {
+            Ok(tokens) => Some(tokens),
+            Err(err) => self.error(format!("{}: {}", self.file.display(), err)),
+        }
+    }
+
+    /// Parses the root module and the modules it uses, and links them into a
+    /// single program.
+    pub fn parse(&mut self) -> Option<Program> {
+        let (file, timings) = (&self.file, &mut self.timings);
+        let packages = if self.packages.is_empty() {
+            SourcePackage::next_to(file)
+        } else {
+            self.packages.clone()
+        };
+        let linked = module::parse_source(file, &self.source, timings)
+            .and_then(|program| module::load_package_imports(file, program, &packages, timings))
+            .and_then(ModuleTree::link);
+        match linked {
+            Ok(ast) => Some(ast),
+            Err(err) => self.module_error(err),
+        }
+    }
+
+    /// Parses the root module on its own, without looking at the modules it
+    /// uses.
+    pub fn parse_module(&mut self) -> Option<Module> {
+        match module::parse_source(&self.file, &self.source, &mut self.timings) {
+            Ok(program) => Some(Module {
+                path: Vec::new(),
+                file: self.file.clone(),
+                program,
+            }),
+            Err(err) => self.module_error(err),
+        }
+    }
+
+    fn module_error<T>(&mut self, err: ModuleError) -> Option<T> {
+        let span = err.span();
+        self.add(Some(Diagnostic::error(err).at(span)));
+        None
+    }
+
+    /// The root module in the canonical style, with its comments.
+    pub fn format(&mut self) -> Option<String> {
+        let module = self.parse_module()?;
+        Some(format::format(&module.program, &self.source))
+    }
+
+    pub fn typecheck(&mut self, ast: Program) -> Option<Checked> {
+        let checked = driver::check_with(ast, self.cache.as_mut(), &mut self.timings);
+        self.report_cache();
+        match checked {
+            Ok(mut checked) => {
+                self.add(std::mem::take(&mut checked.warnings));
+                Some(checked)
+            }
+            Err(diagnostics) => {
+                self.add(diagnostics);
+                None
+            }
+        }
+    }
+
+    /// Warns about the cache not being written to.
+    fn report_cache(&mut self) {
+        if let Some(err) = self.cache.as_mut().and_then(Cache::take_error) {
+            let dir = self.cache.as_ref().unwrap().dir().display().to_string();
+            let warning = format!("can't write to the incremental cache in {}: {}", dir, err);
+            self.diagnostics.push(Diagnostic::warning(warning));
+        }
+    }
+
+    pub fn lower(&mut self, checked: Checked) -> Option<Lowered> {
+        let timer = Timer::start();
+        let program = checked.lower();
+        self.timings.record("lower", timer);
+        let program = match program {
+            Ok(program) => program,
+            Err(err) => return self.error(err),
+        };
+        self.validate(Lowered {
+            adts: checked.adts,
+            program,
+        })
+    }
+
+    /// Compiles the program with its tests turned into functions, for `mochi
+    /// test`.
+    pub fn harness(&mut self) -> Option<(Harness, Lowered)> {
+        let mut ast = self.parse()?;
+        let harness = match harness::build(&mut ast) {
+            Ok(harness) => harness,
+            Err(err) => return self.error(err),
+        };
+        let checked = self.typecheck(ast)?;
+        let mut lowered = self.lower(checked)?;
+        harness.export(&mut lowered.program);
+        Some((harness, lowered))
+    }
+
+    /// Reads the source as mir, as written by `--emit mir`, instead of
+    /// compiling it.
+    pub fn parse_mir(&mut self) -> Option<Lowered> {
+        match mir::parse(&self.source) {
+            Ok((adts, program)) => self.validate(Lowered { adts, program }),
+            Err(err) => self.error(format!("{}:{}", self.file.display(), err)),
+        }
+    }
+
+    fn validate(&mut self, lowered: Lowered) -> Option<Lowered> {
+        match mir::validate(&lowered.adts, &lowered.program) {
+            Ok(()) => Some(lowered),
+            Err(err) => self.error(err),
+        }
+    }
+
+    /// Optimizes the program at the session's level, and returns what every
+    /// pass did.
+    pub fn optimize(&mut self, lowered: &mut Lowered) -> PassManager {
+        let mut passes = PassManager::new(self.opt_level);
+        passes.run(&lowered.adts, &mut lowered.program);
+        self.timings.extend("opt ", passes.timings());
+        if let Err(err) = mir::validate(&lowered.adts, &lowered.program) {
+            panic!("optimizations produced invalid mir: {}", err);
+        }
+        passes
+    }
+
+    /// Generates machine code for every function, along with the listings.
+    /// `Codegen::finish` returns the object file.
+    pub fn codegen(&mut self, lowered: &Lowered, listings: &[Listing]) -> Option<Codegen> {
+        let mut codegen = match Codegen::new(self.target.clone()) {
+            Ok(codegen) => codegen,
+            Err(err) => return self.error(err),
+        };
+        for &listing in listings {
+            codegen = codegen.listing(listing);
+        }
+        for decl in &lowered.program.0 {
+            codegen.declare_decl(&lowered.adts, decl);
+        }
+        // listings are only written for functions that are compiled
+        let mut cache = self.cache.as_mut().filter(|_| listings.is_empty());
+        let fingerprints = match &cache {
+            Some(_) => incremental::code_fingerprints(&self.target, &lowered.adts, &lowered.program),
+            None => HashMap::new(),
+        };
+        let funcs: Vec<_> = lowered
+            .program
+            .0
+            .iter()
+            .filter_map(|decl| match decl {
+                mir::Decl::Func(func) => Some(func),
+                mir::Decl::Extern(_, _, _) => None,
+            })
+            .collect();
+        let cached: Vec<_> = funcs
+            .iter()
+            .map(|func| match (&cache, fingerprints.get(&func.name)) {
+                (Some(cache), Some(&fingerprint)) => cache.code(fingerprint),
+                _ => None,
+            })
+            .collect();
+        let missing: Vec<_> = funcs
+            .iter()
+            .zip(&cached)
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(func, _)| *func)
+            .collect();
+        // functions are compiled in parallel but put in the object file in
+        // order, so that it's the same however many threads there are
+        let mut compiled = match codegen.compile_funcs(&lowered.adts, &missing, self.jobs) {
+            Ok(compiled) => compiled.into_iter(),
+            Err(err) => return self.error(err),
+        };
+        for (func, cached) in funcs.iter().zip(cached) {
+            let code = match cached {
+                Some(code) => {
+                    cache.as_mut().unwrap().stats.code_reused.push(func.name.clone());
+                    code
+                }
+                None => {
+                    let code = compiled.next().unwrap();
+                    if let Some(cache) = cache.as_mut() {
+                        cache.store_code(fingerprints[&func.name], &code);
+                        cache.stats.compiled.push(func.name.clone());
+                    }
+                    code
+                }
+            };
+            codegen.define_func(&func.name, code);
+        }
+        self.timings.extend("cranelift ", codegen.timings());
+        self.report_cache();
+        Some(codegen)
+    }
+
+    /// Writes the object file of the generated code.
+    pub fn emit(&mut self, codegen: Codegen) -> Vec<u8> {
+        let timer = Timer::start();
+        let object = codegen.finish();
+        self.timings.record("emit", timer);
+        object
+    }
+
+    /// Runs every phase and returns the object file.
+    pub fn compile(&mut self) -> Option<Vec<u8>> {
+        let ast = self.parse()?;
+        let checked = self.typecheck(ast)?;
+        let mut lowered = self.lower(checked)?;
+        self.optimize(&mut lowered);
+        let codegen = self.codegen(&lowered, &[])?;
+        Some(self.emit(codegen))
+    }
+
+    /// Runs the program with the mir interpreter, and returns its exit
+    /// status.
+    pub fn interpret(&mut self, lowered: &Lowered) -> Option<i64> {
+        match Interpreter::new(&lowered.adts, &lowered.program).run_main() {
+            Ok(code) => Some(code),
+            Err(err) => self.error(err),
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! Where compile time goes, for `-Z time-passes`. Every phase is timed with a
+//! `Timer`, which also counts the memory the phase allocates when the program
+//! uses `CountingAlloc` as its allocator, and phases that run several times,
+//! like typechecking every group of functions, add up under one name.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+thread_local! {
+    /// The allocations made on this thread so far, and their bytes.
+    static ALLOCATED: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+}
+
+/// The system allocator, counting the allocations of every thread so that
+/// phases running on several threads at once are counted apart.
+pub struct CountingAlloc;
+
+fn count(bytes: usize) {
+    // a thread that is exiting has no counters left
+    let _ = ALLOCATED.try_with(|allocated| {
+        let (allocations, total) = allocated.get();
+        allocated.set((allocations + 1, total + bytes as u64));
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Measures a phase from when it's started to when it's recorded, on the
+/// thread it was started on.
+#[derive(Clone, Copy)]
+pub struct Timer {
+    start: Instant,
+    allocated: (u64, u64),
+}
+
+impl Timer {
+    pub fn start() -> Self {
+        Timer {
+            start: Instant::now(),
+            allocated: ALLOCATED.with(Cell::get),
+        }
+    }
+
+    /// What happened since the timer was started, as the phase `name`.
+    pub fn stop(self, name: &str) -> Phase {
+        let time = self.start.elapsed();
+        let (allocations, bytes) = ALLOCATED.with(Cell::get);
+        Phase {
+            name: name.to_owned(),
+            time,
+            allocations: allocations - self.allocated.0,
+            bytes: bytes - self.allocated.1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Phase {
+    pub name: String,
+    pub time: Duration,
+    pub allocations: u64,
+    pub bytes: u64,
+}
+
+/// The phases that were timed, in the order they first ran.
+#[derive(Clone, Debug, Default)]
+pub struct Timings {
+    phases: Vec<Phase>,
+    index: HashMap<String, usize>,
+}
+
+impl Timings {
+    /// Adds what happened since the timer was started to the phase `name`.
+    pub fn record(&mut self, name: &str, timer: Timer) {
+        self.add(timer.stop(name));
+    }
+
+    /// Adds a phase, to the one with the same name if there is one.
+    pub fn add(&mut self, phase: Phase) {
+        match self.index.get(&phase.name) {
+            Some(&i) => {
+                let total = &mut self.phases[i];
+                total.time += phase.time;
+                total.allocations += phase.allocations;
+                total.bytes += phase.bytes;
+            }
+            None => {
+                self.index.insert(phase.name.clone(), self.phases.len());
+                self.phases.push(phase);
+            }
+        }
+    }
+
+    /// Adds the phases of `other`, with their names after `prefix`.
+    pub fn extend(&mut self, prefix: &str, other: &Timings) {
+        for phase in &other.phases {
+            self.add(Phase {
+                name: format!("{}{}", prefix, phase.name),
+                ..phase.clone()
+            });
+        }
+    }
+
+    pub fn phases(&self) -> &[Phase] {
+        &self.phases
+    }
+
+    /// The phases as a json array of objects with the `name` of each, its
+    /// `seconds`, and the `allocations` and `bytes` it made.
+    pub fn to_json(&self) -> Value {
+        let phases = self.phases.iter().map(|phase| {
+            json!({
+                "name": phase.name,
+                "seconds": phase.time.as_secs_f64(),
+                "allocations": phase.allocations,
+                "bytes": phase.bytes,
+            })
+        });
+        Value::Array(phases.collect())
+    }
+}
+
+impl fmt::Display for Timings {
+    /// Formats the phases as a table, with their times in milliseconds.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.phases.iter().map(|phase| phase.name.len()).max().unwrap_or(0).max(5);
+        writeln!(f, "{:<width$} {:>12} {:>12} {:>12}", "phase", "time (ms)", "allocations", "bytes", width = width)?;
+        for phase in &self.phases {
+            writeln!(
+                f,
+                "{:<width$} {:>12.3} {:>12} {:>12}",
+                phase.name,
+                phase.time.as_secs_f64() * 1000.0,
+                phase.allocations,
+                phase.bytes,
+                width = width
+            )?;
+        }
+        Ok(())
+    }
+}
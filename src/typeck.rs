@@ -1,41 +1,1015 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 use symbol::Symbol;
 
+use crate::adt::AdtTable;
+use crate::class::ClassTable;
 use crate::env::Environment;
-use crate::ast::{Decl as AstDecl, Expr as AstExpr, Stmt as AstStmt, Type};
+use crate::incremental::{self, Cache, FuncTypes};
+use crate::timing::{Timer, Timings};
+use crate::ast::{BinOp, Decl as AstDecl, Expr as AstExpr, Func as AstFunc, Pattern as AstPattern, Program, Span, Spanned, Stmt as AstStmt, StmtKind as AstStmtKind, Type, UnOp};
 
 #[derive(Debug, Hash, Eq, PartialEq)]
-pub struct Constraint(pub Type, pub Type);
+pub enum Constraint {
+    /// The two types are the same.
+    Equal(Type, Type),
+    /// The first type is a struct with the field, which has the second type.
+    /// Solved once the struct is known, since the field alone may not tell.
+    Field(Type, Symbol, Type),
+}
+
+pub type Substitution = HashMap<Symbol, Type>;
+
+/// The arguments and local variables of every function, with their types, in
+/// the order they are bound.
+pub type Locals = HashMap<Symbol, Vec<(Symbol, Type)>>;
+
+/// What a name refers to where it's used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Binding {
+    /// The argument or local variable of the function at this index of its
+    /// locals.
+    Local(usize),
+    /// A top-level item, by the name it has once modules are linked.
+    Global(Symbol),
+}
+
+/// The names every function uses, with the statement each use is in, in the
+/// order they are used. Functions whose types come from the cache have none.
+pub type References = HashMap<Symbol, Vec<(Span, Binding)>>;
+
+/// The constraints on the types of a group of functions, in the order they
+/// were written, and the variables they bind and the names they use.
+pub struct Constraints {
+    /// Each constraint with the statement or function it comes from.
+    pub list: Vec<(Constraint, Span)>,
+    pub locals: Vec<(Symbol, Type)>,
+    pub references: Vec<(Span, Binding)>,
+    /// The index in `locals` of every variable in scope, which the type
+    /// environment has in the same scopes.
+    scopes: Environment<Symbol, usize>,
+    /// Where the constraints being added come from.
+    span: Span,
+}
+
+impl Constraints {
+    pub fn new(span: Span) -> Self {
+        Constraints {
+            list: Vec::new(),
+            locals: Vec::new(),
+            references: Vec::new(),
+            scopes: Environment::new(),
+            span,
+        }
+    }
+
+    pub fn insert(&mut self, constraint: Constraint) {
+        self.list.push((constraint, self.span));
+    }
+
+    fn bind(&mut self, type_env: &mut Environment<Symbol, Type>, name: Symbol, ty: Type) {
+        type_env.insert(name, ty.clone());
+        self.scopes.insert(name, self.locals.len());
+        self.locals.push((name, ty));
+    }
+
+    fn push_scope(&mut self, type_env: &mut Environment<Symbol, Type>) {
+        type_env.push_scope();
+        self.scopes.push_scope();
+    }
+
+    fn pop_scope(&mut self, type_env: &mut Environment<Symbol, Type>) {
+        type_env.pop_scope();
+        self.scopes.pop_scope();
+    }
+
+    /// Records the use of a name in the statement the constraints come from.
+    fn refer(&mut self, name: Symbol) {
+        let binding = match self.scopes.lookup(name) {
+            Some(&local) => Binding::Local(local),
+            None => Binding::Global(name),
+        };
+        self.references.push((self.span, binding));
+    }
+}
+
+#[derive(Debug)]
+pub enum TypeError {
+    UnboundName(Symbol),
+    UnknownType(Symbol),
+    UnknownCtor(Symbol),
+    CtorArity(Symbol, usize, usize),
+    Mismatch(Type, Type),
+    Recursive(Symbol, Type),
+    UnknownStruct(Symbol),
+    UnknownField(Type, Symbol),
+    AmbiguousField(Symbol),
+    MissingField(Symbol, Symbol),
+    DuplicateField(Symbol, Symbol),
+    InvalidAssignment,
+    /// A function that doesn't return unit but can reach the end of its body.
+    MissingReturn(Symbol, Type),
+    NoInstance(Symbol, Type),
+    /// A class is used at a type that isn't known, by a method or by a
+    /// function that needs an instance of it.
+    AmbiguousInstance(Symbol, Symbol),
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypeError::UnboundName(name) => write!(f, "cannot find `{}` in this scope", name),
+            TypeError::UnknownType(name) => write!(f, "cannot find type `{}`", name),
+            TypeError::UnknownCtor(name) => write!(f, "`{}` is not a constructor", name),
+            TypeError::CtorArity(name, expected, got) => write!(
+                f,
+                "constructor `{}` takes {} field(s) but the pattern has {}",
+                name, expected, got
+            ),
+            TypeError::Mismatch(a, b) => write!(f, "mismatched types: `{}` and `{}`", a, b),
+            TypeError::Recursive(var, ty) => {
+                write!(f, "cannot construct the infinite type `{}` = `{}`", Type::Var(*var), ty)
+            }
+            TypeError::UnknownStruct(name) => write!(f, "`{}` is not a struct", name),
+            TypeError::UnknownField(ty, field) => write!(f, "`{}` has no field `{}`", ty, field),
+            TypeError::AmbiguousField(field) => write!(
+                f,
+                "several structs have a field `{}`; annotate the type of the argument the value comes from",
+                field
+            ),
+            TypeError::MissingField(name, field) => {
+                write!(f, "missing field `{}` in `{}` literal", field, name)
+            }
+            TypeError::DuplicateField(name, field) => {
+                write!(f, "field `{}` is given more than once in `{}` literal", field, name)
+            }
+            TypeError::MissingReturn(name, ty) => write!(
+                f,
+                "missing return in `{}`: it returns `{}` but can reach its end",
+                name, ty
+            ),
+            TypeError::InvalidAssignment => {
+                write!(f, "only variables and struct fields can be assigned to")
+            }
+            TypeError::NoInstance(class, ty) => write!(f, "no instance of `{}` for `{}`", class, ty),
+            TypeError::AmbiguousInstance(class, name) => write!(
+                f,
+                "can't tell which instance of `{}` to use for `{}`; annotate the types of its arguments",
+                class, name
+            ),
+        }
+    }
+}
+
+/// The variable under which the return type of the enclosing function is
+/// stored. `return` is a keyword, so it can't clash with a user binding.
+fn return_symbol() -> Symbol {
+    Symbol::from("return")
+}
+
+/// Replaces type names that appear in signatures with the types they refer to.
+pub fn resolve_type(type_env: &Environment<Symbol, Type>, adts: &AdtTable, ty: &Type) -> Result<Type, TypeError> {
+    match ty {
+        Type::Name(name) => {
+            if adts.is_type(*name) {
+                return Ok(ty.clone());
+            }
+            match type_env.lookup(*name) {
+                Some(ty @ Type::Int) | Some(ty @ Type::Bool) | Some(ty @ Type::Unit) => Ok(ty.clone()),
+                _ => Err(TypeError::UnknownType(*name)),
+            }
+        }
+        Type::Func(args, ret) => {
+            let args = args
+                .iter()
+                .map(|arg| resolve_type(type_env, adts, arg))
+                .collect::<Result<_, _>>()?;
+            let ret = resolve_type(type_env, adts, ret)?;
+            Ok(Type::Func(args, Box::new(ret)))
+        }
+        Type::Var(_) | Type::Unit | Type::Int | Type::Bool => Ok(ty.clone()),
+    }
+}
+
+/// Resolves the field types of every enum variant and struct.
+pub fn resolve_adts(type_env: &Environment<Symbol, Type>, adts: &mut AdtTable) -> Result<(), TypeError> {
+    let resolved = adts
+        .field_types()
+        .map(|ty| resolve_type(type_env, adts, ty))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (field, ty) in adts.field_types_mut().zip(resolved) {
+        *field = ty;
+    }
+    Ok(())
+}
 
 // get constraints
 
-pub fn get_constraints_decl(type_env: &mut Environment<Symbol, Type>, constraints: &mut HashSet<Constraint>, decl: &AstDecl) {
-    match decl {
-        AstDecl::Extern(_, _, _) => (),
-        AstDecl::Func(func) => {
-            for stmt in &func.body {
-                get_constraints_stmt(type_env, constraints, stmt);
+/// Adds the constraints of a function and returns its return type.
+fn get_constraints_func(type_env: &mut Environment<Symbol, Type>, adts: &AdtTable, constraints: &mut Constraints, func: &AstFunc) -> Result<Type, Spanned<TypeError>> {
+    let at_func = |err| Spanned::new(err, func.span);
+    constraints.span = func.span;
+    constraints.push_scope(type_env);
+    for (name, ty) in &func.args {
+        let ty = resolve_type(type_env, adts, ty).map_err(at_func)?;
+        constraints.bind(type_env, *name, ty);
+    }
+    let returns = resolve_type(type_env, adts, &func.returns).map_err(at_func)?;
+    if let (Type::Var(_), false) = (&func.returns, has_return(&func.body)) {
+        constraints.insert(Constraint::Equal(returns.clone(), Type::Unit));
+    }
+    type_env.insert(return_symbol(), returns.clone());
+    get_constraints_body(type_env, adts, constraints, &func.body)?;
+    constraints.pop_scope(type_env);
+    Ok(returns)
+}
+
+/// Whether any of the statements returns a value. Functions without a return
+/// type that don't return anything return unit.
+fn has_return(stmts: &[AstStmt]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.kind {
+        AstStmtKind::Return(_) => true,
+        AstStmtKind::If(_, tbody, fbody) => has_return(tbody) || has_return(fbody),
+        AstStmtKind::Match(_, arms) => arms.iter().any(|arm| has_return(&arm.body)),
+        AstStmtKind::Expr(_) | AstStmtKind::Let(_, _) | AstStmtKind::Assign(_, _) | AstStmtKind::Assert(_) => false,
+    })
+}
+
+/// Whether the statements return on every path, so that the end can't be
+/// reached. Only functions returning unit may reach their end.
+fn always_returns(stmts: &[AstStmt]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.kind {
+        AstStmtKind::Return(_) => true,
+        AstStmtKind::If(_, tbody, fbody) => always_returns(tbody) && always_returns(fbody),
+        AstStmtKind::Match(_, arms) => arms.iter().all(|arm| always_returns(&arm.body)),
+        AstStmtKind::Expr(_) | AstStmtKind::Let(_, _) | AstStmtKind::Assign(_, _) | AstStmtKind::Assert(_) => false,
+    })
+}
+
+/// Adds the constraints of a block, leaving those added after it to come
+/// from the statement or function around it.
+fn get_constraints_body(type_env: &mut Environment<Symbol, Type>, adts: &AdtTable, constraints: &mut Constraints, body: &[AstStmt]) -> Result<(), Spanned<TypeError>> {
+    let span = constraints.span;
+    for stmt in body {
+        get_constraints_stmt(type_env, adts, constraints, stmt)?;
+    }
+    constraints.span = span;
+    Ok(())
+}
+
+/// Adds the constraints of a statement. Errors are about the innermost
+/// statement they're found in.
+pub fn get_constraints_stmt(type_env: &mut Environment<Symbol, Type>, adts: &AdtTable, constraints: &mut Constraints, stmt: &AstStmt) -> Result<(), Spanned<TypeError>> {
+    let at_stmt = |err| Spanned::new(err, stmt.span);
+    constraints.span = stmt.span;
+    match &stmt.kind {
+        AstStmtKind::Expr(expr) => {
+            get_constraints_expr(type_env, adts, constraints, expr).map_err(at_stmt)?;
+        }
+        AstStmtKind::Let(name, expr) => {
+            let ty = get_constraints_expr(type_env, adts, constraints, expr).map_err(at_stmt)?;
+            constraints.bind(type_env, *name, ty);
+        }
+        AstStmtKind::Assign(target, expr) => {
+            match target {
+                AstExpr::Ident(_) | AstExpr::Field(_, _) => (),
+                _ => return Err(at_stmt(TypeError::InvalidAssignment)),
+            }
+            let target = get_constraints_expr(type_env, adts, constraints, target).map_err(at_stmt)?;
+            let ty = get_constraints_expr(type_env, adts, constraints, expr).map_err(at_stmt)?;
+            constraints.insert(Constraint::Equal(target, ty));
+        }
+        AstStmtKind::Return(expr) => {
+            let ty = get_constraints_expr(type_env, adts, constraints, expr).map_err(at_stmt)?;
+            let returns = type_env.lookup(return_symbol()).cloned().expect("return outside of a function");
+            constraints.insert(Constraint::Equal(returns, ty));
+        }
+        AstStmtKind::If(cond, tbody, fbody) => {
+            let ty = get_constraints_expr(type_env, adts, constraints, cond).map_err(at_stmt)?;
+            constraints.insert(Constraint::Equal(ty, Type::Bool));
+            for body in &[tbody, fbody] {
+                constraints.push_scope(type_env);
+                get_constraints_body(type_env, adts, constraints, body)?;
+                constraints.pop_scope(type_env);
+            }
+        }
+        AstStmtKind::Match(expr, arms) => {
+            let ty = get_constraints_expr(type_env, adts, constraints, expr).map_err(at_stmt)?;
+            for arm in arms {
+                constraints.push_scope(type_env);
+                let pattern_ty = get_constraints_pattern(type_env, adts, constraints, &arm.pattern).map_err(at_stmt)?;
+                constraints.insert(Constraint::Equal(ty.clone(), pattern_ty));
+                get_constraints_body(type_env, adts, constraints, &arm.body)?;
+                constraints.pop_scope(type_env);
+            }
+        }
+        AstStmtKind::Assert(_) => unreachable!("assertions are taken out before typechecking"),
+    }
+    Ok(())
+}
+
+/// Binds the variables of the pattern in the current scope and returns the
+/// type of value the pattern matches against.
+pub fn get_constraints_pattern(type_env: &mut Environment<Symbol, Type>, adts: &AdtTable, constraints: &mut Constraints, pattern: &AstPattern) -> Result<Type, TypeError> {
+    match pattern {
+        AstPattern::Wildcard => Ok(Type::gen()),
+        AstPattern::Int(_) => Ok(Type::Int),
+        AstPattern::Ident(name) => match adts.get_ctor(*name) {
+            Some(ctor) if ctor.fields.is_empty() => Ok(Type::Name(ctor.enum_name)),
+            Some(ctor) => Err(TypeError::CtorArity(*name, ctor.fields.len(), 0)),
+            None => {
+                let ty = Type::gen();
+                constraints.bind(type_env, *name, ty.clone());
+                Ok(ty)
+            }
+        },
+        AstPattern::Ctor(name, args) => {
+            let ctor = adts.get_ctor(*name).ok_or(TypeError::UnknownCtor(*name))?;
+            if ctor.fields.len() != args.len() {
+                return Err(TypeError::CtorArity(*name, ctor.fields.len(), args.len()));
+            }
+            for (field, arg) in ctor.fields.iter().zip(args) {
+                let ty = get_constraints_pattern(type_env, adts, constraints, arg)?;
+                constraints.insert(Constraint::Equal(field.clone(), ty));
+            }
+            Ok(Type::Name(ctor.enum_name))
+        }
+    }
+}
+
+pub fn get_constraints_expr(type_env: &mut Environment<Symbol, Type>, adts: &AdtTable, constraints: &mut Constraints, expr: &AstExpr) -> Result<Type, TypeError> {
+    match expr {
+        AstExpr::Int(_) => Ok(Type::Int),
+        AstExpr::Ident(name) => {
+            let ty = type_env.lookup(*name).cloned().ok_or(TypeError::UnboundName(*name))?;
+            constraints.refer(*name);
+            Ok(ty)
+        }
+        AstExpr::Method(name, _, ty) | AstExpr::Global(name, ty) => {
+            constraints.refer(*name);
+            Ok(ty.clone())
+        }
+        AstExpr::Call(func, args) => {
+            let func = get_constraints_expr(type_env, adts, constraints, func)?;
+            let args = args
+                .iter()
+                .map(|arg| get_constraints_expr(type_env, adts, constraints, arg))
+                .collect::<Result<_, _>>()?;
+            let returns = Type::gen();
+            constraints.insert(Constraint::Equal(func, Type::Func(args, Box::new(returns.clone()))));
+            Ok(returns)
+        }
+        AstExpr::BinOp(op, left, right) => {
+            let (operand, result) = binop_type(op);
+            let left = get_constraints_expr(type_env, adts, constraints, left)?;
+            let right = get_constraints_expr(type_env, adts, constraints, right)?;
+            constraints.insert(Constraint::Equal(left, operand.clone()));
+            constraints.insert(Constraint::Equal(right, operand));
+            Ok(result)
+        }
+        AstExpr::UnOp(op, expr) => {
+            let ty = match op {
+                UnOp::LogicalNot => Type::Bool,
+                UnOp::BitwiseNot => Type::Int,
+            };
+            let expr = get_constraints_expr(type_env, adts, constraints, expr)?;
+            constraints.insert(Constraint::Equal(expr, ty.clone()));
+            Ok(ty)
+        }
+        AstExpr::Struct(name, fields) => {
+            let info = adts.get_struct(*name).ok_or(TypeError::UnknownStruct(*name))?;
+            constraints.refer(*name);
+            for (i, (field, expr)) in fields.iter().enumerate() {
+                if fields[..i].iter().any(|(other, _)| other == field) {
+                    return Err(TypeError::DuplicateField(*name, *field));
+                }
+                let (_, field_ty) = info
+                    .get_field(*field)
+                    .ok_or(TypeError::UnknownField(Type::Name(*name), *field))?;
+                let ty = get_constraints_expr(type_env, adts, constraints, expr)?;
+                constraints.insert(Constraint::Equal(field_ty.clone(), ty));
+            }
+            for (field, _) in &info.fields {
+                if !fields.iter().any(|(other, _)| other == field) {
+                    return Err(TypeError::MissingField(*name, *field));
+                }
+            }
+            Ok(Type::Name(*name))
+        }
+        AstExpr::Field(expr, field) => {
+            let ty = get_constraints_expr(type_env, adts, constraints, expr)?;
+            let field_ty = Type::gen();
+            constraints.insert(Constraint::Field(ty, *field, field_ty.clone()));
+            Ok(field_ty)
+        }
+    }
+}
+
+/// The type of both operands and the type of the result. Comparisons written
+/// in the source are calls to `Eq` and `Ord` by now, those left compare the
+/// results of `cmp`.
+fn binop_type(op: &BinOp) -> (Type, Type) {
+    match op {
+        BinOp::LogicalOr | BinOp::LogicalAnd => (Type::Bool, Type::Bool),
+        BinOp::Equals
+        | BinOp::NotEquals
+        | BinOp::LessThan
+        | BinOp::LessThanEquals
+        | BinOp::GreaterThan
+        | BinOp::GreaterThanEquals => (Type::Int, Type::Bool),
+        BinOp::BitwiseOr
+        | BinOp::BitwiseXor
+        | BinOp::BitwiseAnd
+        | BinOp::LeftShift
+        | BinOp::RightShift
+        | BinOp::Add
+        | BinOp::Sub
+        | BinOp::Mul
+        | BinOp::Div
+        | BinOp::Mod => (Type::Int, Type::Int),
+    }
+}
+
+// infer whole programs
+
+/// Infers the types of every function in the program. Functions are checked
+/// callees first, one group of mutually recursive functions at a time, and
+/// each group is generalized once solved: the type variables left in its
+/// signatures can be instantiated differently at every use outside the group.
+/// Returns the solution along with the variables of every function and the
+/// names it uses.
+pub fn check_program(type_env: &mut Environment<Symbol, Type>, adts: &AdtTable, classes: &ClassTable, program: &Program, mut cache: Option<&mut Cache>, timings: &mut Timings) -> Result<(Substitution, Locals, References), Spanned<TypeError>> {
+    let mut funcs = Vec::new();
+    for decl in &program.0 {
+        match decl {
+            AstDecl::Func(func) => funcs.push((func.name, func)),
+            AstDecl::Instance(instance) => {
+                for func in &instance.methods {
+                    funcs.push((instance.method_symbol(func.name), func));
+                }
+            }
+            AstDecl::Class(_) | AstDecl::Enum(_) | AstDecl::Extern(_, _, _) | AstDecl::Struct(_) | AstDecl::Test(_) | AstDecl::Use(_, _) => (),
+        }
+    }
+    let index = funcs
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| (*name, i))
+        .collect::<HashMap<_, _>>();
+    let uses = funcs
+        .iter()
+        .map(|(_, func)| {
+            let mut uses = Vec::new();
+            collect_globals(&func.body, &mut uses);
+            uses
+        })
+        .collect::<Vec<_>>();
+    let edges = uses
+        .iter()
+        .map(|uses| uses.iter().filter_map(|(name, _)| index.get(name).cloned()).collect())
+        .collect::<Vec<_>>();
+
+    let mut subst = Substitution::new();
+    let mut locals = Locals::new();
+    let mut references = References::new();
+    let mut generalized = HashSet::new();
+    let mut predicates = Predicates::new();
+    let declarations = cache.as_ref().map(|_| incremental::declarations_fingerprint(program));
+    for group in components(&edges) {
+        let fingerprint = declarations.map(|declarations| {
+            let members: Vec<_> = group.iter().map(|&i| funcs[i]).collect();
+            let callees: Vec<_> = group
+                .iter()
+                .flat_map(|&i| &uses[i])
+                .filter(|(name, _)| !members.iter().any(|(member, _)| member == name))
+                .filter_map(|(name, _)| Some((*name, apply(&subst, type_env.lookup(*name)?))))
+                .collect();
+            incremental::group_fingerprint(declarations, &members, &callees)
+        });
+        let cached = match (cache.as_deref(), fingerprint) {
+            (Some(cache), Some(fingerprint)) => cache.types(fingerprint),
+            _ => None,
+        };
+        let fits = |types: &Vec<FuncTypes>| {
+            types.len() == group.len()
+                && group
+                    .iter()
+                    .zip(types)
+                    .all(|(&i, types)| types.vars.len() == type_vars(funcs[i].1).len())
+        };
+        if let Some(types) = cached.filter(fits) {
+            for (&i, types) in group.iter().zip(types) {
+                for (var, ty) in type_vars(funcs[i].1).into_iter().zip(&types.vars) {
+                    unify(&mut subst, &Type::Var(var), ty).map_err(|err| Spanned::new(err, funcs[i].1.span))?;
+                }
+                locals.insert(funcs[i].0, types.locals);
+            }
+            if let Some(cache) = cache.as_deref_mut() {
+                cache.stats.types_reused.extend(group.iter().map(|&i| funcs[i].0));
+            }
+            check_instances(type_env, classes, &subst, &funcs, &group, &mut predicates)?;
+            generalized.extend(group.iter().map(|&i| funcs[i].0));
+            continue;
+        }
+
+        let timer = Timer::start();
+        let mut constraints = Constraints::new(funcs[group[0]].1.span);
+        // the functions that can reach their end, with their return types
+        let mut open_ends = Vec::new();
+        for &i in &group {
+            let returns = get_constraints_func(type_env, adts, &mut constraints, funcs[i].1)?;
+            if !always_returns(&funcs[i].1.body) {
+                open_ends.push((funcs[i].0, returns, funcs[i].1.span));
+            }
+            locals.insert(funcs[i].0, std::mem::take(&mut constraints.locals));
+            references.insert(funcs[i].0, std::mem::take(&mut constraints.references));
+            for (name, ty) in &uses[i] {
+                let callee = type_env
+                    .lookup(*name)
+                    .cloned()
+                    .ok_or_else(|| Spanned::new(TypeError::UnboundName(*name), funcs[i].1.span))?;
+                let callee = if generalized.contains(name) {
+                    instantiate(&apply(&subst, &callee))
+                } else {
+                    callee
+                };
+                constraints.insert(Constraint::Equal(ty.clone(), callee));
+            }
+        }
+        timings.record("constraints", timer);
+        let timer = Timer::start();
+        let mut fields = Vec::new();
+        for (constraint, span) in &constraints.list {
+            match constraint {
+                Constraint::Equal(a, b) => unify(&mut subst, a, b).map_err(|err| Spanned::new(err, *span))?,
+                Constraint::Field(ty, field, field_ty) => {
+                    if !solve_field(adts, &mut subst, ty, *field, field_ty).map_err(|err| Spanned::new(err, *span))? {
+                        fields.push((ty, *field, field_ty, *span));
+                    }
+                }
+            }
+        }
+        solve_fields(adts, &mut subst, fields)?;
+        timings.record("solve", timer);
+        for (name, returns, span) in open_ends {
+            let returns = apply(&subst, &returns);
+            if returns != Type::Unit {
+                return Err(Spanned::new(TypeError::MissingReturn(name, returns), span));
+            }
+        }
+        if let (Some(cache), Some(fingerprint)) = (cache.as_deref_mut(), fingerprint) {
+            let types: Vec<_> = group
+                .iter()
+                .map(|&i| FuncTypes {
+                    vars: type_vars(funcs[i].1)
+                        .into_iter()
+                        .map(|var| apply(&subst, &Type::Var(var)))
+                        .collect(),
+                    locals: locals[&funcs[i].0]
+                        .iter()
+                        .map(|(name, ty)| (*name, apply(&subst, ty)))
+                        .collect(),
+                })
+                .collect();
+            cache.store_types(fingerprint, &types);
+            cache.stats.typechecked.extend(group.iter().map(|&i| funcs[i].0));
+        }
+        check_instances(type_env, classes, &subst, &funcs, &group, &mut predicates)?;
+        generalized.extend(group.iter().map(|&i| funcs[i].0));
+    }
+    Ok((subst, locals, references))
+}
+
+/// The instances each generalized function needs, as classes and types in
+/// the variables of its signature.
+type Predicates = HashMap<Symbol, Vec<(Symbol, Type)>>;
+
+/// Checks that every class a solved group of functions uses has an instance
+/// for the type it is used at. Types that depend on the variables of a
+/// signature can't be checked yet: they become predicates of the function,
+/// which are checked for the types each use outside the group instantiates
+/// it at.
+fn check_instances(
+    type_env: &Environment<Symbol, Type>,
+    classes: &ClassTable,
+    subst: &Substitution,
+    funcs: &[(Symbol, &AstFunc)],
+    group: &[usize],
+    predicates: &mut Predicates,
+) -> Result<(), Spanned<TypeError>> {
+    let signature = |name| apply(subst, type_env.lookup(name).expect("functions are declared"));
+    let vars: Vec<_> = group.iter().map(|&i| free_vars(&signature(funcs[i].0))).collect();
+    let mut found = Vec::new();
+    for &i in group {
+        let mut uses = Vec::new();
+        collect_uses(&funcs[i].1.body, &mut uses);
+        for (name, ty, span) in uses {
+            let ty = apply(subst, &ty);
+            let needed = match classes.method_class(name) {
+                Some(class) => vec![(class.name, ty)],
+                None => match predicates.get(&name) {
+                    Some(callee) => {
+                        let mut instance = Substitution::new();
+                        bind_vars(&signature(name), &ty, &mut instance);
+                        callee.iter().map(|(class, ty)| (*class, apply(&instance, ty))).collect()
+                    }
+                    None => continue,
+                },
+            };
+            for (class, ty) in needed {
+                let needs = free_vars(&ty);
+                if needs.is_empty() {
+                    if !classes.has_instance(class, &ty) {
+                        return Err(Spanned::new(TypeError::NoInstance(class, ty), span));
+                    }
+                    continue;
+                }
+                let mut carried = false;
+                for (&j, vars) in group.iter().zip(&vars) {
+                    if needs.iter().all(|var| vars.contains(var)) {
+                        found.push((funcs[j].0, class, ty.clone()));
+                        carried = true;
+                    }
+                }
+                if !carried {
+                    return Err(Spanned::new(TypeError::AmbiguousInstance(class, name), span));
+                }
+            }
+        }
+    }
+    for (name, class, ty) in found {
+        let predicates = predicates.entry(name).or_default();
+        if !predicates.contains(&(class, ty.clone())) {
+            predicates.push((class, ty));
+        }
+    }
+    Ok(())
+}
+
+/// The type variables of a function that typechecking solves: those of its
+/// signature, then those of the types it uses functions and methods at, in
+/// the order they appear.
+fn type_vars(func: &AstFunc) -> Vec<Symbol> {
+    fn stmts(body: &[AstStmt], vars: &mut Vec<Symbol>) {
+        for stmt in body {
+            match &stmt.kind {
+                AstStmtKind::Expr(e) | AstStmtKind::Let(_, e) | AstStmtKind::Return(e) => expr(e, vars),
+                AstStmtKind::Assign(target, e) => {
+                    expr(target, vars);
+                    expr(e, vars);
+                }
+                AstStmtKind::If(cond, tbody, fbody) => {
+                    expr(cond, vars);
+                    stmts(tbody, vars);
+                    stmts(fbody, vars);
+                }
+                AstStmtKind::Match(e, arms) => {
+                    expr(e, vars);
+                    for arm in arms {
+                        stmts(&arm.body, vars);
+                    }
+                }
+                AstStmtKind::Assert(_) => unreachable!("assertions are taken out before typechecking"),
+            }
+        }
+    }
+
+    fn expr(e: &AstExpr, vars: &mut Vec<Symbol>) {
+        match e {
+            AstExpr::Int(_) | AstExpr::Ident(_) => (),
+            AstExpr::Method(_, ty, method) => {
+                vars.extend(free_vars(ty));
+                vars.extend(free_vars(method));
+            }
+            AstExpr::Global(_, ty) => vars.extend(free_vars(ty)),
+            AstExpr::Call(func, args) => {
+                expr(func, vars);
+                for arg in args {
+                    expr(arg, vars);
+                }
+            }
+            AstExpr::BinOp(_, left, right) => {
+                expr(left, vars);
+                expr(right, vars);
+            }
+            AstExpr::UnOp(_, e) | AstExpr::Field(e, _) => expr(e, vars),
+            AstExpr::Struct(_, fields) => {
+                for (_, e) in fields {
+                    expr(e, vars);
+                }
+            }
+        }
+    }
+
+    let mut vars = Vec::new();
+    for (_, ty) in &func.args {
+        vars.extend(free_vars(ty));
+    }
+    vars.extend(free_vars(&func.returns));
+    stmts(&func.body, &mut vars);
+    vars
+}
+
+/// The strongly connected components of the call graph, each one after all
+/// of the components it calls into.
+pub fn components(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    struct Tarjan<'a> {
+        edges: &'a [Vec<usize>],
+        index: Vec<Option<usize>>,
+        lowlink: Vec<usize>,
+        stack: Vec<usize>,
+        on_stack: Vec<bool>,
+        next: usize,
+        components: Vec<Vec<usize>>,
+    }
+
+    impl<'a> Tarjan<'a> {
+        fn visit(&mut self, node: usize) {
+            self.index[node] = Some(self.next);
+            self.lowlink[node] = self.next;
+            self.next += 1;
+            self.stack.push(node);
+            self.on_stack[node] = true;
+            for &next in &self.edges[node] {
+                match self.index[next] {
+                    None => {
+                        self.visit(next);
+                        self.lowlink[node] = self.lowlink[node].min(self.lowlink[next]);
+                    }
+                    Some(index) if self.on_stack[next] => {
+                        self.lowlink[node] = self.lowlink[node].min(index);
+                    }
+                    Some(_) => (),
+                }
+            }
+            if Some(self.lowlink[node]) == self.index[node] {
+                let mut component = Vec::new();
+                loop {
+                    let member = self.stack.pop().unwrap();
+                    self.on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                component.reverse();
+                self.components.push(component);
+            }
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        edges,
+        index: vec![None; edges.len()],
+        lowlink: vec![0; edges.len()],
+        stack: Vec::new(),
+        on_stack: vec![false; edges.len()],
+        next: 0,
+        components: Vec::new(),
+    };
+    for node in 0..edges.len() {
+        if tarjan.index[node].is_none() {
+            tarjan.visit(node);
+        }
+    }
+    tarjan.components
+}
+
+/// Every use of a top-level function in the statements, with the type it is
+/// used at.
+pub fn collect_globals(stmts: &[AstStmt], uses: &mut Vec<(Symbol, Type)>) {
+    for stmt in stmts {
+        match &stmt.kind {
+            AstStmtKind::Expr(expr) | AstStmtKind::Let(_, expr) | AstStmtKind::Return(expr) => collect_globals_expr(expr, uses),
+            AstStmtKind::Assign(target, expr) => {
+                collect_globals_expr(target, uses);
+                collect_globals_expr(expr, uses);
+            }
+            AstStmtKind::If(cond, tbody, fbody) => {
+                collect_globals_expr(cond, uses);
+                collect_globals(tbody, uses);
+                collect_globals(fbody, uses);
+            }
+            AstStmtKind::Match(expr, arms) => {
+                collect_globals_expr(expr, uses);
+                for arm in arms {
+                    collect_globals(&arm.body, uses);
+                }
+            }
+            AstStmtKind::Assert(_) => unreachable!("assertions are taken out before typechecking"),
+        }
+    }
+}
+
+fn collect_globals_expr(expr: &AstExpr, uses: &mut Vec<(Symbol, Type)>) {
+    match expr {
+        AstExpr::Int(_) | AstExpr::Ident(_) | AstExpr::Method(_, _, _) => (),
+        AstExpr::Global(name, ty) => uses.push((*name, ty.clone())),
+        AstExpr::Call(func, args) => {
+            collect_globals_expr(func, uses);
+            for arg in args {
+                collect_globals_expr(arg, uses);
+            }
+        }
+        AstExpr::BinOp(_, left, right) => {
+            collect_globals_expr(left, uses);
+            collect_globals_expr(right, uses);
+        }
+        AstExpr::UnOp(_, expr) | AstExpr::Field(expr, _) => collect_globals_expr(expr, uses),
+        AstExpr::Struct(_, fields) => {
+            for (_, expr) in fields {
+                collect_globals_expr(expr, uses);
             }
         }
     }
 }
 
-pub fn get_constraints_stmt(type_env: &mut Environment<Symbol, Type>, constraints: &mut HashSet<Constraint>, stmt: &AstStmt) {
-    match stmt {
-        AstStmt::Return(expr) | AstStmt::Expr(expr) => get_constraints_expr(type_env, constraints, expr),
-        AstStmt::If(cond, tbody, fbody) => {
-            // TODO: finish
-            get_constraints_expr(type_env, constraints, cond);
+/// Every use of a method or of a top-level function in the statements, with
+/// the type it is used at and the statement it is in. Methods are used at the
+/// type of their class.
+fn collect_uses(stmts: &[AstStmt], uses: &mut Vec<(Symbol, Type, Span)>) {
+    for stmt in stmts {
+        let mut exprs = Vec::new();
+        match &stmt.kind {
+            AstStmtKind::Expr(expr) | AstStmtKind::Let(_, expr) | AstStmtKind::Return(expr) => collect_uses_expr(expr, &mut exprs),
+            AstStmtKind::Assign(target, expr) => {
+                collect_uses_expr(target, &mut exprs);
+                collect_uses_expr(expr, &mut exprs);
+            }
+            AstStmtKind::If(cond, _, _) | AstStmtKind::Match(cond, _) => collect_uses_expr(cond, &mut exprs),
+            AstStmtKind::Assert(_) => unreachable!("assertions are taken out before typechecking"),
+        }
+        uses.extend(exprs.into_iter().map(|(name, ty)| (name, ty, stmt.span)));
+        match &stmt.kind {
+            AstStmtKind::If(_, tbody, fbody) => {
+                collect_uses(tbody, uses);
+                collect_uses(fbody, uses);
+            }
+            AstStmtKind::Match(_, arms) => {
+                for arm in arms {
+                    collect_uses(&arm.body, uses);
+                }
+            }
+            _ => (),
         }
     }
 }
 
-pub fn get_constraints_expr(type_env: &mut Environment<Symbol, Type>, constraints: &mut HashSet<Constraint>, expr: &AstExpr) {
+fn collect_uses_expr(expr: &AstExpr, uses: &mut Vec<(Symbol, Type)>) {
     match expr {
-        AstExpr::Int(_) => (),
-        AstExpr::Call(_, _) => {
-            // TODO: finish this
+        AstExpr::Method(name, ty, _) | AstExpr::Global(name, ty) => uses.push((*name, ty.clone())),
+        AstExpr::Int(_) | AstExpr::Ident(_) => (),
+        AstExpr::Call(func, args) => {
+            collect_uses_expr(func, uses);
+            for arg in args {
+                collect_uses_expr(arg, uses);
+            }
+        }
+        AstExpr::BinOp(_, left, right) => {
+            collect_uses_expr(left, uses);
+            collect_uses_expr(right, uses);
+        }
+        AstExpr::UnOp(_, expr) | AstExpr::Field(expr, _) => collect_uses_expr(expr, uses),
+        AstExpr::Struct(_, fields) => {
+            for (_, expr) in fields {
+                collect_uses_expr(expr, uses);
+            }
+        }
+    }
+}
+
+/// Replaces every type variable in a generalized type with a fresh one.
+fn instantiate(ty: &Type) -> Type {
+    let subst = free_vars(ty).into_iter().map(|var| (var, Type::gen())).collect();
+    apply(&subst, ty)
+}
+
+/// Binds the type variables of `generic` to the parts of `ty` they line up
+/// with. Typechecking has already made sure that the two agree.
+pub fn bind_vars(generic: &Type, ty: &Type, subst: &mut Substitution) {
+    match (generic, ty) {
+        (Type::Var(var), ty) => {
+            subst.insert(*var, ty.clone());
+        }
+        (Type::Func(args1, ret1), Type::Func(args2, ret2)) => {
+            for (arg1, arg2) in args1.iter().zip(args2) {
+                bind_vars(arg1, arg2, subst);
+            }
+            bind_vars(ret1, ret2, subst);
+        }
+        _ => (),
+    }
+}
+
+/// The type variables of `ty`, in the order they first appear.
+pub fn free_vars(ty: &Type) -> Vec<Symbol> {
+    fn go(ty: &Type, vars: &mut Vec<Symbol>) {
+        match ty {
+            Type::Var(var) => {
+                if !vars.contains(var) {
+                    vars.push(*var);
+                }
+            }
+            Type::Func(args, ret) => {
+                for arg in args {
+                    go(arg, vars);
+                }
+                go(ret, vars);
+            }
+            Type::Name(_) | Type::Unit | Type::Int | Type::Bool => (),
+        }
+    }
+
+    let mut vars = Vec::new();
+    go(ty, &mut vars);
+    vars
+}
+
+// solve constraints
+
+/// Solves a field constraint if the type of the struct is known. Returns
+/// whether it was.
+fn solve_field(adts: &AdtTable, subst: &mut Substitution, ty: &Type, field: Symbol, field_ty: &Type) -> Result<bool, TypeError> {
+    let ty = apply(subst, ty);
+    if let Type::Var(_) = ty {
+        return Ok(false);
+    }
+    let (_, expected) = adts
+        .as_struct(&ty)
+        .and_then(|info| info.get_field(field))
+        .ok_or_else(|| TypeError::UnknownField(ty.clone(), field))?;
+    unify(subst, field_ty, expected)?;
+    Ok(true)
+}
+
+/// Solves the field constraints that were left for when the rest was solved.
+/// The struct of those still unknown then is the only one with the field,
+/// taken one at a time, since knowing it can tell the struct of others.
+fn solve_fields(adts: &AdtTable, subst: &mut Substitution, mut fields: Vec<(&Type, Symbol, &Type, Span)>) -> Result<(), Spanned<TypeError>> {
+    loop {
+        let mut left = Vec::new();
+        for (ty, field, field_ty, span) in fields {
+            if !solve_field(adts, subst, ty, field, field_ty).map_err(|err| Spanned::new(err, span))? {
+                left.push((ty, field, field_ty, span));
+            }
+        }
+        fields = left;
+        let (ty, field, _, span) = match fields.first() {
+            Some(&first) => first,
+            None => return Ok(()),
+        };
+        let name = match adts.structs_with_field(field).as_slice() {
+            [info] => info.name,
+            [] => return Err(Spanned::new(TypeError::UnknownField(apply(subst, ty), field), span)),
+            _ => return Err(Spanned::new(TypeError::AmbiguousField(field), span)),
+        };
+        unify(subst, ty, &Type::Name(name)).map_err(|err| Spanned::new(err, span))?;
+    }
+}
+
+fn unify(subst: &mut Substitution, a: &Type, b: &Type) -> Result<(), TypeError> {
+    let a = apply(subst, a);
+    let b = apply(subst, b);
+    match (&a, &b) {
+        (Type::Var(x), Type::Var(y)) if x == y => Ok(()),
+        (Type::Var(var), ty) | (ty, Type::Var(var)) => {
+            if occurs(*var, ty) {
+                return Err(TypeError::Recursive(*var, ty.clone()));
+            }
+            subst.insert(*var, ty.clone());
+            Ok(())
+        }
+        (Type::Func(args1, ret1), Type::Func(args2, ret2)) if args1.len() == args2.len() => {
+            for (arg1, arg2) in args1.iter().zip(args2) {
+                unify(subst, arg1, arg2)?;
+            }
+            unify(subst, ret1, ret2)
+        }
+        (a, b) if a == b => Ok(()),
+        _ => Err(TypeError::Mismatch(a, b)),
+    }
+}
+
+fn occurs(var: Symbol, ty: &Type) -> bool {
+    match ty {
+        Type::Var(other) => var == *other,
+        Type::Func(args, ret) => args.iter().any(|arg| occurs(var, arg)) || occurs(var, ret),
+        Type::Name(_) | Type::Unit | Type::Int | Type::Bool => false,
+    }
+}
+
+/// Substitutes every solved type variable in `ty`.
+pub fn apply(subst: &Substitution, ty: &Type) -> Type {
+    match ty {
+        Type::Var(var) => match subst.get(var) {
+            Some(ty) => apply(subst, ty),
+            None => ty.clone(),
+        },
+        Type::Func(args, ret) => {
+            let args = args.iter().map(|arg| apply(subst, arg)).collect();
+            Type::Func(args, Box::new(apply(subst, ret)))
         }
+        Type::Name(_) | Type::Unit | Type::Int | Type::Bool => ty.clone(),
     }
 }
@@ -0,0 +1,106 @@
+mod common;
+
+use std::fs;
+use std::process::Command;
+
+use common::TempDir;
+use mochi::Session;
+
+const SOURCE: &str = "\
+fn main:
+  return 0
+";
+
+fn compile_for(target: &str) -> Result<Vec<u8>, Vec<String>> {
+    let mut session = Session::new("main.mo", SOURCE).target(target.parse().unwrap());
+    let object = session.compile();
+    object.ok_or_else(|| session.diagnostics().iter().map(|d| d.message.clone()).collect())
+}
+
+#[test]
+fn compiles_for_elf_and_mach_o() {
+    assert!(compile_for("x86_64-unknown-linux-gnu").is_ok());
+    assert!(compile_for("x86_64-apple-darwin").is_ok());
+}
+
+#[test]
+fn rejects_unsupported_targets() {
+    for target in &["x86_64-pc-windows-msvc", "aarch64-unknown-linux-gnu"] {
+        assert_eq!(
+            compile_for(target).unwrap_err(),
+            vec![format!(
+                "can't compile for `{}`, only for x86-64 with ELF or Mach-O object files",
+                target
+            )]
+        );
+    }
+}
+
+/// Structs larger than two words are passed to C on the stack, and one that
+/// doesn't fit in the registers that are left goes there too, as it does
+/// between mochi functions.
+const STRUCTS: &str = "\
+struct Box:
+  a: int
+  b: int
+  c: int
+
+struct Pair:
+  x: int
+  y: int
+
+extern fn weigh(first: int, b: Box, last: int) -> int
+extern fn crowd(a: int, b: int, c: int, d: int, e: int, p: Pair, b: Box, f: int) -> int
+extern fn make(a: int) -> Box
+
+fn spread(a: int, b: int, c: int, d: int, e: int, p: Pair, x: Box, f: int) -> int:
+  return a + b + c + d + e + p.x + p.y + x.a + x.b + x.c + f
+
+fn main:
+  let b = Box { a: 1, b: 2, c: 3 }
+  let m = make(7)
+  if weigh(4, b, 5) == 54123:
+    if crowd(1, 2, 3, 4, 5, Pair { x: 6, y: 7 }, b, 8) == 1:
+      if spread(1, 2, 3, 4, 5, Pair { x: 6, y: 7 }, b, 8) == 42:
+        return m.a + m.b + m.c
+  return 1
+";
+
+const STRUCTS_C: &str = "\
+struct box { long a, b, c; };
+struct pair { long x, y; };
+
+long weigh(long first, struct box b, long last) {
+    return last * 10000 + first * 1000 + b.a * 100 + b.b * 10 + b.c;
+}
+
+long crowd(long a, long b, long c, long d, long e, struct pair p, struct box x, long f) {
+    return a == 1 && b == 2 && c == 3 && d == 4 && e == 5 && p.x == 6 && p.y == 7
+        && x.a == 1 && x.b == 2 && x.c == 3 && f == 8;
+}
+
+struct box make(long a) {
+    struct box b = { a, a * 2, a * 3 };
+    return b;
+}
+";
+
+#[test]
+fn passes_structs_to_c() {
+    let dir = TempDir::new("codegen-structs");
+    let mut session = Session::new("main.mo", STRUCTS);
+    let object = session.compile().expect("compiles");
+    fs::write(dir.join("main.o"), object).unwrap();
+    let c = dir.write("structs.c", STRUCTS_C);
+    let executable = dir.join("main");
+    let status = Command::new("cc")
+        .arg(dir.join("main.o"))
+        .arg(&c)
+        .arg("-o")
+        .arg(&executable)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let status = Command::new(&executable).status().unwrap();
+    assert_eq!(status.code(), Some(42));
+}
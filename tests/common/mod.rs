@@ -0,0 +1,51 @@
+//! Helpers shared by the integration tests.
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A directory of its own under the system's temporary directory, removed
+/// with everything in it once dropped, whether the test passed or not.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates an empty directory, named after the test and the process so
+    /// that tests running at the same time don't share one.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("mochi-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    /// Writes a file at a path relative to the directory, creating the
+    /// directories it's in, and returns its full path.
+    // not every test writes files, but every test crate compiles this
+    #[allow(dead_code)]
+    pub fn write(&self, file: &str, text: &str) -> PathBuf {
+        let path = self.0.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, text).unwrap();
+        path
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
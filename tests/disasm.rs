@@ -0,0 +1,106 @@
+use mochi::disasm::{instructions, line, Symbols};
+
+/// Checks that the bytes are printed as a single instruction.
+fn assert_disasm(code: &[u8], text: &str) {
+    assert_disasm_with(code, text, &Symbols::default());
+}
+
+fn assert_disasm_with(code: &[u8], text: &str, symbols: &Symbols) {
+    assert_eq!(instructions(code, 0, code.len(), symbols), line(code, 0, code.len(), text));
+}
+
+#[test]
+fn prints_registers() {
+    assert_disasm(&[0x55], "push rbp");
+    assert_disasm(&[0x41, 0x57], "push r15");
+    assert_disasm(&[0x48, 0x89, 0xe5], "mov rbp, rsp");
+    assert_disasm(&[0x4d, 0x89, 0xc8], "mov r8, r9");
+    assert_disasm(&[0x89, 0xc8], "mov eax, ecx");
+    assert_disasm(&[0x66, 0x89, 0xc8], "mov ax, cx");
+    // the fifth to eighth byte registers depend on the rex prefix
+    assert_disasm(&[0x88, 0xf0], "mov al, dh");
+    assert_disasm(&[0x40, 0x88, 0xf0], "mov al, sil");
+}
+
+#[test]
+fn prints_memory_operands() {
+    assert_disasm(&[0x48, 0x8b, 0x45, 0xf8], "mov rax, qword ptr [rbp - 0x8]");
+    assert_disasm(&[0x48, 0x89, 0x47, 0x10], "mov qword ptr [rdi + 0x10], rax");
+    assert_disasm(&[0x8b, 0x04, 0x24], "mov eax, dword ptr [rsp]");
+    assert_disasm(&[0x4a, 0x8b, 0x04, 0xc8], "mov rax, qword ptr [rax + r9*8]");
+    assert_disasm(&[0x48, 0x8b, 0x87, 0x00, 0x01, 0x00, 0x00], "mov rax, qword ptr [rdi + 0x100]");
+    assert_disasm(&[0x66, 0x89, 0x08], "mov word ptr [rax], cx");
+    assert_disasm(&[0xc6, 0x45, 0xff, 0x01], "mov byte ptr [rbp - 0x1], 0x1");
+    assert_disasm(&[0x48, 0x8d, 0x44, 0x24, 0x08], "lea rax, [rsp + 0x8]");
+}
+
+#[test]
+fn prints_immediates() {
+    assert_disasm(&[0x48, 0x83, 0xc4, 0x10], "add rsp, 0x10");
+    assert_disasm(&[0x48, 0x81, 0xec, 0x00, 0x01, 0x00, 0x00], "sub rsp, 0x100");
+    assert_disasm(&[0x48, 0x83, 0xf8, 0xff], "cmp rax, -0x1");
+    assert_disasm(&[0xb8, 0x2a, 0x00, 0x00, 0x00], "mov eax, 0x2a");
+    assert_disasm(
+        &[0x48, 0xb8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11],
+        "mov rax, 0x1122334455667788",
+    );
+    assert_disasm(&[0x48, 0x6b, 0xc1, 0x03], "imul rax, rcx, 0x3");
+}
+
+#[test]
+fn prints_arithmetic() {
+    assert_disasm(&[0x48, 0x01, 0xc8], "add rax, rcx");
+    assert_disasm(&[0x48, 0x29, 0xc8], "sub rax, rcx");
+    assert_disasm(&[0x48, 0x0f, 0xaf, 0xc1], "imul rax, rcx");
+    assert_disasm(&[0x48, 0x99], "cqo");
+    assert_disasm(&[0x48, 0xf7, 0xf9], "idiv rcx");
+    assert_disasm(&[0x48, 0xf7, 0xd8], "neg rax");
+    assert_disasm(&[0x48, 0xc1, 0xe0, 0x03], "shl rax, 0x3");
+    assert_disasm(&[0x48, 0xd3, 0xf8], "sar rax, cl");
+    assert_disasm(&[0x48, 0x85, 0xc0], "test rax, rax");
+    assert_disasm(&[0x0f, 0x94, 0xc0], "sete al");
+    assert_disasm(&[0x0f, 0xb6, 0xc0], "movzx eax, al");
+    assert_disasm(&[0x48, 0x63, 0xc1], "movsxd rax, ecx");
+    assert_disasm(&[0x48, 0x0f, 0x4c, 0xc1], "cmovl rax, rcx");
+}
+
+#[test]
+fn prints_control_flow() {
+    assert_disasm(&[0xc3], "ret");
+    assert_disasm(&[0x0f, 0x0b], "ud2");
+    assert_disasm(&[0xff, 0xe0], "jmp rax");
+    // targets without a label are offsets from the start of the code
+    assert_disasm(&[0xeb, 0x02], "jmp 0x4");
+    assert_disasm(&[0x0f, 0x85, 0xfa, 0xff, 0xff, 0xff], "jne 0x0");
+
+    let mut symbols = Symbols::default();
+    symbols.labels.insert(4, "ebb1".to_string());
+    assert_disasm_with(&[0x74, 0x02], "je ebb1", &symbols);
+    assert_disasm_with(&[0x48, 0x8d, 0x05, 0xfd, 0xff, 0xff, 0xff], "lea rax, [rip + ebb1]", &symbols);
+    symbols.relocs.insert(1, "malloc".to_string());
+    assert_disasm_with(&[0xe8, 0x00, 0x00, 0x00, 0x00], "call malloc", &symbols);
+}
+
+#[test]
+fn prints_what_it_cant_decode_as_bytes() {
+    // syscall
+    let code = [0x55, 0x0f, 0x05, 0xc3];
+    let listing = instructions(&code, 0, code.len(), &Symbols::default());
+    assert_eq!(
+        listing,
+        format!("{}{}", line(&code, 0, 1, "push rbp"), line(&code, 1, 4, ".byte 0x0f, 0x05, 0xc3"))
+    );
+}
+
+#[test]
+fn prints_a_listing_with_offsets() {
+    let code = [0x55, 0x48, 0x89, 0xe5, 0x5d, 0xc3];
+    let listing = instructions(&code, 0, code.len(), &Symbols::default());
+    assert_eq!(
+        listing,
+        "  0000:  55                             push rbp\n\
+         \x20 0001:  48 89 e5                       mov rbp, rsp\n\
+         \x20 0004:  5d                             pop rbp\n\
+         \x20 0005:  c3                             ret\n"
+    );
+}
@@ -0,0 +1,109 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use mochi::Session;
+use regex::Regex;
+
+/// Every source file under the directory.
+fn sources(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(sources(&path));
+        } else if path.extension() == Some("mo".as_ref()) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+/// The ast of the source, with the names of inferred types left out since
+/// every parse makes up new ones, and the spans since formatting moves
+/// things around.
+fn parse(file: &Path, source: &str) -> String {
+    let module = Session::new(file, source)
+        .parse_module()
+        .unwrap_or_else(|| panic!("{} doesn't parse", file.display()));
+    let ast = format!("{:#?}", module.program);
+    let ast = Regex::new(r#""G#\d+""#).unwrap().replace_all(&ast, "_");
+    Regex::new(r"(?s)span: Span \{.*?\},").unwrap().replace_all(&ast, "span: _,").into_owned()
+}
+
+fn format(file: &Path, source: &str) -> String {
+    Session::new(file, source)
+        .format()
+        .unwrap_or_else(|| panic!("{} doesn't parse", file.display()))
+}
+
+#[test]
+fn examples_round_trip() {
+    let examples = sources(Path::new("examples"));
+    assert!(!examples.is_empty());
+    for file in examples {
+        let source = fs::read_to_string(&file).unwrap();
+        let formatted = format(&file, &source);
+        assert_eq!(parse(&file, &formatted), parse(&file, &source), "{}", file.display());
+        assert_eq!(format(&file, &formatted), formatted, "{}", file.display());
+    }
+}
+
+#[test]
+fn canonical_style() {
+    let source = "\
+struct P: (x: int; y: int)
+
+fn f (a: int, b) -> int:
+    let x = (a + b) * (a - (b - 1))
+    let y = (a - b) - (a - b)
+    if ! (x == y) && ((x < y) || (y < x)): (return 1)
+    match x:
+        0 => return (1)
+        _ =>
+            return f(b, a).x
+";
+    let expected = "\
+struct P:
+  x: int
+  y: int
+
+fn f(a: int, b) -> int:
+  let x = (a + b) * (a - (b - 1))
+  let y = a - b - (a - b)
+  if !(x == y) && (x < y || y < x):
+    return 1
+  match x:
+    0 => return 1
+    _ => return f(b, a).x
+";
+    assert_eq!(format(Path::new("f.mo"), source), expected);
+}
+
+#[test]
+fn comments_are_kept() {
+    let source = "\
+// the origin
+use geo.Point // for points
+
+
+fn main: // entry
+  // nothing yet
+
+  return 0
+  // done
+";
+    let expected = "\
+// the origin
+use geo.Point // for points
+
+fn main: // entry
+  // nothing yet
+
+  return 0
+  // done
+";
+    let file = Path::new("main.mo");
+    assert_eq!(format(file, source), expected);
+    assert_eq!(parse(file, expected), parse(file, source));
+}
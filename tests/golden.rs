@@ -0,0 +1,421 @@
+//! Runs the programs under `tests/` and checks what they do against the
+//! comments in them. A program starts with a header of `//@` lines:
+//!
+//! - `//@ exit: 42`, the exit status it should have, 0 if there's none;
+//! - `//@ stdout: text`, a line it should print, one for every line;
+//! - `//@ run: native` or `//@ run: interpret`, to only compile and link it,
+//!   or only run it in the interpreter, instead of both;
+//! - `//@ flags: -O2`, more arguments for `mochi run`.
+//!
+//! An error or a warning is expected wherever there's a `//~ ERROR message` or
+//! `//~ WARNING message` comment, on the line it's about, and the message only
+//! needs to be part of the real one. Those that aren't about a part of the
+//! program are expected on its first line. Programs with errors aren't run.
+//!
+//! The mir files under `tests/mir/` test a single optimization pass, the one
+//! their directory is named after: `tests/mir/dce/loop.mir` is what `dce` is
+//! run on, and `tests/mir/dce/loop.after.mir` is what it should write.
+//!
+//! `cargo test --test golden -- --bless` writes what the programs do into
+//! their comments, and what the passes write into the `.after.mir` files,
+//! instead of checking it. Other arguments only run the
+//! programs whose path contains one of them.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use mochi::ast::Span;
+use mochi::mir::{self, opt::PassManager};
+use mochi::{Diagnostic, Level, Session};
+use serde_json::Value;
+
+const MOCHI: &str = env!("CARGO_BIN_EXE_mochi");
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Native,
+    Interpret,
+}
+
+/// What a program is expected to do, from its comments, or what it did.
+#[derive(Debug, Default, PartialEq)]
+struct Outcome {
+    /// The line, the level and the message of every diagnostic.
+    diagnostics: Vec<(usize, Level, String)>,
+    exit: i32,
+    stdout: Vec<String>,
+}
+
+struct Test {
+    path: PathBuf,
+    source: String,
+    /// The lines after the header.
+    body: Vec<String>,
+    modes: Vec<Mode>,
+    flags: Vec<String>,
+    expected: Outcome,
+}
+
+/// Every source file and every mir file to run a pass on under the
+/// directory.
+fn sources(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let after = path.to_string_lossy().ends_with(".after.mir");
+        if path.is_dir() {
+            files.extend(sources(&path));
+        } else if path.extension() == Some("mo".as_ref()) || (path.extension() == Some("mir".as_ref()) && !after) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Splits a line into its code and the markers in it.
+fn markers(line: &str) -> (&str, Vec<&str>) {
+    let mut parts = line.split("//~");
+    let code = parts.next().unwrap_or_default();
+    (code.trim_end(), parts.map(str::trim).collect())
+}
+
+impl Test {
+    fn read(path: PathBuf) -> Result<Self, String> {
+        let source = fs::read_to_string(&path).map_err(|err| err.to_string())?;
+        let mut test = Test {
+            path,
+            body: Vec::new(),
+            modes: vec![Mode::Native, Mode::Interpret],
+            flags: Vec::new(),
+            expected: Outcome::default(),
+            source: String::new(),
+        };
+        let mut lines = source.lines().enumerate().peekable();
+        while let Some((_, line)) = lines.next_if(|(_, line)| line.starts_with("//@")) {
+            let annotation = line["//@".len()..].trim();
+            let (key, value) = match annotation.find(':') {
+                Some(colon) => (&annotation[..colon], annotation[colon + 1..].trim()),
+                None => return Err(format!("bad annotation `{}`", line)),
+            };
+            match key {
+                "exit" => test.expected.exit = value.parse().map_err(|_| format!("bad exit status `{}`", value))?,
+                "stdout" => test.expected.stdout.push(value.to_owned()),
+                "run" if value == "native" => test.modes = vec![Mode::Native],
+                "run" if value == "interpret" => test.modes = vec![Mode::Interpret],
+                "flags" => test.flags.extend(value.split_whitespace().map(str::to_owned)),
+                _ => return Err(format!("bad annotation `{}`", line)),
+            }
+        }
+        for (i, line) in lines {
+            for marker in markers(line).1 {
+                let (level, message) = if let Some(message) = marker.strip_prefix("ERROR") {
+                    (Level::Error, message)
+                } else if let Some(message) = marker.strip_prefix("WARNING") {
+                    (Level::Warning, message)
+                } else {
+                    return Err(format!("bad marker `//~ {}` on line {}", marker, i + 1));
+                };
+                test.expected.diagnostics.push((i + 1, level, message.trim().to_owned()));
+            }
+            test.body.push(line.to_owned());
+        }
+        test.source = source;
+        Ok(test)
+    }
+
+    /// Runs mochi on the program, and returns its exit status, what it printed
+    /// and the diagnostics it reported.
+    fn mochi(&self, args: &[&str]) -> Result<(i32, String, Vec<Diagnostic>), String> {
+        let output = Command::new(MOCHI)
+            .args(args)
+            .args(["--error-format", "json"])
+            .arg(&self.path)
+            .output()
+            .map_err(|err| format!("couldn't run mochi: {}", err))?;
+        let code = output.status.code().ok_or("mochi was killed")?;
+        let mut diagnostics = Vec::new();
+        // a native program writes its own errors here too
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            if let Ok(json) = serde_json::from_str::<Value>(line) {
+                let span = &json["span"];
+                diagnostics.push(Diagnostic {
+                    level: if json["level"] == "warning" { Level::Warning } else { Level::Error },
+                    message: json["message"].as_str().unwrap_or_default().to_owned(),
+                    span: span["file"].as_str().map(|file| Span {
+                        file: file.into(),
+                        lo: span["lo"].as_u64().unwrap_or(0) as usize,
+                        hi: span["hi"].as_u64().unwrap_or(0) as usize,
+                    }),
+                    location: None,
+                });
+            }
+        }
+        Ok((code, String::from_utf8_lossy(&output.stdout).into_owned(), diagnostics))
+    }
+
+    /// The errors and warnings of the program, by the lines they're about.
+    fn check(&self) -> Result<Vec<(usize, Level, String)>, String> {
+        let (_, _, diagnostics) = self.mochi(&["check"])?;
+        let session = Session::new(&self.path, self.source.as_str());
+        let located = diagnostics.iter().map(|diagnostic| {
+            let (pos, _) = session.locate(diagnostic).unwrap_or((0, 0));
+            let line = self.source[..pos].matches('\n').count() + 1;
+            (line, diagnostic.level, session.message(diagnostic).to_owned())
+        });
+        Ok(located.collect())
+    }
+
+    fn run(&self, mode: Mode) -> Result<(i32, Vec<String>), String> {
+        let mut args = vec!["run"];
+        if mode == Mode::Interpret {
+            args.push("--interpret");
+        }
+        args.extend(self.flags.iter().map(String::as_str));
+        let (code, stdout, diagnostics) = self.mochi(&args)?;
+        // the program was checked already, so these come from linking it or
+        // from the interpreter
+        let errors: Vec<_> = diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.level == Level::Error)
+            .map(ToString::to_string)
+            .collect();
+        if !errors.is_empty() {
+            return Err(format!("running it in {:?} failed:\n{}", mode, errors.join("\n")));
+        }
+        Ok((code, stdout.lines().map(str::to_owned).collect()))
+    }
+
+    /// What the program does, which has to be the same in every mode.
+    fn outcome(&self) -> Result<Outcome, String> {
+        let mut outcome = Outcome {
+            diagnostics: self.check()?,
+            ..Outcome::default()
+        };
+        if outcome.diagnostics.iter().any(|(_, level, _)| *level == Level::Error) {
+            return Ok(outcome);
+        }
+        let mut first: Option<(Mode, (i32, Vec<String>))> = None;
+        for &mode in &self.modes {
+            let result = self.run(mode)?;
+            match &first {
+                Some((other, expected)) if *expected != result => {
+                    let mut report = format!("{:?} and {:?} don't do the same:\n", other, mode);
+                    write_result(&mut report, *other, expected);
+                    write_result(&mut report, mode, &result);
+                    return Err(report);
+                }
+                Some(_) => (),
+                None => first = Some((mode, result)),
+            }
+        }
+        if let Some((_, (exit, stdout))) = first {
+            outcome.exit = exit;
+            outcome.stdout = stdout;
+        }
+        Ok(outcome)
+    }
+
+    /// How what the program did differs from what was expected.
+    fn compare(&self, actual: &Outcome) -> String {
+        let expected = &self.expected;
+        let mut report = String::new();
+        for diagnostic in &expected.diagnostics {
+            if !actual.diagnostics.iter().any(|other| matches(diagnostic, other)) {
+                writeln!(report, "expected {}", describe(diagnostic)).unwrap();
+            }
+        }
+        for diagnostic in &actual.diagnostics {
+            if !expected.diagnostics.iter().any(|other| matches(other, diagnostic)) {
+                writeln!(report, "unexpected {}", describe(diagnostic)).unwrap();
+            }
+        }
+        if actual.diagnostics.iter().any(|(_, level, _)| *level == Level::Error) {
+            return report;
+        }
+        if actual.exit != expected.exit {
+            writeln!(report, "expected exit status {}, got {}", expected.exit, actual.exit).unwrap();
+        }
+        if actual.stdout != expected.stdout {
+            writeln!(report, "stdout differs:").unwrap();
+            report.push_str(&diff(&expected.stdout, &actual.stdout));
+        }
+        report
+    }
+
+    /// Rewrites the comments of the program to expect what it did.
+    fn bless(&self, actual: &Outcome) -> Result<(), String> {
+        let mut source = String::new();
+        if self.modes.len() == 1 {
+            let mode = if self.modes[0] == Mode::Native { "native" } else { "interpret" };
+            writeln!(source, "//@ run: {}", mode).unwrap();
+        }
+        if !self.flags.is_empty() {
+            writeln!(source, "//@ flags: {}", self.flags.join(" ")).unwrap();
+        }
+        let ran = !actual.diagnostics.iter().any(|(_, level, _)| *level == Level::Error);
+        if ran && actual.exit != 0 {
+            writeln!(source, "//@ exit: {}", actual.exit).unwrap();
+        }
+        if ran {
+            for line in &actual.stdout {
+                writeln!(source, "//@ stdout: {}", line).unwrap();
+            }
+        }
+        let header_lines = self.source.lines().count() - self.body.len();
+        let mut body = self.body.iter().enumerate().peekable();
+        if source.is_empty() {
+            while body.next_if(|(_, line)| line.is_empty()).is_some() {}
+        } else if body.peek().is_some_and(|(_, line)| !line.is_empty()) {
+            source.push('\n');
+        }
+        for (i, line) in body {
+            let mut line = markers(line).0.to_owned();
+            for (at, level, message) in &actual.diagnostics {
+                if *at == header_lines + i + 1 {
+                    let level = if *level == Level::Error { "ERROR" } else { "WARNING" };
+                    write!(line, " //~ {} {}", level, message).unwrap();
+                }
+            }
+            source.push_str(&line);
+            source.push('\n');
+        }
+        fs::write(&self.path, source).map_err(|err| err.to_string())
+    }
+}
+
+/// Runs the pass a mir file is for on it, and compares what it writes with
+/// the `.after.mir` file next to it, or writes that.
+fn check_pass(path: &Path, bless: bool) -> Result<(), String> {
+    let pass = path.parent().and_then(Path::file_name).unwrap_or_default().to_string_lossy();
+    let mut passes = PassManager::only(&pass).ok_or_else(|| format!("there's no pass called `{}`", pass))?;
+    let source = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let mut session = Session::new(path, source.as_str());
+    let mut lowered = match session.parse_mir() {
+        Some(lowered) => lowered,
+        None => {
+            let errors: Vec<_> = session.diagnostics().iter().map(ToString::to_string).collect();
+            return Err(errors.join("\n"));
+        }
+    };
+    passes.run(&lowered.adts, &mut lowered.program);
+    mir::validate(&lowered.adts, &lowered.program).map_err(|err| format!("`{}` wrote invalid mir: {}", pass, err))?;
+    let actual = mir::dump(&lowered.adts, &lowered.program);
+
+    let expected_path = path.with_extension("after.mir");
+    if bless {
+        return fs::write(&expected_path, actual).map_err(|err| err.to_string());
+    }
+    let expected = fs::read_to_string(&expected_path)
+        .map_err(|err| format!("couldn't read {}: {}", expected_path.display(), err))?;
+    if actual == expected {
+        return Ok(());
+    }
+    let lines = |text: &str| text.lines().map(str::to_owned).collect::<Vec<_>>();
+    Err(format!("`{}` wrote different mir:\n{}", pass, diff(&lines(&expected), &lines(&actual))))
+}
+
+fn matches(expected: &(usize, Level, String), actual: &(usize, Level, String)) -> bool {
+    expected.0 == actual.0 && expected.1 == actual.1 && actual.2.contains(&expected.2)
+}
+
+fn describe((line, level, message): &(usize, Level, String)) -> String {
+    format!("{} on line {}: {}", level, line, message)
+}
+
+fn write_result(report: &mut String, mode: Mode, (exit, stdout): &(i32, Vec<String>)) {
+    writeln!(report, "{:?} exited with {} and printed:", mode, exit).unwrap();
+    for line in stdout {
+        writeln!(report, "  {}", line).unwrap();
+    }
+}
+
+/// The lines that were taken out of `old` and put into `new`, marked with
+/// `-` and `+`, with the lines they have in common in between.
+fn diff(old: &[String], new: &[String]) -> String {
+    // common[i][j] is the length of the longest common subsequence of
+    // old[i..] and new[j..]
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            writeln!(out, "   {}", old[i]).unwrap();
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            writeln!(out, " - {}", old[i]).unwrap();
+            i += 1;
+        } else {
+            writeln!(out, " + {}", new[j]).unwrap();
+            j += 1;
+        }
+    }
+    out
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let bless = args.iter().any(|arg| arg == "--bless");
+    // the options of the usual test harness don't mean anything here
+    let filters: Vec<_> = args.iter().filter(|arg| !arg.starts_with('-')).collect();
+    let paths: Vec<_> = sources(Path::new("tests"))
+        .into_iter()
+        .filter(|path| filters.is_empty() || filters.iter().any(|filter| path.to_string_lossy().contains(filter.as_str())))
+        .collect();
+
+    let total = paths.len();
+    println!("\nrunning {} tests", total);
+    let mut failures = Vec::new();
+    for path in paths {
+        let result = if path.extension() == Some("mir".as_ref()) {
+            check_pass(&path, bless)
+        } else {
+            Test::read(path.clone()).and_then(|test| {
+                let actual = test.outcome()?;
+                if bless {
+                    return test.bless(&actual);
+                }
+                match test.compare(&actual) {
+                    report if report.is_empty() => Ok(()),
+                    report => Err(report),
+                }
+            })
+        };
+        match result {
+            Ok(()) => println!("test {} ... ok", path.display()),
+            Err(report) => {
+                println!("test {} ... FAILED", path.display());
+                failures.push((path, report));
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        println!("\nfailures:");
+        for (path, report) in &failures {
+            println!("\n---- {} ----\n{}", path.display(), report.trim_end());
+        }
+        println!("\nrun `cargo test --test golden -- --bless` to expect what the programs and passes do now");
+    }
+    println!(
+        "\ntest result: {}. {} passed; {} failed\n",
+        if failures.is_empty() { "ok" } else { "FAILED" },
+        total - failures.len(),
+        failures.len()
+    );
+    if !failures.is_empty() {
+        process::exit(1);
+    }
+}
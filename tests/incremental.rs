@@ -0,0 +1,84 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::TempDir;
+use mochi::incremental::Stats;
+use mochi::Session;
+
+const SOURCE: &str = "\
+fn square(x: int) -> int:
+  return x * x
+
+fn id(x):
+  return x
+
+fn main:
+  return id(square(3)) + id(1)
+";
+
+/// Compiles the source with the cache in `dir`, and returns the object file
+/// with what was done with every function.
+fn compile(source: &str, dir: &Path) -> (Vec<u8>, Vec<String>, Vec<String>) {
+    let mut session = Session::new("main.mo", source).incremental(dir);
+    let object = session.compile().expect("the program compiles");
+    assert!(session.diagnostics().is_empty());
+    let Stats {
+        typechecked, compiled, ..
+    } = session.cache_stats().unwrap();
+    let typechecked = typechecked.iter().map(|name| name.to_string()).collect();
+    let compiled = compiled.iter().map(|name| name.to_string()).collect();
+    (object, typechecked, compiled)
+}
+
+#[test]
+fn reuses_everything_when_nothing_changed() {
+    let dir = TempDir::new("incremental-unchanged");
+    let (first, typechecked, compiled) = compile(SOURCE, &dir);
+    assert_eq!(typechecked.len(), 3);
+    assert_eq!(compiled.len(), 3);
+
+    let mut session = Session::new("main.mo", SOURCE).incremental(&*dir);
+    let second = session.compile().unwrap();
+    let stats = session.cache_stats().unwrap();
+    assert!(stats.typechecked.is_empty() && stats.compiled.is_empty());
+    assert_eq!(stats.types_reused.len(), 3);
+    assert_eq!(stats.code_reused.len(), 3);
+
+    assert_eq!(first, second);
+    assert_eq!(first, Session::new("main.mo", SOURCE).compile().unwrap());
+}
+
+#[test]
+fn recompiles_only_edited_functions() {
+    let dir = TempDir::new("incremental-edited");
+    compile(SOURCE, &dir);
+    let edited = SOURCE.replace("return x * x", "return x * x + 1");
+    let (object, typechecked, compiled) = compile(&edited, &dir);
+    assert_eq!(typechecked, vec!["square"]);
+    assert_eq!(compiled, vec!["square"]);
+    assert_eq!(object, Session::new("main.mo", edited.as_str()).compile().unwrap());
+
+    // functions are checked again when a function they use gets a new type,
+    // but not when its type is only written differently
+    let edited = edited.replace("fn square(x: int) -> int:", "fn square(x) -> int:");
+    let (_, typechecked, _) = compile(&edited, &dir);
+    assert_eq!(typechecked, vec!["square"]);
+    let edited = edited.replace("  return x\n", "  return x + 0\n");
+    let (_, typechecked, _) = compile(&edited, &dir);
+    assert_eq!(typechecked, vec!["id", "main"]);
+}
+
+#[test]
+fn recovers_from_corrupt_entries() {
+    let dir = TempDir::new("incremental-corrupt");
+    compile(SOURCE, &dir);
+    for entry in fs::read_dir(&dir).unwrap() {
+        fs::write(entry.unwrap().path(), "fn\nvar int int\ncode 0").unwrap();
+    }
+    let (object, typechecked, compiled) = compile(SOURCE, &dir);
+    assert_eq!(typechecked.len(), 3);
+    assert_eq!(compiled.len(), 3);
+    assert_eq!(object, Session::new("main.mo", SOURCE).compile().unwrap());
+}
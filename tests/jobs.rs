@@ -0,0 +1,32 @@
+use std::path::Path;
+
+use mochi::mir::opt::OptLevel;
+use mochi::Session;
+
+const EXAMPLES: &[&str] = &[
+    "classes.mo",
+    "generics.mo",
+    "inline.mo",
+    "math.mo",
+    "modules/main.mo",
+    "points.mo",
+    "shapes.mo",
+];
+
+#[test]
+fn objects_dont_depend_on_the_number_of_threads() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    for example in EXAMPLES {
+        let compile = |jobs| {
+            let mut session = Session::from_file(dir.join(example))
+                .unwrap()
+                .opt_level(OptLevel::O2)
+                .jobs(jobs);
+            session.compile().expect("the examples compile")
+        };
+        let sequential = compile(1);
+        for &jobs in &[2, 3, 16] {
+            assert!(compile(jobs) == sequential, "{} differs with {} threads", example, jobs);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+mod common;
+
+use std::io::{BufRead, Cursor, Read};
+
+use serde_json::{json, Value};
+
+use common::TempDir;
+
+const URI: &str = "file:///work/main.mo";
+
+const SOURCE: &str = "\
+fn twice(x: int) -> int:
+  let y = x + x
+  return y
+
+fn main:
+  return twice(21)
+";
+
+/// A client that sends its messages in one go, and reads what the server
+/// answered once it exits.
+struct Client {
+    input: Vec<u8>,
+    next_id: u64,
+    /// The document it opens and asks about.
+    uri: String,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client {
+            input: Vec::new(),
+            next_id: 0,
+            uri: URI.to_owned(),
+        }
+    }
+}
+
+impl Client {
+    fn send(&mut self, message: Value) {
+        let body = message.to_string();
+        self.input
+            .extend(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).bytes());
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+        id
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    fn at(&mut self, method: &str, line: u32, character: u32) -> u64 {
+        let params = json!({
+            "textDocument": { "uri": self.uri },
+            "position": { "line": line, "character": character },
+        });
+        self.request(method, params)
+    }
+
+    fn open(&mut self, text: &str) {
+        let document = json!({ "uri": self.uri, "languageId": "mochi", "version": 1, "text": text });
+        self.notify("textDocument/didOpen", json!({ "textDocument": document }));
+    }
+
+    /// Shuts the server down, runs it, and returns its exit status and the
+    /// messages it sent.
+    fn run(mut self) -> (i32, Vec<Value>) {
+        self.request("shutdown", Value::Null);
+        self.notify("exit", Value::Null);
+        let mut output = Vec::new();
+        let code = mochi::lsp::serve(Cursor::new(self.input), &mut output).unwrap();
+        let mut output = Cursor::new(output);
+        let mut messages = Vec::new();
+        loop {
+            let mut header = String::new();
+            if output.read_line(&mut header).unwrap() == 0 {
+                break;
+            }
+            let length: usize = header["Content-Length: ".len()..].trim().parse().unwrap();
+            output.read_line(&mut String::new()).unwrap();
+            let mut body = vec![0; length];
+            output.read_exact(&mut body).unwrap();
+            messages.push(serde_json::from_slice(&body).unwrap());
+        }
+        (code, messages)
+    }
+}
+
+fn response(messages: &[Value], id: u64) -> &Value {
+    let message = messages
+        .iter()
+        .find(|message| message["id"] == id)
+        .unwrap_or_else(|| panic!("no response to request {}", id));
+    &message["result"]
+}
+
+fn diagnostics(messages: &[Value]) -> Vec<&Value> {
+    messages
+        .iter()
+        .filter(|message| message["method"] == "textDocument/publishDiagnostics")
+        .map(|message| &message["params"]["diagnostics"])
+        .collect()
+}
+
+#[test]
+fn answers_requests() {
+    let mut client = Client::default();
+    let initialize = client.request("initialize", json!({ "capabilities": {} }));
+    client.notify("initialized", json!({}));
+    client.open(SOURCE);
+    let hover_local = client.at("textDocument/hover", 2, 9);
+    let hover_function = client.at("textDocument/hover", 5, 10);
+    let definition = client.at("textDocument/definition", 2, 9);
+    let completion = client.at("textDocument/completion", 2, 9);
+    let symbols = client.request("textDocument/documentSymbol", json!({ "textDocument": { "uri": URI } }));
+    let (code, messages) = client.run();
+    assert_eq!(code, 0);
+
+    assert_eq!(response(&messages, initialize)["capabilities"]["hoverProvider"], true);
+    assert_eq!(diagnostics(&messages), vec![&json!([])]);
+    assert_eq!(
+        response(&messages, hover_local)["contents"]["value"],
+        "```mochi\ny: int\n```"
+    );
+    assert_eq!(
+        response(&messages, hover_function)["contents"]["value"],
+        "```mochi\ntwice: (int) -> int\n```"
+    );
+    assert_eq!(
+        response(&messages, definition)["range"],
+        json!({ "start": { "line": 1, "character": 6 }, "end": { "line": 1, "character": 7 } })
+    );
+
+    let labels: Vec<_> = response(&messages, completion)["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["label"].as_str().unwrap())
+        .collect();
+    for name in &["main", "twice", "x", "y", "let", "return"] {
+        assert!(labels.contains(name), "{} isn't completed in {:?}", name, labels);
+    }
+
+    let symbols = response(&messages, symbols).as_array().unwrap();
+    let names: Vec<_> = symbols.iter().map(|symbol| &symbol["name"]).collect();
+    assert_eq!(names, vec!["twice", "main"]);
+    assert_eq!(symbols[0]["range"]["start"], json!({ "line": 0, "character": 0 }));
+    assert_eq!(symbols[0]["range"]["end"]["line"], 2);
+}
+
+#[test]
+fn publishes_diagnostics_on_change() {
+    let mut client = Client::default();
+    client.open(SOURCE);
+    let broken = SOURCE.replace("x + x", "x + true");
+    client.notify(
+        "textDocument/didChange",
+        json!({
+            "textDocument": { "uri": URI, "version": 2 },
+            "contentChanges": [{ "text": broken }],
+        }),
+    );
+    client.notify(
+        "textDocument/didChange",
+        json!({
+            "textDocument": { "uri": URI, "version": 3 },
+            "contentChanges": [{
+                "range": { "start": { "line": 1, "character": 14 }, "end": { "line": 1, "character": 14 } },
+                "text": "(",
+            }],
+        }),
+    );
+    let (_, messages) = client.run();
+    let published = diagnostics(&messages);
+    assert_eq!(published.len(), 3);
+    assert_eq!(published[0], &json!([]));
+
+    let type_error = &published[1][0];
+    assert_eq!(type_error["severity"], 1);
+    assert!(!type_error["message"].as_str().unwrap().starts_with("/work"));
+
+    let syntax_error = &published[2][0];
+    assert_eq!(syntax_error["range"]["start"]["line"], 1);
+}
+
+/// Every change is at positions in the text the changes before it left.
+#[test]
+fn applies_changes_in_order() {
+    let mut client = Client::default();
+    client.open(SOURCE);
+    let at = |line, start, end| {
+        json!({ "start": { "line": line, "character": start }, "end": { "line": line, "character": end } })
+    };
+    client.notify(
+        "textDocument/didChange",
+        json!({
+            "textDocument": { "uri": URI, "version": 2 },
+            "contentChanges": [
+                { "range": at(0, 0, 0), "text": "\n" },
+                { "range": at(2, 14, 15), "text": "z" },
+            ],
+        }),
+    );
+    let (_, messages) = client.run();
+    let published = diagnostics(&messages);
+    assert_eq!(published.len(), 2);
+    let error = &published[1][0];
+    assert_eq!(error["message"], "cannot find `z` in this scope");
+    assert_eq!(error["range"], at(2, 2, 15));
+}
+
+#[test]
+fn goes_to_shadowed_definitions() {
+    let mut client = Client::default();
+    client.open(
+        "\
+fn pick(x: int) -> int:
+  let x = x + 1
+  if x > 3:
+    let x = 0
+    return x
+  return x
+",
+    );
+    let outer = client.at("textDocument/definition", 1, 10);
+    let inner = client.at("textDocument/definition", 4, 11);
+    let after = client.at("textDocument/definition", 5, 9);
+    let (_, messages) = client.run();
+    let at = |line, character| json!({ "line": line, "character": character });
+    assert_eq!(response(&messages, outer)["range"]["start"], at(0, 8));
+    assert_eq!(response(&messages, inner)["range"]["start"], at(3, 8));
+    assert_eq!(response(&messages, after)["range"]["start"], at(1, 6));
+}
+
+#[test]
+fn goes_to_imported_definitions() {
+    let dir = TempDir::new("lsp-imported");
+    let module = dir.write("geo/shapes.mo", "pub fn double(x: int) -> int:\n  return x * 2\n");
+    let mut client = Client {
+        uri: format!("file://{}", dir.join("main.mo").display()),
+        ..Client::default()
+    };
+    client.open("use geo.shapes.double\n\nfn main:\n  return double(21)\n");
+    let definition = client.at("textDocument/definition", 3, 11);
+    let (_, messages) = client.run();
+    let location = response(&messages, definition);
+    assert_eq!(location["uri"], format!("file://{}", module.display()));
+    assert_eq!(
+        location["range"],
+        json!({ "start": { "line": 0, "character": 7 }, "end": { "line": 0, "character": 13 } })
+    );
+}
+
+/// Integer literals too large for `int` are reported instead of crashing the
+/// server.
+#[test]
+fn reports_large_integer_literals() {
+    let mut client = Client::default();
+    client.open("fn main:\n  return 99999999999999999999\n");
+    let (code, messages) = client.run();
+    assert_eq!(code, 0);
+    let error = &diagnostics(&messages)[0][0];
+    assert_eq!(error["range"]["start"], json!({ "line": 1, "character": 9 }));
+}
+
+#[test]
+fn exits_with_an_error_without_shutdown() {
+    let mut input = Vec::new();
+    let body = json!({ "jsonrpc": "2.0", "method": "exit" }).to_string();
+    input.extend(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).bytes());
+    let code = mochi::lsp::serve(Cursor::new(input), Vec::new()).unwrap();
+    assert_eq!(code, 1);
+}
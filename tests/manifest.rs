@@ -0,0 +1,120 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use common::TempDir;
+use mochi::manifest::{Manifest, ManifestError, Packages};
+use mochi::mir::opt::OptLevel;
+
+fn parse(text: &str) -> Result<Manifest, ManifestError> {
+    Manifest::parse(Path::new("mochi.toml"), text)
+}
+
+#[test]
+fn parses_manifests() {
+    let manifest = parse(
+        "\
+# an application
+[package]
+name = \"app\"   # its name
+entry = 'main.mo'
+opt-level = 2
+libraries = [
+  \"m\",   # for sqrt
+  \"pthread\",
+]
+
+[dependencies]
+geo = { path = \"../geo\" }
+",
+    )
+    .unwrap();
+    assert_eq!(manifest.name, "app");
+    assert_eq!(manifest.entry, PathBuf::from("main.mo"));
+    assert_eq!(manifest.source_dirs, vec![PathBuf::from("")]);
+    assert_eq!(manifest.target, None);
+    assert_eq!(manifest.opt_level, Some(OptLevel::O2));
+    assert_eq!(manifest.libraries, vec!["m", "pthread"]);
+    assert_eq!(manifest.dependencies, vec![("geo".to_owned(), PathBuf::from("../geo"))]);
+
+    let defaults = parse("[package]\nname = \"geo\"\n").unwrap();
+    assert_eq!(defaults.entry, PathBuf::from("src/main.mo"));
+    assert_eq!(defaults.source_dirs, vec![PathBuf::from("src")]);
+
+    let syntax = parse("[package]\nname = \"app\nentry = \"main.mo\"\n").unwrap_err().to_string();
+    assert!(syntax.starts_with("mochi.toml: ") && syntax.contains("line 2"), "{}", syntax);
+
+    let errors = [
+        ("[package]\n\nopt-level = \"2\"\n", "mochi.toml: `package.opt-level` should be an integer, not a string"),
+        ("[package]\nname = \"app\"\nopt-level = 3\n", "mochi.toml: unknown optimization level `3`, expected 0, 1 or 2"),
+        ("[package]\nname = \"my-app\"\n", "mochi.toml: `my-app` isn't a valid package name"),
+        ("[package]\nname = \"app\"\nversion = 1\n", "mochi.toml: unknown key `package.version`"),
+        ("[dependencies]\ngeo = \"../geo\"\n", "mochi.toml: `dependencies.geo` should be a table like `{ path = \"../geo\" }`, not a string"),
+        ("[dependencies]\ngeo = { git = \"../geo\" }\n", "mochi.toml: the dependency `geo` needs a `path` and nothing else"),
+        ("name = \"app\"\n", "mochi.toml: unknown key `name`"),
+        ("[package]\nentry = \"main.mo\"\n", "mochi.toml: `package.name` is missing"),
+    ];
+    for (text, error) in &errors {
+        assert_eq!(parse(text).unwrap_err().to_string(), *error);
+    }
+}
+
+/// An application using a package of shapes, which has a module named like
+/// one of the application's.
+fn packages(dir: &TempDir) {
+    dir.write(
+        "app/mochi.toml",
+        "[package]\nname = \"app\"\nlibraries = [\"c\"]\n\n[dependencies]\ngeo = { path = \"../geo\" }\n",
+    );
+    dir.write(
+        "app/src/main.mo",
+        "use geo.shapes.area\nuse util.id\n\nfn main:\n  return id(area(3))\n",
+    );
+    dir.write("app/src/util.mo", "pub fn id(x: int) -> int:\n  return x\n");
+    dir.write("geo/mochi.toml", "[package]\nname = \"geo\"\nsource-dirs = [\"lib\"]\n");
+    dir.write(
+        "geo/lib/shapes.mo",
+        "use util.twice\n\npub fn area(side: int) -> int:\n  return twice(side * side)\n",
+    );
+    dir.write("geo/lib/util.mo", "pub fn twice(x: int) -> int:\n  return x + x\n");
+}
+
+#[test]
+fn builds_packages_with_their_dependencies() {
+    let dir = TempDir::new("manifest-build");
+    packages(&dir);
+    let app = dir.join("app");
+
+    let packages = Packages::discover(&app.join("src")).unwrap();
+    let names: Vec<_> = packages.packages.iter().map(|package| package.manifest.name.as_str()).collect();
+    assert_eq!(names, vec!["geo", "app"]);
+    assert_eq!(packages.libraries(), vec!["c"]);
+
+    let mochi = env!("CARGO_BIN_EXE_mochi");
+    let run = Command::new(mochi).arg("run").current_dir(app.join("src")).status().unwrap();
+    assert_eq!(run.code(), Some(18));
+    let build = Command::new(mochi).arg("build").current_dir(&app).status().unwrap();
+    assert!(build.success());
+    let executable = app.join("target/app");
+    let status = Command::new(&executable).status().unwrap();
+    assert_eq!(status.code(), Some(18));
+}
+
+#[test]
+fn rejects_dependency_cycles() {
+    let dir = TempDir::new("manifest-cycle");
+    packages(&dir);
+    dir.write(
+        "geo/mochi.toml",
+        "[package]\nname = \"geo\"\n\n[dependencies]\napp = { path = \"../app\" }\n",
+    );
+    let err = Packages::load(&dir.join("app")).unwrap_err();
+    let output = Command::new(env!("CARGO_BIN_EXE_mochi"))
+        .arg("build")
+        .current_dir(dir.join("app"))
+        .output()
+        .unwrap();
+    assert_eq!(err.to_string(), "packages depend on each other: app -> geo -> app");
+    assert_eq!(output.status.code(), Some(2));
+}
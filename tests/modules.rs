@@ -0,0 +1,47 @@
+mod common;
+
+use common::TempDir;
+use mochi::Session;
+
+/// Parses `main` as the root module next to two modules that both have a
+/// public `f`, and returns the errors.
+fn errors(name: &str, main: &str) -> Vec<String> {
+    let dir = TempDir::new(name);
+    dir.write("a.mo", "pub fn f() -> int:\n  return 1\n");
+    dir.write("b.mo", "pub fn f() -> int:\n  return 2\n");
+    let file = dir.write("main.mo", main);
+    let mut session = Session::from_file(file).unwrap();
+    session.parse();
+    session.diagnostics().iter().map(|diagnostic| diagnostic.message.clone()).collect()
+}
+
+#[test]
+fn rejects_conflicting_imports() {
+    let errors = errors("modules-conflict", "use a.f\nuse b.f\n\nfn main:\n  return f()\n");
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("`f` is imported from both `a.f` and `b.f` in "));
+}
+
+#[test]
+fn allows_importing_an_item_twice() {
+    assert!(errors("modules-twice", "use a.f\nuse a.f\n\nfn main:\n  return f()\n").is_empty());
+}
+
+/// Errors are printed with the file, line and column they're about, in the
+/// module they're in.
+#[test]
+fn errors_say_where_they_are() {
+    let dir = TempDir::new("modules-located");
+    let module = dir.write("a.mo", "pub fn f() -> int:\n  return g()\n");
+    let file = dir.write("main.mo", "use a.f\n\nfn main:\n  return f()\n");
+    let mut session = Session::from_file(&file).unwrap();
+    let ast = session.parse().unwrap();
+    session.typecheck(ast);
+    let errors: Vec<_> = session.diagnostics().iter().map(ToString::to_string).collect();
+    assert_eq!(errors, vec![format!("{}:2:3: error: cannot find `g` in this scope", module.display())]);
+
+    let mut session = Session::new(&file, "fn main:\n  return 1 +\n");
+    session.parse();
+    let error = session.diagnostics()[0].to_string();
+    assert!(error.starts_with(&format!("{}:2:13: error: unexpected", file.display())), "{}", error);
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use std::fs;
+use std::process::Command;
+
+use common::TempDir;
+use mochi::Session;
+
+const SOURCE: &str = "\
+extern fn print(a: int)
+
+fn gcd(a: int, b: int) -> int:
+  if b == 0:
+    return a
+  return gcd(b, a % b)
+
+fn main:
+  return gcd(12, 18)
+
+test \"gcd of coprimes\":
+  assert(gcd(9, 4) == 1)
+
+test \"gcd of multiples\":
+  print(6)
+  let g = gcd(12, 18)
+  assert_eq(g + 1, 6)
+
+test \"division by zero\":
+  let zero = 0
+  assert(1 / zero == 1)
+";
+
+#[test]
+fn runs_each_test_on_its_own() {
+    let mut session = Session::new("gcd.mo", SOURCE);
+    let (harness, lowered) = session.harness().expect("the tests compile");
+    let names: Vec<_> = harness.tests.iter().map(|test| test.name.as_str()).collect();
+    assert_eq!(names, vec!["gcd of coprimes", "gcd of multiples", "division by zero"]);
+
+    let results: Vec<_> = harness
+        .tests
+        .iter()
+        .map(|test| harness.run(&lowered.adts, &lowered.program, test))
+        .collect();
+    assert_eq!(results[0], (Ok(()), Vec::new()));
+    assert_eq!(
+        results[1],
+        (
+            Err("assertion failed at gcd.mo:17: assert_eq(g + 1, 6)\n  left: 7\n right: 6".to_owned()),
+            b"6\n".to_vec()
+        )
+    );
+    let division = results[2].0.as_ref().unwrap_err();
+    assert!(division.contains("division by zero") && !division.contains("test$"), "{}", division);
+}
+
+#[test]
+fn runs_each_test_in_a_process() {
+    let dir = TempDir::new("test-blocks-native");
+    let mut session = Session::new("gcd.mo", SOURCE);
+    let (harness, lowered) = session.harness().expect("the tests compile");
+    let codegen = session.codegen(&lowered, &[]).expect("the tests compile");
+    let object = dir.join("tests.o");
+    fs::write(&object, session.emit(codegen)).unwrap();
+    let runner = dir.write("runner.c", &harness.runner(&lowered.program));
+    let executable = dir.join("tests");
+    let status = Command::new("cc").arg(&object).arg(&runner).arg("-o").arg(&executable).status().unwrap();
+    assert!(status.success());
+
+    let results: Vec<_> = harness.tests.iter().map(|test| harness.run_native(&executable, test)).collect();
+    assert_eq!(results[0], (Ok(()), Vec::new()));
+    assert_eq!(
+        results[1],
+        (
+            Err("assertion failed at gcd.mo:17: assert_eq(g + 1, 6)\n  left: 7\n right: 6".to_owned()),
+            b"6\n".to_vec()
+        )
+    );
+    // dividing by zero kills the test, not the others
+    assert!(results[2].0.as_ref().unwrap_err().starts_with("the test was killed by "));
+}
+
+#[test]
+fn asserts_only_in_tests() {
+    let source = "fn main:\n  assert(1 == 1)\n  return 0\n";
+    for harness in &[false, true] {
+        let mut session = Session::new("main.mo", source);
+        if *harness {
+            assert!(session.harness().is_none());
+        } else {
+            let ast = session.parse().unwrap();
+            assert!(session.typecheck(ast).is_none());
+        }
+        assert_eq!(
+            session.diagnostics()[0].message,
+            "`main` asserts on line 2, but assertions can only be used in tests"
+        );
+    }
+}
+
+#[test]
+fn builds_leave_tests_out() {
+    let mut session = Session::new("gcd.mo", SOURCE);
+    let ast = session.parse().unwrap();
+    let lowered = session.typecheck(ast).and_then(|checked| session.lower(checked)).unwrap();
+    assert_eq!(session.interpret(&lowered), Some(6));
+}
+
+#[test]
+fn filters_by_name() {
+    let dir = TempDir::new("test-blocks");
+    let file = dir.write("gcd.mo", SOURCE);
+    let run = |filter: &str, interpret: bool| {
+        let output = Command::new(env!("CARGO_BIN_EXE_mochi"))
+            .arg("test")
+            .args(if interpret { &["--interpret"][..] } else { &[] })
+            .arg(&file)
+            .arg(filter)
+            .output()
+            .unwrap();
+        (output.status.code(), String::from_utf8(output.stdout).unwrap())
+    };
+    let (passed, failed) = (run("coprimes", false), run("gcd", false));
+    assert_eq!(run("coprimes", true), passed);
+    assert_eq!(run("gcd", true), failed);
+
+    assert_eq!(passed.0, Some(0));
+    assert_eq!(
+        passed.1,
+        "running 1 test\ntest gcd of coprimes ... ok\n\ntest result: ok. 1 passed; 0 failed; 2 filtered out\n"
+    );
+    assert_eq!(failed.0, Some(1));
+    assert!(failed.1.contains("test gcd of multiples ... FAILED\n"));
+    assert!(failed.1.contains("---- gcd of multiples ----\n6\nassertion failed at "));
+    assert!(failed.1.ends_with("test result: FAILED. 1 passed; 1 failed; 1 filtered out\n"));
+}
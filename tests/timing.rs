@@ -0,0 +1,69 @@
+mod common;
+
+use std::process::Command;
+
+use common::TempDir;
+use mochi::mir::opt::OptLevel;
+use mochi::timing::CountingAlloc;
+use mochi::Session;
+use serde_json::Value;
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+const SOURCE: &str = "\
+fn square(x: int) -> int:
+  return x * x
+
+fn main:
+  return square(3) + 1
+";
+
+#[test]
+fn times_every_phase() {
+    let mut session = Session::new("main.mo", SOURCE).opt_level(OptLevel::O1).jobs(2);
+    session.compile().unwrap();
+    let timings = session.timings();
+    let names: Vec<_> = timings.phases().iter().map(|phase| phase.name.as_str()).collect();
+    assert_eq!(
+        names,
+        vec![
+            "scan",
+            "parse",
+            "constraints",
+            "solve",
+            "lower",
+            "opt const-fold",
+            "opt simplify-cfg",
+            "opt dce",
+            "cranelift square",
+            "cranelift main",
+            "emit",
+            "total",
+        ]
+    );
+    // functions compiled on other threads are counted there
+    for phase in timings.phases() {
+        if ["parse", "cranelift square", "cranelift main", "emit"].contains(&phase.name.as_str()) {
+            assert!(phase.allocations > 0 && phase.bytes > 0, "{} didn't allocate", phase.name);
+        }
+    }
+}
+
+#[test]
+fn prints_json() {
+    let dir = TempDir::new("timing");
+    let file = dir.write("main.mo", SOURCE);
+    let output = Command::new(env!("CARGO_BIN_EXE_mochi"))
+        .args(["check", "-Z", "time-passes=json"])
+        .arg(&file)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let report: Value = serde_json::from_slice(&output.stderr).unwrap();
+    let phases = report["phases"].as_array().unwrap();
+    assert_eq!(phases[0]["name"], "scan");
+    assert_eq!(phases.last().unwrap()["name"], "total");
+    assert!(phases.iter().all(|phase| phase["seconds"].is_f64() && phase["bytes"].is_u64()));
+}